@@ -0,0 +1,144 @@
+//! A plain text debugger for stepping a simulated machine by hand.
+//! After each step it renders the current state, the commands valid in
+//! that state and the most recent transitions, as seen by an observer
+//! attached to the runner.
+//!
+//! Run with `cargo run --example debugger` and enter commands at the prompt.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+use fsm_laboratory::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+use fsm_laboratory::descriptor::{Describe, MachineDescriptor};
+use fsm_laboratory::observer::Observer;
+use fsm_laboratory::runner::Runner;
+
+// A door that can be locked while closed
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Door {
+    Open,
+    Closed,
+    Locked,
+}
+
+#[derive(Debug)]
+enum DoorCommand {
+    Open,
+    Close,
+    Lock,
+    Unlock,
+}
+
+#[derive(Debug)]
+struct Moved(Door);
+
+impl Command<Door, ()> for DoorCommand {
+    type Output = Moved;
+    fn execute(&self, s: &Door, _h: &mut ()) -> Option<Moved> {
+        match (s, self) {
+            (Door::Closed, DoorCommand::Open) => Some(Moved(Door::Open)),
+            (Door::Open, DoorCommand::Close) => Some(Moved(Door::Closed)),
+            (Door::Closed, DoorCommand::Lock) => Some(Moved(Door::Locked)),
+            (Door::Locked, DoorCommand::Unlock) => Some(Moved(Door::Closed)),
+            _ => None,
+        }
+    }
+}
+
+impl Validate<Door> for DoorCommand {}
+
+impl Event<Door> for Moved {
+    fn fire(&self, s: &Door) -> Transition<Door> {
+        if *s == self.0 {
+            Transition::Same
+        } else {
+            Transition::Next(self.0)
+        }
+    }
+}
+
+struct DoorFsm {}
+
+impl Fsm<Door, ()> for DoorFsm {}
+
+impl Describe<Door> for DoorFsm {
+    fn descriptor() -> MachineDescriptor {
+        MachineDescriptor::new("door")
+            .transition("Closed", "Open", "Moved", "Open")
+            .transition("Open", "Close", "Moved", "Closed")
+            .transition("Closed", "Lock", "Moved", "Locked")
+            .transition("Locked", "Unlock", "Moved", "Closed")
+    }
+
+    fn state_name(state: &Door) -> String {
+        format!("{state:?}")
+    }
+}
+
+// Remembers recent transitions for display
+
+#[derive(Clone, Default)]
+struct Recent(Arc<Mutex<VecDeque<String>>>);
+
+impl Observer<(), Door> for Recent {
+    fn transitioned(&mut self, _key: &(), from: &Door, event: &str, to: &Door) {
+        let mut recent = self.0.lock().unwrap();
+        recent.push_front(format!("{from:?} -> {to:?} on {event}"));
+        recent.truncate(5);
+    }
+
+    fn ignored(&mut self, _key: &(), state: &Door, command: &str) {
+        let mut recent = self.0.lock().unwrap();
+        recent.push_front(format!("{command} ignored in {state:?}"));
+        recent.truncate(5);
+    }
+}
+
+fn render(runner: &Runner<(), Door, (), DoorFsm>, recent: &Recent) {
+    println!("----------------------------------------");
+    println!("state:    {:?}", runner.state(&()).unwrap());
+    println!(
+        "commands: {}",
+        runner.valid_commands(&()).unwrap_or_default().join(", ")
+    );
+    println!("recent:");
+    for line in recent.0.lock().unwrap().iter() {
+        println!("  {line}");
+    }
+}
+
+fn main() -> io::Result<()> {
+    let recent = Recent::default();
+    let mut runner =
+        Runner::<(), Door, (), DoorFsm>::new(()).with_observer(Box::new(recent.clone()));
+    runner.insert((), Door::Closed);
+
+    render(&runner, &recent);
+    let stdin = io::stdin();
+    loop {
+        print!("step> ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+        let command = match line.trim().to_lowercase().as_str() {
+            "open" => DoorCommand::Open,
+            "close" => DoorCommand::Close,
+            "lock" => DoorCommand::Lock,
+            "unlock" => DoorCommand::Unlock,
+            "quit" | "q" => break,
+            other => {
+                println!("unknown command {other:?}");
+                continue;
+            }
+        };
+        if let Err(e) = runner.send(&(), &command) {
+            println!("rejected: {e:?}");
+        }
+        render(&runner, &recent);
+    }
+    Ok(())
+}
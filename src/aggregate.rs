@@ -0,0 +1,195 @@
+//! An aggregate in the style of event sourcing frameworks. It bundles the
+//! state of one entity with its version, the number of events applied,
+//! and the events emitted since they were last taken for persistence.
+//! Commands are handled with `Fsm::step` and events applied with
+//! `Fsm::for_event_view`.
+
+use std::marker::PhantomData;
+
+use crate::batch::{apply_batch, Batch, Invariant};
+use crate::command_and_event_traits::{
+    Command, Event, Fsm, Rejection, StepError, Transition, Validate,
+};
+
+/// The state of an entity of FSM F with events of type E.
+pub struct Aggregate<S, H, F, E> {
+    state: S,
+    version: u64,
+    uncommitted: Vec<E>,
+    fsm: PhantomData<fn(&mut H) -> F>,
+}
+
+impl<S, H, F, E> Aggregate<S, H, F, E>
+where
+    F: Fsm<S, H>,
+    E: Event<S>,
+{
+    /// A new aggregate at version 0.
+    pub fn new(state: S) -> Self {
+        Self {
+            state,
+            version: 0,
+            uncommitted: Vec::new(),
+            fsm: PhantomData,
+        }
+    }
+
+    /// Rehydrate an aggregate from its history of events.
+    pub fn from_events(initial: S, events: impl IntoIterator<Item = E>) -> Self {
+        let mut aggregate = Self::new(initial);
+        for event in events {
+            aggregate.apply(&event);
+        }
+        aggregate
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// The number of events applied.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Handle a command, applying and recording the event emitted, if any.
+    pub fn handle<C>(&mut self, command: &C, handler: &mut H) -> Result<Option<&E>, StepError>
+    where
+        C: Command<S, H, Output = E> + Validate<S>,
+    {
+        let (event, transition) = F::step(&self.state, command, handler);
+        let transition = transition?;
+        let Some(event) = event else {
+            return Ok(None);
+        };
+        if let Transition::Next(s) = transition {
+            self.state = s;
+        }
+        self.version += 1;
+        self.uncommitted.push(event);
+        Ok(self.uncommitted.last())
+    }
+
+    /// Handle a command emitting a batch of events, applying and recording
+    /// all of them, or none if any leads to a state violating an invariant.
+    pub fn handle_batch<C>(&mut self, command: &C, handler: &mut H) -> Result<&[E], Rejection>
+    where
+        C: Command<S, H, Output = Batch<E>> + Validate<S>,
+        F: Invariant<S>,
+    {
+        command.validate(&self.state)?;
+        let Some(Batch(events)) = F::for_command(&self.state, command, handler) else {
+            return Ok(&[]);
+        };
+        if let Transition::Next(s) = apply_batch::<F, S, E>(&self.state, &events)? {
+            self.state = s;
+        }
+        self.version += events.len() as u64;
+        let from = self.uncommitted.len();
+        self.uncommitted.extend(events);
+        Ok(&self.uncommitted[from..])
+    }
+
+    /// Apply an event without recording it, as when rehydrating.
+    pub fn apply(&mut self, event: &E) {
+        if let Transition::Next(s) = F::for_event_view(&self.state, event) {
+            self.state = s;
+        }
+        self.version += 1;
+    }
+
+    /// The events emitted since last taken, e.g. to be persisted.
+    pub fn take_uncommitted(&mut self) -> Vec<E> {
+        std::mem::take(&mut self.uncommitted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::ValidationError;
+
+    #[test]
+    fn test_aggregate() {
+        // A bank account which cannot be overdrawn
+
+        #[derive(Debug, PartialEq)]
+        struct Account {
+            balance: u32,
+        }
+
+        enum Transact {
+            Deposit(u32),
+            Withdraw(u32),
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Transacted {
+            Deposited(u32),
+            Withdrew(u32),
+        }
+
+        impl Command<Account, ()> for Transact {
+            type Output = Transacted;
+            fn execute(&self, _s: &Account, _h: &mut ()) -> Option<Transacted> {
+                match self {
+                    Transact::Deposit(0) | Transact::Withdraw(0) => None,
+                    Transact::Deposit(n) => Some(Transacted::Deposited(*n)),
+                    Transact::Withdraw(n) => Some(Transacted::Withdrew(*n)),
+                }
+            }
+        }
+
+        impl Validate<Account> for Transact {
+            fn validate(&self, s: &Account) -> Result<(), ValidationError> {
+                match self {
+                    Transact::Withdraw(n) if *n > s.balance => {
+                        Err(ValidationError("insufficient funds".to_string()))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        impl Event<Account> for Transacted {
+            fn fire(&self, s: &Account) -> Transition<Account> {
+                let balance = match self {
+                    Transacted::Deposited(n) => s.balance + n,
+                    Transacted::Withdrew(n) => s.balance - n,
+                };
+                Transition::Next(Account { balance })
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<Account, ()> for MyFsm {}
+
+        let mut account = Aggregate::<_, (), MyFsm, Transacted>::new(Account { balance: 0 });
+        assert_eq!(
+            account.handle(&Transact::Deposit(10), &mut ()),
+            Ok(Some(&Transacted::Deposited(10)))
+        );
+        assert_eq!(account.handle(&Transact::Deposit(0), &mut ()), Ok(None));
+        assert_eq!(
+            account.handle(&Transact::Withdraw(20), &mut ()),
+            Err(StepError::Invalid(ValidationError(
+                "insufficient funds".to_string()
+            )))
+        );
+        account.handle(&Transact::Withdraw(3), &mut ()).unwrap();
+        assert_eq!(account.state(), &Account { balance: 7 });
+        assert_eq!(account.version(), 2);
+
+        let history = account.take_uncommitted();
+        assert_eq!(
+            history,
+            vec![Transacted::Deposited(10), Transacted::Withdrew(3)]
+        );
+        assert!(account.take_uncommitted().is_empty());
+
+        let rehydrated = Aggregate::<_, (), MyFsm, _>::from_events(Account { balance: 0 }, history);
+        assert_eq!(rehydrated.state(), account.state());
+        assert_eq!(rehydrated.version(), 2);
+    }
+}
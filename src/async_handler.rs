@@ -0,0 +1,906 @@
+//! Asynchronous effects. An async command does not perform its effect
+//! while the runner waits. Instead it returns the effect as a future,
+//! which is awaited by the caller's task, leaving the runner free to
+//! step other entities. The event produced is then applied by the runner.
+//!
+//! The effect handler of an async command is an `AsyncHandler`, which is
+//! cloned into each effect in flight. Typically it is a cheap handle to
+//! shared clients with async methods, for HTTP calls or database writes.
+//!
+//! Each effect is given a `CancellationToken`. The effect is abandoned,
+//! and its event not applied, if the token is cancelled: when the entity
+//! is removed, or when a command that supersedes it arrives, even after
+//! the effect has completed but before the runner applies its event. An
+//! effect may also watch the token to clean up after itself.
+//!
+//! An effect may also be given a deadline, either by the command or as a
+//! default for the runner. An effect that overruns it is cancelled and
+//! answered with the command's timeout event, if it has one, or else with
+//! `RunError::TimedOut`.
+//!
+//! Since other commands may be stepped while an effect is in flight, the
+//! event is applied to the entity's state as it is when the effect
+//! completes, which may differ from the state the command was validated
+//! against. The `before_command` hook is not called for async commands.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::async_runner::{Admission, Executor, Paced, Task};
+use crate::command_and_event_traits::{Event, Fsm, StepError, Validate};
+use crate::correlation::Correlated;
+use crate::mailbox::{mailbox, reply, Receiver, ReplySender, Sender};
+use crate::observer::type_label;
+use crate::runner::{RunError, Runner};
+
+/// An effect handler for asynchronous effects, shared by cloning.
+pub trait AsyncHandler: Clone + Send + Sync + 'static {}
+
+impl<H> AsyncHandler for H where H: Clone + Send + Sync + 'static {}
+
+/// An effect in flight, resolving to the event emitted, if any.
+pub type Effect<E> = Pin<Box<dyn Future<Output = Option<E>> + Send>>;
+
+/// A command whose effect is asynchronous.
+pub trait AsyncCommand<S, H: AsyncHandler> {
+    type Output: Event<S>;
+    fn execute(&self, state: &S, handler: H, cancel: CancellationToken) -> Effect<Self::Output>;
+
+    /// Whether this command cancels the effects in flight for its entity.
+    fn supersedes(&self) -> bool {
+        false
+    }
+
+    /// The deadline for this command's effect, overriding the runner's.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The event to apply if the effect overruns its deadline.
+    fn timed_out(&self, _state: &S) -> Option<Self::Output> {
+        None
+    }
+
+    /// The name of the command, as `Fsm::allows` and a descriptor know
+    /// it. By default the `type_label` of its type.
+    fn name(&self) -> String {
+        type_label::<Self>()
+    }
+}
+
+/// A correlated async command emits its event correlated with it.
+impl<S, H, C> AsyncCommand<S, H> for Correlated<C>
+where
+    H: AsyncHandler,
+    C: AsyncCommand<S, H>,
+    C::Output: 'static,
+{
+    type Output = Correlated<C::Output>;
+    fn execute(&self, state: &S, handler: H, cancel: CancellationToken) -> Effect<Self::Output> {
+        let ids = self.ids.event();
+        let effect = self.body.execute(state, handler, cancel);
+        Box::pin(async move { effect.await.map(|e| Correlated::new(ids, e)) })
+    }
+
+    fn supersedes(&self) -> bool {
+        self.body.supersedes()
+    }
+
+    fn timeout(&self) -> Option<Duration> {
+        self.body.timeout()
+    }
+
+    fn timed_out(&self, state: &S) -> Option<Self::Output> {
+        let event = self.body.timed_out(state)?;
+        Some(Correlated::new(self.ids.event(), event))
+    }
+
+    fn name(&self) -> String {
+        self.body.name()
+    }
+}
+
+#[derive(Default)]
+struct Cancellation {
+    cancelled: bool,
+    wakers: Vec<Waker>,
+}
+
+/// Signals that an effect should be abandoned.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<Mutex<Cancellation>>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cancellation> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn cancel(&self) {
+        let mut c = self.lock();
+        c.cancelled = true;
+        for w in c.wakers.drain(..) {
+            w.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.lock().cancelled
+    }
+
+    /// A future that completes when the token is cancelled.
+    pub fn cancelled(&self) -> Cancelled {
+        Cancelled(self.clone())
+    }
+
+    fn same(&self, other: &CancellationToken) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// The future of `CancellationToken::cancelled`.
+pub struct Cancelled(CancellationToken);
+
+impl Future for Cancelled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut c = self.0.lock();
+        if c.cancelled {
+            Poll::Ready(())
+        } else {
+            if !c.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                c.wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+
+/// An effect ready to be awaited.
+struct Prepared<E> {
+    effect: Effect<E>,
+    cancel: CancellationToken,
+    timeout: Option<Duration>,
+    timed_out: Option<E>,
+}
+
+enum Finished<E> {
+    Done(Option<E>),
+    Cancelled,
+    TimedOut,
+}
+
+/// Await an effect unless it is cancelled or its timer completes first,
+/// dropping it if so.
+struct Abortable<E> {
+    effect: Effect<E>,
+    cancelled: Cancelled,
+    timer: Option<Task>,
+}
+
+impl<E> Future for Abortable<E> {
+    type Output = Finished<E>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if Pin::new(&mut self.cancelled).poll(cx).is_ready() {
+            return Poll::Ready(Finished::Cancelled);
+        }
+        if let Some(timer) = &mut self.timer {
+            if timer.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Finished::TimedOut);
+            }
+        }
+        self.effect.as_mut().poll(cx).map(Finished::Done)
+    }
+}
+
+enum Request<K, C, E> {
+    Execute {
+        key: K,
+        command: C,
+        reply: ReplySender<Admission<K, C, Result<Prepared<E>, RunError>>>,
+    },
+    Apply {
+        key: K,
+        event: E,
+        cancel: CancellationToken,
+        reply: ReplySender<Result<Option<E>, RunError>>,
+    },
+    Finished {
+        key: K,
+        cancel: CancellationToken,
+    },
+    Remove {
+        key: K,
+    },
+}
+
+/// Tells the runner's task that an effect has finished, however the
+/// future awaiting it ends, so that its token is forgotten.
+struct Finishing<K, C, E> {
+    key: Option<K>,
+    cancel: CancellationToken,
+    mailbox: Sender<Request<K, C, E>>,
+}
+
+impl<K, C, E> Drop for Finishing<K, C, E> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let cancel = self.cancel.clone();
+            let _ = self.mailbox.send(Request::Finished { key, cancel });
+        }
+    }
+}
+
+/// A handle to a runner in a task, accepting async commands of type C
+/// for entities with keys K, and answering with events of type E.
+pub struct AsyncEffectRunner<K, C, E> {
+    mailbox: Sender<Request<K, C, E>>,
+    executor: Arc<dyn Executor + Send + Sync>,
+    timeout: Option<Duration>,
+}
+
+impl<K, C, E> Clone for AsyncEffectRunner<K, C, E> {
+    fn clone(&self) -> Self {
+        Self {
+            mailbox: self.mailbox.clone(),
+            executor: self.executor.clone(),
+            timeout: self.timeout,
+        }
+    }
+}
+
+impl<K, C, E> AsyncEffectRunner<K, C, E>
+where
+    K: Clone + Send + 'static,
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawn a task owning the runner. The task ends when all handles to it
+    /// have been dropped.
+    pub fn spawn<S, H, F, X>(runner: Runner<K, S, H, F>, executor: X) -> Self
+    where
+        K: Eq + Hash + Clone,
+        S: Send + 'static,
+        H: AsyncHandler,
+        F: Fsm<S, H> + 'static,
+        C: AsyncCommand<S, H, Output = E> + Validate<S>,
+        E: Event<S>,
+        X: Executor + Send + Sync + 'static,
+    {
+        Self::spawn_with(runner, executor, |_, _, _| Ok(()))
+    }
+
+    /// Spawn a task owning the runner, which refuses commands that `check`
+    /// fails before their effects start.
+    pub(crate) fn spawn_with<S, H, F, X>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        check: impl FnMut(&mut Runner<K, S, H, F>, &K, &C) -> Result<(), RunError> + Send + 'static,
+    ) -> Self
+    where
+        K: Eq + Hash + Clone,
+        S: Send + 'static,
+        H: AsyncHandler,
+        F: Fsm<S, H> + 'static,
+        C: AsyncCommand<S, H, Output = E> + Validate<S>,
+        E: Event<S>,
+        X: Executor + Send + Sync + 'static,
+    {
+        let mut check = check;
+        Self::spawn_paced(runner, executor, move |runner, key, command| {
+            Ok(check(runner, key, command))
+        })
+    }
+
+    /// Spawn a task owning the runner, which refuses commands that `check`
+    /// fails before their effects start, or learns from it how long to
+    /// wait before a command can start. Such a command is returned to its
+    /// sender, which sends it again after waiting.
+    pub(crate) fn spawn_paced<S, H, F, X>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        check: impl FnMut(&mut Runner<K, S, H, F>, &K, &C) -> Paced<Result<(), RunError>>
+            + Send
+            + 'static,
+    ) -> Self
+    where
+        K: Eq + Hash + Clone,
+        S: Send + 'static,
+        H: AsyncHandler,
+        F: Fsm<S, H> + 'static,
+        C: AsyncCommand<S, H, Output = E> + Validate<S>,
+        E: Event<S>,
+        X: Executor + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mailbox();
+        executor.spawn(Box::pin(run(runner, receiver, check)));
+        Self {
+            mailbox: sender,
+            executor: Arc::new(executor),
+            timeout: None,
+        }
+    }
+
+    /// Give effects sent through this handle a deadline, unless their
+    /// commands specify one.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send a command to an entity, await its effect, and then the
+    /// event emitted, if any.
+    pub async fn send(&self, key: K, command: C) -> Result<Option<E>, RunError> {
+        let mut command = command;
+        let prepared = loop {
+            let (reply_to, replied) = reply();
+            self.post(Request::Execute {
+                key: key.clone(),
+                command,
+                reply: reply_to,
+            })?;
+            match replied.await {
+                Some(Admission::Done(prepared)) => break prepared?,
+                Some(Admission::Wait(wait, _, c)) => {
+                    self.executor.sleep(wait).await;
+                    command = c;
+                }
+                None => return Err(RunError::Stopped),
+            }
+        };
+        let cancel = prepared.cancel;
+        let _finishing = Finishing {
+            key: Some(key.clone()),
+            cancel: cancel.clone(),
+            mailbox: self.mailbox.clone(),
+        };
+        let finished = Abortable {
+            effect: prepared.effect,
+            cancelled: cancel.cancelled(),
+            timer: prepared
+                .timeout
+                .or(self.timeout)
+                .map(|d| self.executor.sleep(d)),
+        }
+        .await;
+        let event = match finished {
+            Finished::Done(Some(_)) | Finished::TimedOut if cancel.is_cancelled() => {
+                return Err(RunError::Cancelled)
+            }
+            Finished::Done(Some(event)) => event,
+            Finished::Done(None) => return Ok(None),
+            Finished::Cancelled => return Err(RunError::Cancelled),
+            Finished::TimedOut => {
+                cancel.cancel();
+                prepared.timed_out.ok_or(RunError::TimedOut)?
+            }
+        };
+        let (reply_to, replied) = reply();
+        // The runner checks the token again, in case a command that
+        // supersedes this one arrives first
+        self.post(Request::Apply {
+            key,
+            event,
+            cancel,
+            reply: reply_to,
+        })?;
+        replied.await.unwrap_or(Err(RunError::Stopped))
+    }
+
+    /// Remove an entity, cancelling its effects in flight.
+    pub fn remove(&self, key: K) -> Result<(), RunError> {
+        self.post(Request::Remove { key })
+    }
+
+    fn post(&self, request: Request<K, C, E>) -> Result<(), RunError> {
+        self.mailbox.send(request).map_err(|_| RunError::Stopped)
+    }
+}
+
+async fn run<K, S, H, F, C>(
+    mut runner: Runner<K, S, H, F>,
+    mut mailbox: Receiver<Request<K, C, C::Output>>,
+    mut check: impl FnMut(&mut Runner<K, S, H, F>, &K, &C) -> Paced<Result<(), RunError>>,
+) where
+    K: Eq + Hash + Clone,
+    H: AsyncHandler,
+    F: Fsm<S, H>,
+    C: AsyncCommand<S, H> + Validate<S>,
+{
+    let mut in_flight: HashMap<K, Vec<CancellationToken>> = HashMap::new();
+    while let Some(request) = mailbox.recv().await {
+        match request {
+            Request::Execute {
+                key,
+                command,
+                reply,
+            } => {
+                // A refused or waiting command does not supersede others
+                let checked = match check(&mut runner, &key, &command) {
+                    Ok(checked) => checked,
+                    Err(wait) => {
+                        reply.send(Admission::Wait(wait, key, command));
+                        continue;
+                    }
+                };
+                let cancel = CancellationToken::new();
+                let result = checked.and_then(|_| {
+                    if command.supersedes() {
+                        cancel_all(&mut in_flight, &key);
+                    }
+                    execute(&runner, &key, &command, cancel.clone())
+                });
+                if result.is_ok() {
+                    in_flight.entry(key).or_default().push(cancel);
+                }
+                reply.send(Admission::Done(result))
+            }
+            Request::Apply {
+                key,
+                event,
+                cancel,
+                reply,
+            } => {
+                // Superseding and removal forget the tokens they cancel,
+                // while a timeout cancels one still in flight
+                let tokens = in_flight.get(&key).map(Vec::as_slice).unwrap_or_default();
+                match tokens.iter().any(|t| t.same(&cancel)) {
+                    true => reply.send(runner.apply(&key, event)),
+                    false => reply.send(Err(RunError::Cancelled)),
+                }
+            }
+            Request::Finished { key, cancel } => {
+                if let Some(tokens) = in_flight.get_mut(&key) {
+                    tokens.retain(|t| !t.same(&cancel));
+                    if tokens.is_empty() {
+                        in_flight.remove(&key);
+                    }
+                }
+            }
+            Request::Remove { key } => {
+                cancel_all(&mut in_flight, &key);
+                runner.remove(&key);
+            }
+        }
+    }
+}
+
+fn cancel_all<K: Eq + Hash>(in_flight: &mut HashMap<K, Vec<CancellationToken>>, key: &K) {
+    for token in in_flight.remove(key).unwrap_or_default() {
+        token.cancel();
+    }
+}
+
+fn execute<K, S, H, F, C>(
+    runner: &Runner<K, S, H, F>,
+    key: &K,
+    command: &C,
+    cancel: CancellationToken,
+) -> Result<Prepared<C::Output>, RunError>
+where
+    K: Eq + Hash,
+    H: AsyncHandler,
+    F: Fsm<S, H>,
+    C: AsyncCommand<S, H> + Validate<S>,
+{
+    let state = runner.state(key).ok_or_else(|| match runner.failure(key) {
+        Some(reason) => RunError::Failed(reason.to_string()),
+        None => RunError::Unknown,
+    })?;
+    let name = command.name();
+    if !F::allows(state, &name) {
+        return Err(RunError::Step(StepError::NotAllowed(name)));
+    }
+    command
+        .validate(state)
+        .map_err(|e| RunError::Step(StepError::Invalid(e)))?;
+    Ok(Prepared {
+        effect: command.execute(state, runner.handler().clone(), cancel.clone()),
+        cancel,
+        timeout: command.timeout(),
+        timed_out: command.timed_out(state),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_runner::{block_on, Sleep, ThreadExecutor};
+    use crate::command_and_event_traits::{Transition, ValidationError};
+    use crate::correlation::Correlation;
+    use crate::descriptor::{Describe, MachineDescriptor};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_async_effects() {
+        // Accounts whose deposits are recorded by a slow ledger service
+
+        #[derive(Clone)]
+        struct Ledger {
+            writes: Arc<AtomicU32>,
+        }
+
+        impl Ledger {
+            async fn record(&self, amount: u32) -> bool {
+                ThreadExecutor.sleep(Duration::from_millis(50)).await;
+                self.writes.fetch_add(1, Ordering::SeqCst);
+                amount > 0
+            }
+        }
+
+        struct Deposit(u32);
+
+        #[derive(Debug, PartialEq)]
+        struct Deposited(u32);
+
+        impl AsyncCommand<u32, Ledger> for Deposit {
+            type Output = Deposited;
+            fn execute(
+                &self,
+                _s: &u32,
+                ledger: Ledger,
+                _cancel: CancellationToken,
+            ) -> Effect<Deposited> {
+                let amount = self.0;
+                Box::pin(async move { ledger.record(amount).await.then_some(Deposited(amount)) })
+            }
+        }
+
+        impl Validate<u32> for Deposit {}
+
+        impl Event<u32> for Deposited {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s + self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, Ledger> for MyFsm {}
+
+        let ledger = Ledger {
+            writes: Arc::new(AtomicU32::new(0)),
+        };
+        let mut runner = Runner::<&str, u32, Ledger, MyFsm>::new(ledger.clone());
+        runner.insert("a", 0);
+        runner.insert("b", 0);
+        let handle = AsyncEffectRunner::spawn(runner, ThreadExecutor);
+
+        // Effects for both accounts are in flight at once
+
+        let other = handle.clone();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || sender.send(block_on(other.send("b", Deposit(5)))));
+        block_on(async {
+            assert_eq!(handle.send("a", Deposit(10)).await, Ok(Some(Deposited(10))));
+            assert_eq!(handle.send("a", Deposit(0)).await, Ok(None));
+            assert_eq!(handle.send("c", Deposit(1)).await, Err(RunError::Unknown));
+        });
+        assert_eq!(receiver.recv().unwrap(), Ok(Some(Deposited(5))));
+        assert_eq!(ledger.writes.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_cancellation() {
+        // Jobs that take a while, where a newer job may supersede older ones
+
+        struct Job {
+            millis: u64,
+            supersede: bool,
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Done(u64);
+
+        impl AsyncCommand<u64, ()> for Job {
+            type Output = Done;
+            fn execute(&self, _s: &u64, _h: (), _cancel: CancellationToken) -> Effect<Done> {
+                let millis = self.millis;
+                Box::pin(async move {
+                    ThreadExecutor.sleep(Duration::from_millis(millis)).await;
+                    Some(Done(millis))
+                })
+            }
+
+            fn supersedes(&self) -> bool {
+                self.supersede
+            }
+        }
+
+        impl Validate<u64> for Job {}
+
+        impl Event<u64> for Done {
+            fn fire(&self, _s: &u64) -> Transition<u64> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u64, ()> for MyFsm {}
+
+        let mut runner = Runner::<&str, u64, (), MyFsm>::new(());
+        runner.insert("a", 0);
+        let handle = AsyncEffectRunner::spawn(runner, ThreadExecutor);
+        let slow = |handle: &AsyncEffectRunner<&'static str, Job, Done>| {
+            let handle = handle.clone();
+            std::thread::spawn(move || {
+                block_on(handle.send(
+                    "a",
+                    Job {
+                        millis: 500,
+                        supersede: false,
+                    },
+                ))
+            })
+        };
+
+        let superseded = slow(&handle);
+        std::thread::sleep(Duration::from_millis(50));
+        let quick = Job {
+            millis: 1,
+            supersede: true,
+        };
+        assert_eq!(block_on(handle.send("a", quick)), Ok(Some(Done(1))));
+        assert_eq!(superseded.join().unwrap(), Err(RunError::Cancelled));
+
+        let removed = slow(&handle);
+        std::thread::sleep(Duration::from_millis(50));
+        handle.remove("a").unwrap();
+        assert_eq!(removed.join().unwrap(), Err(RunError::Cancelled));
+    }
+
+    #[test]
+    fn test_superseded_before_apply() {
+        // An effect that completes while the runner is busy validating
+        // another command, after which a superseding command is queued
+        // ahead of its event
+
+        struct Job {
+            value: u64,
+            validating: u64,
+            effect: u64,
+            supersede: bool,
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Done(u64);
+
+        impl AsyncCommand<u64, ()> for Job {
+            type Output = Done;
+            fn execute(&self, _s: &u64, _h: (), _cancel: CancellationToken) -> Effect<Done> {
+                let (value, millis) = (self.value, self.effect);
+                Box::pin(async move {
+                    ThreadExecutor.sleep(Duration::from_millis(millis)).await;
+                    Some(Done(value))
+                })
+            }
+
+            fn supersedes(&self) -> bool {
+                self.supersede
+            }
+        }
+
+        impl Validate<u64> for Job {
+            fn validate(&self, _s: &u64) -> Result<(), ValidationError> {
+                std::thread::sleep(Duration::from_millis(self.validating));
+                Ok(())
+            }
+        }
+
+        impl Event<u64> for Done {
+            fn fire(&self, _s: &u64) -> Transition<u64> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u64, ()> for MyFsm {}
+
+        let mut runner = Runner::<&str, u64, (), MyFsm>::new(());
+        runner.insert("a", 0);
+        runner.insert("b", 0);
+        let handle = AsyncEffectRunner::spawn(runner, ThreadExecutor);
+        let job = |value, validating, effect, supersede| Job {
+            value,
+            validating,
+            effect,
+            supersede,
+        };
+        let send = |key, job| {
+            let handle = handle.clone();
+            std::thread::spawn(move || block_on(handle.send(key, job)))
+        };
+
+        let stale = send("a", job(1, 0, 100, false));
+        std::thread::sleep(Duration::from_millis(20));
+        let busy = send("b", job(2, 300, 0, false));
+        std::thread::sleep(Duration::from_millis(20));
+        let superseding = send("a", job(3, 0, 0, true));
+
+        assert_eq!(stale.join().unwrap(), Err(RunError::Cancelled));
+        assert_eq!(busy.join().unwrap(), Ok(Some(Done(2))));
+        assert_eq!(superseding.join().unwrap(), Ok(Some(Done(3))));
+    }
+
+    #[test]
+    fn test_finished_tokens_forgotten() {
+        // The runner holds a token only while its effect is in flight
+
+        struct Job(Arc<Mutex<Vec<CancellationToken>>>);
+
+        struct Set(u64);
+
+        impl AsyncCommand<u64, ()> for Job {
+            type Output = Set;
+            fn execute(&self, _s: &u64, _h: (), cancel: CancellationToken) -> Effect<Set> {
+                self.0.lock().unwrap().push(cancel);
+                Box::pin(async { None })
+            }
+        }
+
+        impl Validate<u64> for Job {}
+
+        impl Event<u64> for Set {
+            fn fire(&self, _s: &u64) -> Transition<u64> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u64, ()> for MyFsm {}
+
+        let mut runner = Runner::<&str, u64, (), MyFsm>::new(());
+        runner.insert("a", 0);
+        runner.insert("b", 0);
+        let handle = AsyncEffectRunner::spawn(runner, ThreadExecutor);
+        let tokens = Arc::new(Mutex::new(Vec::new()));
+        block_on(async {
+            assert!(handle.send("a", Job(tokens.clone())).await.is_ok());
+            // Once another request has been handled, the first was finished
+            assert!(handle.send("b", Job(tokens.clone())).await.is_ok());
+        });
+        let first = tokens.lock().unwrap()[0].clone();
+        assert_eq!(Arc::strong_count(&first.0), 2);
+    }
+
+    #[test]
+    fn test_timeout() {
+        // Waits that give up on a deadline, some falling back to an event
+
+        struct Wait {
+            millis: u64,
+            fallback: bool,
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Waited(u64);
+
+        impl AsyncCommand<u64, ()> for Wait {
+            type Output = Waited;
+            fn execute(&self, _s: &u64, _h: (), _cancel: CancellationToken) -> Effect<Waited> {
+                let millis = self.millis;
+                Box::pin(async move {
+                    ThreadExecutor.sleep(Duration::from_millis(millis)).await;
+                    Some(Waited(millis))
+                })
+            }
+
+            fn timed_out(&self, _s: &u64) -> Option<Waited> {
+                self.fallback.then_some(Waited(0))
+            }
+        }
+
+        impl Validate<u64> for Wait {}
+
+        impl Event<u64> for Waited {
+            fn fire(&self, _s: &u64) -> Transition<u64> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u64, ()> for MyFsm {}
+
+        let mut runner = Runner::<&str, u64, (), MyFsm>::new(());
+        runner.insert("a", 7);
+        let handle = AsyncEffectRunner::spawn(runner, ThreadExecutor)
+            .with_timeout(Duration::from_millis(100));
+        let wait = |millis, fallback| Wait { millis, fallback };
+
+        block_on(async {
+            assert_eq!(
+                handle.send("a", wait(1000, false)).await,
+                Err(RunError::TimedOut)
+            );
+            assert_eq!(
+                handle.send("a", wait(1000, true)).await,
+                Ok(Some(Waited(0)))
+            );
+            assert_eq!(handle.send("a", wait(1, true)).await, Ok(Some(Waited(1))));
+        });
+    }
+
+    #[test]
+    fn test_allowed_commands() {
+        // A door, opened by a slow motor, whose descriptor declares the
+        // commands allowed in each state. A correlated command is allowed
+        // as the command it wraps.
+
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        enum Door {
+            Open,
+            Closed,
+        }
+
+        struct Open {}
+
+        #[derive(Debug, PartialEq)]
+        struct Opened {}
+
+        impl AsyncCommand<Door, ()> for Open {
+            type Output = Opened;
+            fn execute(&self, _s: &Door, _h: (), _cancel: CancellationToken) -> Effect<Opened> {
+                Box::pin(async {
+                    ThreadExecutor.sleep(Duration::from_millis(10)).await;
+                    Some(Opened {})
+                })
+            }
+        }
+
+        impl Validate<Door> for Open {}
+
+        impl Event<Door> for Opened {
+            fn fire(&self, _s: &Door) -> Transition<Door> {
+                Transition::Next(Door::Open)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Describe<Door> for MyFsm {
+            fn descriptor() -> MachineDescriptor {
+                MachineDescriptor::new("door").transition("Closed", "Open", "Opened", "Open")
+            }
+
+            fn state_name(state: &Door) -> String {
+                format!("{state:?}")
+            }
+        }
+
+        impl Fsm<Door, ()> for MyFsm {
+            fn allows(state: &Door, command: &str) -> bool {
+                Self::declares(state, command)
+            }
+        }
+
+        let mut runner = Runner::<u32, Door, (), MyFsm>::new(());
+        runner.insert(1, Door::Closed);
+        let handle = AsyncEffectRunner::spawn(runner, ThreadExecutor);
+        let open = || Correlated::new(Correlation::new("m1"), Open {});
+
+        block_on(async {
+            let opened = handle.send(1, open()).await;
+            assert_eq!(opened.unwrap().map(|e| e.body), Some(Opened {}));
+            assert_eq!(
+                handle.send(1, open()).await.unwrap_err(),
+                RunError::Step(StepError::NotAllowed("Open".to_string()))
+            );
+        });
+    }
+}
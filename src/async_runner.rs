@@ -0,0 +1,324 @@
+//! An asynchronous runner owns a `Runner` within a task and receives
+//! commands through a mailbox. Callers hold a cloneable `AsyncRunner`
+//! handle to send commands and await their events.
+//!
+//! The runner is independent of any particular async runtime. Tasks are
+//! spawned, and time is waited upon, through the `Spawn` and `Sleep`
+//! traits. These are a few lines to implement for tokio, async-std or
+//! smol, for example `smol::spawn(task).detach()` and
+//! `Box::pin(async move { smol::Timer::after(duration).await; })`.
+//! `ThreadExecutor` implements them with std threads.
+
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::command_and_event_traits::{Command, Fsm, Validate};
+use crate::mailbox::{mailbox, reply, Receiver, ReplySender, Sender};
+use crate::runner::{RunError, Runner};
+
+/// A boxed task, as spawned by an executor.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Spawns tasks to run concurrently.
+pub trait Spawn {
+    fn spawn(&self, task: Task);
+}
+
+/// Provides futures that complete after a duration.
+pub trait Sleep {
+    fn sleep(&self, duration: Duration) -> Task;
+}
+
+/// An executor can both spawn tasks and sleep.
+pub trait Executor: Spawn + Sleep {}
+
+impl<X> Executor for X where X: Spawn + Sleep {}
+
+/// An executor running each task on its own thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadExecutor;
+
+impl Spawn for ThreadExecutor {
+    fn spawn(&self, task: Task) {
+        thread::spawn(move || block_on(task));
+    }
+}
+
+impl Sleep for ThreadExecutor {
+    fn sleep(&self, duration: Duration) -> Task {
+        Box::pin(Delay {
+            deadline: Instant::now() + duration,
+            timer: None,
+        })
+    }
+}
+
+struct Delay {
+    deadline: Instant,
+    timer: Option<Arc<Mutex<Option<Waker>>>>,
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if Instant::now() >= self.deadline {
+            return Poll::Ready(());
+        }
+        match &self.timer {
+            Some(waker) => {
+                *waker.lock().unwrap_or_else(|e| e.into_inner()) = Some(cx.waker().clone())
+            }
+            None => {
+                let waker = Arc::new(Mutex::new(Some(cx.waker().clone())));
+                let deadline = self.deadline;
+                let timer = waker.clone();
+                thread::spawn(move || {
+                    thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                    if let Some(w) = timer.lock().unwrap_or_else(|e| e.into_inner()).take() {
+                        w.wake();
+                    }
+                });
+                self.timer = Some(waker);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+struct Unpark(thread::Thread);
+
+impl Wake for Unpark {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run a future to completion on the current thread.
+pub fn block_on<T>(future: impl Future<Output = T>) -> T {
+    let mut future = std::pin::pin!(future);
+    let waker = Waker::from(Arc::new(Unpark(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(value) = future.as_mut().poll(&mut cx) {
+            return value;
+        }
+        thread::park();
+    }
+}
+
+/// An outcome, or how long to wait before the command can be stepped.
+pub(crate) type Paced<T> = Result<T, Duration>;
+
+/// A runner's answer to a command: the outcome, or how long the sender
+/// should wait before sending the command, returned with it, again.
+pub(crate) enum Admission<K, C, T> {
+    Done(T),
+    Wait(Duration, K, C),
+}
+
+struct Request<K, C, E> {
+    key: K,
+    command: C,
+    reply: ReplySender<Admission<K, C, Result<Option<E>, RunError>>>,
+}
+
+/// A handle to a runner in a task, accepting commands of type C for
+/// entities with keys K, and answering with events of type E.
+pub struct AsyncRunner<K, C, E> {
+    mailbox: Sender<Request<K, C, E>>,
+    executor: Arc<dyn Executor + Send + Sync>,
+}
+
+impl<K, C, E> Clone for AsyncRunner<K, C, E> {
+    fn clone(&self) -> Self {
+        Self {
+            mailbox: self.mailbox.clone(),
+            executor: self.executor.clone(),
+        }
+    }
+}
+
+impl<K, C, E> AsyncRunner<K, C, E>
+where
+    K: Send + 'static,
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawn a task owning the runner. The task ends when all handles to it
+    /// have been dropped.
+    pub fn spawn<S, H, F, X>(runner: Runner<K, S, H, F>, executor: X) -> Self
+    where
+        K: Eq + Hash,
+        S: Send + 'static,
+        H: Send + 'static,
+        F: Fsm<S, H> + 'static,
+        C: Command<S, H, Output = E> + Validate<S>,
+        X: Executor + Send + Sync + 'static,
+    {
+        Self::spawn_with(runner, executor, |runner, key, command| {
+            runner.send(key, command)
+        })
+    }
+
+    /// Spawn a task owning the runner, stepping commands with `step`.
+    pub(crate) fn spawn_with<S, H, F, X>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        step: impl FnMut(&mut Runner<K, S, H, F>, &K, &C) -> Result<Option<E>, RunError>
+            + Send
+            + 'static,
+    ) -> Self
+    where
+        K: Eq + Hash,
+        S: Send + 'static,
+        H: Send + 'static,
+        F: Fsm<S, H> + 'static,
+        X: Executor + Send + Sync + 'static,
+    {
+        let mut step = step;
+        Self::spawn_paced(runner, executor, move |runner, key, command| {
+            Ok(step(runner, key, command))
+        })
+    }
+
+    /// Spawn a task owning the runner, stepping commands with `step`, or
+    /// learning from it how long to wait before a command can be stepped.
+    /// Such a command is returned to its sender, which sends it again
+    /// after waiting, so that the task is not held up in the meantime.
+    pub(crate) fn spawn_paced<S, H, F, X>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        step: impl FnMut(&mut Runner<K, S, H, F>, &K, &C) -> Paced<Result<Option<E>, RunError>>
+            + Send
+            + 'static,
+    ) -> Self
+    where
+        K: Eq + Hash,
+        S: Send + 'static,
+        H: Send + 'static,
+        F: Fsm<S, H> + 'static,
+        X: Executor + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mailbox();
+        executor.spawn(Box::pin(run(runner, receiver, step)));
+        Self {
+            mailbox: sender,
+            executor: Arc::new(executor),
+        }
+    }
+
+    /// Send a command to an entity and await the event emitted, if any.
+    pub async fn send(&self, key: K, command: C) -> Result<Option<E>, RunError> {
+        let (mut key, mut command) = (key, command);
+        loop {
+            let (reply, replied) = reply();
+            let request = Request {
+                key,
+                command,
+                reply,
+            };
+            if self.mailbox.send(request).is_err() {
+                return Err(RunError::Stopped);
+            }
+            match replied.await {
+                Some(Admission::Done(result)) => return result,
+                Some(Admission::Wait(wait, k, c)) => {
+                    self.executor.sleep(wait).await;
+                    (key, command) = (k, c);
+                }
+                None => return Err(RunError::Stopped),
+            }
+        }
+    }
+
+    /// Send a command to an entity after a delay, without awaiting its event.
+    pub fn send_after(&self, delay: Duration, key: K, command: C) {
+        let handle = self.clone();
+        let sleep = self.executor.sleep(delay);
+        self.executor.spawn(Box::pin(async move {
+            sleep.await;
+            let _ = handle.send(key, command).await;
+        }));
+    }
+}
+
+async fn run<K, S, H, F, C, E>(
+    mut runner: Runner<K, S, H, F>,
+    mut mailbox: Receiver<Request<K, C, E>>,
+    mut step: impl FnMut(&mut Runner<K, S, H, F>, &K, &C) -> Paced<Result<Option<E>, RunError>>,
+) {
+    while let Some(Request {
+        key,
+        command,
+        reply,
+    }) = mailbox.recv().await
+    {
+        match step(&mut runner, &key, &command) {
+            Ok(result) => reply.send(Admission::Done(result)),
+            Err(wait) => reply.send(Admission::Wait(wait, key, command)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Transition};
+
+    #[test]
+    fn test_async_runner() {
+        // A counter incremented by commands
+
+        struct Increment {}
+
+        #[derive(Debug, PartialEq)]
+        struct Incremented(u32);
+
+        impl Command<u32, ()> for Increment {
+            type Output = Incremented;
+            fn execute(&self, s: &u32, _h: &mut ()) -> Option<Incremented> {
+                Some(Incremented(s + 1))
+            }
+        }
+
+        impl Validate<u32> for Increment {}
+
+        impl Event<u32> for Incremented {
+            fn fire(&self, _s: &u32) -> Transition<u32> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, ()> for MyFsm {}
+
+        let mut runner = Runner::<&str, u32, (), MyFsm>::new(());
+        runner.insert("a", 0);
+        let handle = AsyncRunner::spawn(runner, ThreadExecutor);
+
+        block_on(async {
+            assert_eq!(
+                handle.send("a", Increment {}).await,
+                Ok(Some(Incremented(1)))
+            );
+            assert_eq!(
+                handle.clone().send("b", Increment {}).await,
+                Err(RunError::Unknown)
+            );
+
+            handle.send_after(Duration::from_millis(10), "a", Increment {});
+            ThreadExecutor.sleep(Duration::from_millis(100)).await;
+            assert_eq!(
+                handle.send("a", Increment {}).await,
+                Ok(Some(Incremented(3)))
+            );
+        });
+    }
+}
@@ -0,0 +1,310 @@
+//! An AsyncAPI document for carrying a machine's messages over a broker,
+//! generated from its `MachineSchemas` so that the event catalog agrees
+//! with the code. A machine has two channels, a Kafka topic or NATS or
+//! MQTT subject for each direction:
+//!
+//! ```text
+//! {name}.commands   commands the machine receives
+//! {name}.events     events the machine sends
+//! ```
+//!
+//! Each command and event is a message whose payload is its schema. On
+//! Kafka the message key is the entity's key, which places it in
+//! partition `shard_of(&key, partitions)`. The document is AsyncAPI 3.0,
+//! whose payloads default to JSON Schema, so the command and event
+//! schemas are its components as they are.
+
+use crate::json::Json;
+use crate::json_schema::MachineSchemas;
+
+fn text(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        members
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+fn reference(path: &str) -> Json {
+    object(vec![("$ref", text(path))])
+}
+
+/// A broker that serves the channels.
+struct Server {
+    name: String,
+    host: String,
+    protocol: String,
+}
+
+/// An AsyncAPI document for a machine's channels.
+pub struct AsyncApi<'a> {
+    schemas: &'a MachineSchemas,
+    version: String,
+    commands: String,
+    events: String,
+    servers: Vec<Server>,
+}
+
+impl<'a> AsyncApi<'a> {
+    /// The channels `{name}.commands` and `{name}.events`, on no server.
+    pub fn new(schemas: &'a MachineSchemas) -> Self {
+        Self {
+            schemas,
+            version: "1".to_string(),
+            commands: format!("{}.commands", schemas.name),
+            events: format!("{}.events", schemas.name),
+            servers: Vec::new(),
+        }
+    }
+
+    /// The version of the API, as given in the document's info.
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Other addresses for the channels, the topics or subjects to which
+    /// commands and events are published.
+    pub fn with_addresses(mut self, commands: &str, events: &str) -> Self {
+        self.commands = commands.to_string();
+        self.events = events.to_string();
+        self
+    }
+
+    /// A broker serving the channels, with a protocol such as `kafka`,
+    /// `nats` or `mqtt`.
+    pub fn with_server(mut self, name: &str, host: &str, protocol: &str) -> Self {
+        self.servers.push(Server {
+            name: name.to_string(),
+            host: host.to_string(),
+            protocol: protocol.to_string(),
+        });
+        self
+    }
+
+    fn message(&self, name: &str) -> Json {
+        let mut members = vec![
+            ("name", text(name)),
+            ("contentType", text("application/json")),
+            (
+                "payload",
+                reference(&format!("#/components/schemas/{name}")),
+            ),
+        ];
+        if self.servers.iter().any(|s| s.protocol == "kafka") {
+            let key = object(vec![("key", object(vec![("type", text("string"))]))]);
+            members.push(("bindings", object(vec![("kafka", key)])));
+        }
+        object(members)
+    }
+
+    /// The AsyncAPI document.
+    pub fn document(&self) -> Json {
+        let names = |messages: &[(String, Json)]| -> Vec<String> {
+            messages.iter().map(|(n, _)| n.clone()).collect()
+        };
+        let commands = names(&self.schemas.commands);
+        let events = names(&self.schemas.events);
+
+        let channel = |address: &str, description: &str, names: &[String]| {
+            let messages = names
+                .iter()
+                .map(|n| (n.clone(), reference(&format!("#/components/messages/{n}"))))
+                .collect();
+            object(vec![
+                ("address", text(address)),
+                ("description", text(description)),
+                ("messages", Json::Object(messages)),
+            ])
+        };
+        let operation = |action: &str, channel: &str, names: &[String]| {
+            let messages = names
+                .iter()
+                .map(|n| reference(&format!("#/channels/{channel}/messages/{n}")))
+                .collect();
+            object(vec![
+                ("action", text(action)),
+                ("channel", reference(&format!("#/channels/{channel}"))),
+                ("messages", Json::Array(messages)),
+            ])
+        };
+
+        let servers = self
+            .servers
+            .iter()
+            .map(|s| {
+                let server = object(vec![
+                    ("host", text(&s.host)),
+                    ("protocol", text(&s.protocol)),
+                ]);
+                (s.name.clone(), server)
+            })
+            .collect();
+        let messages = commands
+            .iter()
+            .chain(&events)
+            .map(|n| (n.clone(), self.message(n)))
+            .collect();
+
+        let name = &self.schemas.name;
+        object(vec![
+            ("asyncapi", text("3.0.0")),
+            (
+                "info",
+                object(vec![
+                    ("title", text(name)),
+                    ("version", text(&self.version)),
+                ]),
+            ),
+            ("servers", Json::Object(servers)),
+            (
+                "channels",
+                object(vec![
+                    (
+                        "commands",
+                        channel(&self.commands, "Commands to the machine", &commands),
+                    ),
+                    (
+                        "events",
+                        channel(&self.events, "Events of the machine", &events),
+                    ),
+                ]),
+            ),
+            (
+                "operations",
+                Json::Object(vec![
+                    (
+                        format!("receive_{name}_commands"),
+                        operation("receive", "commands", &commands),
+                    ),
+                    (
+                        format!("send_{name}_events"),
+                        operation("send", "events", &events),
+                    ),
+                ]),
+            ),
+            (
+                "components",
+                object(vec![
+                    ("schemas", Json::Object(self.schemas.components())),
+                    ("messages", Json::Object(messages)),
+                ]),
+            ),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_schema::{tagged_schema, JsonSchema};
+
+    #[test]
+    fn test_asyncapi() {
+        // An account's deposits on a Kafka topic, documented in the event
+        // catalog
+
+        struct Deposit {}
+
+        struct Deposited {}
+
+        impl JsonSchema for Deposit {
+            fn json_schema() -> Json {
+                tagged_schema("Deposit", vec![("amount", u64::json_schema())], &[])
+            }
+        }
+
+        impl JsonSchema for Deposited {
+            fn json_schema() -> Json {
+                tagged_schema("Deposited", vec![("amount", u64::json_schema())], &[])
+            }
+        }
+
+        let schemas = MachineSchemas::new("account")
+            .command::<Deposit>()
+            .event::<Deposited>();
+        let document = AsyncApi::new(&schemas)
+            .with_server("production", "kafka.example.com:9092", "kafka")
+            .document();
+
+        assert_eq!(
+            document.get("asyncapi").and_then(Json::as_str),
+            Some("3.0.0")
+        );
+        let channels = document.get("channels").unwrap();
+        let events = channels.get("events").unwrap();
+        assert_eq!(
+            events.get("address").and_then(Json::as_str),
+            Some("account.events")
+        );
+        assert_eq!(
+            events.get("messages").and_then(|m| m.get("Deposited")),
+            Some(&reference("#/components/messages/Deposited"))
+        );
+        assert!(channels
+            .get("commands")
+            .and_then(|c| c.get("messages"))
+            .and_then(|m| m.get("Deposited"))
+            .is_none());
+
+        let receive = document
+            .get("operations")
+            .and_then(|o| o.get("receive_account_commands"))
+            .unwrap();
+        assert_eq!(
+            receive.get("action").and_then(Json::as_str),
+            Some("receive")
+        );
+        assert_eq!(
+            receive.get("messages"),
+            Some(&Json::Array(vec![reference(
+                "#/channels/commands/messages/Deposit"
+            )]))
+        );
+
+        let components = document.get("components").unwrap();
+        let deposit = components
+            .get("messages")
+            .and_then(|m| m.get("Deposit"))
+            .unwrap();
+        assert_eq!(
+            deposit.get("payload"),
+            Some(&reference("#/components/schemas/Deposit"))
+        );
+        assert!(deposit
+            .get("bindings")
+            .and_then(|b| b.get("kafka"))
+            .is_some());
+        assert_eq!(
+            components
+                .get("schemas")
+                .and_then(|s| s.get("events"))
+                .map(|e| e.to_string()),
+            Some(r##"{"oneOf":[{"$ref":"#/components/schemas/Deposited"}]}"##.to_string())
+        );
+
+        // NATS subjects have their own addresses and no Kafka key
+        let document = AsyncApi::new(&schemas)
+            .with_server("local", "localhost:4222", "nats")
+            .with_addresses("accounts.cmd", "accounts.evt")
+            .document();
+        let commands = document.get("channels").and_then(|c| c.get("commands"));
+        assert_eq!(
+            commands
+                .and_then(|c| c.get("address"))
+                .and_then(Json::as_str),
+            Some("accounts.cmd")
+        );
+        assert!(document
+            .get("components")
+            .and_then(|c| c.get("messages"))
+            .and_then(|m| m.get("Deposit"))
+            .and_then(|d| d.get("bindings"))
+            .is_none());
+    }
+}
@@ -0,0 +1,293 @@
+//! Audit records of each step, for shipping to an audit log. An `Auditor`
+//! sends commands to a runner on behalf of someone, and produces one
+//! `AuditRecord` per step: who sent what command to which entity, the
+//! prior state, the event, the new state and the outcome. Values are
+//! written with their `Debug` representations, passed through a
+//! redaction hook so that sensitive payloads stay out of the log.
+//! `redact_fields` makes a hook blanking the values of named fields.
+
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::time::SystemTime;
+
+use crate::command_and_event_traits::{Command, Fsm, Validate};
+use crate::json::Json;
+use crate::observer::type_label;
+use crate::runner::{RunError, Runner};
+
+/// What a step did.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditOutcome {
+    /// The event transitioned the entity to a new state
+    Transitioned,
+    /// The event left the entity in the same state
+    Unchanged,
+    /// The command emitted no event
+    Ignored,
+    /// The command was rejected, for a reason
+    Rejected(String),
+}
+
+/// One step, as recorded in an audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub at: SystemTime,
+    pub who: String,
+    pub entity: String,
+    pub command_type: String,
+    pub command: String,
+    pub prior: Option<String>,
+    pub event: Option<String>,
+    pub state: Option<String>,
+    pub outcome: AuditOutcome,
+}
+
+impl AuditRecord {
+    /// The record as a JSON object, with the time in Unix milliseconds.
+    pub fn to_json(&self) -> Json {
+        let text = |s: &Option<String>| match s {
+            Some(s) => Json::String(s.clone()),
+            None => Json::Null,
+        };
+        let millis = self
+            .at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let (outcome, reason) = match &self.outcome {
+            AuditOutcome::Transitioned => ("transitioned", None),
+            AuditOutcome::Unchanged => ("unchanged", None),
+            AuditOutcome::Ignored => ("ignored", None),
+            AuditOutcome::Rejected(r) => ("rejected", Some(r.clone())),
+        };
+        Json::Object(vec![
+            ("at".to_string(), Json::Number(millis as f64)),
+            ("who".to_string(), Json::String(self.who.clone())),
+            ("entity".to_string(), Json::String(self.entity.clone())),
+            (
+                "command_type".to_string(),
+                Json::String(self.command_type.clone()),
+            ),
+            ("command".to_string(), Json::String(self.command.clone())),
+            ("prior".to_string(), text(&self.prior)),
+            ("event".to_string(), text(&self.event)),
+            ("state".to_string(), text(&self.state)),
+            ("outcome".to_string(), Json::String(outcome.to_string())),
+            ("reason".to_string(), text(&reason)),
+        ])
+    }
+}
+
+/// Receives audit records.
+pub trait AuditSink {
+    fn record(&mut self, record: AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: FnMut(AuditRecord),
+{
+    fn record(&mut self, record: AuditRecord) {
+        self(record)
+    }
+}
+
+/// A redaction hook, given the `Debug` text of a value.
+pub type Redactor = Box<dyn Fn(&str) -> String + Send>;
+
+/// A redaction hook replacing the values of the named fields, wherever
+/// they appear in `Debug` text, with `"<redacted>"`.
+pub fn redact_fields(fields: &[&str]) -> Redactor {
+    let fields: Vec<String> = fields.iter().map(|f| format!("{f}: ")).collect();
+    Box::new(move |text| {
+        let mut text = text.to_string();
+        for field in &fields {
+            let mut from = 0;
+            while let Some(i) = text[from..].find(field.as_str()).map(|i| i + from) {
+                let start = i + field.len();
+                from = start;
+                let bounded = text[..i].ends_with([' ', '{', '(']) || i == 0;
+                if bounded {
+                    let value = &text[start..start + value_len(&text[start..])];
+                    let end = start + value.trim_end().len();
+                    text.replace_range(start..end, "\"<redacted>\"");
+                }
+            }
+        }
+        text
+    })
+}
+
+/// The length of the `Debug` text of a field value at the start of text.
+fn value_len(text: &str) -> usize {
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if quoted => {}
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' if depth == 0 => return i,
+            ')' | ']' | '}' => depth -= 1,
+            ',' if depth == 0 => return i,
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+/// Sends commands to runners, auditing each step to a sink A.
+pub struct Auditor<A> {
+    sink: A,
+    redact: Redactor,
+}
+
+impl<A> Auditor<A>
+where
+    A: AuditSink,
+{
+    pub fn new(sink: A) -> Self {
+        Self {
+            sink,
+            redact: Box::new(str::to_string),
+        }
+    }
+
+    /// Pass the text of commands, events and states through a redaction hook.
+    pub fn with_redactor(mut self, redact: Redactor) -> Self {
+        self.redact = redact;
+        self
+    }
+
+    pub fn sink(&self) -> &A {
+        &self.sink
+    }
+
+    /// Step an entity with a command on behalf of someone, auditing the step.
+    pub fn send<K, S, H, F, C>(
+        &mut self,
+        runner: &mut Runner<K, S, H, F>,
+        who: &str,
+        key: &K,
+        command: &C,
+    ) -> Result<Option<C::Output>, RunError>
+    where
+        K: Eq + Hash + Debug,
+        F: Fsm<S, H>,
+        S: Debug,
+        C: Command<S, H> + Validate<S> + Debug,
+        C::Output: Debug,
+    {
+        let redact = |value: &dyn Debug| (self.redact)(&format!("{value:?}"));
+        let prior = runner.state(key).map(|s| redact(s));
+        let result = runner.send(key, command);
+        let state = runner.state(key).map(|s| redact(s));
+        let (event, outcome) = match &result {
+            Ok(Some(e)) if state == prior => (Some(redact(e)), AuditOutcome::Unchanged),
+            Ok(Some(e)) => (Some(redact(e)), AuditOutcome::Transitioned),
+            Ok(None) => (None, AuditOutcome::Ignored),
+            Err(e) => (None, AuditOutcome::Rejected(redact(e))),
+        };
+        let record = AuditRecord {
+            at: SystemTime::now(),
+            who: who.to_string(),
+            entity: format!("{key:?}"),
+            command_type: type_label::<C>(),
+            command: redact(command),
+            prior,
+            event,
+            state,
+            outcome,
+        };
+        self.sink.record(record);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Transition, ValidationError};
+
+    #[test]
+    fn test_auditor() {
+        // Accounts paid into by card, where card numbers must not be logged
+
+        #[derive(Debug)]
+        struct Pay {
+            amount: u32,
+            card: String,
+        }
+
+        #[derive(Debug)]
+        struct Paid {
+            amount: u32,
+        }
+
+        impl Command<u32, ()> for Pay {
+            type Output = Paid;
+            fn execute(&self, _s: &u32, _h: &mut ()) -> Option<Paid> {
+                Some(Paid {
+                    amount: self.amount,
+                })
+            }
+        }
+
+        impl Validate<u32> for Pay {
+            fn validate(&self, _s: &u32) -> Result<(), ValidationError> {
+                if self.card.is_empty() {
+                    Err(ValidationError("no card".to_string()))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        impl Event<u32> for Paid {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s + self.amount)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, ()> for MyFsm {}
+
+        let mut runner = Runner::<&str, u32, (), MyFsm>::new(());
+        runner.insert("acc", 10);
+        let mut records = Vec::new();
+        let mut auditor = Auditor::new(|r| records.push(r)).with_redactor(redact_fields(&["card"]));
+
+        let pay = |amount| Pay {
+            amount,
+            card: "4111, \"1111\"".to_string(),
+        };
+        auditor.send(&mut runner, "alice", &"acc", &pay(5)).unwrap();
+        auditor.send(&mut runner, "bob", &"acc", &pay(0)).unwrap();
+        auditor
+            .send(&mut runner, "bob", &"none", &pay(1))
+            .unwrap_err();
+        drop(auditor);
+
+        let r = &records[0];
+        assert_eq!(r.who, "alice");
+        assert_eq!(r.entity, "\"acc\"");
+        assert_eq!(r.command_type, "Pay");
+        assert_eq!(r.command, "Pay { amount: 5, card: \"<redacted>\" }");
+        assert_eq!(r.prior.as_deref(), Some("10"));
+        assert_eq!(r.event.as_deref(), Some("Paid { amount: 5 }"));
+        assert_eq!(r.state.as_deref(), Some("15"));
+        assert_eq!(r.outcome, AuditOutcome::Transitioned);
+        assert_eq!(records[1].outcome, AuditOutcome::Unchanged);
+        assert_eq!(
+            records[2].outcome,
+            AuditOutcome::Rejected("Unknown".to_string())
+        );
+
+        let json = records[2].to_json();
+        assert_eq!(json.get("outcome").and_then(Json::as_str), Some("rejected"));
+        assert_eq!(json.get("prior"), Some(&Json::Null));
+    }
+}
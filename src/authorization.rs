@@ -0,0 +1,499 @@
+//! Access control for the commands sent to a runner. A command carries
+//! the identity of its caller, a `Principal`, in its `Correlation`, and an
+//! `Authorizer` decides whether the caller may send it, given the entity's
+//! state, before it is validated or its effects run. Commands without a
+//! principal are decided for the anonymous principal. A denial is reported
+//! to the runner's observers as a rejection.
+//!
+//! The check is made by `Authorized` for a runner on the caller's thread,
+//! and inside the task or thread of a `ThreadRunner`, `AsyncRunner` or
+//! `AsyncEffectRunner` spawned with `spawn_authorized`, so that no handle
+//! to the runner bypasses it.
+//!
+//! Since follow-up commands and events take their `Correlation` from the
+//! message that caused them, the principal is carried through a pipeline
+//! and onto the events in a journal, recording who caused them.
+
+use std::hash::Hash;
+
+use crate::async_handler::{AsyncCommand, AsyncEffectRunner, AsyncHandler};
+use crate::async_runner::{AsyncRunner, Executor};
+use crate::command_and_event_traits::{Command, Event, Fsm, Validate};
+use crate::correlation::Correlated;
+use crate::observer::type_label;
+use crate::pipeline::Dispatch;
+use crate::runner::{RunError, Runner};
+use crate::thread_runner::ThreadRunner;
+
+/// The identity of a caller, with the roles it holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Principal {
+    pub id: String,
+    pub roles: Vec<String>,
+}
+
+impl Principal {
+    pub fn new(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            roles: Vec::new(),
+        }
+    }
+
+    /// The principal of a caller that did not identify itself.
+    pub fn anonymous() -> Self {
+        Self::default()
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.id.is_empty()
+    }
+
+    pub fn with_role(mut self, role: &str) -> Self {
+        self.roles.push(role.to_string());
+        self
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// Whether a principal may send a command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Allow,
+    /// The command is refused, with the reason
+    Deny(String),
+}
+
+/// Decides whether principals may send commands C to entities in states S.
+pub trait Authorizer<S, C> {
+    fn allow(&self, principal: &Principal, command: &C, state: &S) -> Decision;
+}
+
+impl<S, C, A> Authorizer<S, C> for A
+where
+    A: Fn(&Principal, &C, &S) -> Decision,
+{
+    fn allow(&self, principal: &Principal, command: &C, state: &S) -> Decision {
+        self(principal, command, state)
+    }
+}
+
+/// Check that the principal of a command may send it to an entity, or
+/// fail with `RunError::Denied`, reported to the runner's observers.
+/// Commands for entities the runner does not have are left for it to fail.
+pub fn authorize<K, S, H, F, C, A>(
+    runner: &mut Runner<K, S, H, F>,
+    authorizer: &A,
+    key: &K,
+    command: &Correlated<C>,
+) -> Result<(), RunError>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    A: Authorizer<S, C>,
+{
+    let Some(state) = runner.state(key) else {
+        return Ok(());
+    };
+    let anonymous = Principal::anonymous();
+    let principal = command.ids.principal.as_ref().unwrap_or(&anonymous);
+    match authorizer.allow(principal, &command.body, state) {
+        Decision::Allow => Ok(()),
+        Decision::Deny(reason) => {
+            let label = type_label::<C>();
+            Err(runner.reject(key, &label, RunError::Denied(reason)))
+        }
+    }
+}
+
+/// A runner whose commands are authorized by A.
+pub struct Authorized<K, S, H, F, A> {
+    runner: Runner<K, S, H, F>,
+    authorizer: A,
+}
+
+impl<K, S, H, F, A> Authorized<K, S, H, F, A>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+{
+    pub fn new(runner: Runner<K, S, H, F>, authorizer: A) -> Self {
+        Self { runner, authorizer }
+    }
+
+    pub fn runner(&self) -> &Runner<K, S, H, F> {
+        &self.runner
+    }
+
+    pub fn into_inner(self) -> Runner<K, S, H, F> {
+        self.runner
+    }
+
+    /// Step an entity with a command if its principal is allowed to
+    /// send it, or fail with `RunError::Denied`.
+    pub fn send<C>(
+        &mut self,
+        key: &K,
+        command: &Correlated<C>,
+    ) -> Result<Option<Correlated<C::Output>>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+        A: Authorizer<S, C>,
+    {
+        authorize(&mut self.runner, &self.authorizer, key, command)?;
+        self.runner.send(key, command)
+    }
+}
+
+impl<K, S, H, F, C> ThreadRunner<K, S, H, F, Correlated<C>>
+where
+    K: Eq + Hash + Send + 'static,
+    S: Send + 'static,
+    H: Send + 'static,
+    F: Fsm<S, H> + 'static,
+    C: Command<S, H> + Validate<S> + Send + 'static,
+    C::Output: Send + 'static,
+{
+    /// Start a thread owning the runner, whose commands are authorized by A.
+    pub fn spawn_authorized<A>(runner: Runner<K, S, H, F>, authorizer: A) -> Self
+    where
+        A: Authorizer<S, C> + Send + 'static,
+    {
+        Self::spawn_with(runner, move |runner, key, command| {
+            authorize(runner, &authorizer, key, command)?;
+            runner.send(key, command)
+        })
+    }
+}
+
+impl<K, C, E> AsyncRunner<K, Correlated<C>, Correlated<E>>
+where
+    K: Eq + Hash + Send + 'static,
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawn a task owning the runner, whose commands are authorized by A.
+    pub fn spawn_authorized<S, H, F, X, A>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        authorizer: A,
+    ) -> Self
+    where
+        S: Send + 'static,
+        H: Send + 'static,
+        F: Fsm<S, H> + 'static,
+        C: Command<S, H, Output = E> + Validate<S>,
+        X: Executor + Send + Sync + 'static,
+        A: Authorizer<S, C> + Send + 'static,
+    {
+        Self::spawn_with(runner, executor, move |runner, key, command| {
+            authorize(runner, &authorizer, key, command)?;
+            runner.send(key, command)
+        })
+    }
+}
+
+impl<K, C, E> AsyncEffectRunner<K, Correlated<C>, Correlated<E>>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawn a task owning the runner, refusing commands whose principals
+    /// A does not authorize before their effects start.
+    pub fn spawn_authorized<S, H, F, X, A>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        authorizer: A,
+    ) -> Self
+    where
+        S: Send + 'static,
+        H: AsyncHandler,
+        F: Fsm<S, H> + 'static,
+        C: AsyncCommand<S, H, Output = E> + Validate<S>,
+        E: Event<S>,
+        X: Executor + Send + Sync + 'static,
+        A: Authorizer<S, C> + Send + 'static,
+    {
+        Self::spawn_with(runner, executor, move |runner, key, command| {
+            authorize(runner, &authorizer, key, command)
+        })
+    }
+}
+
+impl<K, S, H, F, A, C> Dispatch<K, Correlated<C>> for Authorized<K, S, H, F, A>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    A: Authorizer<S, C>,
+{
+    type Output = Correlated<C::Output>;
+    fn dispatch(
+        &mut self,
+        key: &K,
+        command: &Correlated<C>,
+    ) -> Result<Option<Correlated<C::Output>>, RunError> {
+        self.send(key, command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_handler::{CancellationToken, Effect};
+    use crate::async_runner::{block_on, ThreadExecutor};
+    use crate::command_and_event_traits::{Rejection, Transition};
+    use crate::correlation::Correlation;
+    use crate::observer::Observer;
+    use std::sync::{Arc, Mutex};
+
+    // An account that its owner may withdraw from, and a teller may
+    // freeze, whoever owns it
+
+    struct Account {
+        owner: &'static str,
+        balance: u32,
+        frozen: bool,
+    }
+
+    enum Op {
+        Withdraw(u32),
+        Freeze,
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Changed {
+        Withdrew(u32),
+        Froze,
+    }
+
+    impl Command<Account, u32> for Op {
+        type Output = Changed;
+        fn execute(&self, _s: &Account, effects: &mut u32) -> Option<Changed> {
+            *effects += 1;
+            Some(self.changed())
+        }
+    }
+
+    impl Op {
+        fn changed(&self) -> Changed {
+            match self {
+                Op::Withdraw(n) => Changed::Withdrew(*n),
+                Op::Freeze => Changed::Froze,
+            }
+        }
+    }
+
+    impl Validate<Account> for Op {}
+
+    impl Event<Account> for Changed {
+        fn fire(&self, s: &Account) -> Transition<Account> {
+            Transition::Next(match self {
+                Changed::Withdrew(n) => Account {
+                    balance: s.balance - n,
+                    ..*s
+                },
+                Changed::Froze => Account { frozen: true, ..*s },
+            })
+        }
+    }
+
+    struct Bank {}
+
+    impl Fsm<Account, u32> for Bank {}
+
+    fn policy(p: &Principal, op: &Op, s: &Account) -> Decision {
+        match op {
+            _ if p.is_anonymous() => Decision::Deny("sign in first".to_string()),
+            Op::Withdraw(_) if p.id != s.owner => Decision::Deny("not the owner".to_string()),
+            Op::Freeze if !p.has_role("teller") => Decision::Deny("tellers only".to_string()),
+            _ => Decision::Allow,
+        }
+    }
+
+    fn bank() -> Runner<u32, Account, u32, Bank> {
+        let mut runner = Runner::new(0);
+        runner.insert(
+            1,
+            Account {
+                owner: "ann",
+                balance: 10,
+                frozen: false,
+            },
+        );
+        runner
+    }
+
+    fn by(id: &str, principal: Option<Principal>, op: Op) -> Correlated<Op> {
+        let ids = Correlation::new(id);
+        let ids = match principal {
+            Some(p) => ids.with_principal(p),
+            None => ids,
+        };
+        Correlated::new(ids, op)
+    }
+
+    /// Records the rejections a runner reports.
+    #[derive(Clone, Default)]
+    struct Rejections(Arc<Mutex<Vec<String>>>);
+
+    impl Observer<u32, Account> for Rejections {
+        fn rejected(&mut self, key: &u32, command: &str, error: &RunError) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{key} {command} {error:?}"));
+        }
+    }
+
+    #[test]
+    fn test_authorized() {
+        let mut bank = Authorized::new(bank(), policy);
+        let ann = Principal::new("ann");
+        let bob = Principal::new("bob").with_role("teller");
+
+        let anonymous = bank.send(&1, &by("m1", None, Op::Withdraw(3)));
+        assert_eq!(
+            anonymous,
+            Err(RunError::Denied("sign in first".to_string()))
+        );
+        let denied = bank
+            .send(&1, &by("m2", Some(bob.clone()), Op::Withdraw(3)))
+            .unwrap_err();
+        assert_eq!(
+            denied.rejection(),
+            Some(Rejection::Unauthorized("not the owner".to_string()))
+        );
+        assert!(bank
+            .send(&1, &by("m3", Some(ann.clone()), Op::Freeze))
+            .is_err());
+        assert_eq!(*bank.runner().handler(), 0);
+
+        // Events carry the principal that caused them
+        let withdrew = bank
+            .send(&1, &by("m4", Some(ann.clone()), Op::Withdraw(3)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(withdrew.body, Changed::Withdrew(3));
+        assert_eq!(withdrew.ids.principal, Some(ann));
+        assert!(bank.dispatch(&1, &by("m5", Some(bob), Op::Freeze)).is_ok());
+        let account = bank.runner().state(&1).unwrap();
+        assert_eq!((account.balance, account.frozen), (7, true));
+
+        assert_eq!(
+            bank.send(&2, &by("m6", None, Op::Freeze)),
+            Err(RunError::Unknown)
+        );
+    }
+
+    #[test]
+    fn test_denial_observed() {
+        // Denials reach the runner's observers like any other rejection
+        let rejections = Rejections::default();
+        let runner = bank().with_observer(Box::new(rejections.clone()));
+        let mut bank = Authorized::new(runner, policy);
+        let _ = bank.send(&1, &by("m1", None, Op::Freeze));
+        assert_eq!(
+            *rejections.0.lock().unwrap(),
+            vec![r#"1 Op Denied("sign in first")"#]
+        );
+    }
+
+    #[test]
+    fn test_thread_authorized() {
+        // The thread's runner checks each command, whichever sender sent it
+        let rejections = Rejections::default();
+        let runner = bank().with_observer(Box::new(rejections.clone()));
+        let thread = ThreadRunner::spawn_authorized(runner, policy);
+        let sender = thread.sender();
+        let ann = Principal::new("ann");
+        assert_eq!(
+            sender.send(1, by("m1", None, Op::Withdraw(3))),
+            Err(RunError::Denied("sign in first".to_string()))
+        );
+        let withdrew = thread.send(1, by("m2", Some(ann), Op::Withdraw(3)));
+        assert_eq!(
+            withdrew.unwrap().map(|e| e.body),
+            Some(Changed::Withdrew(3))
+        );
+        let runner = thread.shutdown();
+        assert_eq!(*runner.handler(), 1);
+        assert_eq!(rejections.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_async_authorized() {
+        let handle = AsyncRunner::spawn_authorized(bank(), ThreadExecutor, policy);
+        let bob = Principal::new("bob").with_role("teller");
+        block_on(async {
+            assert_eq!(
+                handle
+                    .send(1, by("m1", Some(bob.clone()), Op::Withdraw(3)))
+                    .await,
+                Err(RunError::Denied("not the owner".to_string()))
+            );
+            let froze = handle.send(1, by("m2", Some(bob), Op::Freeze)).await;
+            assert_eq!(froze.unwrap().map(|e| e.body), Some(Changed::Froze));
+        });
+    }
+
+    // The same operations as async commands, whose effects count
+    // themselves in a shared counter
+
+    #[derive(Clone, Default)]
+    struct Effects(Arc<Mutex<u32>>);
+
+    impl AsyncCommand<Account, Effects> for Op {
+        type Output = Changed;
+        fn execute(
+            &self,
+            _s: &Account,
+            effects: Effects,
+            _c: CancellationToken,
+        ) -> Effect<Changed> {
+            let changed = self.changed();
+            Box::pin(async move {
+                *effects.0.lock().unwrap() += 1;
+                Some(changed)
+            })
+        }
+    }
+
+    impl Fsm<Account, Effects> for Bank {}
+
+    #[test]
+    fn test_effect_authorized() {
+        // A denied command never starts its effect
+        let effects = Effects::default();
+        let mut runner = Runner::<u32, Account, Effects, Bank>::new(effects.clone());
+        runner.insert(
+            1,
+            Account {
+                owner: "ann",
+                balance: 10,
+                frozen: false,
+            },
+        );
+        let handle = AsyncEffectRunner::spawn_authorized(runner, ThreadExecutor, policy);
+        let ann = Principal::new("ann");
+        block_on(async {
+            assert_eq!(
+                handle
+                    .send(1, by("m1", Some(ann.clone()), Op::Freeze))
+                    .await,
+                Err(RunError::Denied("tellers only".to_string()))
+            );
+            assert_eq!(*effects.0.lock().unwrap(), 0);
+            let withdrew = handle.send(1, by("m2", Some(ann), Op::Withdraw(3))).await;
+            assert_eq!(
+                withdrew.unwrap().map(|e| e.body),
+                Some(Changed::Withdrew(3))
+            );
+        });
+        assert_eq!(*effects.0.lock().unwrap(), 1);
+    }
+}
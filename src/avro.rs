@@ -0,0 +1,819 @@
+//! The Avro format, for event streams read by data-platform tooling. Each
+//! event type has an `AvroSchema`, registered under a subject in an
+//! `AvroRegistry`, which numbers its versions and refuses a new version
+//! that could not read the events written with any previous one.
+//!
+//! `AvroCodec` encodes `Json` values with the latest schema of a subject,
+//! prefixed as in Confluent's wire format by a zero byte and the schema's
+//! version as 4 bytes big-endian. It decodes a value written with any
+//! version by resolving the writer's schema against the latest, following
+//! Avro's rules: fields the reader lacks are dropped, fields the writer
+//! lacks take the reader's defaults, and numbers are promoted.
+//!
+//! Schemas are written and parsed as Avro schema JSON, for exchange with a
+//! schema registry. Named types are written inline rather than referred
+//! to by name, and bytes and fixed types, which have no `Json`
+//! equivalent, are not supported.
+
+use std::collections::HashMap;
+
+use crate::codec::{Codec, CodecError};
+use crate::envelope::CODEC_AVRO;
+use crate::json::Json;
+
+/// An Avro schema.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AvroSchema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    String,
+    Array(Box<AvroSchema>),
+    Map(Box<AvroSchema>),
+    Enum {
+        name: String,
+        symbols: Vec<String>,
+    },
+    Record {
+        name: String,
+        fields: Vec<AvroField>,
+    },
+    Union(Vec<AvroSchema>),
+}
+
+/// A field of a record, with the default a reader gives it when the
+/// writer's schema lacks it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvroField {
+    pub name: String,
+    pub schema: AvroSchema,
+    pub default: Option<Json>,
+}
+
+impl AvroField {
+    pub fn new(name: &str, schema: AvroSchema) -> Self {
+        Self {
+            name: name.to_string(),
+            schema,
+            default: None,
+        }
+    }
+
+    pub fn with_default(mut self, default: Json) -> Self {
+        self.default = Some(default);
+        self
+    }
+}
+
+fn text(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+impl AvroSchema {
+    /// The name of the schema's type, as in Avro schema JSON.
+    pub fn type_name(&self) -> &str {
+        match self {
+            AvroSchema::Null => "null",
+            AvroSchema::Boolean => "boolean",
+            AvroSchema::Int => "int",
+            AvroSchema::Long => "long",
+            AvroSchema::Float => "float",
+            AvroSchema::Double => "double",
+            AvroSchema::String => "string",
+            AvroSchema::Array(_) => "array",
+            AvroSchema::Map(_) => "map",
+            AvroSchema::Enum { .. } => "enum",
+            AvroSchema::Record { .. } => "record",
+            AvroSchema::Union(_) => "union",
+        }
+    }
+
+    /// The schema as Avro schema JSON.
+    pub fn to_json(&self) -> Json {
+        let object = |members: Vec<(&str, Json)>| {
+            Json::Object(
+                members
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+            )
+        };
+        match self {
+            AvroSchema::Array(items) => {
+                object(vec![("type", text("array")), ("items", items.to_json())])
+            }
+            AvroSchema::Map(values) => {
+                object(vec![("type", text("map")), ("values", values.to_json())])
+            }
+            AvroSchema::Enum { name, symbols } => object(vec![
+                ("type", text("enum")),
+                ("name", text(name)),
+                (
+                    "symbols",
+                    Json::Array(symbols.iter().map(|s| text(s)).collect()),
+                ),
+            ]),
+            AvroSchema::Record { name, fields } => {
+                let fields = fields
+                    .iter()
+                    .map(|f| {
+                        let mut members =
+                            vec![("name", text(&f.name)), ("type", f.schema.to_json())];
+                        if let Some(default) = &f.default {
+                            members.push(("default", default.clone()));
+                        }
+                        object(members)
+                    })
+                    .collect();
+                object(vec![
+                    ("type", text("record")),
+                    ("name", text(name)),
+                    ("fields", Json::Array(fields)),
+                ])
+            }
+            AvroSchema::Union(branches) => {
+                Json::Array(branches.iter().map(AvroSchema::to_json).collect())
+            }
+            primitive => text(primitive.type_name()),
+        }
+    }
+
+    /// A schema from Avro schema JSON.
+    pub fn from_json(json: &Json) -> Result<Self, String> {
+        let primitive = |name: &str| match name {
+            "null" => Ok(AvroSchema::Null),
+            "boolean" => Ok(AvroSchema::Boolean),
+            "int" => Ok(AvroSchema::Int),
+            "long" => Ok(AvroSchema::Long),
+            "float" => Ok(AvroSchema::Float),
+            "double" => Ok(AvroSchema::Double),
+            "string" => Ok(AvroSchema::String),
+            name => Err(format!("type {name} is not supported")),
+        };
+        let member = |name: &str| json.get(name).ok_or(format!("a schema lacks {name}"));
+        let name = || -> Result<String, String> {
+            Ok(member("name")?
+                .as_str()
+                .ok_or("a name is not a string")?
+                .to_string())
+        };
+        match json {
+            Json::String(name) => primitive(name),
+            Json::Array(branches) => Ok(AvroSchema::Union(
+                branches
+                    .iter()
+                    .map(AvroSchema::from_json)
+                    .collect::<Result<_, _>>()?,
+            )),
+            Json::Object(_) => match member("type")? {
+                Json::String(t) if t == "array" => Ok(AvroSchema::Array(Box::new(
+                    Self::from_json(member("items")?)?,
+                ))),
+                Json::String(t) if t == "map" => Ok(AvroSchema::Map(Box::new(Self::from_json(
+                    member("values")?,
+                )?))),
+                Json::String(t) if t == "enum" => {
+                    let symbols = member("symbols")?
+                        .as_array()
+                        .ok_or("symbols is not an array")?
+                        .iter()
+                        .map(|s| {
+                            s.as_str()
+                                .map(str::to_string)
+                                .ok_or("a symbol is not a string")
+                        })
+                        .collect::<Result<_, _>>()?;
+                    Ok(AvroSchema::Enum {
+                        name: name()?,
+                        symbols,
+                    })
+                }
+                Json::String(t) if t == "record" => {
+                    let fields = member("fields")?
+                        .as_array()
+                        .ok_or("fields is not an array")?
+                        .iter()
+                        .map(|f| {
+                            Ok(AvroField {
+                                name: f
+                                    .get("name")
+                                    .and_then(Json::as_str)
+                                    .ok_or("a field lacks a name")?
+                                    .to_string(),
+                                schema: Self::from_json(
+                                    f.get("type").ok_or("a field lacks a type")?,
+                                )?,
+                                default: f.get("default").cloned(),
+                            })
+                        })
+                        .collect::<Result<_, String>>()?;
+                    Ok(AvroSchema::Record {
+                        name: name()?,
+                        fields,
+                    })
+                }
+                other => Self::from_json(other),
+            },
+            _ => Err(format!("{json} is not a schema")),
+        }
+    }
+
+    /// Whether a value can be written with this schema, for choosing the
+    /// branch of a union.
+    fn admits(&self, value: &Json) -> bool {
+        match (self, value) {
+            (AvroSchema::Null, Json::Null) => true,
+            (AvroSchema::Boolean, Json::Bool(_)) => true,
+            (AvroSchema::Int, Json::Number(n)) => {
+                n.fract() == 0.0 && i32::try_from(*n as i64).is_ok()
+            }
+            (AvroSchema::Long, Json::Number(n)) => n.fract() == 0.0,
+            (AvroSchema::Float | AvroSchema::Double, Json::Number(_)) => true,
+            (AvroSchema::String, Json::String(_)) => true,
+            (AvroSchema::Enum { symbols, .. }, Json::String(s)) => symbols.contains(s),
+            (AvroSchema::Array(items), Json::Array(values)) => {
+                values.iter().all(|v| items.admits(v))
+            }
+            (AvroSchema::Map(schema), Json::Object(members)) => {
+                members.iter().all(|(_, v)| schema.admits(v))
+            }
+            (AvroSchema::Record { fields, .. }, Json::Object(_)) => {
+                fields.iter().all(|f| match value.get(&f.name) {
+                    Some(v) => f.schema.admits(v),
+                    None => f.default.is_some(),
+                })
+            }
+            (AvroSchema::Union(branches), value) => branches.iter().any(|b| b.admits(value)),
+            _ => false,
+        }
+    }
+
+    /// Encode a value with this schema.
+    pub fn encode(&self, value: &Json, out: &mut Vec<u8>) -> Result<(), String> {
+        let mismatch = || format!("{value} is not a {}", self.type_name());
+        match (self, value) {
+            (AvroSchema::Null, Json::Null) => {}
+            (AvroSchema::Boolean, Json::Bool(b)) => out.push(*b as u8),
+            (AvroSchema::Int | AvroSchema::Long, Json::Number(n)) if self.admits(value) => {
+                write_long(out, *n as i64)
+            }
+            (AvroSchema::Float, Json::Number(n)) => {
+                out.extend_from_slice(&(*n as f32).to_le_bytes())
+            }
+            (AvroSchema::Double, Json::Number(n)) => out.extend_from_slice(&n.to_le_bytes()),
+            (AvroSchema::String, Json::String(s)) => write_string(out, s),
+            (AvroSchema::Enum { symbols, .. }, Json::String(s)) => {
+                let index = symbols.iter().position(|x| x == s).ok_or_else(mismatch)?;
+                write_long(out, index as i64)
+            }
+            (AvroSchema::Array(items), Json::Array(values)) => {
+                if !values.is_empty() {
+                    write_long(out, values.len() as i64);
+                    for v in values {
+                        items.encode(v, out)?;
+                    }
+                }
+                write_long(out, 0)
+            }
+            (AvroSchema::Map(schema), Json::Object(members)) => {
+                if !members.is_empty() {
+                    write_long(out, members.len() as i64);
+                    for (k, v) in members {
+                        write_string(out, k);
+                        schema.encode(v, out)?;
+                    }
+                }
+                write_long(out, 0)
+            }
+            (AvroSchema::Record { fields, .. }, Json::Object(_)) => {
+                for f in fields {
+                    let v = match (value.get(&f.name), &f.default) {
+                        (Some(v), _) | (None, Some(v)) => v,
+                        (None, None) => return Err(format!("field {} is missing", f.name)),
+                    };
+                    f.schema.encode(v, out)?;
+                }
+            }
+            (AvroSchema::Union(branches), value) => {
+                let index = branches
+                    .iter()
+                    .position(|b| b.admits(value))
+                    .ok_or_else(mismatch)?;
+                write_long(out, index as i64);
+                branches[index].encode(value, out)?;
+            }
+            _ => return Err(mismatch()),
+        }
+        Ok(())
+    }
+
+    /// Decode a value written with this schema from the start of the input.
+    pub fn decode(&self, input: &mut &[u8]) -> Result<Json, String> {
+        Ok(match self {
+            AvroSchema::Null => Json::Null,
+            AvroSchema::Boolean => match take(input, 1)? {
+                [0] => Json::Bool(false),
+                [1] => Json::Bool(true),
+                [b] => return Err(format!("{b} is not a boolean")),
+                _ => unreachable!(),
+            },
+            AvroSchema::Int | AvroSchema::Long => Json::Number(read_long(input)? as f64),
+            AvroSchema::Float => {
+                let bytes = take(input, 4)?;
+                Json::Number(f32::from_le_bytes(bytes.try_into().expect("4 bytes")) as f64)
+            }
+            AvroSchema::Double => {
+                let bytes = take(input, 8)?;
+                Json::Number(f64::from_le_bytes(bytes.try_into().expect("8 bytes")))
+            }
+            AvroSchema::String => Json::String(read_string(input)?),
+            AvroSchema::Enum { symbols, .. } => {
+                let index = read_long(input)?;
+                let symbol = usize::try_from(index).ok().and_then(|i| symbols.get(i));
+                text(symbol.ok_or(format!("{index} is not a symbol"))?)
+            }
+            AvroSchema::Array(items) => {
+                let mut values = Vec::new();
+                read_blocks(input, |input| {
+                    values.push(items.decode(input)?);
+                    Ok(())
+                })?;
+                Json::Array(values)
+            }
+            AvroSchema::Map(schema) => {
+                let mut members = Vec::new();
+                read_blocks(input, |input| {
+                    let key = read_string(input)?;
+                    members.push((key, schema.decode(input)?));
+                    Ok(())
+                })?;
+                Json::Object(members)
+            }
+            AvroSchema::Record { fields, .. } => Json::Object(
+                fields
+                    .iter()
+                    .map(|f| Ok((f.name.clone(), f.schema.decode(input)?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+            AvroSchema::Union(branches) => {
+                let index = read_long(input)?;
+                let branch = usize::try_from(index).ok().and_then(|i| branches.get(i));
+                branch
+                    .ok_or(format!("{index} is not a branch"))?
+                    .decode(input)?
+            }
+        })
+    }
+}
+
+/// Whether a reader's schema can read values written with a writer's,
+/// and why not if it cannot.
+pub fn can_read(writer: &AvroSchema, reader: &AvroSchema) -> Result<(), String> {
+    use AvroSchema::{Array, Boolean, Double, Enum, Float, Int, Long, Map, Null, Record, Union};
+    match (writer, reader) {
+        (Union(branches), _) => branches.iter().try_for_each(|b| can_read(b, reader)),
+        (_, Union(branches)) => {
+            if branches.iter().any(|b| can_read(writer, b).is_ok()) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "no branch of the union reads a {}",
+                    writer.type_name()
+                ))
+            }
+        }
+        (Null, Null) | (Boolean, Boolean) | (AvroSchema::String, AvroSchema::String) => Ok(()),
+        (Int, Int | Long | Float | Double) | (Long, Long | Float | Double) => Ok(()),
+        (Float, Float | Double) | (Double, Double) => Ok(()),
+        (Array(w), Array(r)) | (Map(w), Map(r)) => can_read(w, r),
+        (Enum { symbols: w, .. }, Enum { symbols: r, name }) => {
+            match w.iter().find(|s| !r.contains(s)) {
+                Some(symbol) => Err(format!("enum {name} lacks the symbol {symbol}")),
+                None => Ok(()),
+            }
+        }
+        (Record { fields: w, .. }, Record { fields: r, name }) => {
+            r.iter()
+                .try_for_each(|f| match w.iter().find(|wf| wf.name == f.name) {
+                    Some(wf) => can_read(&wf.schema, &f.schema),
+                    None if f.default.is_some() => Ok(()),
+                    None => Err(format!("field {} of {name} has no default", f.name)),
+                })
+        }
+        _ => Err(format!(
+            "a {} cannot be read as a {}",
+            writer.type_name(),
+            reader.type_name()
+        )),
+    }
+}
+
+/// A value written with a writer's schema, as read with a reader's.
+pub fn resolve(value: Json, writer: &AvroSchema, reader: &AvroSchema) -> Result<Json, String> {
+    use AvroSchema::{Array, Map, Record, Union};
+    match (writer, reader) {
+        (Union(branches), _) => {
+            let branch = branches
+                .iter()
+                .find(|b| b.admits(&value))
+                .ok_or("no branch admits the value")?;
+            resolve(value, branch, reader)
+        }
+        (_, Union(branches)) => {
+            let branch = branches
+                .iter()
+                .find(|b| can_read(writer, b).is_ok())
+                .ok_or(format!(
+                    "no branch of the union reads a {}",
+                    writer.type_name()
+                ))?;
+            resolve(value, writer, branch)
+        }
+        (Array(w), Array(r)) => match value {
+            Json::Array(values) => Ok(Json::Array(
+                values
+                    .into_iter()
+                    .map(|v| resolve(v, w, r))
+                    .collect::<Result<_, _>>()?,
+            )),
+            _ => Err("expected an array".to_string()),
+        },
+        (Map(w), Map(r)) => match value {
+            Json::Object(members) => Ok(Json::Object(
+                members
+                    .into_iter()
+                    .map(|(k, v)| Ok((k, resolve(v, w, r)?)))
+                    .collect::<Result<_, String>>()?,
+            )),
+            _ => Err("expected a map".to_string()),
+        },
+        (Record { fields: w, .. }, Record { fields: r, name }) => {
+            let Json::Object(mut members) = value else {
+                return Err("expected a record".to_string());
+            };
+            let mut resolved = Vec::with_capacity(r.len());
+            for f in r {
+                let written = w.iter().find(|wf| wf.name == f.name);
+                let taken = members
+                    .iter()
+                    .position(|(k, _)| *k == f.name)
+                    .map(|i| members.swap_remove(i).1);
+                let v = match (written, taken, &f.default) {
+                    (Some(wf), Some(v), _) => resolve(v, &wf.schema, &f.schema)?,
+                    (_, _, Some(default)) => default.clone(),
+                    _ => return Err(format!("field {} of {name} has no default", f.name)),
+                };
+                resolved.push((f.name.clone(), v));
+            }
+            Ok(Json::Object(resolved))
+        }
+        _ => can_read(writer, reader).map(|_| value),
+    }
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if input.len() < n {
+        return Err(format!("expected {n} more bytes, found {}", input.len()));
+    }
+    let (taken, rest) = input.split_at(n);
+    *input = rest;
+    Ok(taken)
+}
+
+/// Write a long, zigzag encoded as a variable length integer.
+fn write_long(out: &mut Vec<u8>, n: i64) {
+    let mut z = ((n << 1) ^ (n >> 63)) as u64;
+    while z >= 0x80 {
+        out.push((z as u8) | 0x80);
+        z >>= 7;
+    }
+    out.push(z as u8);
+}
+
+fn read_long(input: &mut &[u8]) -> Result<i64, String> {
+    let mut z = 0u64;
+    for shift in (0..64).step_by(7) {
+        let &[b] = take(input, 1)? else {
+            unreachable!()
+        };
+        z |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Ok((z >> 1) as i64 ^ -((z & 1) as i64));
+        }
+    }
+    Err("a long is too long".to_string())
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_long(out, s.len() as i64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn read_string(input: &mut &[u8]) -> Result<String, String> {
+    let len = usize::try_from(read_long(input)?).map_err(|_| "a length is negative")?;
+    String::from_utf8(take(input, len)?.to_vec()).map_err(|e| e.to_string())
+}
+
+/// Read the blocks of an array or map, each a count of items, or a
+/// negated count and a size in bytes, ended by an empty block.
+///
+/// A block may count no more items than there are bytes left, which
+/// bounds the work a corrupt count causes. Only arrays of items that take
+/// no bytes, such as nulls, are longer, and those are refused.
+fn read_blocks(
+    input: &mut &[u8],
+    mut item: impl FnMut(&mut &[u8]) -> Result<(), String>,
+) -> Result<(), String> {
+    loop {
+        let mut count = read_long(input)?;
+        if count == 0 {
+            return Ok(());
+        }
+        if count < 0 {
+            count = count.checked_neg().ok_or("a block count is out of range")?;
+            read_long(input)?;
+        }
+        if count as u64 > input.len() as u64 {
+            return Err(format!(
+                "a block of {count} items exceeds the {} bytes left",
+                input.len()
+            ));
+        }
+        for _ in 0..count {
+            item(input)?;
+        }
+    }
+}
+
+/// Versions of schemas, by subject.
+#[derive(Debug, Clone, Default)]
+pub struct AvroRegistry {
+    subjects: HashMap<String, Vec<AvroSchema>>,
+}
+
+impl AvroRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new version of a subject's schema, numbered from 1,
+    /// provided it can read values written with every previous version,
+    /// since codecs read them all with the latest. Registering the latest
+    /// schema again answers its version.
+    pub fn register(&mut self, subject: &str, schema: AvroSchema) -> Result<u32, String> {
+        let versions = self.subjects.entry(subject.to_string()).or_default();
+        if versions.last() == Some(&schema) {
+            return Ok(versions.len() as u32);
+        }
+        for (i, previous) in versions.iter().enumerate() {
+            can_read(previous, &schema)
+                .map_err(|e| format!("{subject} is incompatible with version {}: {e}", i + 1))?;
+        }
+        versions.push(schema);
+        Ok(versions.len() as u32)
+    }
+
+    /// A version of a subject's schema.
+    pub fn schema(&self, subject: &str, version: u32) -> Option<&AvroSchema> {
+        let versions = self.subjects.get(subject)?;
+        versions.get(usize::try_from(version).ok()?.checked_sub(1)?)
+    }
+
+    /// The latest version of a subject's schema.
+    pub fn latest(&self, subject: &str) -> Option<(u32, &AvroSchema)> {
+        let versions = self.subjects.get(subject)?;
+        versions.last().map(|s| (versions.len() as u32, s))
+    }
+}
+
+/// `Json` values of one subject in the Avro format, with their schema's
+/// version.
+#[derive(Debug, Clone)]
+pub struct AvroCodec {
+    versions: Vec<AvroSchema>,
+}
+
+impl AvroCodec {
+    /// A codec for the versions of a subject registered so far.
+    pub fn new(registry: &AvroRegistry, subject: &str) -> Option<Self> {
+        let versions = registry.subjects.get(subject)?;
+        (!versions.is_empty()).then(|| Self {
+            versions: versions.clone(),
+        })
+    }
+}
+
+impl Codec<Json> for AvroCodec {
+    fn id(&self) -> u8 {
+        CODEC_AVRO
+    }
+
+    /// Encode a value with the latest schema, unless it does not match it.
+    fn encode(&self, value: &Json) -> Result<Vec<u8>, CodecError> {
+        let mut out = vec![0];
+        out.extend_from_slice(&(self.versions.len() as u32).to_be_bytes());
+        self.versions
+            .last()
+            .expect("a codec has a schema")
+            .encode(value, &mut out)
+            .map_err(CodecError::Encode)?;
+        Ok(out)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Json, CodecError> {
+        let Some(([0, v0, v1, v2, v3], mut input)) = bytes.split_first_chunk::<5>() else {
+            return Err(CodecError::Decode(
+                "the value lacks its schema version".to_string(),
+            ));
+        };
+        let version = u32::from_be_bytes([*v0, *v1, *v2, *v3]);
+        let writer = (version as usize)
+            .checked_sub(1)
+            .and_then(|i| self.versions.get(i))
+            .ok_or(CodecError::Decode(format!(
+                "schema version {version} is unknown"
+            )))?;
+        let value = writer.decode(&mut input).map_err(CodecError::Decode)?;
+        if !input.is_empty() {
+            let trailing = format!("{} bytes follow the value", input.len());
+            return Err(CodecError::Decode(trailing));
+        }
+        resolve(
+            value,
+            writer,
+            self.versions.last().expect("a codec has a schema"),
+        )
+        .map_err(CodecError::Decode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecJournal;
+    use crate::journal::{Journal, JournalError, MemJournal};
+
+    #[test]
+    fn test_avro() {
+        // A deposit event whose schema gains a currency with a default,
+        // and loses a memo
+
+        let v1 = AvroSchema::Record {
+            name: "Deposited".to_string(),
+            fields: vec![
+                AvroField::new("account", AvroSchema::String),
+                AvroField::new("amount", AvroSchema::Int),
+                AvroField::new(
+                    "memo",
+                    AvroSchema::Union(vec![AvroSchema::Null, AvroSchema::String]),
+                ),
+            ],
+        };
+        let schema = Json::parse(
+            r#"{"type":"record","name":"Deposited","fields":[
+                {"name":"account","type":"string"},
+                {"name":"amount","type":"long"},
+                {"name":"currency","type":{"type":"enum","name":"Currency","symbols":["EUR","USD"]},"default":"EUR"}]}"#,
+        )
+        .unwrap();
+        let v2 = AvroSchema::from_json(&schema).unwrap();
+        assert_eq!(AvroSchema::from_json(&v2.to_json()), Ok(v2.clone()));
+
+        let mut registry = AvroRegistry::new();
+        assert_eq!(registry.register("deposited", v1.clone()), Ok(1));
+        let old = AvroCodec::new(&registry, "deposited").unwrap();
+        assert_eq!(registry.register("deposited", v2.clone()), Ok(2));
+        assert_eq!(registry.register("deposited", v2.clone()), Ok(2));
+        let new = AvroCodec::new(&registry, "deposited").unwrap();
+
+        let written = Json::parse(r#"{"account":"acc","amount":-3,"memo":"rent"}"#).unwrap();
+        let bytes = old.encode(&written).unwrap();
+        // Version 1, "acc", -3 zigzagged, the second branch and "rent"
+        assert_eq!(bytes, b"\0\0\0\0\x01\x06acc\x05\x02\x08rent");
+        assert_eq!(old.decode(&bytes), Ok(written));
+        assert_eq!(
+            new.decode(&bytes),
+            Ok(Json::parse(r#"{"account":"acc","amount":-3,"currency":"EUR"}"#).unwrap())
+        );
+
+        let current =
+            Json::parse(r#"{"account":"b","amount":5000000000,"currency":"USD"}"#).unwrap();
+        assert_eq!(new.decode(&new.encode(&current).unwrap()), Ok(current));
+        assert_eq!(
+            new.encode(&Json::parse(r#"{"account":"b"}"#).unwrap()),
+            Err(CodecError::Encode("field amount is missing".to_string()))
+        );
+        assert!(old
+            .decode(
+                &new.encode(&Json::parse(r#"{"account":"b","amount":1}"#).unwrap())
+                    .unwrap()
+            )
+            .is_err());
+
+        // A field without a default cannot be added
+        let v3 = AvroSchema::Record {
+            name: "Deposited".to_string(),
+            fields: vec![AvroField::new("branch", AvroSchema::String)],
+        };
+        assert!(registry.register("deposited", v3).is_err());
+        assert_eq!(registry.latest("deposited").map(|(v, _)| v), Some(2));
+        assert_eq!(registry.schema("deposited", 1), Some(&v1));
+    }
+
+    #[test]
+    fn test_transitive_compatibility() {
+        // A currency is added with a default, then the amount and the
+        // default are dropped, which version 2 can be read as but not 1
+        let record = |fields| AvroSchema::Record {
+            name: "Deposited".to_string(),
+            fields,
+        };
+        let amount = AvroField::new("amount", AvroSchema::Long);
+        let currency = AvroField::new("currency", AvroSchema::String);
+        let defaulted = currency
+            .clone()
+            .with_default(Json::String("EUR".to_string()));
+
+        let mut registry = AvroRegistry::new();
+        registry
+            .register("deposited", record(vec![amount.clone()]))
+            .unwrap();
+        registry
+            .register("deposited", record(vec![amount, defaulted]))
+            .unwrap();
+        let v3 = record(vec![currency]);
+        assert!(can_read(registry.schema("deposited", 2).unwrap(), &v3).is_ok());
+        assert_eq!(
+            registry.register("deposited", v3),
+            Err("deposited is incompatible with version 1: field currency of Deposited has no default".to_string())
+        );
+        assert_eq!(registry.latest("deposited").map(|(v, _)| v), Some(2));
+    }
+
+    #[test]
+    fn test_unencodable() {
+        // A journal of Avro events refuses an event that does not match
+        // the schema, rather than panicking
+        let mut registry = AvroRegistry::new();
+        let schema = AvroSchema::Record {
+            name: "Deposited".to_string(),
+            fields: vec![AvroField::new("amount", AvroSchema::Long)],
+        };
+        registry.register("deposited", schema).unwrap();
+        let codec = AvroCodec::new(&registry, "deposited").unwrap();
+        let mut journal = CodecJournal::new(MemJournal::new(), codec);
+
+        let deposit = Json::parse(r#"{"amount":5}"#).unwrap();
+        assert!(journal.append(&"a", deposit.clone()).is_ok());
+        assert_eq!(
+            journal.append(&"a", Json::parse(r#"{"amount":"five"}"#).unwrap()),
+            Err(JournalError::Failed(
+                "cannot encode: \"five\" is not a long".to_string()
+            ))
+        );
+        let events: Vec<Json> = journal
+            .read(&"a", 1)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.event)
+            .collect();
+        assert_eq!(events, vec![deposit]);
+    }
+
+    #[test]
+    fn test_corrupt_blocks() {
+        // Counts that would panic, loop or exhaust memory are refused
+        let nulls = AvroSchema::Array(Box::new(AvroSchema::Null));
+        let decode = |schema: &AvroSchema, bytes: &[u8]| schema.decode(&mut &bytes[..]);
+
+        // i64::MIN, zigzagged, whose negation overflows
+        let min = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(
+            decode(&nulls, &min),
+            Err("a block count is out of range".to_string())
+        );
+
+        // i64::MAX items, and 64 map entries in a byte
+        let max = [0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(decode(&nulls, &max).is_err());
+        assert!(decode(
+            &AvroSchema::Map(Box::new(AvroSchema::Null)),
+            &[0x80, 0x01, 0x00]
+        )
+        .is_err());
+
+        // Blocks within the input still decode, blocked or not
+        let longs = AvroSchema::Array(Box::new(AvroSchema::Long));
+        assert_eq!(
+            decode(&longs, &[0x04, 0x02, 0x04, 0x01, 0x02, 0x06, 0x00]),
+            Ok(Json::parse("[1,2,3]").unwrap())
+        );
+        assert_eq!(
+            decode(&nulls, &[0x02, 0x00]),
+            Ok(Json::parse("[null]").unwrap())
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Backfilling a fresh snapshot store by replaying an existing journal
+//! through a new version of a machine, so that a reworked state
+//! definition can be deployed against historical data.
+//!
+//! Each journalled event is interpreted as an event of the new version.
+//! Events that cannot be interpreted are skipped and reported, with
+//! their offsets, so that they can be examined before the new version
+//! goes live. The snapshots reflect every record of an entity, skipped
+//! or not, so recovery does not try to interpret them again.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::command_and_event_traits::{Event, Transition};
+use crate::journal::{Journal, JournalError, Record};
+use crate::snapshot::SnapshotStore;
+
+/// A journalled event that the new version could not interpret.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uninterpretable<K> {
+    pub offset: u64,
+    pub key: K,
+    pub seq: u64,
+    pub reason: String,
+}
+
+/// What a backfill did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackfillReport<K> {
+    /// The number of entities snapshotted.
+    pub entities: usize,
+    /// The number of events applied.
+    pub applied: usize,
+    pub uninterpretable: Vec<Uninterpretable<K>>,
+}
+
+/// Why a backfill failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackfillError {
+    Journal(JournalError),
+    Save(String),
+}
+
+/// Replay a journal into a snapshot store, converting each event with
+/// `interpret` and applying it to the entity's state, starting from
+/// `initial`. Every entity in the journal is then saved as of its last
+/// sequence number.
+pub fn backfill<K, E, S, T, J, St, I>(
+    journal: &J,
+    snapshots: &mut St,
+    initial: impl Fn(&K) -> S,
+    mut interpret: I,
+) -> Result<BackfillReport<K>, BackfillError>
+where
+    K: Eq + Hash + Clone,
+    J: Journal<K, E>,
+    St: SnapshotStore<K, S>,
+    T: Event<S>,
+    I: FnMut(&Record<K, E>) -> Result<T, String>,
+{
+    let records = journal.read_all(0).map_err(BackfillError::Journal)?;
+    let mut states: HashMap<K, (u64, S)> = HashMap::new();
+    let mut order = Vec::new();
+    let mut applied = 0;
+    let mut uninterpretable = Vec::new();
+
+    for record in &records {
+        let (seq, state) = states.entry(record.key.clone()).or_insert_with(|| {
+            order.push(record.key.clone());
+            (0, initial(&record.key))
+        });
+        *seq = record.seq;
+        match interpret(record) {
+            Ok(event) => {
+                if let Transition::Next(s) = event.fire(state) {
+                    *state = s;
+                }
+                applied += 1;
+            }
+            Err(reason) => uninterpretable.push(Uninterpretable {
+                offset: record.offset,
+                key: record.key.clone(),
+                seq: record.seq,
+                reason,
+            }),
+        }
+    }
+
+    for key in &order {
+        let (seq, state) = &states[key];
+        snapshots
+            .save(key, *seq, state)
+            .map_err(BackfillError::Save)?;
+    }
+    Ok(BackfillReport {
+        entities: order.len(),
+        applied,
+        uninterpretable,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::MemJournal;
+    use crate::snapshot::MemSnapshotStore;
+
+    #[test]
+    fn test_backfill() {
+        // Version 1 journalled account movements as signed strings,
+        // version 2 keeps a balance and a count of movements
+
+        #[derive(Debug, Clone, PartialEq, Default)]
+        struct Account {
+            balance: i64,
+            movements: u32,
+        }
+
+        struct Moved(i64);
+
+        impl Event<Account> for Moved {
+            fn fire(&self, state: &Account) -> Transition<Account> {
+                Transition::Next(Account {
+                    balance: state.balance + self.0,
+                    movements: state.movements + 1,
+                })
+            }
+        }
+
+        let mut journal = MemJournal::new();
+        journal.append(&"a", "+10").unwrap();
+        journal.append(&"b", "+5").unwrap();
+        journal.append(&"a", "oops").unwrap();
+        journal.append(&"a", "-3").unwrap();
+
+        let interpret = |r: &Record<&str, &str>| {
+            r.event
+                .parse()
+                .map(Moved)
+                .map_err(|_| format!("not an amount: {}", r.event))
+        };
+        let mut snapshots = MemSnapshotStore::new();
+        let report = backfill(&journal, &mut snapshots, |_| Account::default(), interpret);
+
+        assert_eq!(
+            report,
+            Ok(BackfillReport {
+                entities: 2,
+                applied: 3,
+                uninterpretable: vec![Uninterpretable {
+                    offset: 2,
+                    key: "a",
+                    seq: 2,
+                    reason: "not an amount: oops".to_string()
+                }]
+            })
+        );
+        assert_eq!(
+            snapshots.load(&"a"),
+            Ok(Some((
+                3,
+                Account {
+                    balance: 7,
+                    movements: 2
+                }
+            )))
+        );
+
+        snapshots.fail_saves(true);
+        assert!(matches!(
+            backfill(&journal, &mut snapshots, |_| Account::default(), interpret),
+            Err(BackfillError::Save(_))
+        ));
+    }
+}
@@ -0,0 +1,180 @@
+//! Commands that emit several events at once, as a `Batch`. A batch is
+//! applied all or nothing: each event is applied to the state produced by
+//! the one before, and if any intermediate state violates the machine's
+//! `Invariant` the whole batch is refused, leaving the state as it was
+//! before the step. States are only ever replaced, never updated in place,
+//! so no undo is needed.
+//!
+//! Journals append a batch with `Journal::append_batch`, which is all or
+//! nothing for the journals here. Others may leave part of a failed batch
+//! appended, as its documentation describes.
+
+use crate::command_and_event_traits::{Event, Rejection, Transition};
+
+/// Several events emitted together.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch<E>(pub Vec<E>);
+
+/// The events of a batch fired in turn, without checking invariants.
+impl<S, E> Event<S> for Batch<E>
+where
+    E: Event<S>,
+{
+    fn fire(&self, state: &S) -> Transition<S> {
+        fire_all(state, &self.0, |_| Ok(())).unwrap_or(Transition::Same)
+    }
+}
+
+/// Conditions every state of a machine must satisfy.
+pub trait Invariant<S> {
+    fn invariant(state: &S) -> Result<(), String>;
+}
+
+/// An event of a batch that led to a state violating an invariant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub index: usize,
+    pub reason: String,
+}
+
+impl From<Violation> for Rejection {
+    fn from(v: Violation) -> Self {
+        Rejection::InvariantViolated(format!("event {}: {}", v.index, v.reason))
+    }
+}
+
+/// Apply a batch of events, checking the invariants of a machine F after
+/// each one, and refusing the whole batch if any is violated.
+pub fn apply_batch<F, S, E>(state: &S, events: &[E]) -> Result<Transition<S>, Violation>
+where
+    F: Invariant<S>,
+    E: Event<S>,
+{
+    fire_all(state, events, F::invariant)
+}
+
+fn fire_all<S, E>(
+    state: &S,
+    events: &[E],
+    check: impl Fn(&S) -> Result<(), String>,
+) -> Result<Transition<S>, Violation>
+where
+    E: Event<S>,
+{
+    let mut current: Option<S> = None;
+    for (index, event) in events.iter().enumerate() {
+        if let Transition::Next(s) = event.fire(current.as_ref().unwrap_or(state)) {
+            check(&s).map_err(|reason| Violation { index, reason })?;
+            current = Some(s);
+        }
+    }
+    Ok(match current {
+        Some(s) => Transition::Next(s),
+        None => Transition::Same,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregate::Aggregate;
+    use crate::command_and_event_traits::{Command, Fsm, Validate};
+    use crate::journal::{Journal, MemJournal};
+
+    #[test]
+    fn test_batch() {
+        // Stock transfers between two bins which must never go negative
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct Bins(i32, i32);
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Moved {
+            Out(i32),
+            In(i32),
+        }
+
+        impl Event<Bins> for Moved {
+            fn fire(&self, s: &Bins) -> Transition<Bins> {
+                match self {
+                    Moved::Out(n) => Transition::Next(Bins(s.0 - n, s.1)),
+                    Moved::In(n) => Transition::Next(Bins(s.0, s.1 + n)),
+                }
+            }
+        }
+
+        struct Transfer(Vec<i32>);
+
+        impl Command<Bins, ()> for Transfer {
+            type Output = Batch<Moved>;
+            fn execute(&self, _s: &Bins, _h: &mut ()) -> Option<Batch<Moved>> {
+                let moves = self.0.iter().flat_map(|n| [Moved::Out(*n), Moved::In(*n)]);
+                Some(Batch(moves.collect()))
+            }
+        }
+
+        impl Validate<Bins> for Transfer {}
+
+        struct MyFsm {}
+
+        impl Fsm<Bins, ()> for MyFsm {}
+
+        impl Invariant<Bins> for MyFsm {
+            fn invariant(s: &Bins) -> Result<(), String> {
+                if s.0 < 0 || s.1 < 0 {
+                    Err(format!("negative stock in {s:?}"))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let bins = Bins(5, 0);
+        let ok = [Moved::Out(2), Moved::In(2), Moved::Out(3), Moved::In(3)];
+        assert_eq!(
+            apply_batch::<MyFsm, _, _>(&bins, &ok),
+            Ok(Transition::Next(Bins(0, 5)))
+        );
+
+        let overdrawn = [Moved::Out(2), Moved::In(2), Moved::Out(4), Moved::In(4)];
+        assert_eq!(
+            apply_batch::<MyFsm, _, _>(&bins, &overdrawn),
+            Err(Violation {
+                index: 2,
+                reason: "negative stock in Bins(-1, 2)".to_string()
+            })
+        );
+
+        // Aggregates refuse the whole batch
+
+        let mut aggregate = Aggregate::<_, (), MyFsm, Moved>::new(bins);
+        assert_eq!(
+            aggregate
+                .handle_batch(&Transfer(vec![2, 4]), &mut ())
+                .unwrap_err(),
+            Rejection::InvariantViolated("event 2: negative stock in Bins(-1, 2)".to_string())
+        );
+        assert_eq!(aggregate.state(), &Bins(5, 0));
+        assert_eq!(aggregate.version(), 0);
+        assert_eq!(
+            aggregate
+                .handle_batch(&Transfer(vec![1]), &mut ())
+                .map(|e| e.len()),
+            Ok(2)
+        );
+        assert_eq!(aggregate.state(), &Bins(4, 1));
+        assert_eq!(aggregate.version(), 2);
+
+        // Journals append the whole batch
+
+        let mut journal = MemJournal::new();
+        journal.append(&"a", Moved::In(1)).unwrap();
+        let positions = journal
+            .append_batch(&"a", aggregate.take_uncommitted())
+            .unwrap();
+        assert_eq!(positions.len(), 2);
+        assert_eq!(journal.last_seq(&"a"), Ok(3));
+        journal.truncate(1).unwrap();
+        assert_eq!(journal.last_seq(&"a"), Ok(1));
+    }
+}
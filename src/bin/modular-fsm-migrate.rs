@@ -0,0 +1,100 @@
+//! Copy a journal file to another, upcasting events on the way. Both
+//! files are in the JSON lines form of `fsm_laboratory::file_journal`.
+//! Upcasters are given as a JSON object mapping an event's `type` member
+//! to a JSON patch, which is applied to each event of that type:
+//!
+//! ```text
+//! {"Opened": [{"op": "add", "path": "/by", "value": null}]}
+//! ```
+//!
+//! ```text
+//! modular-fsm-migrate <source> <target> [--from <offset>] [--upcast <patches>]
+//! ```
+
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+use fsm_laboratory::file_journal::FileJournal;
+use fsm_laboratory::journal::Record;
+use fsm_laboratory::json::Json;
+use fsm_laboratory::migrate::{migrate, MigrateError};
+use fsm_laboratory::patch::PatchEvent;
+
+const USAGE: &str =
+    "usage: modular-fsm-migrate <source> <target> [--from <offset>] [--upcast <patches>]";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<(), String> {
+    let mut args = env::args().skip(1);
+    let mut paths = Vec::new();
+    let mut from = 0;
+    let mut upcast = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = args.next().and_then(|n| n.parse().ok()).ok_or(USAGE)?,
+            "--upcast" => upcast = Some(args.next().ok_or(USAGE)?),
+            _ => paths.push(arg),
+        }
+    }
+    let [source_path, target_path] = paths.as_slice() else {
+        return Err(USAGE.to_string());
+    };
+
+    let patches = match &upcast {
+        Some(path) => load_patches(path)?,
+        None => Vec::new(),
+    };
+    let source = FileJournal::open(source_path).map_err(|e| format!("{source_path}: {e:?}"))?;
+    let mut target = FileJournal::open(target_path).map_err(|e| format!("{target_path}: {e:?}"))?;
+
+    let upcast = |record: &Record<String, Json>| {
+        let kind = record.event.get("type").and_then(Json::as_str);
+        match patches.iter().find(|(k, _)| Some(k.as_str()) == kind) {
+            Some((_, patch)) => patch
+                .apply(&record.event)
+                .map_err(|e| format!("patch operation {}: {}", e.index, e.message)),
+            None => Ok(record.event.clone()),
+        }
+    };
+    match migrate(&source, &mut target, from, upcast) {
+        Ok(n) => {
+            println!("copied {n} records from {source_path} to {target_path}");
+            Ok(())
+        }
+        Err(MigrateError::Read(e)) => Err(format!("{source_path}: {e:?}")),
+        Err(MigrateError::Upcast { offset, reason }) => {
+            Err(format!("{source_path}: record {offset}: {reason}"))
+        }
+        Err(MigrateError::Write { offset, error }) => {
+            Err(format!("{target_path}: record {offset}: {error:?}"))
+        }
+    }
+}
+
+/// The patch for each event type.
+fn load_patches(path: &str) -> Result<Vec<(String, PatchEvent)>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    let json =
+        Json::parse(&text).map_err(|e| format!("{path}: offset {}: {}", e.offset, e.message))?;
+    let members = json
+        .as_object()
+        .ok_or(format!("{path}: expected an object of patches"))?;
+    members
+        .iter()
+        .map(|(kind, patch)| {
+            PatchEvent::parse(patch)
+                .map(|p| (kind.clone(), p))
+                .map_err(|e| format!("{path}: {kind}: operation {}: {}", e.index, e.message))
+        })
+        .collect()
+}
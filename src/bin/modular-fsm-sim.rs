@@ -0,0 +1,144 @@
+//! Simulate a table driven machine. The table is loaded from a file in
+//! the text form described in `fsm_laboratory::table`. Commands are read
+//! one per line from stdin, or from a trace file given with `--replay`,
+//! and the events emitted and state changes are printed. Commands entered
+//! can be recorded to a trace file with `--record`.
+//!
+//! With `--soak`, the machine is instead soak tested for a number of
+//! steps, choosing among the commands valid in each state at random, with
+//! a seed given by `--seed`, and the coverage of its transitions is
+//! printed, with those never taken.
+//!
+//! ```text
+//! modular-fsm-sim <table> [--replay <trace>] [--record <trace>]
+//! modular-fsm-sim <table> --soak <steps> [--seed <seed>]
+//! ```
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::ExitCode;
+
+use fsm_laboratory::command_and_event_traits::{Fsm, Transition};
+use fsm_laboratory::descriptor::MachineDescriptor;
+use fsm_laboratory::soak::Soak;
+use fsm_laboratory::table::{parse_table, Table, TableCommand, TableFsm};
+
+const USAGE: &str = "usage: modular-fsm-sim <table> [--replay <trace>] [--record <trace>]
+       modular-fsm-sim <table> --soak <steps> [--seed <seed>]";
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{message}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run() -> Result<(), String> {
+    let mut args = env::args().skip(1);
+    let mut table_path = None;
+    let mut replay = None;
+    let mut record = None;
+    let mut soak = None;
+    let mut seed = 0;
+    let number = |arg: Option<String>| -> Result<u64, String> {
+        arg.and_then(|n| n.parse().ok()).ok_or(USAGE.to_string())
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--replay" => replay = Some(args.next().ok_or(USAGE)?),
+            "--record" => record = Some(args.next().ok_or(USAGE)?),
+            "--soak" => soak = Some(number(args.next())?),
+            "--seed" => seed = number(args.next())?,
+            _ if table_path.is_none() => table_path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    let table_path = table_path.ok_or(USAGE)?;
+
+    let text = fs::read_to_string(&table_path).map_err(|e| format!("{table_path}: {e}"))?;
+    let descriptor =
+        parse_table(&text).map_err(|e| format!("{table_path}:{}: {}", e.line, e.message))?;
+    let mut state = descriptor
+        .initial
+        .clone()
+        .or_else(|| descriptor.states.first().cloned())
+        .ok_or_else(|| format!("{table_path}: the table has no states"))?;
+    if let Some(steps) = soak {
+        return run_soak(descriptor, state, steps, seed);
+    }
+    let mut table = Table::new(descriptor);
+
+    let input: Box<dyn BufRead> = match &replay {
+        Some(path) => Box::new(BufReader::new(
+            File::open(path).map_err(|e| format!("{path}: {e}"))?,
+        )),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+    let mut recording = match &record {
+        Some(path) => Some(File::create(path).map_err(|e| format!("{path}: {e}"))?),
+        None => None,
+    };
+
+    println!("{} in {state}", table.descriptor().name);
+    for line in input.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let command = line.trim();
+        if command.is_empty() || command.starts_with('#') {
+            continue;
+        }
+        if let Some(file) = &mut recording {
+            writeln!(file, "{command}").map_err(|e| e.to_string())?;
+        }
+        let (event, trans) = TableFsm::step(&state, &TableCommand(command.to_string()), &mut table);
+        match (event, trans) {
+            (Some(e), Ok(Transition::Next(s))) => {
+                println!("{command}: {} {state} -> {s}", e.name);
+                state = s;
+            }
+            (Some(e), Ok(Transition::Same)) => println!("{command}: {} in {state}", e.name),
+            (None, _) => println!("{command}: ignored in {state}"),
+            (Some(_), Err(e)) => println!("{command}: rejected {e:?}"),
+        }
+    }
+    Ok(())
+}
+
+fn run_soak(
+    descriptor: MachineDescriptor,
+    state: String,
+    steps: u64,
+    seed: u64,
+) -> Result<(), String> {
+    let mut soak = Soak::new(String::clone).seed(seed).steps(steps);
+    for command in &descriptor.commands {
+        soak = soak.command(command, TableCommand(command.clone()));
+        for s in &descriptor.states {
+            if !descriptor.commands_in(s).contains(&command.as_str()) {
+                soak = soak.weight(s, command, 0);
+            }
+        }
+    }
+    let mut table = Table::new(descriptor.clone());
+    let coverage = soak
+        .run::<TableFsm, _>(&state, &mut table)
+        .map_err(|f| format!("{} broken in {}", f.invariant, f.state))?;
+
+    println!(
+        "{} soaked for {} steps in {} episodes from {state}, seed {seed}",
+        descriptor.name, coverage.steps, coverage.episodes
+    );
+    for ((from, command, to), count) in &coverage.transitions {
+        println!("{count:>8} {from} --{command}--> {to}");
+    }
+    for t in coverage.uncovered(&descriptor) {
+        println!("   never {} --{}--> {}", t.from, t.command, t.to);
+    }
+    for s in coverage.unvisited(&descriptor) {
+        println!("   never in {s}");
+    }
+    Ok(())
+}
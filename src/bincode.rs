@@ -0,0 +1,239 @@
+//! The bincode format, for compact and fast persistence of events shared
+//! only between Rust programs. Types implement `Bincode` by encoding
+//! their fields in order, as serde's derive would, and the bytes are
+//! those of bincode 1 with its default options: integers little-endian
+//! and of fixed width, lengths as `u64`, enum variants as `u32` indexes
+//! and options as a tag byte.
+//!
+//! The format is not self-describing, so a change to a type's fields
+//! changes its encoding. Add a schema version to the envelope, and keep
+//! the old type to decode old events, when an event changes.
+
+use crate::codec::{Codec, CodecError};
+use crate::envelope::CODEC_BINCODE;
+
+/// Values with a bincode encoding.
+pub trait Bincode: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decode a value from the start of the input, advancing it.
+    fn decode(input: &mut &[u8]) -> Result<Self, String>;
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if input.len() < n {
+        return Err(format!("expected {n} more bytes, found {}", input.len()));
+    }
+    let (taken, rest) = input.split_at(n);
+    *input = rest;
+    Ok(taken)
+}
+
+/// Encode the index of an enum's variant, before its fields.
+pub fn encode_variant(index: u32, out: &mut Vec<u8>) {
+    index.encode(out)
+}
+
+/// Decode the index of an enum's variant.
+pub fn decode_variant(input: &mut &[u8]) -> Result<u32, String> {
+    u32::decode(input)
+}
+
+macro_rules! bincode_number {
+    ($($t:ty),*) => {
+        $(
+            impl Bincode for $t {
+                fn encode(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn decode(input: &mut &[u8]) -> Result<Self, String> {
+                    let bytes = take(input, std::mem::size_of::<$t>())?;
+                    Ok(<$t>::from_le_bytes(bytes.try_into().expect("the size was taken")))
+                }
+            }
+        )*
+    };
+}
+
+bincode_number!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+impl Bincode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, String> {
+        match u8::decode(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(format!("{b} is not a bool")),
+        }
+    }
+}
+
+impl Bincode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, String> {
+        let len = u64::decode(input)? as usize;
+        let bytes = take(input, len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+}
+
+impl<T: Bincode> Bincode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, String> {
+        let len = u64::decode(input)? as usize;
+        // Each item takes at least a byte, which bounds a corrupt length
+        let mut items = Vec::with_capacity(len.min(input.len()));
+        for _ in 0..len {
+            items.push(T::decode(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Bincode> Bincode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            None => out.push(0),
+            Some(value) => {
+                out.push(1);
+                value.encode(out);
+            }
+        }
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, String> {
+        match u8::decode(input)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode(input)?)),
+            b => Err(format!("{b} is not an option tag")),
+        }
+    }
+}
+
+impl<A: Bincode, B: Bincode> Bincode for (A, B) {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.0.encode(out);
+        self.1.encode(out);
+    }
+
+    fn decode(input: &mut &[u8]) -> Result<Self, String> {
+        Ok((A::decode(input)?, B::decode(input)?))
+    }
+}
+
+/// Values encoded in the bincode format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl<T: Bincode> Codec<T> for BincodeCodec {
+    fn id(&self) -> u8 {
+        CODEC_BINCODE
+    }
+
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut out = Vec::new();
+        value.encode(&mut out);
+        Ok(out)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        let mut input = bytes;
+        let value = T::decode(&mut input).map_err(CodecError::Decode)?;
+        if !input.is_empty() {
+            let trailing = format!("{} bytes follow the value", input.len());
+            return Err(CodecError::Decode(trailing));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CodecJournal;
+    use crate::journal::{Journal, MemJournal};
+
+    #[test]
+    fn test_bincode() {
+        // Account events, persisted in a journal of bytes
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum AccountEvent {
+            Deposited { account: String, amount: u64 },
+            Closed(Option<String>),
+        }
+
+        impl Bincode for AccountEvent {
+            fn encode(&self, out: &mut Vec<u8>) {
+                match self {
+                    AccountEvent::Deposited { account, amount } => {
+                        encode_variant(0, out);
+                        account.encode(out);
+                        amount.encode(out);
+                    }
+                    AccountEvent::Closed(reason) => {
+                        encode_variant(1, out);
+                        reason.encode(out);
+                    }
+                }
+            }
+
+            fn decode(input: &mut &[u8]) -> Result<Self, String> {
+                match decode_variant(input)? {
+                    0 => Ok(AccountEvent::Deposited {
+                        account: String::decode(input)?,
+                        amount: u64::decode(input)?,
+                    }),
+                    1 => Ok(AccountEvent::Closed(Option::decode(input)?)),
+                    i => Err(format!("unknown variant {i}")),
+                }
+            }
+        }
+
+        let deposited = AccountEvent::Deposited {
+            account: "acc".to_string(),
+            amount: 5,
+        };
+        // The bytes bincode 1 writes for the same type with serde
+        let bytes = BincodeCodec.encode(&deposited).unwrap();
+        assert_eq!(
+            bytes,
+            [0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, b'a', b'c', b'c', 5, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(BincodeCodec.decode(&bytes), Ok(deposited.clone()));
+        assert!(Codec::<AccountEvent>::decode(&BincodeCodec, &bytes[..10]).is_err());
+        assert!(
+            Codec::<AccountEvent>::decode(&BincodeCodec, &[bytes.clone(), vec![0]].concat())
+                .is_err()
+        );
+
+        let mut journal = CodecJournal::new(MemJournal::new(), BincodeCodec);
+        journal.append(&"a", deposited.clone()).unwrap();
+        journal.append(&"a", AccountEvent::Closed(None)).unwrap();
+        let events: Vec<AccountEvent> = journal
+            .read(&"a", 1)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.event)
+            .collect();
+        assert_eq!(events, vec![deposited, AccountEvent::Closed(None)]);
+        assert_eq!(
+            journal.inner().read(&"a", 2).unwrap()[0].event,
+            [1, 0, 0, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Routing a percentage of entities to a new version of a machine, for
+//! a gradual rollout. Each entity is assigned to the canary or to the
+//! stable version by a stable hash of its key, so the assignment is
+//! sticky: an entity stays with one version for as long as it lives,
+//! and raising the percentage only moves entities inserted afterwards.
+//!
+//! Both versions must emit the same events for a command, so the rest
+//! of the system does not see which version stepped an entity. Metrics
+//! are kept per version, to compare the canary with the stable version.
+
+use std::hash::Hash;
+
+use crate::command_and_event_traits::{Command, Fsm, Validate};
+use crate::integrity::stable_hash;
+use crate::runner::{RunError, Runner};
+
+/// The version of the machine for an entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Version {
+    Stable,
+    Canary,
+}
+
+/// Counts of the commands sent to a version, of those that emitted no
+/// event, and of those that failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VersionMetrics {
+    pub sent: u64,
+    pub ignored: u64,
+    pub failed: u64,
+}
+
+/// A stable runner, of FSM F with states S, and a canary runner of FSM
+/// G with states T.
+pub struct Canary<K, S, H, F, T, I, G> {
+    stable: Runner<K, S, H, F>,
+    canary: Runner<K, T, I, G>,
+    convert: Box<dyn Fn(&S) -> T + Send>,
+    percent: u8,
+    stable_metrics: VersionMetrics,
+    canary_metrics: VersionMetrics,
+}
+
+impl<K, S, H, F, T, I, G> Canary<K, S, H, F, T, I, G>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    G: Fsm<T, I>,
+{
+    /// Route a percentage of new entities to the canary, converting their
+    /// initial states with `convert`.
+    pub fn new(
+        stable: Runner<K, S, H, F>,
+        canary: Runner<K, T, I, G>,
+        percent: u8,
+        convert: impl Fn(&S) -> T + Send + 'static,
+    ) -> Self {
+        Self {
+            stable,
+            canary,
+            convert: Box::new(convert),
+            percent: percent.min(100),
+            stable_metrics: VersionMetrics::default(),
+            canary_metrics: VersionMetrics::default(),
+        }
+    }
+
+    pub fn percent(&self) -> u8 {
+        self.percent
+    }
+
+    /// Change the percentage of entities inserted from now on that are
+    /// routed to the canary.
+    pub fn set_percent(&mut self, percent: u8) {
+        self.percent = percent.min(100);
+    }
+
+    /// The version a new entity with a key would be assigned.
+    pub fn assign(&self, key: &K) -> Version {
+        if stable_hash(key) % 100 < self.percent as u64 {
+            Version::Canary
+        } else {
+            Version::Stable
+        }
+    }
+
+    /// The version stepping an entity, if it exists.
+    pub fn version_of(&self, key: &K) -> Option<Version> {
+        if self.canary.state(key).is_some() || self.canary.failure(key).is_some() {
+            Some(Version::Canary)
+        } else if self.stable.state(key).is_some() || self.stable.failure(key).is_some() {
+            Some(Version::Stable)
+        } else {
+            None
+        }
+    }
+
+    /// Add an entity to the version assigned it, which is answered.
+    pub fn insert(&mut self, key: K, state: S) -> Version {
+        let version = self.assign(&key);
+        match version {
+            Version::Stable => self.stable.insert(key, state),
+            Version::Canary => self.canary.insert(key, (self.convert)(&state)),
+        }
+        version
+    }
+
+    pub fn stable(&self) -> &Runner<K, S, H, F> {
+        &self.stable
+    }
+
+    pub fn canary(&self) -> &Runner<K, T, I, G> {
+        &self.canary
+    }
+
+    pub fn metrics(&self, version: Version) -> VersionMetrics {
+        match version {
+            Version::Stable => self.stable_metrics,
+            Version::Canary => self.canary_metrics,
+        }
+    }
+
+    /// Step an entity with a command, in the version stepping it.
+    pub fn send<C, E>(&mut self, key: &K, command: &C) -> Result<Option<E>, RunError>
+    where
+        C: Command<S, H, Output = E> + Validate<S> + Command<T, I, Output = E> + Validate<T>,
+    {
+        let (result, metrics) = match self.version_of(key) {
+            Some(Version::Canary) => (self.canary.send(key, command), &mut self.canary_metrics),
+            Some(Version::Stable) => (self.stable.send(key, command), &mut self.stable_metrics),
+            None => return Err(RunError::Unknown),
+        };
+        metrics.sent += 1;
+        match &result {
+            Ok(None) => metrics.ignored += 1,
+            Err(_) => metrics.failed += 1,
+            Ok(Some(_)) => {}
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Transition, ValidationError};
+
+    #[test]
+    fn test_canary() {
+        // A counter, reworked to count in a wider type
+
+        struct Add(u8);
+
+        #[derive(Debug, PartialEq)]
+        struct Added(u8);
+
+        impl Command<u8, ()> for Add {
+            type Output = Added;
+            fn execute(&self, s: &u8, _h: &mut ()) -> Option<Added> {
+                s.checked_add(self.0).map(|_| Added(self.0))
+            }
+        }
+
+        impl Validate<u8> for Add {
+            fn validate(&self, _s: &u8) -> Result<(), ValidationError> {
+                if self.0 == 0 {
+                    Err(ValidationError("nothing to add".to_string()))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        impl Event<u8> for Added {
+            fn fire(&self, s: &u8) -> Transition<u8> {
+                Transition::Next(s + self.0)
+            }
+        }
+
+        impl Command<u64, ()> for Add {
+            type Output = Added;
+            fn execute(&self, _s: &u64, _h: &mut ()) -> Option<Added> {
+                Some(Added(self.0))
+            }
+        }
+
+        impl Validate<u64> for Add {}
+
+        impl Event<u64> for Added {
+            fn fire(&self, s: &u64) -> Transition<u64> {
+                Transition::Next(s + self.0 as u64)
+            }
+        }
+
+        struct Old {}
+
+        impl Fsm<u8, ()> for Old {}
+
+        struct New {}
+
+        impl Fsm<u64, ()> for New {}
+
+        let mut canary = Canary::new(
+            Runner::<u32, u8, (), Old>::new(()),
+            Runner::<u32, u64, (), New>::new(()),
+            0,
+            |s: &u8| *s as u64,
+        );
+        for key in 0..50 {
+            assert_eq!(canary.insert(key, 0), Version::Stable);
+        }
+
+        // About a quarter of new entities go to the canary
+        canary.set_percent(25);
+        let versions: Vec<_> = (50..250).map(|key| canary.insert(key, 0)).collect();
+        let canaries = versions.iter().filter(|v| **v == Version::Canary).count();
+        assert!((30..70).contains(&canaries), "{canaries} canaries");
+
+        // Entities stay with their version
+        let key = (50..250)
+            .find(|k| canary.assign(k) == Version::Canary)
+            .unwrap();
+        canary.set_percent(0);
+        assert_eq!(canary.version_of(&key), Some(Version::Canary));
+        for _ in 0..2 {
+            assert_eq!(canary.send(&key, &Add(200)), Ok(Some(Added(200))));
+        }
+        assert_eq!(canary.canary().state(&key), Some(&400));
+
+        assert_eq!(canary.send(&0, &Add(200)), Ok(Some(Added(200))));
+        assert_eq!(canary.send(&0, &Add(200)), Ok(None));
+        assert!(canary.send(&0, &Add(0)).is_err());
+        assert_eq!(canary.send(&1000, &Add(1)), Err(RunError::Unknown));
+
+        assert_eq!(
+            canary.metrics(Version::Canary),
+            VersionMetrics {
+                sent: 2,
+                ignored: 0,
+                failed: 0
+            }
+        );
+        assert_eq!(
+            canary.metrics(Version::Stable),
+            VersionMetrics {
+                sent: 3,
+                ignored: 1,
+                failed: 1
+            }
+        );
+    }
+}
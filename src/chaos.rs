@@ -0,0 +1,355 @@
+//! Injected failures and latency, so that the recovery, retry and
+//! compensation paths of a machine are exercised in tests. A `Chaos`
+//! fails a fraction of the operations it is asked about, and delays each
+//! by a latency within a range. It is seeded, so that a failing test is
+//! reproduced with the same seed, and it is a handle, so a clone kept by
+//! the test can count the faults injected or calm things down.
+//!
+//! A `ChaosHandler` wraps an effect handler, and a command performs each
+//! effect through `perform`, which may inject a fault instead of
+//! performing it. A `ChaosJournal` wraps a journal, so that any of its
+//! operations may fail with `JournalError::Failed`.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::journal::{Journal, JournalError, Position, Record};
+use crate::soak::Rng;
+
+#[derive(Debug)]
+struct ChaosState {
+    rng: Rng,
+    failure_rate: f64,
+    latency: (Duration, Duration),
+    operations: u64,
+    faults: u64,
+}
+
+/// Decides which operations fail and how long each takes.
+#[derive(Debug, Clone)]
+pub struct Chaos {
+    state: Arc<Mutex<ChaosState>>,
+}
+
+/// A failure injected into an operation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    pub operation: String,
+}
+
+impl Chaos {
+    /// Chaos that fails nothing and adds no latency until configured.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ChaosState {
+                rng: Rng::new(seed),
+                failure_rate: 0.0,
+                latency: (Duration::ZERO, Duration::ZERO),
+                operations: 0,
+                faults: 0,
+            })),
+        }
+    }
+
+    /// Fail a fraction of operations, from 0.0 for none to 1.0 for all.
+    pub fn with_failure_rate(self, rate: f64) -> Self {
+        self.set_failure_rate(rate);
+        self
+    }
+
+    /// Delay each operation by a latency between min and max.
+    pub fn with_latency(self, min: Duration, max: Duration) -> Self {
+        self.lock().latency = (min, max.max(min));
+        self
+    }
+
+    /// Change the fraction of operations that fail, for example to 0.0
+    /// so that a test can check how the system recovers.
+    pub fn set_failure_rate(&self, rate: f64) {
+        self.lock().failure_rate = rate.clamp(0.0, 1.0);
+    }
+
+    /// Delay an operation, then answer whether it should fail.
+    pub fn inject(&self, operation: &str) -> Result<(), Fault> {
+        let (delay, fail) = {
+            let mut state = self.lock();
+            let (min, max) = state.latency;
+            let spread = (max - min).as_nanos() as u64;
+            let delay = min + Duration::from_nanos(state.rng.below(spread + 1));
+            let rate = state.failure_rate;
+            let fail = state.rng.chance(rate);
+            state.operations += 1;
+            state.faults += fail as u64;
+            (delay, fail)
+        };
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        match fail {
+            true => Err(Fault {
+                operation: operation.to_string(),
+            }),
+            false => Ok(()),
+        }
+    }
+
+    /// The number of operations asked about.
+    pub fn operations(&self) -> u64 {
+        self.lock().operations
+    }
+
+    /// The number of faults injected.
+    pub fn faults(&self) -> u64 {
+        self.lock().faults
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ChaosState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An effect handler H whose effects may fail.
+pub struct ChaosHandler<H> {
+    inner: H,
+    chaos: Chaos,
+}
+
+impl<H> ChaosHandler<H> {
+    pub fn new(inner: H, chaos: Chaos) -> Self {
+        Self { inner, chaos }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+
+    /// Perform an effect with the inner handler, unless a fault is
+    /// injected, in which case it is not performed.
+    pub fn perform<R>(
+        &mut self,
+        effect: &str,
+        perform: impl FnOnce(&mut H) -> R,
+    ) -> Result<R, Fault> {
+        self.chaos.inject(effect)?;
+        Ok(perform(&mut self.inner))
+    }
+}
+
+/// A journal J whose operations may fail.
+pub struct ChaosJournal<J> {
+    inner: J,
+    chaos: Chaos,
+}
+
+impl<J> ChaosJournal<J> {
+    pub fn new(inner: J, chaos: Chaos) -> Self {
+        Self { inner, chaos }
+    }
+
+    pub fn inner(&self) -> &J {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> J {
+        self.inner
+    }
+
+    fn inject(&self, operation: &str) -> Result<(), JournalError> {
+        self.chaos
+            .inject(operation)
+            .map_err(|f| JournalError::Failed(format!("{} failed by chaos", f.operation)))
+    }
+}
+
+impl<K, E, J> Journal<K, E> for ChaosJournal<J>
+where
+    J: Journal<K, E>,
+{
+    fn append(&mut self, key: &K, event: E) -> Result<Position, JournalError> {
+        self.inject("append")?;
+        self.inner.append(key, event)
+    }
+
+    fn read(&self, key: &K, from_seq: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.inject("read")?;
+        self.inner.read(key, from_seq)
+    }
+
+    fn read_all(&self, from_offset: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.inject("read")?;
+        self.inner.read_all(from_offset)
+    }
+
+    fn read_page(
+        &self,
+        key: &K,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.inject("read")?;
+        self.inner.read_page(key, from_seq, limit)
+    }
+
+    fn last_seq(&self, key: &K) -> Result<u64, JournalError> {
+        self.inject("read")?;
+        self.inner.last_seq(key)
+    }
+
+    fn prune(&mut self, key: &K, up_to_seq: u64) -> Result<(), JournalError> {
+        self.inject("prune")?;
+        self.inner.prune(key, up_to_seq)
+    }
+
+    fn truncate(&mut self, from_offset: u64) -> Result<(), JournalError> {
+        self.inject("truncate")?;
+        self.inner.truncate(from_offset)
+    }
+
+    fn append_batch(&mut self, key: &K, events: Vec<E>) -> Result<Vec<Position>, JournalError> {
+        self.inject("append")?;
+        self.inner.append_batch(key, events)
+    }
+
+    fn append_expected(
+        &mut self,
+        key: &K,
+        expected: u64,
+        event: E,
+    ) -> Result<Position, JournalError> {
+        self.inject("append")?;
+        self.inner.append_expected(key, expected, event)
+    }
+
+    fn append_group(&mut self, events: Vec<(K, E)>) -> Vec<Result<Position, JournalError>> {
+        match self.inject("append") {
+            Ok(()) => self.inner.append_group(events),
+            Err(e) => events.iter().map(|_| Err(e.clone())).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Fsm, Transition, Validate};
+    use crate::fallible::{Outcome, Recover, TryCommand};
+    use crate::journal::MemJournal;
+    use std::time::Instant;
+
+    #[test]
+    fn test_chaos() {
+        // A payment whose charge fails now and then, moving the order to a
+        // state from which the charge is retried
+
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        enum Order {
+            Unpaid,
+            Retrying,
+            Paid,
+        }
+
+        type Gateway = ChaosHandler<Vec<u32>>;
+
+        struct Charge(u32);
+
+        #[derive(Debug, PartialEq)]
+        struct Charged {}
+
+        #[derive(Debug, PartialEq)]
+        struct ChargeFailed {}
+
+        impl TryCommand<Order, Gateway> for Charge {
+            type Output = Charged;
+            type Error = ChargeFailed;
+            fn try_execute(
+                &self,
+                _s: &Order,
+                h: &mut Gateway,
+            ) -> Result<Option<Charged>, ChargeFailed> {
+                h.perform("charge", |charges| charges.push(self.0))
+                    .map(|()| Some(Charged {}))
+                    .map_err(|_| ChargeFailed {})
+            }
+        }
+
+        impl Validate<Order> for Recover<Charge> {}
+
+        impl Event<Order> for Charged {
+            fn fire(&self, _s: &Order) -> Transition<Order> {
+                Transition::Next(Order::Paid)
+            }
+        }
+
+        impl Event<Order> for ChargeFailed {
+            fn fire(&self, _s: &Order) -> Transition<Order> {
+                Transition::Next(Order::Retrying)
+            }
+        }
+
+        struct Payments {}
+
+        impl Fsm<Order, Gateway> for Payments {}
+
+        let chaos = Chaos::new(11).with_failure_rate(0.5);
+        let mut gateway = ChaosHandler::new(Vec::new(), chaos.clone());
+        let mut retried = 0;
+        for _ in 0..20 {
+            let mut order = Order::Unpaid;
+            while order != Order::Paid {
+                let (event, trans) = Payments::step(&order, &Recover(Charge(10)), &mut gateway);
+                if let Some(Outcome::Failed(_)) = event {
+                    retried += 1;
+                }
+                if let Ok(Transition::Next(s)) = trans {
+                    order = s;
+                }
+            }
+        }
+        assert_eq!(gateway.inner().len(), 20);
+        assert_eq!(chaos.faults(), retried);
+        assert_eq!(chaos.operations(), 20 + retried);
+        assert!(retried > 5 && retried < 40);
+
+        // Journals fail as often, reproducibly for a seed
+        let failures = |seed| {
+            let mut journal =
+                ChaosJournal::new(MemJournal::new(), Chaos::new(seed).with_failure_rate(0.3));
+            let failed: Vec<bool> = (0..20).map(|i| journal.append(&"a", i).is_err()).collect();
+            let stored = journal.inner().read(&"a", 1).unwrap().len();
+            assert_eq!(stored, failed.iter().filter(|f| !**f).count());
+            failed
+        };
+        assert_eq!(failures(3), failures(3));
+        assert_ne!(failures(3), failures(4));
+
+        let chaos = Chaos::new(0).with_failure_rate(1.0);
+        let mut journal = ChaosJournal::new(MemJournal::new(), chaos.clone());
+        assert_eq!(
+            journal.append(&"a", 1),
+            Err(JournalError::Failed("append failed by chaos".to_string()))
+        );
+        assert!(journal
+            .append_group(vec![("a", 1), ("b", 2)])
+            .iter()
+            .all(Result::is_err));
+        chaos.set_failure_rate(0.0);
+        assert!(journal.append(&"a", 1).is_ok());
+
+        // Latency slows every operation
+        let chaos = Chaos::new(0).with_latency(Duration::from_millis(2), Duration::from_millis(4));
+        let started = Instant::now();
+        for _ in 0..5 {
+            assert_eq!(chaos.inject("read"), Ok(()));
+        }
+        assert!(started.elapsed() >= Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,288 @@
+//! Codecs encode events and states as bytes for persistence and for the
+//! wire. Each codec has an id, written in an `Envelope` so that a reader
+//! knows how to decode its payload. `CodecJournal` keeps events of any
+//! type in a journal of bytes, encoded with a codec, and so composes with
+//! `CompressedJournal` and with backends that store only bytes.
+//!
+//! `JsonCodec` encodes `Json` values as UTF-8 text. Binary codecs are in
+//! their own modules.
+//!
+//! A codec may refuse a value, such as one that does not match its schema,
+//! and `CodecJournal` reports that as a `JournalError` without appending.
+
+use std::fmt::{self, Display};
+use std::marker::PhantomData;
+
+use crate::envelope::CODEC_JSON;
+use crate::journal::{Journal, JournalError, Position, Record};
+use crate::json::Json;
+
+/// Why a codec could not encode a value or decode some bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodecError {
+    /// The value cannot be written by the codec
+    Encode(String),
+    /// The bytes are not a value written by the codec
+    Decode(String),
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Encode(reason) => write!(f, "cannot encode: {reason}"),
+            CodecError::Decode(reason) => write!(f, "cannot decode: {reason}"),
+        }
+    }
+}
+
+/// Encodes and decodes values of type T.
+pub trait Codec<T> {
+    /// The id of the codec, as written in envelopes.
+    fn id(&self) -> u8;
+
+    fn encode(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+
+    fn decode(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// `Json` values as UTF-8 text.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec<Json> for JsonCodec {
+    fn id(&self) -> u8 {
+        CODEC_JSON
+    }
+
+    fn encode(&self, value: &Json) -> Result<Vec<u8>, CodecError> {
+        Ok(value.to_string().into_bytes())
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Json, CodecError> {
+        let text = std::str::from_utf8(bytes).map_err(|e| CodecError::Decode(e.to_string()))?;
+        Json::parse(text)
+            .map_err(|e| CodecError::Decode(format!("offset {}: {}", e.offset, e.message)))
+    }
+}
+
+/// A journal of events E, each encoded by a codec C in a journal J of bytes.
+pub struct CodecJournal<J, C, E> {
+    journal: J,
+    codec: C,
+    events: PhantomData<fn() -> E>,
+}
+
+impl<J, C, E> CodecJournal<J, C, E>
+where
+    C: Codec<E>,
+{
+    pub fn new(journal: J, codec: C) -> Self {
+        Self {
+            journal,
+            codec,
+            events: PhantomData,
+        }
+    }
+
+    /// The journal of encoded events.
+    pub fn inner(&self) -> &J {
+        &self.journal
+    }
+
+    fn encode(&self, event: &E) -> Result<Vec<u8>, JournalError> {
+        self.codec
+            .encode(event)
+            .map_err(|e| JournalError::Failed(e.to_string()))
+    }
+
+    fn decode<K>(
+        &self,
+        records: Vec<Record<K, Vec<u8>>>,
+    ) -> Result<Vec<Record<K, E>>, JournalError> {
+        records
+            .into_iter()
+            .map(|r| match self.codec.decode(&r.event) {
+                Ok(event) => Ok(Record {
+                    offset: r.offset,
+                    key: r.key,
+                    seq: r.seq,
+                    event,
+                }),
+                Err(e) => Err(JournalError::Failed(format!("offset {}: {e}", r.offset))),
+            })
+            .collect()
+    }
+}
+
+impl<K, J, C, E> Journal<K, E> for CodecJournal<J, C, E>
+where
+    J: Journal<K, Vec<u8>>,
+    C: Codec<E>,
+{
+    fn append(&mut self, key: &K, event: E) -> Result<Position, JournalError> {
+        self.journal.append(key, self.encode(&event)?)
+    }
+
+    fn read(&self, key: &K, from_seq: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.decode(self.journal.read(key, from_seq)?)
+    }
+
+    fn read_all(&self, from_offset: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.decode(self.journal.read_all(from_offset)?)
+    }
+
+    fn read_page(
+        &self,
+        key: &K,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.decode(self.journal.read_page(key, from_seq, limit)?)
+    }
+
+    fn last_seq(&self, key: &K) -> Result<u64, JournalError> {
+        self.journal.last_seq(key)
+    }
+
+    fn prune(&mut self, key: &K, up_to_seq: u64) -> Result<(), JournalError> {
+        self.journal.prune(key, up_to_seq)
+    }
+
+    fn truncate(&mut self, from_offset: u64) -> Result<(), JournalError> {
+        self.journal.truncate(from_offset)
+    }
+
+    fn append_batch(&mut self, key: &K, events: Vec<E>) -> Result<Vec<Position>, JournalError> {
+        // Nothing is appended unless every event is encoded
+        let events = events
+            .iter()
+            .map(|e| self.encode(e))
+            .collect::<Result<_, _>>()?;
+        self.journal.append_batch(key, events)
+    }
+
+    fn append_expected(
+        &mut self,
+        key: &K,
+        expected: u64,
+        event: E,
+    ) -> Result<Position, JournalError> {
+        self.journal
+            .append_expected(key, expected, self.encode(&event)?)
+    }
+
+    fn append_group(&mut self, events: Vec<(K, E)>) -> Vec<Result<Position, JournalError>> {
+        // Events that cannot be encoded fail alone, in their place
+        let mut encoded = Vec::new();
+        let mut results: Vec<_> = events
+            .into_iter()
+            .map(|(k, e)| match self.encode(&e) {
+                Ok(bytes) => {
+                    encoded.push((k, bytes));
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            })
+            .collect();
+        let mut appended = self.journal.append_group(encoded).into_iter();
+        for result in results.iter_mut().filter(|r| r.is_none()) {
+            *result = appended.next();
+        }
+        results
+            .into_iter()
+            .map(|r| r.unwrap_or_else(|| Err(JournalError::Failed("not appended".to_string()))))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::MemJournal;
+
+    /// Encodes only strings, as a codec with a schema refuses values that
+    /// do not match it.
+    struct Strings;
+
+    impl Codec<Json> for Strings {
+        fn id(&self) -> u8 {
+            CODEC_JSON
+        }
+
+        fn encode(&self, value: &Json) -> Result<Vec<u8>, CodecError> {
+            match value {
+                Json::String(_) => JsonCodec.encode(value),
+                _ => Err(CodecError::Encode("not a string".to_string())),
+            }
+        }
+
+        fn decode(&self, bytes: &[u8]) -> Result<Json, CodecError> {
+            JsonCodec.decode(bytes)
+        }
+    }
+
+    fn text(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut journal = CodecJournal::new(MemJournal::new(), JsonCodec);
+        let event = Json::parse(r#"{"type":"Deposited","amount":5}"#).unwrap();
+        journal.append(&"a", event.clone()).unwrap();
+        journal
+            .append_batch(&"b", vec![text("x"), text("y")])
+            .unwrap();
+        journal.append_expected(&"a", 1, Json::Null).unwrap();
+
+        let a: Vec<Json> = journal
+            .read(&"a", 1)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.event)
+            .collect();
+        assert_eq!(a, vec![event, Json::Null]);
+        assert_eq!(journal.read_all(0).unwrap().len(), 4);
+        assert_eq!(journal.inner().read(&"b", 2).unwrap()[0].event, b"\"y\"");
+    }
+
+    #[test]
+    fn test_corrupt_input() {
+        // Bytes that do not decode fail the read, naming their offset
+        let mut bytes = MemJournal::new();
+        bytes.append(&"a", b"\"ok\"".to_vec()).unwrap();
+        bytes.append(&"a", b"{\"broken".to_vec()).unwrap();
+        let journal = CodecJournal::new(bytes, JsonCodec);
+        match journal.read(&"a", 1) {
+            Err(JournalError::Failed(reason)) => assert!(reason.starts_with("offset 1: ")),
+            other => panic!("expected a failure, found {other:?}"),
+        }
+        assert_eq!(journal.read(&"a", 1).ok(), None);
+        assert_eq!(journal.read_page(&"a", 1, 1).map(|r| r.len()), Ok(1));
+
+        let invalid = JsonCodec.decode(&[0xff]);
+        assert!(matches!(invalid, Err(CodecError::Decode(_))));
+    }
+
+    #[test]
+    fn test_unencodable() {
+        // A value the codec refuses is an error, and is not appended
+        let mut journal = CodecJournal::new(MemJournal::new(), Strings);
+        assert_eq!(
+            journal.append(&"a", Json::Null),
+            Err(JournalError::Failed(
+                "cannot encode: not a string".to_string()
+            ))
+        );
+        assert!(journal
+            .append_batch(&"a", vec![text("x"), Json::Null])
+            .is_err());
+        assert_eq!(journal.last_seq(&"a"), Ok(0));
+
+        let results =
+            journal.append_group(vec![("a", text("x")), ("b", Json::Null), ("c", text("z"))]);
+        let appended: Vec<bool> = results.iter().map(Result::is_ok).collect();
+        assert_eq!(appended, vec![true, false, true]);
+        assert_eq!(journal.read_all(0).unwrap().len(), 2);
+    }
+}
@@ -5,6 +5,8 @@
 //!
 //! For more background on [Event-driven Finite State Machines](http://christopherhunt-software.blogspot.com/2021/02/event-driven-finite-state-machines.html).
 
+use crate::observer::type_label;
+
 /// Describes how to transition from one state to another
 #[derive(Debug, PartialEq)]
 pub enum Transition<S> {
@@ -14,16 +16,129 @@ pub enum Transition<S> {
     Same,
 }
 
+impl<S> Transition<S> {
+    /// Map the next state, leaving `Same` alone.
+    pub fn map<T, F>(self, f: F) -> Transition<T>
+    where
+        F: FnOnce(S) -> T,
+    {
+        match self {
+            Transition::Next(s) => Transition::Next(f(s)),
+            Transition::Same => Transition::Same,
+        }
+    }
+
+    /// Chain a further transition from the next state.
+    /// `Same` propagates without calling `f`.
+    pub fn and_then<T, F>(self, f: F) -> Transition<T>
+    where
+        F: FnOnce(S) -> Transition<T>,
+    {
+        match self {
+            Transition::Next(s) => f(s),
+            Transition::Same => Transition::Same,
+        }
+    }
+
+    /// Use an alternative transition if this one is `Same`.
+    pub fn or(self, other: Transition<S>) -> Transition<S> {
+        match self {
+            Transition::Next(s) => Transition::Next(s),
+            Transition::Same => other,
+        }
+    }
+}
+
+/// An entry hook's refusal of a transition, with the reason.
+#[derive(Debug, PartialEq)]
+pub struct Veto(pub String);
+
+/// A command's failed precondition, with the reason.
+#[derive(Debug, PartialEq)]
+pub struct ValidationError(pub String);
+
+/// Why a step did not produce a transition.
+#[derive(Debug, PartialEq)]
+pub enum StepError {
+    /// The command failed validation and was not executed
+    Invalid(ValidationError),
+    /// The command's event was refused by an entry hook
+    Vetoed(Veto),
+    /// The command, named, is not allowed in the state and was not executed
+    NotAllowed(String),
+}
+
+/// Why a command was rejected, as a caller may need to branch on it.
+/// The validation, fallible command and persistence layers all convert
+/// their errors into these.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    /// The command is not one the state accepts
+    NotAllowedInState,
+    /// The command failed validation, with the reason
+    ValidationFailed(String),
+    /// The transition was refused by an entry hook, with the reason
+    Vetoed(String),
+    /// The entity was changed concurrently, so was not at the expected version
+    ConcurrencyConflict { expected: u64, actual: u64 },
+    /// The command's effect failed, with the error
+    EffectFailed(String),
+    /// The command's events led to a state violating an invariant
+    InvariantViolated(String),
+    /// The caller is not authorized to send the command, with the reason
+    Unauthorized(String),
+    /// The command exceeded a rate limit
+    RateLimited,
+    /// The command needs a feature flag that is off, named
+    FeatureDisabled(String),
+}
+
+impl From<ValidationError> for Rejection {
+    fn from(ValidationError(reason): ValidationError) -> Self {
+        Rejection::ValidationFailed(reason)
+    }
+}
+
+impl From<Veto> for Rejection {
+    fn from(Veto(reason): Veto) -> Self {
+        Rejection::Vetoed(reason)
+    }
+}
+
+impl From<StepError> for Rejection {
+    fn from(error: StepError) -> Self {
+        match error {
+            StepError::Invalid(e) => e.into(),
+            StepError::Vetoed(v) => v.into(),
+            StepError::NotAllowed(_) => Rejection::NotAllowedInState,
+        }
+    }
+}
+
+/// The outcome of a step: the event emitted by the command, if any,
+/// and the resulting transition or the reason there was none.
+pub type StepOutcome<E, S> = (Option<E>, Result<Transition<S>, StepError>);
+
 /// How to operate on just part of the state.
 /// Self is the state of an FSM and T
 /// is a view of that state of interest to
 /// some Event or Command.
-trait Lens<T> {
+pub trait Lens<T> {
     /// Extract a view of state.
     fn extract(&self) -> &T;
 
-    /// Update state to accord with a view.
-    fn inject(&self, view: T) -> Self;
+    /// Update state in place to accord with a view.
+    fn inject_mut(&mut self, view: T);
+
+    /// Consume state and return it updated to accord with a view.
+    /// Parts of the state outside the view are moved, not cloned.
+    fn inject(mut self, view: T) -> Self
+    where
+        Self: Sized,
+    {
+        self.inject_mut(view);
+        self
+    }
 }
 
 /// THis blanket implementation views the whole of the state.
@@ -32,7 +147,11 @@ impl<S> Lens<S> for S {
         self
     }
 
-    fn inject(&self, part: Self) -> Self {
+    fn inject_mut(&mut self, part: Self) {
+        *self = part;
+    }
+
+    fn inject(self, part: Self) -> Self {
         part
     }
 }
@@ -47,6 +166,27 @@ pub trait Event<S> {
 pub trait Command<S, H> {
     type Output: Event<S>;
     fn execute(&self, state: &S, handler: &mut H) -> Option<Self::Output>;
+
+    /// The name of the command, as `Fsm::allows` and a descriptor know
+    /// it. By default the `type_label` of its type.
+    fn name(&self) -> String {
+        type_label::<Self>()
+    }
+}
+
+/// A question about a state, answered without any transition or effect.
+pub trait Query<S> {
+    type Reply;
+    fn answer(&self, state: &S) -> Self::Reply;
+}
+
+/// Pure precondition checks for a command, run before it is executed.
+/// These have no access to the effect handler, so can also be used
+/// to check a command before submitting it. The default accepts everything.
+pub trait Validate<S> {
+    fn validate(&self, _state: &S) -> Result<(), ValidationError> {
+        Ok(())
+    }
 }
 
 /// Describes the behavior of a Finite State Machine (FSM) that can receive commands and produce
@@ -56,7 +196,7 @@ pub trait Command<S, H> {
 /// The generic types refer to:
 /// S  = State          - the state of your FSM
 /// H = State Effect   - the effect handler, required by commands
-trait Fsm<S, H> {
+pub trait Fsm<S, H> {
     /// Given a state and command, optionally emit an event. Can perform side
     /// effects along the way. This function is generally only called from the
     /// `step` function.
@@ -71,44 +211,165 @@ trait Fsm<S, H> {
     /// Given a state and event, produce a transition, which could transition to
     /// the next state. No side effects are to be performed. Can be used to replay
     /// events to attain a new state i.e. the major function of event sourcing.
+    /// The next state is a copy of the state with the view injected, so
+    /// state outside the view is cloned; `for_event_mut` avoids that.
     fn for_event<E, T>(state: &S, event: &E) -> Transition<S>
+    where
+        E: Event<T>,
+        S: Lens<T> + Clone,
+    {
+        Self::for_event_view(state, event).map(|t| state.clone().inject(t))
+    }
+
+    /// Given a state and event, produce the transition of the view the
+    /// event is defined over, without building a new state. For an event
+    /// over the whole state this is its next state, which is how `step`
+    /// applies events.
+    fn for_event_view<E, T>(state: &S, event: &E) -> Transition<T>
     where
         E: Event<T>,
         S: Lens<T>,
     {
-        match event.fire(state.extract()) {
-            Transition::Next(t) => Transition::Next(state.inject(t)),
-            Transition::Same => Transition::Same,
+        event.fire(state.extract())
+    }
+
+    /// Apply an event to a state in place, answering whether a transition
+    /// occurred. Like `for_event` there are no side effects, but the state
+    /// is updated with `Lens::inject_mut` rather than rebuilt, which suits
+    /// replaying long runs of events against a large state.
+    fn for_event_mut<E, T>(state: &mut S, event: &E) -> bool
+    where
+        E: Event<T>,
+        S: Lens<T>,
+    {
+        match event.fire(<S as Lens<T>>::extract(state)) {
+            Transition::Next(t) => {
+                state.inject_mut(t);
+                true
+            }
+            Transition::Same => false,
         }
     }
 
+    /// Optional guard for when about to transition into a new state.
+    /// Returning a `Veto` keeps the old state, for example when entering the
+    /// new state depends on a capacity known only to the effect handler.
+    fn on_entry(_old_s: &S, _new_s: &S, _h: &mut H) -> Result<(), Veto> {
+        Ok(())
+    }
+
     /// Optional logic for when transitioning into a new state.
     fn on_transition(_old_s: &S, _new_s: &S, _h: &mut H) {}
 
+    /// Optional declaration of the commands allowed in a state, by name.
+    /// A command not allowed is refused with `StepError::NotAllowed`
+    /// before it is validated or executed, so that commands need not
+    /// check for the states they do not accept. The default allows every
+    /// command, and `Describe::declares` allows those a descriptor does.
+    fn allows(_state: &S, _command: &str) -> bool {
+        true
+    }
+
+    /// Optional logic for when a command is about to be executed
+    /// e.g. auditing commands as they arrive.
+    fn before_command<C>(_s: &S, _c: &C, _h: &mut H)
+    where
+        C: Command<S, H>,
+    {
+    }
+
+    /// Optional logic for when an event has been applied, given the state
+    /// it was applied to and the resulting transition. Called after any
+    /// `on_transition` processing, but not if the transition was vetoed.
+    fn after_event<E>(_s: &S, _e: &E, _t: &Transition<S>, _h: &mut H)
+    where
+        E: Event<S>,
+    {
+    }
+
     /// This is the main entry point to the event driven FSM.
     /// Runs the state machine for a command, optionally performing effects,
     /// producing an event and transitioning to a new state. Also
     /// applies any "Entry/" or "Exit/" processing when arriving
-    /// at a new state.
+    /// at a new state. The command is checked to be allowed in the state
+    /// and validated before it is executed. A transition refused by
+    /// `on_entry` is reported as the `Veto`, alongside the event that
+    /// would have caused it.
     fn step<C>(
         state: &S,
         command: &C,
         handler: &mut H,
-    ) -> (Option<<C as Command<S, H>>::Output>, Transition<S>)
+    ) -> StepOutcome<<C as Command<S, H>>::Output, S>
     where
-        C: Command<S, H>,
+        C: Command<S, H> + Validate<S>,
     {
-        let result = Self::for_command(state, command, handler);
-        let trans = if let Some(event) = &result {
-            let trans = Self::for_event(state, event);
-            if let Transition::Next(new_s) = &trans {
-                Self::on_transition(state, new_s, handler);
-            };
-            trans
-        } else {
-            Transition::Same
-        };
-        (result, trans)
+        match Self::step_command(state, command, handler) {
+            Ok(Some(event)) => {
+                let trans = Self::step_event(state, &event, handler);
+                (Some(event), trans)
+            }
+            Ok(None) => (None, Ok(Transition::Same)),
+            Err(e) => (None, Err(e)),
+        }
+    }
+
+    /// The first half of `step`, producing the event for a command without
+    /// applying it. Calls the `before_command` hook, then checks that the
+    /// command is allowed and valid before executing it.
+    fn step_command<C>(
+        state: &S,
+        command: &C,
+        handler: &mut H,
+    ) -> Result<Option<<C as Command<S, H>>::Output>, StepError>
+    where
+        C: Command<S, H> + Validate<S>,
+    {
+        Self::before_command(state, command, handler);
+        let name = command.name();
+        if !Self::allows(state, &name) {
+            return Err(StepError::NotAllowed(name));
+        }
+        command.validate(state).map_err(StepError::Invalid)?;
+        Ok(Self::for_command(state, command, handler))
+    }
+
+    /// The second half of `step`, for an event already produced by a
+    /// command. Transitions for the event, applying the "Entry/" and
+    /// "Exit/" processing and the `after_event` hook. This is
+    /// `enter_event` followed by `complete_event`.
+    fn step_event<E>(state: &S, event: &E, handler: &mut H) -> Result<Transition<S>, StepError>
+    where
+        E: Event<S>,
+    {
+        let trans = Self::enter_event(state, event, handler)?;
+        Self::complete_event(state, event, &trans, handler);
+        Ok(trans)
+    }
+
+    /// The transition for an event, checked by `on_entry`, but with no
+    /// further hooks called. Between this and `complete_event` the event
+    /// is known to be accepted and can be persisted.
+    fn enter_event<E>(state: &S, event: &E, handler: &mut H) -> Result<Transition<S>, StepError>
+    where
+        E: Event<S>,
+    {
+        let trans = Self::for_event_view(state, event);
+        if let Transition::Next(new_s) = &trans {
+            Self::on_entry(state, new_s, handler).map_err(StepError::Vetoed)?;
+        }
+        Ok(trans)
+    }
+
+    /// Finish applying an event accepted by `enter_event`, calling
+    /// `on_transition` for a new state and then `after_event`.
+    fn complete_event<E>(state: &S, event: &E, trans: &Transition<S>, handler: &mut H)
+    where
+        E: Event<S>,
+    {
+        if let Transition::Next(new_s) = trans {
+            Self::on_transition(state, new_s, handler);
+        }
+        Self::after_event(state, event, trans, handler);
     }
 }
 
@@ -117,6 +378,31 @@ mod tests {
     use super::*;
 
     #[test]
+    fn test_transition_combinators() {
+        let next: Transition<u32> = Transition::Next(1);
+        let same: Transition<u32> = Transition::Same;
+
+        assert_eq!(next.map(|s| s + 1), Transition::Next(2));
+        assert_eq!(Transition::<u32>::Same.map(|s| s + 1), Transition::Same);
+
+        assert_eq!(
+            Transition::Next(1).and_then(|s| Transition::Next(s * 10)),
+            Transition::Next(10)
+        );
+        assert_eq!(
+            Transition::Next(1).and_then(|_| Transition::<u32>::Same),
+            Transition::Same
+        );
+
+        assert_eq!(same.or(Transition::Next(3)), Transition::Next(3));
+        assert_eq!(
+            Transition::Next(1).or(Transition::Next(3)),
+            Transition::Next(1)
+        );
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_step() {
         // Declare our state, commands and events
 
@@ -182,6 +468,8 @@ mod tests {
             }
         }
 
+        impl Validate<State> for Command {}
+
         impl super::Event<State> for Event {
             fn fire(&self, s: &State) -> Transition<State> {
                 match (s, self) {
@@ -224,7 +512,7 @@ mod tests {
 
         let (e, t) = MyFsm::step(&State::Stopped, &Command::Start, &mut se);
         assert_eq!(e, Some(Event::Started));
-        assert_eq!(t, Transition::Next(State::Started));
+        assert_eq!(t, Ok(Transition::Next(State::Started)));
         assert_eq!(se.started, 1);
         assert_eq!(se.stopped, 0);
         assert_eq!(se.transitioned_started_to_stopped, 0);
@@ -232,7 +520,7 @@ mod tests {
 
         let (e, t) = MyFsm::step(&State::Started, &Command::Start, &mut se);
         assert_eq!(e, None);
-        assert_eq!(t, Transition::Same);
+        assert_eq!(t, Ok(Transition::Same));
         assert_eq!(se.started, 1);
         assert_eq!(se.stopped, 0);
         assert_eq!(se.transitioned_started_to_stopped, 0);
@@ -240,7 +528,7 @@ mod tests {
 
         let (e, t) = MyFsm::step(&State::Started, &Command::Stop, &mut se);
         assert_eq!(e, Some(Event::Stopped));
-        assert_eq!(t, Transition::Next(State::Stopped));
+        assert_eq!(t, Ok(Transition::Next(State::Stopped)));
         assert_eq!(se.started, 1);
         assert_eq!(se.stopped, 1);
         assert_eq!(se.transitioned_started_to_stopped, 1);
@@ -248,7 +536,7 @@ mod tests {
 
         let (e, t) = MyFsm::step(&&State::Stopped, &Command::Stop, &mut se);
         assert_eq!(e, None);
-        assert_eq!(t, Transition::Same);
+        assert_eq!(t, Ok(Transition::Same));
         assert_eq!(se.started, 1);
         assert_eq!(se.stopped, 1);
         assert_eq!(se.transitioned_started_to_stopped, 1);
@@ -256,6 +544,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_step_alt() {
         // Declare our state, commands and events
 
@@ -314,6 +603,8 @@ mod tests {
             }
         }
 
+        impl Validate<State> for Start {}
+
         impl Command<State, EffectHandlers> for Stop {
             type Output = Stopped;
             fn execute(&self, s: &State, se: &mut EffectHandlers) -> Option<Stopped> {
@@ -327,6 +618,8 @@ mod tests {
             }
         }
 
+        impl Validate<State> for Stop {}
+
         impl Event<State> for Started {
             fn fire(&self, s: &State) -> Transition<State> {
                 match s {
@@ -376,7 +669,7 @@ mod tests {
 
         let (e, t) = MyFsm::step(&State::Stopped, &Start {}, &mut se);
         assert_eq!(e, Some(Started {}));
-        assert_eq!(t, Transition::Next(State::Started));
+        assert_eq!(t, Ok(Transition::Next(State::Started)));
         assert_eq!(se.started, 1);
         assert_eq!(se.stopped, 0);
         assert_eq!(se.transitioned_started_to_stopped, 0);
@@ -384,7 +677,7 @@ mod tests {
 
         let (e, t) = MyFsm::step(&State::Started, &Start {}, &mut se);
         assert_eq!(e, None);
-        assert_eq!(t, Transition::Same);
+        assert_eq!(t, Ok(Transition::Same));
         assert_eq!(se.started, 1);
         assert_eq!(se.stopped, 0);
         assert_eq!(se.transitioned_started_to_stopped, 0);
@@ -392,7 +685,7 @@ mod tests {
 
         let (e, t) = MyFsm::step(&State::Started, &Stop {}, &mut se);
         assert_eq!(e, Some(Stopped {}));
-        assert_eq!(t, Transition::Next(State::Stopped));
+        assert_eq!(t, Ok(Transition::Next(State::Stopped)));
         assert_eq!(se.started, 1);
         assert_eq!(se.stopped, 1);
         assert_eq!(se.transitioned_started_to_stopped, 1);
@@ -400,10 +693,266 @@ mod tests {
 
         let (e, t) = MyFsm::step(&&State::Stopped, &Stop {}, &mut se);
         assert_eq!(e, None);
-        assert_eq!(t, Transition::Same);
+        assert_eq!(t, Ok(Transition::Same));
         assert_eq!(se.started, 1);
         assert_eq!(se.stopped, 1);
         assert_eq!(se.transitioned_started_to_stopped, 1);
         assert_eq!(se.transitioned_stopped_to_started, 1);
     }
+
+    #[test]
+    fn test_for_event_mut() {
+        // A large state where events are only interested in the status
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Status {
+            Open,
+            Closed,
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct Account {
+            status: Status,
+            history: Vec<u32>,
+        }
+
+        impl Lens<Status> for Account {
+            fn extract(&self) -> &Status {
+                &self.status
+            }
+
+            fn inject_mut(&mut self, view: Status) {
+                self.status = view;
+            }
+        }
+
+        struct Closed {}
+
+        impl Event<Status> for Closed {
+            fn fire(&self, s: &Status) -> Transition<Status> {
+                match s {
+                    Status::Open => Transition::Next(Status::Closed),
+                    Status::Closed => Transition::Same,
+                }
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<Account, ()> for MyFsm {}
+
+        // Replay the event twice, only the first causes a transition
+
+        let mut account = Account {
+            status: Status::Open,
+            history: vec![1, 2, 3],
+        };
+
+        assert!(MyFsm::for_event_mut(&mut account, &Closed {}));
+        assert!(!MyFsm::for_event_mut(&mut account, &Closed {}));
+        assert_eq!(
+            account,
+            Account {
+                status: Status::Closed,
+                history: vec![1, 2, 3],
+            }
+        );
+
+        // An owned state can be updated by consuming it
+
+        let account = account.inject(Status::Open);
+        assert_eq!(account.status, Status::Open);
+        assert_eq!(account.history, vec![1, 2, 3]);
+
+        // Events replayed against a borrowed state give the whole next
+        // state, or just the view
+
+        assert_eq!(
+            MyFsm::for_event(&account, &Closed {}),
+            Transition::Next(Account {
+                status: Status::Closed,
+                history: vec![1, 2, 3],
+            })
+        );
+        assert_eq!(
+            MyFsm::for_event_view(&account, &Closed {}),
+            Transition::Next(Status::Closed)
+        );
+    }
+
+    #[test]
+    fn test_step_hooks() {
+        // A light switch that records each hook in its effect handler
+
+        struct Toggle {}
+
+        #[derive(Debug, PartialEq)]
+        struct Toggled {}
+
+        impl Command<bool, Vec<String>> for Toggle {
+            type Output = Toggled;
+            fn execute(&self, _s: &bool, log: &mut Vec<String>) -> Option<Toggled> {
+                log.push("execute".to_string());
+                Some(Toggled {})
+            }
+        }
+
+        impl Validate<bool> for Toggle {}
+
+        impl Event<bool> for Toggled {
+            fn fire(&self, s: &bool) -> Transition<bool> {
+                Transition::Next(!s)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<bool, Vec<String>> for MyFsm {
+            fn on_transition(_old_s: &bool, _new_s: &bool, log: &mut Vec<String>) {
+                log.push("on_transition".to_string());
+            }
+
+            fn before_command<C>(s: &bool, _c: &C, log: &mut Vec<String>)
+            where
+                C: Command<bool, Vec<String>>,
+            {
+                log.push(format!("before_command {s}"));
+            }
+
+            fn after_event<E>(s: &bool, _e: &E, t: &Transition<bool>, log: &mut Vec<String>)
+            where
+                E: Event<bool>,
+            {
+                log.push(format!("after_event {s} {t:?}"));
+            }
+        }
+
+        let mut log = Vec::new();
+        let (e, t) = MyFsm::step(&false, &Toggle {}, &mut log);
+        assert_eq!(e, Some(Toggled {}));
+        assert_eq!(t, Ok(Transition::Next(true)));
+        assert_eq!(
+            log,
+            vec![
+                "before_command false",
+                "execute",
+                "on_transition",
+                "after_event false Next(true)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_step_veto() {
+        // A room that can be occupied while the effect handler has capacity
+
+        struct Enter {}
+
+        #[derive(Debug, PartialEq)]
+        struct Entered {}
+
+        impl Command<bool, u32> for Enter {
+            type Output = Entered;
+            fn execute(&self, _s: &bool, _capacity: &mut u32) -> Option<Entered> {
+                Some(Entered {})
+            }
+        }
+
+        impl Validate<bool> for Enter {}
+
+        impl Event<bool> for Entered {
+            fn fire(&self, s: &bool) -> Transition<bool> {
+                if *s {
+                    Transition::Same
+                } else {
+                    Transition::Next(true)
+                }
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<bool, u32> for MyFsm {
+            fn on_entry(_old_s: &bool, _new_s: &bool, capacity: &mut u32) -> Result<(), Veto> {
+                if *capacity == 0 {
+                    Err(Veto("no capacity".to_string()))
+                } else {
+                    Ok(())
+                }
+            }
+
+            fn on_transition(_old_s: &bool, _new_s: &bool, capacity: &mut u32) {
+                *capacity -= 1;
+            }
+        }
+
+        let mut capacity = 1;
+        let (e, t) = MyFsm::step(&false, &Enter {}, &mut capacity);
+        assert_eq!(e, Some(Entered {}));
+        assert_eq!(t, Ok(Transition::Next(true)));
+        assert_eq!(capacity, 0);
+
+        let (e, t) = MyFsm::step(&false, &Enter {}, &mut capacity);
+        assert_eq!(e, Some(Entered {}));
+        assert_eq!(t, Err(StepError::Vetoed(Veto("no capacity".to_string()))));
+        assert_eq!(capacity, 0);
+    }
+
+    #[test]
+    fn test_step_validate() {
+        // Withdrawals from a balance, counting executions in the handler
+
+        struct Withdraw(u32);
+
+        #[derive(Debug, PartialEq)]
+        struct Withdrawn(u32);
+
+        impl Command<u32, u32> for Withdraw {
+            type Output = Withdrawn;
+            fn execute(&self, _s: &u32, executed: &mut u32) -> Option<Withdrawn> {
+                *executed += 1;
+                Some(Withdrawn(self.0))
+            }
+        }
+
+        impl Validate<u32> for Withdraw {
+            fn validate(&self, s: &u32) -> Result<(), ValidationError> {
+                if self.0 > *s {
+                    Err(ValidationError("insufficient funds".to_string()))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        impl Event<u32> for Withdrawn {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s - self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, u32> for MyFsm {}
+
+        // The check can be made without an effect handler
+
+        assert!(Withdraw(5).validate(&10).is_ok());
+
+        let mut executed = 0;
+        let (e, t) = MyFsm::step(&10, &Withdraw(5), &mut executed);
+        assert_eq!(e, Some(Withdrawn(5)));
+        assert_eq!(t, Ok(Transition::Next(5)));
+        assert_eq!(executed, 1);
+
+        let (e, t) = MyFsm::step(&5, &Withdraw(10), &mut executed);
+        assert_eq!(e, None);
+        assert_eq!(
+            t,
+            Err(StepError::Invalid(ValidationError(
+                "insufficient funds".to_string()
+            )))
+        );
+        assert_eq!(executed, 1);
+    }
 }
@@ -0,0 +1,242 @@
+//! Background compaction: snapshotting idle entities and pruning the
+//! journal records their snapshots cover, so that recovery is quick and
+//! the journal does not grow without bound.
+//!
+//! A `Compactor` is attached to a runner as an observer to learn when
+//! each entity was last active. It is a handle, and a clone is run
+//! periodically, for example from a timer thread, to compact the entities
+//! that have been idle for long enough and have enough new events. Work
+//! is rate limited by a token bucket, one token per entity, so that
+//! maintenance does not starve live traffic. Entities left over are
+//! compacted on a later run.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::command_and_event_traits::Fsm;
+use crate::journal::Journal;
+use crate::observer::Observer;
+use crate::rate_limit::TokenBucket;
+use crate::runner::{RunError, Runner};
+use crate::snapshot::SnapshotStore;
+
+/// Which entities to compact.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompactionPolicy {
+    /// How long an entity must have been inactive
+    pub idle_after: Duration,
+    /// How many events it must have had since its last compaction
+    pub min_events: u64,
+    /// Whether to prune the journal records covered by the snapshot
+    pub prune: bool,
+}
+
+/// What a run of the compactor did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompactionReport<K> {
+    pub compacted: Vec<K>,
+    /// Entities due for compaction but held back by the rate limit
+    pub deferred: usize,
+    pub failed: Vec<(K, String)>,
+}
+
+struct Inner<K> {
+    policy: CompactionPolicy,
+    bucket: TokenBucket,
+    active: HashMap<K, Instant>,
+    compacted: HashMap<K, u64>,
+}
+
+/// Compacts the idle entities of a runner.
+pub struct Compactor<K> {
+    inner: Arc<Mutex<Inner<K>>>,
+}
+
+impl<K> Clone for Compactor<K> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K> Compactor<K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Compact according to a policy, at the rate the bucket allows.
+    pub fn new(policy: CompactionPolicy, bucket: TokenBucket) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                policy,
+                bucket,
+                active: HashMap::new(),
+                compacted: HashMap::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Note that an entity was active.
+    pub fn touch(&self, key: &K, now: Instant) {
+        self.lock().active.insert(key.clone(), now);
+    }
+
+    /// The sequence number an entity was last compacted at, if it has been.
+    pub fn compacted_at(&self, key: &K) -> Option<u64> {
+        self.lock().compacted.get(key).copied()
+    }
+
+    /// Snapshot, and prune if the policy says so, the entities due for compaction.
+    pub fn run<S, H, F, J, E, T>(
+        &self,
+        now: Instant,
+        runner: &Runner<K, S, H, F>,
+        journal: &mut J,
+        snapshots: &mut T,
+    ) -> CompactionReport<K>
+    where
+        F: Fsm<S, H>,
+        J: Journal<K, E>,
+        T: SnapshotStore<K, S>,
+    {
+        let mut inner = self.lock();
+        let policy = inner.policy;
+        let mut report = CompactionReport {
+            compacted: Vec::new(),
+            deferred: 0,
+            failed: Vec::new(),
+        };
+        let idle: Vec<K> = inner
+            .active
+            .iter()
+            .filter(|(_, at)| now.saturating_duration_since(**at) >= policy.idle_after)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in idle {
+            let Some(state) = runner.state(&key) else {
+                continue;
+            };
+            let seq = match journal.last_seq(&key) {
+                Ok(seq) => seq,
+                Err(e) => {
+                    report.failed.push((key, format!("{e:?}")));
+                    continue;
+                }
+            };
+            let since = inner.compacted.get(&key).copied().unwrap_or(0);
+            if seq < since + policy.min_events.max(1) {
+                continue;
+            }
+            if !inner.bucket.try_take(now) {
+                report.deferred += 1;
+                continue;
+            }
+            let compacted = snapshots.save(&key, seq, state).and_then(|_| {
+                if policy.prune {
+                    journal.prune(&key, seq).map_err(|e| format!("{e:?}"))
+                } else {
+                    Ok(())
+                }
+            });
+            match compacted {
+                Ok(()) => {
+                    inner.compacted.insert(key.clone(), seq);
+                    report.compacted.push(key);
+                }
+                Err(e) => report.failed.push((key, e)),
+            }
+        }
+        report
+    }
+}
+
+impl<K, S> Observer<K, S> for Compactor<K>
+where
+    K: Eq + Hash + Clone,
+{
+    fn inserted(&mut self, key: &K, _state: &S) {
+        self.touch(key, Instant::now());
+    }
+
+    fn removed(&mut self, key: &K) {
+        let mut inner = self.lock();
+        inner.active.remove(key);
+        inner.compacted.remove(key);
+    }
+
+    fn transitioned(&mut self, key: &K, _from: &S, _event: &str, _to: &S) {
+        self.touch(key, Instant::now());
+    }
+
+    fn ignored(&mut self, key: &K, _state: &S, _command: &str) {
+        self.touch(key, Instant::now());
+    }
+
+    fn rejected(&mut self, key: &K, _command: &str, _error: &RunError) {
+        self.touch(key, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Counter, Increment, Incremented};
+    use crate::journal::MemJournal;
+    use crate::projection::Projector;
+    use crate::snapshot::MemSnapshotStore;
+
+    #[test]
+    fn test_compactor() {
+        // Counters, compacted one per second once idle with two new events
+
+        let policy = CompactionPolicy {
+            idle_after: Duration::from_secs(60),
+            min_events: 2,
+            prune: true,
+        };
+        let compactor = Compactor::new(policy, TokenBucket::new(1, 1.0).unwrap());
+        let mut runner =
+            Runner::<&str, u32, (), Counter>::new(()).with_observer(Box::new(compactor.clone()));
+        let mut journal = Arc::new(Mutex::new(MemJournal::new()));
+        let mut projector = Projector::new(journal.clone());
+        for key in ["a", "b", "c"] {
+            runner.insert(key, 0);
+        }
+        for key in ["a", "a", "b", "b", "c"] {
+            runner
+                .send_with(&key, &Increment {}, &mut projector)
+                .unwrap();
+        }
+        let start = Instant::now();
+        let mut snapshots = MemSnapshotStore::new();
+
+        // Nothing is idle yet
+        let report = compactor.run(start, &runner, &mut journal, &mut snapshots);
+        assert_eq!(report, CompactionReport::default());
+
+        // "c" has too few events, and one of "a" and "b" is rate limited
+        let later = start + Duration::from_secs(60);
+        let report = compactor.run(later, &runner, &mut journal, &mut snapshots);
+        assert_eq!((report.compacted.len(), report.deferred), (1, 1));
+        let report = compactor.run(
+            later + Duration::from_secs(1),
+            &runner,
+            &mut journal,
+            &mut snapshots,
+        );
+        assert_eq!((report.compacted.len(), report.deferred), (1, 0));
+
+        assert_eq!(snapshots.load(&"a"), Ok(Some((2, 2))));
+        assert_eq!(compactor.compacted_at(&"b"), Some(2));
+        assert_eq!(compactor.compacted_at(&"c"), None);
+        assert_eq!(journal.read(&"a", 0), Ok(vec![]));
+        assert_eq!(journal.read_all(0).unwrap().len(), 1);
+        assert_eq!(journal.append(&"a", Incremented {}).unwrap().offset, 5);
+    }
+}
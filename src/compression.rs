@@ -0,0 +1,604 @@
+//! Compression of persisted bytes, such as encoded events and snapshots.
+//! A `Compression` frames the output of a `Compressor` with the
+//! compressor's id and the uncompressed length, so that a frame is always
+//! decompressed as it was compressed and never beyond its stated length.
+//!
+//! Frames hold one record, or with `Framing::PerBatch` a whole batch of
+//! records, which compresses better when records are small and alike.
+//! `CompressedJournal` compresses the records of a journal of bytes with
+//! either framing, and snapshots are compressed by composing `encode` and
+//! `decode` with the functions given to `BlobSnapshots`.
+//!
+//! `Lz4` writes the LZ4 block format, so its frames' bodies can be read by
+//! other LZ4 implementations. Other compressors, such as zstd, implement
+//! `Compressor` with an id of their own.
+
+use crate::journal::{Journal, JournalError, Position, Record};
+
+/// A compression algorithm, identified in frames by an id.
+pub trait Compressor {
+    /// The id of the algorithm. 0 is uncompressed, 1 LZ4 and 2 zstd.
+    fn id(&self) -> u8;
+
+    fn compress(&self, bytes: &[u8]) -> Vec<u8>;
+
+    /// Decompress bytes which are `len` bytes long uncompressed.
+    fn decompress(&self, bytes: &[u8], len: usize) -> Result<Vec<u8>, String>;
+}
+
+/// Bytes left as they are.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Uncompressed;
+
+impl Compressor for Uncompressed {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        bytes.to_vec()
+    }
+
+    fn decompress(&self, bytes: &[u8], len: usize) -> Result<Vec<u8>, String> {
+        if bytes.len() != len {
+            return Err(format!("expected {len} bytes, found {}", bytes.len()));
+        }
+        Ok(bytes.to_vec())
+    }
+}
+
+/// The LZ4 block format, compressed greedily for speed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4;
+
+/// A match is at least this long.
+const MIN_MATCH: usize = 4;
+/// No match starts within this many bytes of the end.
+const MATCH_LIMIT: usize = 12;
+/// The last this many bytes are always literals.
+const LAST_LITERALS: usize = 5;
+/// The number of bits hashed to find matches.
+const HASH_BITS: u32 = 12;
+
+fn write_length(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 {
+        out.push(255);
+        n -= 255;
+    }
+    out.push(n as u8);
+}
+
+/// Write a sequence of literals followed, unless it is the last, by a match.
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let lit = literals.len();
+    let len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push((lit.min(15) as u8) << 4 | len.min(15) as u8);
+    if lit >= 15 {
+        write_length(out, lit - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if len >= 15 {
+            write_length(out, len - 15);
+        }
+    }
+}
+
+impl Compressor for Lz4 {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn compress(&self, bytes: &[u8]) -> Vec<u8> {
+        let n = bytes.len();
+        let mut out = Vec::with_capacity(n / 2 + 16);
+        let mut table = vec![0usize; 1 << HASH_BITS];
+        let mut anchor = 0;
+        let mut i = 0;
+        let word =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        while i + MATCH_LIMIT < n {
+            let hash = (word(i).wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+            let candidate = table[hash];
+            table[hash] = i + 1;
+            if candidate > 0 && i - (candidate - 1) <= u16::MAX as usize {
+                let from = candidate - 1;
+                if word(from) == word(i) {
+                    let max = n - LAST_LITERALS - i;
+                    let mut len = MIN_MATCH;
+                    while len < max && bytes[from + len] == bytes[i + len] {
+                        len += 1;
+                    }
+                    write_sequence(&mut out, &bytes[anchor..i], Some((i - from, len)));
+                    i += len;
+                    anchor = i;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        write_sequence(&mut out, &bytes[anchor..], None);
+        out
+    }
+
+    fn decompress(&self, bytes: &[u8], len: usize) -> Result<Vec<u8>, String> {
+        let truncated = || "the block is truncated".to_string();
+        let mut out = Vec::with_capacity(len);
+        let mut i = 0;
+        let read_length = |i: &mut usize, mut n: usize| -> Result<usize, String> {
+            loop {
+                let b = *bytes.get(*i).ok_or_else(truncated)?;
+                *i += 1;
+                n += b as usize;
+                if b != 255 {
+                    return Ok(n);
+                }
+            }
+        };
+        loop {
+            let token = *bytes.get(i).ok_or_else(truncated)?;
+            i += 1;
+            let mut lit = (token >> 4) as usize;
+            if lit == 15 {
+                lit = read_length(&mut i, lit)?;
+            }
+            let literals = bytes.get(i..i + lit).ok_or_else(truncated)?;
+            if out.len() + lit > len {
+                return Err(format!("the block is longer than {len} bytes"));
+            }
+            out.extend_from_slice(literals);
+            i += lit;
+            if i == bytes.len() {
+                break;
+            }
+            let offset = bytes.get(i..i + 2).ok_or_else(truncated)?;
+            let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+            i += 2;
+            if offset == 0 || offset > out.len() {
+                return Err(format!("offset {offset} is out of range"));
+            }
+            let mut matched = (token & 15) as usize;
+            if matched == 15 {
+                matched = read_length(&mut i, matched)?;
+            }
+            matched += MIN_MATCH;
+            if out.len() + matched > len {
+                return Err(format!("the block is longer than {len} bytes"));
+            }
+            let start = out.len() - offset;
+            for k in 0..matched {
+                out.push(out[start + k]);
+            }
+        }
+        if out.len() != len {
+            return Err(format!("expected {len} bytes, found {}", out.len()));
+        }
+        Ok(out)
+    }
+}
+
+/// Whether records are compressed one to a frame or a batch to a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Framing {
+    #[default]
+    PerRecord,
+    PerBatch,
+}
+
+const RECORD_FRAME: u8 = 0;
+const BATCH_FRAME: u8 = 1;
+const PART_FRAME: u8 = 2;
+
+/// Frames compressed by a compressor C.
+///
+/// A frame is the compressor's id, the kind of frame, the uncompressed
+/// length as 4 bytes big-endian, and the compressed bytes. A batch is
+/// compressed as its records, each preceded by its length as 4 bytes
+/// big-endian. A part frame, which stands in a journal for a record of a
+/// batch after the first, has its index in the batch in place of the length
+/// and no body.
+#[derive(Debug, Clone, Default)]
+pub struct Compression<C> {
+    compressor: C,
+    framing: Framing,
+}
+
+impl<C> Compression<C>
+where
+    C: Compressor,
+{
+    pub fn new(compressor: C, framing: Framing) -> Self {
+        Self {
+            compressor,
+            framing,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    fn frame(&self, kind: u8, bytes: &[u8]) -> Vec<u8> {
+        let mut frame = vec![self.compressor.id(), kind];
+        frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        frame.extend(self.compressor.compress(bytes));
+        frame
+    }
+
+    fn unframe(&self, frame: &[u8]) -> Result<(u8, Vec<u8>), String> {
+        let Some(([id, kind, l0, l1, l2, l3], body)) = frame.split_first_chunk::<6>() else {
+            return Err("the frame is truncated".to_string());
+        };
+        if *id != self.compressor.id() {
+            return Err(format!("the frame is compressed with compressor {id}"));
+        }
+        let len = [*l0, *l1, *l2, *l3];
+        if *kind == PART_FRAME {
+            return Ok((*kind, len.to_vec()));
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        Ok((*kind, self.compressor.decompress(body, len)?))
+    }
+
+    fn part(&self, index: usize) -> Vec<u8> {
+        let mut frame = vec![self.compressor.id(), PART_FRAME];
+        frame.extend_from_slice(&(index as u32).to_be_bytes());
+        frame
+    }
+
+    fn split(bytes: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let mut records = Vec::new();
+        let mut rest = bytes;
+        while let Some((len, tail)) = rest.split_first_chunk::<4>() {
+            let len = u32::from_be_bytes(*len) as usize;
+            let record = tail.get(..len).ok_or("the batch is truncated")?;
+            records.push(record.to_vec());
+            rest = &tail[len..];
+        }
+        if !rest.is_empty() {
+            return Err("the batch is truncated".to_string());
+        }
+        Ok(records)
+    }
+
+    /// Compress one record as a frame.
+    pub fn encode(&self, bytes: &[u8]) -> Vec<u8> {
+        self.frame(RECORD_FRAME, bytes)
+    }
+
+    /// Decompress a frame of one record.
+    pub fn decode(&self, frame: &[u8]) -> Result<Vec<u8>, String> {
+        match self.unframe(frame)? {
+            (RECORD_FRAME, bytes) => Ok(bytes),
+            (kind, _) => Err(format!("expected a record frame, found kind {kind}")),
+        }
+    }
+
+    /// Compress records as frames, as the framing requires.
+    pub fn encode_batch(&self, records: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        match self.framing {
+            Framing::PerRecord => records.iter().map(|r| self.encode(r)).collect(),
+            Framing::PerBatch => {
+                let mut batch = Vec::new();
+                for record in records {
+                    batch.extend_from_slice(&(record.len() as u32).to_be_bytes());
+                    batch.extend_from_slice(record);
+                }
+                vec![self.frame(BATCH_FRAME, &batch)]
+            }
+        }
+    }
+
+    /// Decompress frames of either kind into their records.
+    pub fn decode_batch(&self, frames: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, String> {
+        let mut records = Vec::new();
+        for frame in frames {
+            match self.unframe(frame)? {
+                (RECORD_FRAME, bytes) => records.push(bytes),
+                (BATCH_FRAME, bytes) => records.extend(Self::split(&bytes)?),
+                (kind, _) => return Err(format!("unknown frame kind {kind}")),
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// A journal J of bytes, whose records are compressed by C.
+///
+/// With `Framing::PerBatch`, the first record of a batch holds the batch's
+/// frame and the others part frames referring to it, so each event keeps
+/// its own sequence number. A part is read from its batch, and a batch is
+/// pruned only once all of its records can be.
+pub struct CompressedJournal<J, C> {
+    journal: J,
+    compression: Compression<C>,
+}
+
+impl<J, C> CompressedJournal<J, C>
+where
+    C: Compressor,
+{
+    /// A journal compressing each record as a frame of its own.
+    pub fn new(journal: J, compressor: C) -> Self {
+        Self::with_framing(journal, compressor, Framing::PerRecord)
+    }
+
+    pub fn with_framing(journal: J, compressor: C, framing: Framing) -> Self {
+        Self {
+            journal,
+            compression: Compression::new(compressor, framing),
+        }
+    }
+
+    /// The journal of compressed records.
+    pub fn inner(&self) -> &J {
+        &self.journal
+    }
+
+    fn decode<K>(
+        &self,
+        records: Vec<Record<K, Vec<u8>>>,
+    ) -> Result<Vec<Record<K, Vec<u8>>>, JournalError>
+    where
+        J: Journal<K, Vec<u8>>,
+        K: PartialEq + Clone,
+    {
+        // The batches read so far, by key and the sequence number of their first record
+        let mut batches: Vec<(K, u64, Vec<Vec<u8>>)> = Vec::new();
+        let mut decoded = Vec::with_capacity(records.len());
+        for r in records {
+            let failed = |e: String| JournalError::Failed(format!("offset {}: {e}", r.offset));
+            let (kind, bytes) = self.compression.unframe(&r.event).map_err(failed)?;
+            let event = match kind {
+                RECORD_FRAME => bytes,
+                BATCH_FRAME => {
+                    let batch = Compression::<C>::split(&bytes).map_err(failed)?;
+                    let first = batch
+                        .first()
+                        .cloned()
+                        .ok_or_else(|| failed("the batch is empty".to_string()))?;
+                    batches.push((r.key.clone(), r.seq, batch));
+                    first
+                }
+                PART_FRAME => {
+                    let index = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as u64;
+                    let first = r
+                        .seq
+                        .checked_sub(index)
+                        .ok_or_else(|| failed(format!("part {index} is out of range")))?;
+                    let cached = batches
+                        .iter()
+                        .position(|(k, seq, _)| *k == r.key && *seq == first);
+                    let i = match cached {
+                        Some(i) => i,
+                        None => {
+                            let batch = self.batch(&r.key, first)?;
+                            batches.push((r.key.clone(), first, batch));
+                            batches.len() - 1
+                        }
+                    };
+                    batches[i]
+                        .2
+                        .get(index as usize)
+                        .cloned()
+                        .ok_or_else(|| failed(format!("part {index} is out of range")))?
+                }
+                kind => return Err(failed(format!("unknown frame kind {kind}"))),
+            };
+            decoded.push(Record { event, ..r });
+        }
+        Ok(decoded)
+    }
+
+    /// The records of the batch whose first record is at a sequence number.
+    fn batch<K>(&self, key: &K, seq: u64) -> Result<Vec<Vec<u8>>, JournalError>
+    where
+        J: Journal<K, Vec<u8>>,
+    {
+        let records = self.journal.read_page(key, seq, 1)?;
+        let batch = match records.first() {
+            Some(r) if r.seq == seq => match self.compression.unframe(&r.event) {
+                Ok((BATCH_FRAME, bytes)) => Compression::<C>::split(&bytes),
+                Ok((kind, _)) => Err(format!("expected a batch frame, found kind {kind}")),
+                Err(e) => Err(e),
+            },
+            _ => Err("the batch is missing".to_string()),
+        };
+        batch.map_err(|e| JournalError::Failed(format!("sequence number {seq}: {e}")))
+    }
+}
+
+impl<K, J, C> Journal<K, Vec<u8>> for CompressedJournal<J, C>
+where
+    J: Journal<K, Vec<u8>>,
+    C: Compressor,
+    K: PartialEq + Clone,
+{
+    fn append(&mut self, key: &K, event: Vec<u8>) -> Result<Position, JournalError> {
+        self.journal.append(key, self.compression.encode(&event))
+    }
+
+    fn read(&self, key: &K, from_seq: u64) -> Result<Vec<Record<K, Vec<u8>>>, JournalError> {
+        self.decode(self.journal.read(key, from_seq)?)
+    }
+
+    fn read_all(&self, from_offset: u64) -> Result<Vec<Record<K, Vec<u8>>>, JournalError> {
+        self.decode(self.journal.read_all(from_offset)?)
+    }
+
+    fn read_page(
+        &self,
+        key: &K,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Record<K, Vec<u8>>>, JournalError> {
+        self.decode(self.journal.read_page(key, from_seq, limit)?)
+    }
+
+    fn last_seq(&self, key: &K) -> Result<u64, JournalError> {
+        self.journal.last_seq(key)
+    }
+
+    /// Keeps the batch of the record after `up_to_seq`, if it is part of one.
+    fn prune(&mut self, key: &K, up_to_seq: u64) -> Result<(), JournalError> {
+        let after = self.journal.read_page(key, up_to_seq + 1, 1)?;
+        let up_to_seq = match after.first().map(|r| self.compression.unframe(&r.event)) {
+            Some(Ok((PART_FRAME, index))) => {
+                let index = u32::from_be_bytes(index[..4].try_into().unwrap()) as u64;
+                after[0].seq.saturating_sub(index + 1).min(up_to_seq)
+            }
+            _ => up_to_seq,
+        };
+        self.journal.prune(key, up_to_seq)
+    }
+
+    fn truncate(&mut self, from_offset: u64) -> Result<(), JournalError> {
+        self.journal.truncate(from_offset)
+    }
+
+    fn append_batch(
+        &mut self,
+        key: &K,
+        events: Vec<Vec<u8>>,
+    ) -> Result<Vec<Position>, JournalError> {
+        let frames = match self.compression.framing() {
+            Framing::PerBatch if !events.is_empty() => {
+                let mut frames = self.compression.encode_batch(&events);
+                frames.extend((1..events.len()).map(|i| self.compression.part(i)));
+                frames
+            }
+            _ => events.iter().map(|e| self.compression.encode(e)).collect(),
+        };
+        self.journal.append_batch(key, frames)
+    }
+
+    fn append_expected(
+        &mut self,
+        key: &K,
+        expected: u64,
+        event: Vec<u8>,
+    ) -> Result<Position, JournalError> {
+        self.journal
+            .append_expected(key, expected, self.compression.encode(&event))
+    }
+
+    fn append_group(&mut self, events: Vec<(K, Vec<u8>)>) -> Vec<Result<Position, JournalError>> {
+        let events = events
+            .into_iter()
+            .map(|(k, e)| (k, self.compression.encode(&e)))
+            .collect();
+        self.journal.append_group(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::MemJournal;
+
+    #[test]
+    fn test_compression() {
+        // JSON events, which repeat their field names and values
+
+        let event = |i: u32| {
+            format!(
+                r#"{{"type":"Deposited","account":"acc-{}","amount":{}}}"#,
+                i % 7,
+                i % 3
+            )
+            .into_bytes()
+        };
+        let mixed: Vec<u8> = (0..3000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        for bytes in [vec![], b"short".to_vec(), vec![b'a'; 1000], event(1), mixed] {
+            let compressed = Lz4.compress(&bytes);
+            assert_eq!(Lz4.decompress(&compressed, bytes.len()), Ok(bytes.clone()));
+        }
+        // A block that refers before its start, or is longer than stated
+        assert!(Lz4.decompress(&[0x14, b'a', 2, 0], 6).is_err());
+        assert!(Lz4.decompress(&Lz4.compress(&[b'a'; 100]), 99).is_err());
+
+        let records: Vec<Vec<u8>> = (0..200).map(event).collect();
+        let size: usize = records.iter().map(Vec::len).sum();
+        let per_batch = Compression::new(Lz4, Framing::PerBatch);
+        let frames = per_batch.encode_batch(&records);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].len() * 4 < size, "{} of {size}", frames[0].len());
+        assert_eq!(per_batch.decode_batch(&frames), Ok(records.clone()));
+        let per_record = Compression::new(Lz4, Framing::PerRecord);
+        assert_eq!(per_record.encode_batch(&records).len(), 200);
+        assert!(Compression::new(Uncompressed, Framing::PerRecord)
+            .decode(&frames[0])
+            .is_err());
+
+        let mut journal = CompressedJournal::new(MemJournal::new(), Lz4);
+        let big = records.concat();
+        journal.append(&"a", big.clone()).unwrap();
+        assert!(journal.inner().read(&"a", 1).unwrap()[0].event.len() < big.len() / 4);
+        assert_eq!(journal.read(&"a", 1).unwrap()[0].event, big);
+    }
+    #[test]
+    fn test_per_batch_journal() {
+        // Small, similar events appended in batches, compressed together
+        // while each keeps its own sequence number
+        let event = |i: u32| format!(r#"{{"type":"Ticked","n":{}}}"#, i % 5).into_bytes();
+        let events: Vec<Vec<u8>> = (0..100).map(event).collect();
+        let size: usize = events.iter().map(Vec::len).sum();
+
+        let mut journal =
+            CompressedJournal::with_framing(MemJournal::new(), Lz4, Framing::PerBatch);
+        journal.append(&"b", event(0)).unwrap();
+        let positions = journal.append_batch(&"a", events.clone()).unwrap();
+        assert_eq!(positions.len(), 100);
+        journal.append(&"b", event(1)).unwrap();
+        let stored: usize = journal
+            .inner()
+            .read(&"a", 1)
+            .unwrap()
+            .iter()
+            .map(|r| r.event.len())
+            .sum();
+        assert!(stored * 2 < size, "{stored} of {size}");
+
+        // Reads starting part way through a batch find its frame
+        let read = |j: &CompressedJournal<_, _>, from| -> Vec<Vec<u8>> {
+            j.read(&"a", from)
+                .unwrap()
+                .into_iter()
+                .map(|r| r.event)
+                .collect()
+        };
+        assert_eq!(read(&journal, 1), events);
+        assert_eq!(read(&journal, 51), events[50..].to_vec());
+        let all = journal.read_all(0).unwrap();
+        assert_eq!(all.len(), 102);
+        assert_eq!((all[1].seq, &all[1].event), (1, &events[0]));
+        assert_eq!(all[101].event, event(1));
+
+        // Pruning into a batch keeps the whole of it
+        journal.prune(&"a", 60).unwrap();
+        assert_eq!(read(&journal, 61), events[60..].to_vec());
+        assert_eq!(journal.inner().read(&"a", 1).unwrap().len(), 100);
+        journal.prune(&"a", 100).unwrap();
+        assert_eq!(read(&journal, 1), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn test_corrupt_frames() {
+        // Frames which are truncated, of another compressor, or parts of a
+        // batch that is not there, fail the read rather than yield bytes
+        let per_batch = Compression::new(Lz4, Framing::PerBatch);
+        let mut frames = per_batch.encode_batch(&[b"one".to_vec(), b"two".to_vec()]);
+        frames[0].pop();
+        assert!(per_batch.decode_batch(&frames).is_err());
+        assert!(per_batch.decode(&[1, 0, 0]).is_err());
+        assert!(per_batch.decode(&Uncompressed.compress(b"raw")).is_err());
+
+        let mut raw = MemJournal::new();
+        raw.append(&"a", per_batch.part(1)).unwrap();
+        raw.append(&"b", per_batch.encode(b"fine")).unwrap();
+        let journal = CompressedJournal::with_framing(raw, Lz4, Framing::PerBatch);
+        assert!(journal.read(&"a", 1).is_err());
+        assert_eq!(journal.read(&"b", 1).unwrap()[0].event, b"fine".to_vec());
+    }
+}
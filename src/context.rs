@@ -0,0 +1,119 @@
+//! Commands that read a context, such as configuration, the current user
+//! or request metadata, passed by reference separately from the effect
+//! handler. Purely contextual data then need not be smuggled through the
+//! handler, and a context can differ for each command sent. The `InCtx`
+//! adapter pairs a command with its context as an ordinary `Command`, for
+//! use with `step` or a runner.
+
+use crate::command_and_event_traits::{Command, Event, Validate, ValidationError};
+use crate::observer::type_label;
+
+/// A command that reads a context X as well as the state and handler.
+pub trait CtxCommand<S, X, H> {
+    type Output: Event<S>;
+    fn execute_in(&self, state: &S, ctx: &X, handler: &mut H) -> Option<Self::Output>;
+}
+
+/// Adapts a command and its context to a `Command`.
+pub struct InCtx<'a, C, X> {
+    pub command: C,
+    pub ctx: &'a X,
+}
+
+impl<'a, C, X> InCtx<'a, C, X> {
+    pub fn new(command: C, ctx: &'a X) -> Self {
+        Self { command, ctx }
+    }
+}
+
+impl<S, X, H, C> Command<S, H> for InCtx<'_, C, X>
+where
+    C: CtxCommand<S, X, H>,
+{
+    type Output = C::Output;
+    fn execute(&self, state: &S, handler: &mut H) -> Option<Self::Output> {
+        self.command.execute_in(state, self.ctx, handler)
+    }
+
+    fn name(&self) -> String {
+        type_label::<C>()
+    }
+}
+
+impl<S, C, X> Validate<S> for InCtx<'_, C, X>
+where
+    C: Validate<S>,
+{
+    fn validate(&self, state: &S) -> Result<(), ValidationError> {
+        self.command.validate(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Fsm, Transition};
+    use crate::runner::Runner;
+
+    #[test]
+    fn test_in_ctx() {
+        // Documents that record who last edited them, with an audit log
+        // as the effect handler
+
+        struct Edit {}
+
+        #[derive(Debug, PartialEq)]
+        struct Edited(String);
+
+        struct Request {
+            user: String,
+        }
+
+        impl CtxCommand<String, Request, Vec<String>> for Edit {
+            type Output = Edited;
+            fn execute_in(
+                &self,
+                _s: &String,
+                req: &Request,
+                log: &mut Vec<String>,
+            ) -> Option<Edited> {
+                log.push(format!("edit by {}", req.user));
+                Some(Edited(req.user.clone()))
+            }
+        }
+
+        impl Validate<String> for Edit {}
+
+        impl Event<String> for Edited {
+            fn fire(&self, _s: &String) -> Transition<String> {
+                Transition::Next(self.0.clone())
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<String, Vec<String>> for MyFsm {}
+
+        let alice = Request {
+            user: "alice".to_string(),
+        };
+        let (e, t) = MyFsm::step(
+            &String::new(),
+            &InCtx::new(Edit {}, &alice),
+            &mut Vec::new(),
+        );
+        assert_eq!(e, Some(Edited("alice".to_string())));
+        assert_eq!(t, Ok(Transition::Next("alice".to_string())));
+
+        let mut runner = Runner::<u32, String, Vec<String>, MyFsm>::new(Vec::new());
+        runner.insert(1, String::new());
+        let bob = Request {
+            user: "bob".to_string(),
+        };
+        for req in [&alice, &bob] {
+            runner.send(&1, &InCtx::new(Edit {}, req)).unwrap();
+        }
+        assert_eq!(runner.state(&1), Some(&"bob".to_string()));
+        assert_eq!(runner.handler(), &vec!["edit by alice", "edit by bob"]);
+    }
+}
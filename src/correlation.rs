@@ -0,0 +1,175 @@
+//! Correlation and causation IDs, carried from an incoming command onto
+//! the events it emits and onto any follow-up commands, so that the
+//! messages of one distributed operation can be lined up across
+//! services.
+//!
+//! Every message has its own ID. The correlation ID is the ID of the
+//! message that started the operation, and is shared by every message
+//! that follows from it. The causation ID is the ID of the message that
+//! directly caused this one.
+//!
+//! A `Correlated` command emits a `Correlated` event, so journals,
+//! outboxes, publishers and pipeline mappings all see the IDs with the
+//! event. An event's ID is derived from its command's ID, so a retried
+//! command emits an event with the same ID.
+//!
+//! The IDs may also name the `Principal` on whose behalf the operation
+//! is performed, which follows the IDs onto every message of it.
+
+use crate::authorization::Principal;
+use crate::command_and_event_traits::{Command, Event, Transition, Validate, ValidationError};
+
+/// The IDs of a message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Correlation {
+    pub message_id: String,
+    pub correlation_id: String,
+    pub causation_id: Option<String>,
+    pub principal: Option<Principal>,
+}
+
+impl Correlation {
+    /// The IDs of a message starting an operation.
+    pub fn new(message_id: &str) -> Self {
+        Self {
+            message_id: message_id.to_string(),
+            correlation_id: message_id.to_string(),
+            causation_id: None,
+            principal: None,
+        }
+    }
+
+    /// These IDs, for an operation performed on behalf of a principal.
+    pub fn with_principal(mut self, principal: Principal) -> Self {
+        self.principal = Some(principal);
+        self
+    }
+
+    /// The IDs of a message caused by this one.
+    pub fn caused(&self, message_id: &str) -> Self {
+        Self {
+            message_id: message_id.to_string(),
+            correlation_id: self.correlation_id.clone(),
+            causation_id: Some(self.message_id.clone()),
+            principal: self.principal.clone(),
+        }
+    }
+
+    /// The IDs of the event emitted by a command with these IDs.
+    pub fn event(&self) -> Self {
+        self.caused(&format!("{}/event", self.message_id))
+    }
+}
+
+/// A command or event, T, with its IDs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Correlated<T> {
+    pub ids: Correlation,
+    pub body: T,
+}
+
+impl<T> Correlated<T> {
+    pub fn new(ids: Correlation, body: T) -> Self {
+        Self { ids, body }
+    }
+
+    /// A follow-up message caused by this one.
+    pub fn caused<U>(&self, message_id: &str, body: U) -> Correlated<U> {
+        Correlated::new(self.ids.caused(message_id), body)
+    }
+}
+
+impl<S, H, C> Command<S, H> for Correlated<C>
+where
+    C: Command<S, H>,
+{
+    type Output = Correlated<C::Output>;
+    fn execute(&self, state: &S, handler: &mut H) -> Option<Self::Output> {
+        let event = self.body.execute(state, handler)?;
+        Some(Correlated::new(self.ids.event(), event))
+    }
+
+    fn name(&self) -> String {
+        self.body.name()
+    }
+}
+
+impl<S, C> Validate<S> for Correlated<C>
+where
+    C: Validate<S>,
+{
+    fn validate(&self, state: &S) -> Result<(), ValidationError> {
+        self.body.validate(state)
+    }
+}
+
+impl<S, E> Event<S> for Correlated<E>
+where
+    E: Event<S>,
+{
+    fn fire(&self, state: &S) -> Transition<S> {
+        self.body.fire(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::Fsm;
+    use crate::pipeline::{Dispatch, Pipeline};
+    use crate::runner::Runner;
+
+    #[test]
+    fn test_correlated() {
+        // Orders that, once placed, reserve stock, both counting
+
+        struct Place {}
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct Placed {}
+
+        impl Command<u32, ()> for Place {
+            type Output = Placed;
+            fn execute(&self, _s: &u32, _h: &mut ()) -> Option<Placed> {
+                Some(Placed {})
+            }
+        }
+
+        impl Validate<u32> for Place {}
+
+        impl Event<u32> for Placed {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s + 1)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, ()> for MyFsm {}
+
+        let mut orders = Runner::<&str, u32, (), MyFsm>::new(());
+        orders.insert("order", 0);
+        let mut stock = Runner::<&str, u32, (), MyFsm>::new(());
+        stock.insert("stock", 0);
+
+        let follow_up =
+            |_: &&str, e: &Correlated<Placed>| vec![("stock", e.caused("reserve-1", Place {}))];
+        let mut pipeline = Pipeline::new(orders, stock, follow_up);
+        let command = Correlated::new(Correlation::new("web-1"), Place {});
+        let event = pipeline.dispatch(&"order", &command).unwrap().unwrap();
+
+        assert_eq!(
+            event.ids,
+            Correlation {
+                message_id: "web-1/event".to_string(),
+                correlation_id: "web-1".to_string(),
+                causation_id: Some("web-1".to_string()),
+                principal: None,
+            }
+        );
+        let reserve = event.caused("reserve-1", ());
+        assert_eq!(reserve.ids.correlation_id, "web-1");
+        assert_eq!(reserve.ids.causation_id.as_deref(), Some("web-1/event"));
+        assert_eq!(pipeline.downstream().state(&"stock"), Some(&1));
+    }
+}
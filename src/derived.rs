@@ -0,0 +1,138 @@
+//! Derived views of state, cached. A `Derived` is attached to a runner as
+//! an observer and computes a view of each entity's state when it is
+//! inserted and again only when it transitions, so an expensive summary
+//! is not recomputed on every read. Like `TransitionStats`, it is a
+//! handle, so a clone can be kept to read the views alongside the states.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::observer::Observer;
+
+/// Views of type D of the states S of entities with keys K.
+pub struct Derived<K, S, D> {
+    derive: Arc<dyn Fn(&S) -> D + Send + Sync>,
+    views: Arc<Mutex<HashMap<K, Arc<D>>>>,
+}
+
+impl<K, S, D> Clone for Derived<K, S, D> {
+    fn clone(&self) -> Self {
+        Self {
+            derive: self.derive.clone(),
+            views: self.views.clone(),
+        }
+    }
+}
+
+impl<K, S, D> Derived<K, S, D>
+where
+    K: Eq + Hash + Clone,
+{
+    /// Views computed by the given function.
+    pub fn new<F>(derive: F) -> Self
+    where
+        F: Fn(&S) -> D + Send + Sync + 'static,
+    {
+        Self {
+            derive: Arc::new(derive),
+            views: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<K, Arc<D>>> {
+        self.views.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The view of an entity's current state.
+    pub fn get(&self, key: &K) -> Option<Arc<D>> {
+        self.lock().get(key).cloned()
+    }
+
+    fn update(&self, key: &K, state: &S) {
+        let view = Arc::new((self.derive)(state));
+        self.lock().insert(key.clone(), view);
+    }
+}
+
+impl<K, S, D> Observer<K, S> for Derived<K, S, D>
+where
+    K: Eq + Hash + Clone,
+{
+    fn inserted(&mut self, key: &K, state: &S) {
+        self.update(key, state);
+    }
+
+    fn removed(&mut self, key: &K) {
+        self.lock().remove(key);
+    }
+
+    fn transitioned(&mut self, key: &K, _from: &S, _event: &str, to: &S) {
+        self.update(key, to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+    use crate::runner::Runner;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[test]
+    fn test_derived() {
+        // Baskets of prices with a total, which is counted as it is computed
+
+        enum Basket {
+            Add(u32),
+            Look,
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Added(u32);
+
+        impl Command<Vec<u32>, ()> for Basket {
+            type Output = Added;
+            fn execute(&self, _s: &Vec<u32>, _h: &mut ()) -> Option<Added> {
+                match self {
+                    Basket::Add(price) => Some(Added(*price)),
+                    Basket::Look => None,
+                }
+            }
+        }
+
+        impl Validate<Vec<u32>> for Basket {}
+
+        impl Event<Vec<u32>> for Added {
+            fn fire(&self, s: &Vec<u32>) -> Transition<Vec<u32>> {
+                let mut prices = s.clone();
+                prices.push(self.0);
+                Transition::Next(prices)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<Vec<u32>, ()> for MyFsm {}
+
+        let computed = Arc::new(AtomicU32::new(0));
+        let count = computed.clone();
+        let totals = Derived::new(move |prices: &Vec<u32>| {
+            count.fetch_add(1, Ordering::SeqCst);
+            prices.iter().sum::<u32>()
+        });
+        let mut runner =
+            Runner::<&str, Vec<u32>, (), MyFsm>::new(()).with_observer(Box::new(totals.clone()));
+        runner.insert("a", vec![5]);
+        assert_eq!(totals.get(&"a").as_deref(), Some(&5));
+
+        runner.send(&"a", &Basket::Add(3)).unwrap();
+        runner.send(&"a", &Basket::Look).unwrap();
+        assert_eq!(totals.get(&"a").as_deref(), Some(&8));
+        assert_eq!(totals.get(&"a").as_deref(), Some(&8));
+        assert_eq!(computed.load(Ordering::SeqCst), 2);
+
+        runner.remove(&"a");
+        assert_eq!(totals.get(&"a"), None);
+    }
+}
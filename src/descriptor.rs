@@ -0,0 +1,196 @@
+//! A machine may describe its states, commands, events and transition
+//! table at runtime with a `MachineDescriptor`. This can answer questions
+//! such as which commands are valid in the current state, for example to
+//! enable parts of a user interface.
+//!
+//! The descriptor is declared alongside the FSM and is not checked against
+//! the command and event implementations. Names are free form, though
+//! `observer::type_label` gives names consistent with observers.
+
+use std::collections::HashMap;
+
+/// One row of a transition table. A command executed in the `from` state
+/// emits the event, which transitions to the `to` state. The row may be
+/// conditional on a named guard and perform named actions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransitionDescriptor {
+    pub from: String,
+    pub command: String,
+    pub event: String,
+    pub to: String,
+    pub guard: Option<String>,
+    pub actions: Vec<String>,
+}
+
+/// The declared states, commands, events and transitions of a machine.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MachineDescriptor {
+    pub name: String,
+    pub initial: Option<String>,
+    pub states: Vec<String>,
+    pub commands: Vec<String>,
+    pub events: Vec<String>,
+    pub transitions: Vec<TransitionDescriptor>,
+}
+
+impl MachineDescriptor {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Declare the initial state.
+    pub fn initial(mut self, state: &str) -> Self {
+        add_name(&mut self.states, state);
+        self.initial = Some(state.to_string());
+        self
+    }
+
+    /// Declare a state, which need only be done for states not
+    /// mentioned in any transition.
+    pub fn state(mut self, state: &str) -> Self {
+        add_name(&mut self.states, state);
+        self
+    }
+
+    /// Declare a transition, and with it the states, command and event involved.
+    pub fn transition(mut self, from: &str, command: &str, event: &str, to: &str) -> Self {
+        add_name(&mut self.states, from);
+        add_name(&mut self.states, to);
+        add_name(&mut self.commands, command);
+        add_name(&mut self.events, event);
+        self.transitions.push(TransitionDescriptor {
+            from: from.to_string(),
+            command: command.to_string(),
+            event: event.to_string(),
+            to: to.to_string(),
+            guard: None,
+            actions: Vec::new(),
+        });
+        self
+    }
+
+    /// Make the last transition declared conditional on a guard.
+    pub fn guard(mut self, guard: &str) -> Self {
+        if let Some(t) = self.transitions.last_mut() {
+            t.guard = Some(guard.to_string());
+        }
+        self
+    }
+
+    /// Add an action to the last transition declared.
+    pub fn action(mut self, action: &str) -> Self {
+        if let Some(t) = self.transitions.last_mut() {
+            t.actions.push(action.to_string());
+        }
+        self
+    }
+
+    /// The transitions from a state.
+    pub fn transitions_from<'a>(
+        &'a self,
+        state: &'a str,
+    ) -> impl Iterator<Item = &'a TransitionDescriptor> + 'a {
+        self.transitions.iter().filter(move |t| t.from == state)
+    }
+
+    /// The commands that are valid in a state, in declaration order.
+    pub fn commands_in(&self, state: &str) -> Vec<&str> {
+        let mut commands = Vec::new();
+        for t in self.transitions.iter().filter(|t| t.from == state) {
+            if !commands.contains(&t.command.as_str()) {
+                commands.push(t.command.as_str());
+            }
+        }
+        commands
+    }
+
+    /// The transition table indexed by state and command. Where there are
+    /// several guarded transitions for a command in a state, the first is indexed.
+    pub fn table(&self) -> HashMap<(&str, &str), &TransitionDescriptor> {
+        let mut table = HashMap::new();
+        for t in &self.transitions {
+            table
+                .entry((t.from.as_str(), t.command.as_str()))
+                .or_insert(t);
+        }
+        table
+    }
+}
+
+fn add_name(names: &mut Vec<String>, name: &str) {
+    if !names.iter().any(|n| n == name) {
+        names.push(name.to_string());
+    }
+}
+
+/// Registers a descriptor for an FSM over states S.
+pub trait Describe<S> {
+    /// Describe the machine.
+    fn descriptor() -> MachineDescriptor;
+
+    /// The name of a state as it appears in the descriptor.
+    fn state_name(state: &S) -> String;
+
+    /// The commands that are valid in a state.
+    fn valid_commands(state: &S) -> Vec<String> {
+        Self::descriptor()
+            .commands_in(&Self::state_name(state))
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    /// Whether the descriptor declares a command in a state, for an
+    /// `Fsm::allows` that allows only the commands declared.
+    fn declares(state: &S, command: &str) -> bool {
+        Self::descriptor()
+            .commands_in(&Self::state_name(state))
+            .contains(&command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_descriptor() {
+        #[derive(Debug)]
+        enum State {
+            Started,
+            Stopped,
+        }
+
+        struct MyFsm {}
+
+        impl Describe<State> for MyFsm {
+            fn descriptor() -> MachineDescriptor {
+                MachineDescriptor::new("switch")
+                    .transition("Stopped", "Start", "Started", "Started")
+                    .transition("Started", "Stop", "Stopped", "Stopped")
+                    .transition("Started", "Pause", "Stopped", "Stopped")
+                    .state("Broken")
+            }
+
+            fn state_name(state: &State) -> String {
+                format!("{state:?}")
+            }
+        }
+
+        let descriptor = MyFsm::descriptor();
+        assert_eq!(descriptor.states, vec!["Stopped", "Started", "Broken"]);
+        assert_eq!(descriptor.commands, vec!["Start", "Stop", "Pause"]);
+        assert_eq!(descriptor.events, vec!["Started", "Stopped"]);
+        assert_eq!(descriptor.table()[&("Stopped", "Start")].to, "Started");
+
+        assert_eq!(MyFsm::valid_commands(&State::Stopped), vec!["Start"]);
+        assert_eq!(
+            MyFsm::valid_commands(&State::Started),
+            vec!["Stop", "Pause"]
+        );
+        assert!(descriptor.commands_in("Broken").is_empty());
+    }
+}
@@ -0,0 +1,369 @@
+//! Structural differences between states, for logging and debugging
+//! transitions of large states. A `Diff` lists the changes between two
+//! values of a type by path, such as `owner.name` or `items[2]`, with the
+//! values before and after written with `Debug`.
+//!
+//! `Diff` is implemented for primitives, strings, options, vectors and
+//! maps. For a struct, the `derive_diff!` macro implements it field by
+//! field. A type that should be compared as a whole can use
+//! `derive_diff!(Type)` to record a single change when it differs.
+//!
+//! `DiffLog` is an observer writing the changes made by each transition,
+//! rather than both states in full.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Debug, Display};
+use std::hash::Hash;
+use std::io::Write;
+
+use crate::command_and_event_traits::Transition;
+use crate::observer::Observer;
+
+/// A change at a path, from one value to another. An element added to
+/// or removed from a collection has no value on one side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub path: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = if self.path.is_empty() {
+            "."
+        } else {
+            &self.path
+        };
+        match (&self.from, &self.to) {
+            (Some(from), Some(to)) => write!(f, "{path}: {from} -> {to}"),
+            (None, Some(to)) => write!(f, "{path}: added {to}"),
+            (Some(from), None) => write!(f, "{path}: removed {from}"),
+            (None, None) => write!(f, "{path}: unchanged"),
+        }
+    }
+}
+
+/// Values which can be compared part by part.
+pub trait Diff {
+    /// Push the changes from self to other, with paths under the given path.
+    fn diff_into(&self, other: &Self, path: &str, changes: &mut Vec<Change>);
+
+    /// The changes from self to other.
+    fn diff(&self, other: &Self) -> Vec<Change> {
+        let mut changes = Vec::new();
+        self.diff_into(other, "", &mut changes);
+        changes
+    }
+}
+
+/// The path of a field under a path.
+pub fn field_path(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_string()
+    } else {
+        format!("{path}.{field}")
+    }
+}
+
+/// Record a change if two values differ, comparing them as a whole.
+pub fn diff_whole<T>(from: &T, to: &T, path: &str, changes: &mut Vec<Change>)
+where
+    T: PartialEq + Debug,
+{
+    if from != to {
+        changes.push(Change {
+            path: path.to_string(),
+            from: Some(format!("{from:?}")),
+            to: Some(format!("{to:?}")),
+        });
+    }
+}
+
+/// Implement `Diff` for a struct field by field, or for a type as a whole.
+///
+/// ```
+/// use fsm_laboratory::derive_diff;
+///
+/// #[derive(Debug, PartialEq)]
+/// struct Account { owner: String, balance: i64 }
+///
+/// derive_diff!(Account { owner, balance });
+/// ```
+#[macro_export]
+macro_rules! derive_diff {
+    ($t:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::diff::Diff for $t {
+            fn diff_into(
+                &self,
+                other: &Self,
+                path: &str,
+                changes: &mut Vec<$crate::diff::Change>,
+            ) {
+                $(
+                    $crate::diff::Diff::diff_into(
+                        &self.$field,
+                        &other.$field,
+                        &$crate::diff::field_path(path, stringify!($field)),
+                        changes,
+                    );
+                )*
+            }
+        }
+    };
+    ($($t:ty),+ $(,)?) => {
+        $(
+            impl $crate::diff::Diff for $t {
+                fn diff_into(
+                    &self,
+                    other: &Self,
+                    path: &str,
+                    changes: &mut Vec<$crate::diff::Change>,
+                ) {
+                    $crate::diff::diff_whole(self, other, path, changes);
+                }
+            }
+        )+
+    };
+}
+
+derive_diff!(
+    bool,
+    char,
+    u8,
+    u16,
+    u32,
+    u64,
+    u128,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    i128,
+    isize,
+    f32,
+    f64,
+    String,
+    &str,
+    ()
+);
+
+impl<T> Diff for Option<T>
+where
+    T: Diff + Debug,
+{
+    fn diff_into(&self, other: &Self, path: &str, changes: &mut Vec<Change>) {
+        match (self, other) {
+            (Some(a), Some(b)) => a.diff_into(b, path, changes),
+            (None, None) => {}
+            (a, b) => changes.push(Change {
+                path: path.to_string(),
+                from: a.as_ref().map(|a| format!("{a:?}")),
+                to: b.as_ref().map(|b| format!("{b:?}")),
+            }),
+        }
+    }
+}
+
+impl<T> Diff for Vec<T>
+where
+    T: Diff + Debug,
+{
+    fn diff_into(&self, other: &Self, path: &str, changes: &mut Vec<Change>) {
+        for i in 0..self.len().max(other.len()) {
+            let path = format!("{path}[{i}]");
+            match (self.get(i), other.get(i)) {
+                (Some(a), Some(b)) => a.diff_into(b, &path, changes),
+                (a, b) => changes.push(Change {
+                    path,
+                    from: a.map(|a| format!("{a:?}")),
+                    to: b.map(|b| format!("{b:?}")),
+                }),
+            }
+        }
+    }
+}
+
+fn diff_entries<'a, K, V>(
+    keys: impl Iterator<Item = &'a K>,
+    from: impl Fn(&K) -> Option<&'a V>,
+    to: impl Fn(&K) -> Option<&'a V>,
+    path: &str,
+    changes: &mut Vec<Change>,
+) where
+    K: Debug + 'a,
+    V: Diff + Debug + 'a,
+{
+    for key in keys {
+        let path = format!("{path}[{key:?}]");
+        match (from(key), to(key)) {
+            (Some(a), Some(b)) => a.diff_into(b, &path, changes),
+            (a, b) => changes.push(Change {
+                path,
+                from: a.map(|a| format!("{a:?}")),
+                to: b.map(|b| format!("{b:?}")),
+            }),
+        }
+    }
+}
+
+impl<K, V> Diff for BTreeMap<K, V>
+where
+    K: Ord + Debug,
+    V: Diff + Debug,
+{
+    fn diff_into(&self, other: &Self, path: &str, changes: &mut Vec<Change>) {
+        let mut keys: Vec<_> = self.keys().chain(other.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        diff_entries(
+            keys.into_iter(),
+            |k| self.get(k),
+            |k| other.get(k),
+            path,
+            changes,
+        );
+    }
+}
+
+impl<K, V> Diff for HashMap<K, V>
+where
+    K: Eq + Hash + Debug,
+    V: Diff + Debug,
+{
+    fn diff_into(&self, other: &Self, path: &str, changes: &mut Vec<Change>) {
+        // Changes are listed in order of their paths, as map order is arbitrary
+        let keys = self
+            .keys()
+            .chain(other.keys().filter(|k| !self.contains_key(k)));
+        let mut entries = Vec::new();
+        diff_entries(keys, |k| self.get(k), |k| other.get(k), path, &mut entries);
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        changes.extend(entries);
+    }
+}
+
+impl<S> Transition<S>
+where
+    S: Diff,
+{
+    /// The changes this transition makes to a state, none if `Same`.
+    pub fn changes(&self, from: &S) -> Vec<Change> {
+        match self {
+            Transition::Next(to) => from.diff(to),
+            Transition::Same => Vec::new(),
+        }
+    }
+}
+
+/// An observer writing a debug line for each change made by a transition.
+pub struct DiffLog<W> {
+    out: W,
+}
+
+impl<W> DiffLog<W>
+where
+    W: Write,
+{
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+impl<K, S, W> Observer<K, S> for DiffLog<W>
+where
+    K: Debug,
+    S: Diff,
+    W: Write,
+{
+    fn transitioned(&mut self, key: &K, from: &S, event: &str, to: &S) {
+        for change in from.diff(to) {
+            let _ = writeln!(self.out, "DEBUG {key:?}: {change} on {event}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        // An order with a customer, lines and tags
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct Customer {
+            name: String,
+            vip: bool,
+        }
+
+        derive_diff!(Customer { name, vip });
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Status {
+            Open,
+            Paid,
+        }
+
+        derive_diff!(Status);
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct Order {
+            customer: Customer,
+            status: Status,
+            lines: Vec<u32>,
+            tags: BTreeMap<String, String>,
+            note: Option<String>,
+        }
+
+        derive_diff!(Order {
+            customer,
+            status,
+            lines,
+            tags,
+            note
+        });
+
+        let before = Order {
+            customer: Customer {
+                name: "ann".to_string(),
+                vip: false,
+            },
+            status: Status::Open,
+            lines: vec![5, 7],
+            tags: BTreeMap::from([("a".to_string(), "1".to_string())]),
+            note: None,
+        };
+        let mut after = before.clone();
+        after.customer.vip = true;
+        after.status = Status::Paid;
+        after.lines = vec![5, 8, 9];
+        after.tags.clear();
+        after.note = Some("rush".to_string());
+
+        assert_eq!(before.diff(&before), vec![]);
+        let changes: Vec<_> = before.diff(&after).iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            changes,
+            vec![
+                "customer.vip: false -> true",
+                "status: Open -> Paid",
+                "lines[1]: 7 -> 8",
+                "lines[2]: added 9",
+                "tags[\"a\"]: removed \"1\"",
+                "note: added \"rush\"",
+            ]
+        );
+
+        assert_eq!(Transition::Next(2).changes(&1).len(), 1);
+        assert_eq!(Transition::<u32>::Same.changes(&1), vec![]);
+
+        let mut out = Vec::new();
+        Observer::<&str, u32>::transitioned(&mut DiffLog::new(&mut out), &"x", &1, "Inc", &2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "DEBUG \"x\": .: 1 -> 2 on Inc\n"
+        );
+    }
+}
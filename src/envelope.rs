@@ -0,0 +1,262 @@
+//! A versioned binary envelope for event records, so that journals,
+//! brokers and services in other languages can exchange the same records.
+//! An envelope carries an encoded event with what is needed to decode it
+//! and to place it: the codec that encoded it, the version of the event's
+//! schema, the entity and the sequence number.
+//!
+//! All integers are big-endian:
+//!
+//! ```text
+//! magic           4 bytes  "FSME"
+//! format version  1 byte   1
+//! codec id        1 byte   see the CODEC_ constants
+//! schema version  4 bytes
+//! entity length   2 bytes
+//! entity id       UTF-8
+//! sequence        8 bytes
+//! payload length  4 bytes
+//! payload
+//! checksum        4 bytes  CRC-32 (IEEE) of all the bytes before it
+//! ```
+//!
+//! A reader refuses a format version it does not know, so the layout can
+//! change in later versions.
+
+use crate::journal::Record;
+
+/// The first bytes of every envelope.
+pub const MAGIC: [u8; 4] = *b"FSME";
+
+/// The version of the envelope layout written.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Payloads of bytes with no particular encoding.
+pub const CODEC_BYTES: u8 = 0;
+/// Payloads of UTF-8 JSON.
+pub const CODEC_JSON: u8 = 1;
+/// Payloads in the bincode format.
+pub const CODEC_BINCODE: u8 = 2;
+/// Payloads in the MessagePack format.
+pub const CODEC_MESSAGEPACK: u8 = 3;
+/// Payloads in the Avro format, prefixed by their schema's version.
+pub const CODEC_AVRO: u8 = 4;
+
+/// An encoded event of an entity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub codec: u8,
+    pub schema_version: u32,
+    pub entity: String,
+    pub seq: u64,
+    pub payload: Vec<u8>,
+}
+
+/// Why bytes are not an envelope, or an envelope cannot be written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EnvelopeError {
+    /// The entity id is longer than its 2 byte length can hold, in bytes
+    EntityTooLong(usize),
+    /// The payload is longer than its 4 byte length can hold, in bytes
+    PayloadTooLong(usize),
+    Truncated,
+    BadMagic,
+    UnsupportedVersion(u8),
+    Checksum {
+        expected: u32,
+        actual: u32,
+    },
+    BadEntity,
+    TrailingBytes,
+}
+
+impl Envelope {
+    /// The envelope of a journalled record of encoded events.
+    pub fn from_record(record: &Record<String, Vec<u8>>, codec: u8, schema_version: u32) -> Self {
+        Self {
+            codec,
+            schema_version,
+            entity: record.key.clone(),
+            seq: record.seq,
+            payload: record.event.clone(),
+        }
+    }
+
+    /// The envelope as a record at an offset of a journal.
+    pub fn into_record(self, offset: u64) -> Record<String, Vec<u8>> {
+        Record {
+            offset,
+            key: self.entity,
+            seq: self.seq,
+            event: self.payload,
+        }
+    }
+
+    /// The envelope's bytes. Entity ids are limited to 65535 bytes and
+    /// payloads to 4 GiB, and longer ones are refused.
+    pub fn encode(&self) -> Result<Vec<u8>, EnvelopeError> {
+        let entity = self.entity.as_bytes();
+        let entity_len =
+            u16::try_from(entity.len()).map_err(|_| EnvelopeError::EntityTooLong(entity.len()))?;
+        let payload_len = u32::try_from(self.payload.len())
+            .map_err(|_| EnvelopeError::PayloadTooLong(self.payload.len()))?;
+        let mut bytes = Vec::with_capacity(28 + entity.len() + self.payload.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(FORMAT_VERSION);
+        bytes.push(self.codec);
+        bytes.extend_from_slice(&self.schema_version.to_be_bytes());
+        bytes.extend_from_slice(&entity_len.to_be_bytes());
+        bytes.extend_from_slice(entity);
+        bytes.extend_from_slice(&self.seq.to_be_bytes());
+        bytes.extend_from_slice(&payload_len.to_be_bytes());
+        bytes.extend_from_slice(&self.payload);
+        let checksum = crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        Ok(bytes)
+    }
+
+    /// An envelope from its bytes, checking its checksum.
+    pub fn decode(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        let (body, checksum) = bytes
+            .split_last_chunk::<4>()
+            .ok_or(EnvelopeError::Truncated)?;
+        let mut reader = Reader(body);
+        if reader.take(4)? != MAGIC {
+            return Err(EnvelopeError::BadMagic);
+        }
+        let [version] = reader.array()?;
+        if version != FORMAT_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        let expected = u32::from_be_bytes(*checksum);
+        let actual = crc32(body);
+        if expected != actual {
+            return Err(EnvelopeError::Checksum { expected, actual });
+        }
+        let [codec] = reader.array()?;
+        let schema_version = u32::from_be_bytes(reader.array()?);
+        let len = u16::from_be_bytes(reader.array()?) as usize;
+        let entity =
+            String::from_utf8(reader.take(len)?.to_vec()).map_err(|_| EnvelopeError::BadEntity)?;
+        let seq = u64::from_be_bytes(reader.array()?);
+        let len = u32::from_be_bytes(reader.array()?) as usize;
+        let payload = reader.take(len)?.to_vec();
+        if !reader.0.is_empty() {
+            return Err(EnvelopeError::TrailingBytes);
+        }
+        Ok(Self {
+            codec,
+            schema_version,
+            entity,
+            seq,
+            payload,
+        })
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], EnvelopeError> {
+        if self.0.len() < n {
+            return Err(EnvelopeError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], EnvelopeError> {
+        let taken = self.take(N)?;
+        Ok(taken.try_into().expect("N bytes were taken"))
+    }
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 == 1 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 (IEEE) of bytes, as used by zlib and Ethernet.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut c = !0u32;
+    for b in bytes {
+        c = CRC_TABLE[((c ^ u32::from(*b)) & 0xff) as usize] ^ (c >> 8);
+    }
+    !c
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        // A JSON event of a door, exchanged with another service
+
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let record = Record {
+            offset: 7,
+            key: "door-1".to_string(),
+            seq: 3,
+            event: br#"{"type":"Opened"}"#.to_vec(),
+        };
+        let envelope = Envelope::from_record(&record, CODEC_JSON, 2);
+        let bytes = envelope.encode().unwrap();
+        assert_eq!(&bytes[..6], b"FSME\x01\x01");
+        assert_eq!(Envelope::decode(&bytes), Ok(envelope.clone()));
+        assert_eq!(envelope.into_record(7), record);
+
+        let mut corrupt = bytes.clone();
+        corrupt[20] ^= 1;
+        assert!(matches!(
+            Envelope::decode(&corrupt),
+            Err(EnvelopeError::Checksum { .. })
+        ));
+        let mut later = bytes.clone();
+        later[4] = 2;
+        assert_eq!(
+            Envelope::decode(&later),
+            Err(EnvelopeError::UnsupportedVersion(2))
+        );
+        assert!(Envelope::decode(&bytes[..bytes.len() - 10]).is_err());
+        assert_eq!(Envelope::decode(b"FSM"), Err(EnvelopeError::Truncated));
+        assert_eq!(Envelope::decode(b"JSON{}.."), Err(EnvelopeError::BadMagic));
+    }
+
+    #[test]
+    fn test_too_long() {
+        // Fields too long for their lengths are refused, not truncated
+        let envelope = |entity: String| Envelope {
+            codec: CODEC_BYTES,
+            schema_version: 1,
+            entity,
+            seq: 1,
+            payload: Vec::new(),
+        };
+        let longest = envelope("e".repeat(u16::MAX as usize)).encode().unwrap();
+        assert_eq!(
+            Envelope::decode(&longest).map(|e| e.entity.len()),
+            Ok(65535)
+        );
+        assert_eq!(
+            envelope("e".repeat(65536)).encode(),
+            Err(EnvelopeError::EntityTooLong(65536))
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Export a `MachineDescriptor` for use with other tools.
+//!
+//! `to_tla` generates a TLA+ module describing the machine's abstract
+//! behaviour, with a single `state` variable ranging over the declared
+//! states and an action for each transition. Model check it with TLC
+//! alongside other specifications, e.g. against the `TypeOK` invariant.
+
+use std::fmt::Write;
+
+use crate::descriptor::MachineDescriptor;
+
+/// Generate a TLA+ module from a descriptor.
+pub fn to_tla(descriptor: &MachineDescriptor) -> String {
+    let module = match identifier(&descriptor.name) {
+        name if name.is_empty() => "Machine".to_string(),
+        name => name,
+    };
+    let states = descriptor
+        .states
+        .iter()
+        .map(|s| quoted(s))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let initial = descriptor
+        .initial
+        .as_ref()
+        .or_else(|| descriptor.states.first());
+
+    let mut tla = String::new();
+    let _ = writeln!(tla, "---- MODULE {module} ----");
+    let _ = writeln!(tla, "VARIABLE state");
+    let _ = writeln!(tla);
+    let _ = writeln!(tla, "States == {{{states}}}");
+    let _ = writeln!(tla);
+    let _ = writeln!(tla, "TypeOK == state \\in States");
+    let _ = writeln!(tla);
+    let _ = match initial {
+        Some(s) => writeln!(tla, "Init == state = {}", quoted(s)),
+        None => writeln!(tla, "Init == state \\in States"),
+    };
+
+    let mut actions: Vec<String> = Vec::new();
+    for t in &descriptor.transitions {
+        let mut action = format!("{}_{}", identifier(&t.from), identifier(&t.command));
+        if actions.contains(&action) {
+            action = format!("{action}_{}", actions.len());
+        }
+        let _ = writeln!(tla);
+        let _ = writeln!(tla, "\\* {} emits {}", t.command, t.event);
+        let _ = writeln!(
+            tla,
+            "{action} == state = {} /\\ state' = {}",
+            quoted(&t.from),
+            quoted(&t.to)
+        );
+        actions.push(action);
+    }
+
+    let _ = writeln!(tla);
+    if actions.is_empty() {
+        let _ = writeln!(tla, "Next == UNCHANGED state");
+    } else {
+        let _ = writeln!(tla, "Next ==");
+        for action in &actions {
+            let _ = writeln!(tla, "    \\/ {action}");
+        }
+    }
+    let _ = writeln!(tla);
+    let _ = writeln!(tla, "Spec == Init /\\ [][Next]_state");
+    let _ = writeln!(tla);
+    let _ = writeln!(tla, "====");
+    tla
+}
+
+fn identifier(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+fn quoted(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_tla() {
+        let descriptor = MachineDescriptor::new("door")
+            .initial("Closed")
+            .transition("Closed", "Open", "Opened", "Open")
+            .transition("Open", "Close", "Closed", "Closed");
+
+        assert_eq!(
+            to_tla(&descriptor),
+            r#"---- MODULE door ----
+VARIABLE state
+
+States == {"Closed", "Open"}
+
+TypeOK == state \in States
+
+Init == state = "Closed"
+
+\* Open emits Opened
+Closed_Open == state = "Closed" /\ state' = "Open"
+
+\* Close emits Closed
+Open_Close == state = "Open" /\ state' = "Closed"
+
+Next ==
+    \/ Closed_Open
+    \/ Open_Close
+
+Spec == Init /\ [][Next]_state
+
+====
+"#
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Commands whose effects can fail. A failure may be modelled as
+//! machine input by giving the command an error type that is itself
+//! an event. The `Recover` adapter then feeds the failure back into the
+//! machine, so that failure states can be described explicitly rather
+//! than handled by every caller of `step`.
+//!
+//! Alternatively, `try_step` treats a failure as a rejection of the
+//! command, reported as `Rejection::EffectFailed`.
+
+use std::fmt::Debug;
+
+use crate::command_and_event_traits::{
+    Command, Event, Fsm, Rejection, Transition, Validate, ValidationError,
+};
+use crate::observer::type_label;
+
+/// A command that executes an effect which may fail.
+pub trait TryCommand<S, H> {
+    type Output: Event<S>;
+    type Error;
+    fn try_execute(&self, state: &S, handler: &mut H) -> Result<Option<Self::Output>, Self::Error>;
+}
+
+/// The event of a fallible command, describing either its
+/// result or its failure.
+#[derive(Debug, PartialEq)]
+pub enum Outcome<E, F> {
+    Succeeded(E),
+    Failed(F),
+}
+
+impl<S, E, F> Event<S> for Outcome<E, F>
+where
+    E: Event<S>,
+    F: Event<S>,
+{
+    fn fire(&self, state: &S) -> Transition<S> {
+        match self {
+            Outcome::Succeeded(e) => e.fire(state),
+            Outcome::Failed(f) => f.fire(state),
+        }
+    }
+}
+
+/// Adapts a fallible command to a `Command` whose failures are
+/// emitted as events.
+pub struct Recover<C>(pub C);
+
+impl<S, H, C> Command<S, H> for Recover<C>
+where
+    C: TryCommand<S, H>,
+    C::Error: Event<S>,
+{
+    type Output = Outcome<C::Output, C::Error>;
+    fn execute(&self, state: &S, handler: &mut H) -> Option<Self::Output> {
+        match self.0.try_execute(state, handler) {
+            Ok(event) => event.map(Outcome::Succeeded),
+            Err(error) => Some(Outcome::Failed(error)),
+        }
+    }
+
+    fn name(&self) -> String {
+        type_label::<C>()
+    }
+}
+
+impl<S, C> Validate<S> for Recover<C>
+where
+    C: Validate<S>,
+{
+    fn validate(&self, state: &S) -> Result<(), ValidationError> {
+        self.0.validate(state)
+    }
+}
+
+/// A fallible command seen as a `Command`, for the `before_command` hook,
+/// its failure being no event.
+struct Attempt<'a, C>(&'a C);
+
+impl<S, H, C> Command<S, H> for Attempt<'_, C>
+where
+    C: TryCommand<S, H>,
+{
+    type Output = C::Output;
+    fn execute(&self, state: &S, handler: &mut H) -> Option<C::Output> {
+        self.0.try_execute(state, handler).ok().flatten()
+    }
+
+    fn name(&self) -> String {
+        type_label::<C>()
+    }
+}
+
+/// Step a fallible command, rejecting it if it is not allowed in the
+/// state, it is invalid, its effect fails, or its transition is vetoed.
+/// As with `Fsm::step`, the `before_command` hook is called first.
+pub fn try_step<F, S, H, C>(
+    state: &S,
+    command: &C,
+    handler: &mut H,
+) -> Result<(Option<C::Output>, Transition<S>), Rejection>
+where
+    F: Fsm<S, H>,
+    C: TryCommand<S, H> + Validate<S>,
+    C::Error: Debug,
+{
+    F::before_command(state, &Attempt(command), handler);
+    if !F::allows(state, &type_label::<C>()) {
+        return Err(Rejection::NotAllowedInState);
+    }
+    command.validate(state)?;
+    let event = command
+        .try_execute(state, handler)
+        .map_err(|e| Rejection::EffectFailed(format!("{e:?}")))?;
+    let transition = match &event {
+        Some(e) => F::step_event(state, e, handler)?,
+        None => Transition::Same,
+    };
+    Ok((event, transition))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::Fsm;
+
+    #[test]
+    fn test_recover() {
+        // A connection that degrades after three failures
+
+        #[derive(Debug, PartialEq)]
+        enum State {
+            Healthy { failures: u32 },
+            Degraded,
+        }
+
+        struct Connect {}
+
+        #[derive(Debug, PartialEq)]
+        struct Connected {}
+
+        #[derive(Debug, PartialEq)]
+        struct ConnectFailed {}
+
+        struct Network {
+            up: bool,
+        }
+
+        impl TryCommand<State, Network> for Connect {
+            type Output = Connected;
+            type Error = ConnectFailed;
+            fn try_execute(
+                &self,
+                _s: &State,
+                network: &mut Network,
+            ) -> Result<Option<Connected>, ConnectFailed> {
+                if network.up {
+                    Ok(Some(Connected {}))
+                } else {
+                    Err(ConnectFailed {})
+                }
+            }
+        }
+
+        impl Validate<State> for Connect {}
+
+        impl Event<State> for Connected {
+            fn fire(&self, s: &State) -> Transition<State> {
+                match s {
+                    State::Healthy { failures: 0 } => Transition::Same,
+                    _ => Transition::Next(State::Healthy { failures: 0 }),
+                }
+            }
+        }
+
+        impl Event<State> for ConnectFailed {
+            fn fire(&self, s: &State) -> Transition<State> {
+                match s {
+                    State::Healthy { failures } if *failures < 2 => {
+                        Transition::Next(State::Healthy {
+                            failures: failures + 1,
+                        })
+                    }
+                    State::Healthy { .. } => Transition::Next(State::Degraded),
+                    State::Degraded => Transition::Same,
+                }
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<State, Network> for MyFsm {}
+
+        let mut network = Network { up: false };
+        let mut state = State::Healthy { failures: 0 };
+        for _ in 0..3 {
+            let (e, t) = MyFsm::step(&state, &Recover(Connect {}), &mut network);
+            assert_eq!(e, Some(Outcome::Failed(ConnectFailed {})));
+            if let Ok(Transition::Next(s)) = t {
+                state = s;
+            }
+        }
+        assert_eq!(state, State::Degraded);
+
+        network.up = true;
+        let (e, t) = MyFsm::step(&state, &Recover(Connect {}), &mut network);
+        assert_eq!(e, Some(Outcome::Succeeded(Connected {})));
+        assert_eq!(t, Ok(Transition::Next(State::Healthy { failures: 0 })));
+
+        // Or rejected instead
+
+        network.up = false;
+        assert_eq!(
+            try_step::<MyFsm, _, _, _>(&state, &Connect {}, &mut network),
+            Err(Rejection::EffectFailed("ConnectFailed".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_try_step_hooks() {
+        // An audit log of fetches, made before they are checked, so that
+        // fetches refused when closed or invalid are logged too
+
+        struct Fetch(u32);
+
+        #[derive(Debug, PartialEq)]
+        struct Fetched {}
+
+        impl TryCommand<bool, Vec<String>> for Fetch {
+            type Output = Fetched;
+            type Error = ();
+            fn try_execute(&self, _s: &bool, log: &mut Vec<String>) -> Result<Option<Fetched>, ()> {
+                log.push("fetch".to_string());
+                Ok(Some(Fetched {}))
+            }
+        }
+
+        impl Validate<bool> for Fetch {
+            fn validate(&self, _s: &bool) -> Result<(), ValidationError> {
+                match self.0 {
+                    0 => Err(ValidationError("nothing to fetch".to_string())),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        impl Event<bool> for Fetched {
+            fn fire(&self, _s: &bool) -> Transition<bool> {
+                Transition::Same
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<bool, Vec<String>> for MyFsm {
+            fn allows(open: &bool, _command: &str) -> bool {
+                *open
+            }
+
+            fn before_command<C>(_s: &bool, c: &C, log: &mut Vec<String>)
+            where
+                C: Command<bool, Vec<String>>,
+            {
+                log.push(format!("before {}", c.name()));
+            }
+        }
+
+        let mut log = Vec::new();
+        let step = |open, fetch, log: &mut Vec<String>| {
+            try_step::<MyFsm, _, _, _>(&open, &fetch, log).map(|(e, _)| e)
+        };
+        assert_eq!(
+            step(false, Fetch(1), &mut log),
+            Err(Rejection::NotAllowedInState)
+        );
+        assert_eq!(
+            step(true, Fetch(0), &mut log),
+            Err(Rejection::ValidationFailed("nothing to fetch".to_string()))
+        );
+        assert_eq!(step(true, Fetch(1), &mut log), Ok(Some(Fetched {})));
+        assert_eq!(
+            log,
+            vec!["before Fetch", "before Fetch", "before Fetch", "fetch"]
+        );
+    }
+}
@@ -0,0 +1,390 @@
+//! A journal kept in a file, one JSON record per line, for tools and for
+//! small deployments. Keys are strings and events are `Json` values:
+//!
+//! ```text
+//! {"offset":0,"key":"door-1","seq":1,"event":{"type":"Opened"}}
+//! ```
+//!
+//! The records are held in memory as well, and each append is written
+//! before it is acknowledged. A group of appends is written once.
+//!
+//! A crash part way through a write can leave a torn last line. Opening
+//! the journal truncates a last line that is unterminated or cannot be
+//! read, as its append was never acknowledged, while a bad line before it
+//! is an error. A write that fails is likewise truncated, so that later
+//! appends do not follow a partial line.
+//!
+//! Whether a write is also synced to the disk before it is acknowledged
+//! is set by a `DurabilityPolicy`. By default it is left to the operating
+//! system, so a crash of the machine, though not of the process, may lose
+//! the latest appends.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::journal::{Journal, JournalError, MemJournal, Position, Record};
+use crate::json::Json;
+
+/// When a journal syncs its writes to the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DurabilityPolicy {
+    /// Sync every write before acknowledging it
+    Always,
+    /// Sync a write if the last sync was at least this long ago. Syncing
+    /// is lazy, on the next write after the interval, so a journal left
+    /// idle may keep its recent appends unsynced for any length of time
+    Interval(Duration),
+    /// Leave syncing to the operating system
+    #[default]
+    OsDefault,
+}
+
+/// A journal of Json events for string keys, in a JSON lines file.
+pub struct FileJournal {
+    file: File,
+    len: u64,
+    records: MemJournal<String, Json>,
+    next_offset: u64,
+    durability: DurabilityPolicy,
+    last_sync: Instant,
+    syncs: u64,
+}
+
+fn failed(e: impl ToString) -> JournalError {
+    JournalError::Failed(e.to_string())
+}
+
+/// The JSON line of a record.
+pub fn write_record(record: &Record<String, Json>) -> String {
+    Json::Object(vec![
+        ("offset".to_string(), Json::Number(record.offset as f64)),
+        ("key".to_string(), Json::String(record.key.clone())),
+        ("seq".to_string(), Json::Number(record.seq as f64)),
+        ("event".to_string(), record.event.clone()),
+    ])
+    .to_string()
+}
+
+/// A record from its JSON line.
+pub fn read_record(line: &str) -> Result<Record<String, Json>, String> {
+    let json = Json::parse(line).map_err(|e| format!("offset {}: {}", e.offset, e.message))?;
+    let number = |name| match json.get(name) {
+        Some(Json::Number(n)) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as u64),
+        _ => Err(format!("{name} must be a whole number")),
+    };
+    Ok(Record {
+        offset: number("offset")?,
+        key: json
+            .get("key")
+            .and_then(Json::as_str)
+            .ok_or("key must be a string")?
+            .to_string(),
+        seq: number("seq")?,
+        event: json.get("event").ok_or("missing event")?.clone(),
+    })
+}
+
+impl FileJournal {
+    /// Open a journal file, creating it if need be, and load its records.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, JournalError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| failed(format!("{}: {e}", path.display())))?;
+        let mut bytes = Vec::new();
+        (&file).read_to_end(&mut bytes).map_err(failed)?;
+        let mut records = MemJournal::new();
+        let mut len = 0;
+        let mut lines = bytes
+            .split_inclusive(|b| *b == b'\n')
+            .enumerate()
+            .peekable();
+        while let Some((i, line)) = lines.next() {
+            let context = |e| failed(format!("{}:{}: {e}", path.display(), i + 1));
+            let last = lines.peek().is_none();
+            let record = std::str::from_utf8(line)
+                .map_err(|e| e.to_string())
+                .and_then(|l| match l.trim().is_empty() {
+                    true => Ok(None),
+                    false => read_record(l).map(Some),
+                });
+            match record {
+                // A torn last line, whose append was not acknowledged
+                _ if last && !line.ends_with(b"\n") => break,
+                Err(_) if last => break,
+                Err(e) => return Err(context(e)),
+                Ok(None) => {}
+                Ok(Some(record)) => {
+                    let position = records.append(&record.key, record.event)?;
+                    if (position.offset, position.seq) != (record.offset, record.seq) {
+                        return Err(context("records are out of order".to_string()));
+                    }
+                }
+            }
+            len += line.len() as u64;
+        }
+        if len < bytes.len() as u64 {
+            file.set_len(len).map_err(failed)?;
+        }
+        let next_offset = records.len() as u64;
+        Ok(Self {
+            file,
+            len,
+            records,
+            next_offset,
+            durability: DurabilityPolicy::default(),
+            last_sync: Instant::now(),
+            syncs: 0,
+        })
+    }
+
+    /// Sync writes to the disk as set by a policy.
+    pub fn with_durability(mut self, durability: DurabilityPolicy) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn durability(&self) -> DurabilityPolicy {
+        self.durability
+    }
+
+    /// Sync the writes so far to the disk, whatever the policy.
+    pub fn sync(&mut self) -> Result<(), JournalError> {
+        self.file.sync_data().map_err(failed)?;
+        self.last_sync = Instant::now();
+        self.syncs += 1;
+        Ok(())
+    }
+
+    /// Write lines to the file, syncing them as the policy requires. If
+    /// either fails the file is truncated to its length before the write.
+    fn write(&mut self, lines: &str) -> Result<(), JournalError> {
+        let written = self
+            .file
+            .write_all(lines.as_bytes())
+            .and_then(|_| self.file.flush())
+            .map_err(failed)
+            .and_then(|_| match self.durability {
+                DurabilityPolicy::Always => self.sync(),
+                DurabilityPolicy::Interval(d) if self.last_sync.elapsed() >= d => self.sync(),
+                _ => Ok(()),
+            });
+        match written {
+            Ok(()) => {
+                self.len += lines.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                let _ = self.file.set_len(self.len);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+impl Journal<String, Json> for FileJournal {
+    fn append(&mut self, key: &String, event: Json) -> Result<Position, JournalError> {
+        let seq = self.records.last_seq(key)? + 1;
+        let record = Record {
+            offset: self.next_offset,
+            key: key.clone(),
+            seq,
+            event,
+        };
+        self.write(&format!("{}\n", write_record(&record)))?;
+        self.next_offset += 1;
+        self.records.append(key, record.event)
+    }
+
+    fn append_group(&mut self, events: Vec<(String, Json)>) -> Vec<Result<Position, JournalError>> {
+        let mut seqs: HashMap<String, u64> = HashMap::new();
+        let mut lines = String::new();
+        for (i, (key, event)) in events.iter().enumerate() {
+            let seq = match seqs.get(key) {
+                Some(seq) => seq + 1,
+                None => match self.records.last_seq(key) {
+                    Ok(seq) => seq + 1,
+                    Err(e) => return events.iter().map(|_| Err(e.clone())).collect(),
+                },
+            };
+            seqs.insert(key.clone(), seq);
+            let record = Record {
+                offset: self.next_offset + i as u64,
+                key: key.clone(),
+                seq,
+                event: event.clone(),
+            };
+            lines.push_str(&write_record(&record));
+            lines.push('\n');
+        }
+        if let Err(e) = self.write(&lines) {
+            return events.iter().map(|_| Err(e.clone())).collect();
+        }
+        self.next_offset += events.len() as u64;
+        events
+            .into_iter()
+            .map(|(key, event)| self.records.append(&key, event))
+            .collect()
+    }
+
+    /// Written at once, so all or nothing.
+    fn append_batch(
+        &mut self,
+        key: &String,
+        events: Vec<Json>,
+    ) -> Result<Vec<Position>, JournalError> {
+        let first_seq = self.records.last_seq(key)? + 1;
+        let mut lines = String::new();
+        for (i, event) in events.iter().enumerate() {
+            let record = Record {
+                offset: self.next_offset + i as u64,
+                key: key.clone(),
+                seq: first_seq + i as u64,
+                event: event.clone(),
+            };
+            lines.push_str(&write_record(&record));
+            lines.push('\n');
+        }
+        self.write(&lines)?;
+        self.next_offset += events.len() as u64;
+        self.records.append_batch(key, events)
+    }
+
+    fn read(&self, key: &String, from_seq: u64) -> Result<Vec<Record<String, Json>>, JournalError> {
+        self.records.read(key, from_seq)
+    }
+
+    fn read_page(
+        &self,
+        key: &String,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Record<String, Json>>, JournalError> {
+        self.records.read_page(key, from_seq, limit)
+    }
+
+    fn read_all(&self, from_offset: u64) -> Result<Vec<Record<String, Json>>, JournalError> {
+        self.records.read_all(from_offset)
+    }
+
+    fn last_seq(&self, key: &String) -> Result<u64, JournalError> {
+        self.records.last_seq(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_journal() {
+        let path = std::env::temp_dir().join(format!("fsm-journal-{}.jsonl", std::process::id()));
+        let opened = Json::parse(r#"{"type":"Opened"}"#).unwrap();
+        {
+            let mut journal = FileJournal::open(&path).unwrap();
+            journal.append(&"d1".to_string(), opened.clone()).unwrap();
+            journal.append(&"d2".to_string(), Json::Null).unwrap();
+            journal.append(&"d1".to_string(), Json::Bool(true)).unwrap();
+        }
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text.lines().next(),
+            Some(r#"{"offset":0,"key":"d1","seq":1,"event":{"type":"Opened"}}"#)
+        );
+
+        let mut journal = FileJournal::open(&path).unwrap();
+        assert_eq!(journal.len(), 3);
+        assert_eq!(
+            journal.read(&"d1".to_string(), 2).unwrap()[0].event,
+            Json::Bool(true)
+        );
+        assert_eq!(
+            journal.append(&"d2".to_string(), Json::Null),
+            Ok(Position { offset: 3, seq: 2 })
+        );
+        let group = journal.append_group(vec![
+            ("d3".to_string(), Json::Null),
+            ("d1".to_string(), Json::Null),
+            ("d3".to_string(), Json::Null),
+        ]);
+        assert_eq!(group[2], Ok(Position { offset: 6, seq: 2 }));
+        let batch = journal.append_batch(&"d2".to_string(), vec![Json::Null, Json::Null]);
+        assert_eq!(batch.map(|b| b[1]), Ok(Position { offset: 8, seq: 4 }));
+        drop(journal);
+        let mut journal = FileJournal::open(&path)
+            .unwrap()
+            .with_durability(DurabilityPolicy::Always);
+        assert_eq!(journal.last_seq(&"d1".to_string()), Ok(3));
+
+        // Syncing always, then at most once an hour
+        journal.append(&"d1".to_string(), Json::Null).unwrap();
+        journal.append(&"d1".to_string(), Json::Null).unwrap();
+        assert_eq!(journal.syncs, 2);
+        let mut journal =
+            journal.with_durability(DurabilityPolicy::Interval(Duration::from_secs(3600)));
+        journal.append(&"d1".to_string(), Json::Null).unwrap();
+        assert_eq!(journal.syncs, 2);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(read_record(r#"{"offset":-1,"key":"a","seq":1,"event":1}"#).is_err());
+    }
+
+    #[test]
+    fn test_torn_last_line() {
+        // A crash part way through the third append
+        let path = std::env::temp_dir().join(format!("fsm-torn-{}.jsonl", std::process::id()));
+        let good = concat!(
+            r#"{"offset":0,"key":"d1","seq":1,"event":null}"#,
+            "\n",
+            r#"{"offset":1,"key":"d1","seq":2,"event":true}"#,
+            "\n",
+        );
+        // Unterminated, unreadable, and split within a character
+        let torn: [&[u8]; 3] = [
+            br#"{"offset":2,"key":"d1","se"#,
+            b"{\"offset\":2}\n",
+            b"\"\xc3",
+        ];
+        for torn in torn {
+            let mut bytes = good.as_bytes().to_vec();
+            bytes.extend_from_slice(torn);
+            std::fs::write(&path, &bytes).unwrap();
+
+            let mut journal = FileJournal::open(&path).unwrap();
+            assert_eq!(journal.len(), 2);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), good);
+            assert_eq!(
+                journal.append(&"d1".to_string(), Json::Null),
+                Ok(Position { offset: 2, seq: 3 })
+            );
+            drop(journal);
+            assert_eq!(FileJournal::open(&path).unwrap().len(), 3);
+        }
+
+        // A bad line before the last is not a torn write
+        let mut bytes = b"{}\n".to_vec();
+        bytes.extend_from_slice(good.as_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        match FileJournal::open(&path) {
+            Err(JournalError::Failed(reason)) => {
+                assert!(reason.ends_with(":1: offset must be a whole number"))
+            }
+            _ => panic!("expected the journal to fail to open"),
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
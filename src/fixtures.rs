@@ -0,0 +1,30 @@
+//! A counter machine shared by the tests of several modules. Its state is
+//! the count, its handler does nothing, and each `Increment` emits an
+//! `Incremented` adding one.
+
+use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+
+#[derive(Clone)]
+pub struct Increment {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Incremented {}
+
+impl Command<u32, ()> for Increment {
+    type Output = Incremented;
+    fn execute(&self, _s: &u32, _h: &mut ()) -> Option<Incremented> {
+        Some(Incremented {})
+    }
+}
+
+impl Validate<u32> for Increment {}
+
+impl Event<u32> for Incremented {
+    fn fire(&self, s: &u32) -> Transition<u32> {
+        Transition::Next(s + 1)
+    }
+}
+
+pub struct Counter {}
+
+impl Fsm<u32, ()> for Counter {}
@@ -0,0 +1,399 @@
+//! Gating commands by runtime feature flags. A `FlagProvider` answers
+//! whether a flag is on for an entity, so that a service like
+//! LaunchDarkly can roll a feature out entity by entity. A gate names the
+//! flag, if any, each command needs, and refuses the command with
+//! `RunError::FeatureDisabled` while the flag is off, so that flag checks
+//! are not baked into every `execute`. A refusal is reported to the
+//! runner's observers as a rejection.
+//!
+//! The gate is `FlagGate` for a runner on the caller's thread, and is
+//! checked inside the task or thread of a `ThreadRunner`, `AsyncRunner`
+//! or `AsyncEffectRunner` spawned with `spawn_flag_gated`.
+//!
+//! `MemFlags` is a provider held in memory. It is a handle, so a clone
+//! can be kept to switch flags at runtime.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::async_handler::{AsyncCommand, AsyncEffectRunner, AsyncHandler};
+use crate::async_runner::{AsyncRunner, Executor};
+use crate::command_and_event_traits::{Command, Event, Fsm, Validate};
+use crate::pipeline::Dispatch;
+use crate::runner::{RunError, Runner};
+use crate::thread_runner::ThreadRunner;
+
+/// Answers whether feature flags are on for entities with keys K.
+pub trait FlagProvider<K> {
+    fn enabled(&self, flag: &str, key: &K) -> bool;
+}
+
+impl<K, F> FlagProvider<K> for F
+where
+    F: Fn(&str, &K) -> bool,
+{
+    fn enabled(&self, flag: &str, key: &K) -> bool {
+        self(flag, key)
+    }
+}
+
+/// Flags held in memory, on or off for every entity. Unknown flags are off.
+#[derive(Clone, Default)]
+pub struct MemFlags {
+    flags: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl MemFlags {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Switch a flag on or off.
+    pub fn set(&self, flag: &str, on: bool) {
+        self.lock().insert(flag.to_string(), on);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, bool>> {
+        self.flags.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K> FlagProvider<K> for MemFlags {
+    fn enabled(&self, flag: &str, _key: &K) -> bool {
+        self.lock().get(flag).copied().unwrap_or(false)
+    }
+}
+
+/// Check that the flag a command needs, if any, is on for the entity, or
+/// fail with `RunError::FeatureDisabled`, reported to the runner's
+/// observers under the command's name.
+fn check_flag<K, S, H, F, C, P, G>(
+    runner: &mut Runner<K, S, H, F>,
+    flags: &P,
+    flag_for: &G,
+    key: &K,
+    command: &C,
+    name: &str,
+) -> Result<(), RunError>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    P: FlagProvider<K>,
+    G: Fn(&C) -> Option<&'static str>,
+{
+    match flag_for(command) {
+        Some(flag) if !flags.enabled(flag, key) => {
+            let error = RunError::FeatureDisabled(flag.to_string());
+            Err(runner.reject(key, name, error))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A runner refusing commands whose flag, named by G, is off in the
+/// provider P.
+pub struct FlagGate<K, S, H, F, P, G> {
+    runner: Runner<K, S, H, F>,
+    flags: P,
+    flag_for: G,
+}
+
+impl<K, S, H, F, P, G> FlagGate<K, S, H, F, P, G>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+{
+    pub fn new(runner: Runner<K, S, H, F>, flags: P, flag_for: G) -> Self {
+        Self {
+            runner,
+            flags,
+            flag_for,
+        }
+    }
+
+    pub fn runner(&self) -> &Runner<K, S, H, F> {
+        &self.runner
+    }
+
+    pub fn into_inner(self) -> Runner<K, S, H, F> {
+        self.runner
+    }
+
+    /// Step an entity with a command if its flag is on, or fail with
+    /// `RunError::FeatureDisabled`.
+    pub fn send<C>(&mut self, key: &K, command: &C) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+        P: FlagProvider<K>,
+        G: Fn(&C) -> Option<&'static str>,
+    {
+        let name = command.name();
+        check_flag(
+            &mut self.runner,
+            &self.flags,
+            &self.flag_for,
+            key,
+            command,
+            &name,
+        )?;
+        self.runner.send(key, command)
+    }
+}
+
+impl<K, S, H, F, P, G, C> Dispatch<K, C> for FlagGate<K, S, H, F, P, G>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    P: FlagProvider<K>,
+    G: Fn(&C) -> Option<&'static str>,
+{
+    type Output = C::Output;
+    fn dispatch(&mut self, key: &K, command: &C) -> Result<Option<C::Output>, RunError> {
+        self.send(key, command)
+    }
+}
+
+impl<K, S, H, F, C> ThreadRunner<K, S, H, F, C>
+where
+    K: Eq + Hash + Send + 'static,
+    S: Send + 'static,
+    H: Send + 'static,
+    F: Fsm<S, H> + 'static,
+    C: Command<S, H> + Validate<S> + Send + 'static,
+    C::Output: Send + 'static,
+{
+    /// Start a thread owning the runner, refusing commands whose flag is off.
+    pub fn spawn_flag_gated<P, G>(runner: Runner<K, S, H, F>, flags: P, flag_for: G) -> Self
+    where
+        P: FlagProvider<K> + Send + 'static,
+        G: Fn(&C) -> Option<&'static str> + Send + 'static,
+    {
+        Self::spawn_with(runner, move |runner, key, command| {
+            check_flag(runner, &flags, &flag_for, key, command, &command.name())?;
+            runner.send(key, command)
+        })
+    }
+}
+
+impl<K, C, E> AsyncRunner<K, C, E>
+where
+    K: Eq + Hash + Send + 'static,
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawn a task owning the runner, refusing commands whose flag is off.
+    pub fn spawn_flag_gated<S, H, F, X, P, G>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        flags: P,
+        flag_for: G,
+    ) -> Self
+    where
+        S: Send + 'static,
+        H: Send + 'static,
+        F: Fsm<S, H> + 'static,
+        C: Command<S, H, Output = E> + Validate<S>,
+        X: Executor + Send + Sync + 'static,
+        P: FlagProvider<K> + Send + 'static,
+        G: Fn(&C) -> Option<&'static str> + Send + 'static,
+    {
+        Self::spawn_with(runner, executor, move |runner, key, command| {
+            check_flag(runner, &flags, &flag_for, key, command, &command.name())?;
+            runner.send(key, command)
+        })
+    }
+}
+
+impl<K, C, E> AsyncEffectRunner<K, C, E>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawn a task owning the runner, refusing commands whose flag is off
+    /// before their effects start.
+    pub fn spawn_flag_gated<S, H, F, X, P, G>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        flags: P,
+        flag_for: G,
+    ) -> Self
+    where
+        S: Send + 'static,
+        H: AsyncHandler,
+        F: Fsm<S, H> + 'static,
+        C: AsyncCommand<S, H, Output = E> + Validate<S>,
+        E: Event<S>,
+        X: Executor + Send + Sync + 'static,
+        P: FlagProvider<K> + Send + 'static,
+        G: Fn(&C) -> Option<&'static str> + Send + 'static,
+    {
+        Self::spawn_with(runner, executor, move |runner, key, command| {
+            check_flag(runner, &flags, &flag_for, key, command, &command.name())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_handler::{CancellationToken, Effect};
+    use crate::async_runner::{block_on, ThreadExecutor};
+    use crate::command_and_event_traits::{Rejection, Transition};
+    use crate::observer::Observer;
+
+    // A basket where express checkout is behind a flag
+
+    enum Checkout {
+        Standard,
+        Express,
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct CheckedOut(bool);
+
+    impl Command<bool, ()> for Checkout {
+        type Output = CheckedOut;
+        fn execute(&self, _s: &bool, _h: &mut ()) -> Option<CheckedOut> {
+            Some(CheckedOut(matches!(self, Checkout::Express)))
+        }
+    }
+
+    impl AsyncCommand<bool, ()> for Checkout {
+        type Output = CheckedOut;
+        fn execute(&self, _s: &bool, _h: (), _c: CancellationToken) -> Effect<CheckedOut> {
+            let express = matches!(self, Checkout::Express);
+            Box::pin(async move { Some(CheckedOut(express)) })
+        }
+    }
+
+    impl Validate<bool> for Checkout {}
+
+    impl Event<bool> for CheckedOut {
+        fn fire(&self, _s: &bool) -> Transition<bool> {
+            Transition::Next(true)
+        }
+    }
+
+    struct MyFsm {}
+
+    impl Fsm<bool, ()> for MyFsm {}
+
+    fn flag_for(c: &Checkout) -> Option<&'static str> {
+        match c {
+            Checkout::Express => Some("express-checkout"),
+            Checkout::Standard => None,
+        }
+    }
+
+    fn basket() -> Runner<u32, bool, (), MyFsm> {
+        let mut runner = Runner::new(());
+        runner.insert(1, false);
+        runner
+    }
+
+    fn disabled() -> Result<Option<CheckedOut>, RunError> {
+        Err(RunError::FeatureDisabled("express-checkout".to_string()))
+    }
+
+    /// Records the rejections a runner reports.
+    #[derive(Clone, Default)]
+    struct Rejections(Arc<Mutex<Vec<String>>>);
+
+    impl Observer<u32, bool> for Rejections {
+        fn rejected(&mut self, key: &u32, command: &str, error: &RunError) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{key} {command} {error:?}"));
+        }
+    }
+
+    #[test]
+    fn test_flag_gate() {
+        let flags = MemFlags::new();
+        let mut gate = FlagGate::new(basket(), flags.clone(), flag_for);
+
+        let refused = gate.dispatch(&1, &Checkout::Express).unwrap_err();
+        assert_eq!(
+            refused.rejection(),
+            Some(Rejection::FeatureDisabled("express-checkout".to_string()))
+        );
+        assert_eq!(
+            gate.dispatch(&1, &Checkout::Standard),
+            Ok(Some(CheckedOut(false)))
+        );
+
+        flags.set("express-checkout", true);
+        assert_eq!(
+            gate.dispatch(&1, &Checkout::Express),
+            Ok(Some(CheckedOut(true)))
+        );
+
+        // A provider can decide per entity
+        let mut gate = FlagGate::new(gate.into_inner(), |_: &str, k: &u32| *k > 1, flag_for);
+        assert!(gate.dispatch(&1, &Checkout::Express).is_err());
+    }
+
+    #[test]
+    fn test_refusal_observed() {
+        // Refusals reach the runner's observers like any other rejection
+        let rejections = Rejections::default();
+        let runner = basket().with_observer(Box::new(rejections.clone()));
+        let mut gate = FlagGate::new(runner, MemFlags::new(), flag_for);
+        assert_eq!(gate.send(&1, &Checkout::Express), disabled());
+        assert_eq!(
+            *rejections.0.lock().unwrap(),
+            vec![r#"1 Checkout FeatureDisabled("express-checkout")"#]
+        );
+        assert_eq!(gate.runner().state(&1), Some(&false));
+    }
+
+    #[test]
+    fn test_thread_flag_gated() {
+        let flags = MemFlags::new();
+        let rejections = Rejections::default();
+        let runner = basket().with_observer(Box::new(rejections.clone()));
+        let thread = ThreadRunner::spawn_flag_gated(runner, flags.clone(), flag_for);
+        let sender = thread.sender();
+        assert_eq!(sender.send(1, Checkout::Express), disabled());
+        flags.set("express-checkout", true);
+        assert_eq!(
+            thread.send(1, Checkout::Express),
+            Ok(Some(CheckedOut(true)))
+        );
+        thread.shutdown();
+        assert_eq!(rejections.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_async_flag_gated() {
+        let flags = MemFlags::new();
+        let handle =
+            AsyncRunner::spawn_flag_gated(basket(), ThreadExecutor, flags.clone(), flag_for);
+        block_on(async {
+            assert_eq!(handle.send(1, Checkout::Express).await, disabled());
+            assert_eq!(
+                handle.send(1, Checkout::Standard).await,
+                Ok(Some(CheckedOut(false)))
+            );
+        });
+    }
+
+    #[test]
+    fn test_effect_flag_gated() {
+        let flags = MemFlags::new();
+        let handle =
+            AsyncEffectRunner::spawn_flag_gated(basket(), ThreadExecutor, flags.clone(), flag_for);
+        block_on(async {
+            assert_eq!(handle.send(1, Checkout::Express).await, disabled());
+            flags.set("express-checkout", true);
+            assert_eq!(
+                handle.send(1, Checkout::Express).await,
+                Ok(Some(CheckedOut(true)))
+            );
+        });
+    }
+}
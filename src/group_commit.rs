@@ -0,0 +1,208 @@
+//! Group commit of journal appends. A `GroupCommit` owns a journal on a
+//! dedicated thread, and appends sent to it from any thread are gathered
+//! over a short window and appended together with `Journal::append_group`,
+//! so that the cost of making them durable is shared. Each sender blocks
+//! until the group holding its event is durable, so an event is never
+//! acknowledged before it is.
+//!
+//! A `GroupAppender` is also an `EventListener`, so runners on several
+//! threads, e.g. one per shard, persist their events through one group
+//! commit, and each command is answered only once its event is durable.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::journal::{Journal, JournalError, Position};
+use crate::runner::EventListener;
+
+type Reply = mpsc::Sender<Result<Position, JournalError>>;
+
+enum Message<K, E> {
+    Append(K, E, Reply),
+    Stop,
+}
+
+/// Appends events of type E for entities with keys K through a group commit.
+pub struct GroupAppender<K, E> {
+    mailbox: mpsc::Sender<Message<K, E>>,
+}
+
+impl<K, E> Clone for GroupAppender<K, E> {
+    fn clone(&self) -> Self {
+        Self {
+            mailbox: self.mailbox.clone(),
+        }
+    }
+}
+
+impl<K, E> GroupAppender<K, E> {
+    /// Append an event for an entity, blocking until it is durable.
+    pub fn append(&self, key: K, event: E) -> Result<Position, JournalError> {
+        let stopped = || JournalError::Failed("the group commit has stopped".to_string());
+        let (reply, replied) = mpsc::channel();
+        self.mailbox
+            .send(Message::Append(key, event, reply))
+            .map_err(|_| stopped())?;
+        replied.recv().unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+/// Each event is appended, and the step completed, once it is durable.
+impl<K, S, E> EventListener<K, S, E> for GroupAppender<K, E>
+where
+    K: Clone,
+    E: Clone,
+{
+    fn on_event(&mut self, key: &K, _state: &S, event: &E) -> Result<(), String> {
+        self.append(key.clone(), event.clone())
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+/// Owns a journal J on a dedicated thread, committing appends in groups.
+pub struct GroupCommit<K, E, J> {
+    appender: GroupAppender<K, E>,
+    groups: Arc<AtomicU64>,
+    thread: Option<JoinHandle<J>>,
+}
+
+impl<K, E, J> GroupCommit<K, E, J>
+where
+    K: Send + 'static,
+    E: Send + 'static,
+    J: Journal<K, E> + Send + 'static,
+{
+    /// Start a thread owning the journal. A group is committed once
+    /// `window` has passed since its first append, or once it holds
+    /// `max_group` appends.
+    pub fn spawn(journal: J, window: Duration, max_group: usize) -> Self {
+        let (mailbox, received) = mpsc::channel();
+        let groups = Arc::new(AtomicU64::new(0));
+        let committed = groups.clone();
+        let max_group = max_group.max(1);
+        let thread = thread::spawn(move || {
+            let mut journal = journal;
+            let mut stopping = false;
+            while !stopping {
+                let Ok(Message::Append(key, event, reply)) = received.recv() else {
+                    break;
+                };
+                let mut group = vec![(key, event)];
+                let mut replies = vec![reply];
+                let deadline = Instant::now() + window;
+                while group.len() < max_group {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    match received.recv_timeout(wait) {
+                        Ok(Message::Append(key, event, reply)) => {
+                            group.push((key, event));
+                            replies.push(reply);
+                        }
+                        Ok(Message::Stop) => {
+                            stopping = true;
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                let results = journal.append_group(group);
+                committed.fetch_add(1, Ordering::Relaxed);
+                for (reply, result) in replies.into_iter().zip(results) {
+                    let _ = reply.send(result);
+                }
+            }
+            journal
+        });
+        Self {
+            appender: GroupAppender { mailbox },
+            groups,
+            thread: Some(thread),
+        }
+    }
+
+    /// An appender for use from other threads.
+    pub fn appender(&self) -> GroupAppender<K, E> {
+        self.appender.clone()
+    }
+
+    /// The number of groups committed so far.
+    pub fn groups(&self) -> u64 {
+        self.groups.load(Ordering::Relaxed)
+    }
+
+    /// Commit the appends already sent, then stop the thread and return
+    /// the journal. If the thread panicked, the panic is resumed here.
+    pub fn shutdown(mut self) -> J {
+        let thread = self.thread.take().expect("the thread is only taken here");
+        let _ = self.appender.mailbox.send(Message::Stop);
+        match thread.join() {
+            Ok(journal) => journal,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl<K, E, J> Drop for GroupCommit<K, E, J> {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.appender.mailbox.send(Message::Stop);
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Counter, Increment, Incremented};
+    use crate::journal::MemJournal;
+    use crate::runner::{RunError, Runner};
+    use std::sync::Barrier;
+
+    #[test]
+    fn test_group_commit() {
+        // Counters on several threads, each persisting its events through
+        // one group commit
+
+        let commit = GroupCommit::spawn(MemJournal::new(), Duration::from_secs(10), 8);
+        let barrier = Arc::new(Barrier::new(8));
+        let workers: Vec<_> = (0..8u32)
+            .map(|key| {
+                let mut appender = commit.appender();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let mut runner = Runner::<u32, u32, (), Counter>::new(());
+                    runner.insert(key, 0);
+                    barrier.wait();
+                    runner
+                        .send_with(&key, &Increment {}, &mut appender)
+                        .unwrap();
+                    runner.state(&key).copied()
+                })
+            })
+            .collect();
+        for w in workers {
+            assert_eq!(w.join().unwrap(), Some(1));
+        }
+        // All eight arrived within the window and filled one group
+        assert_eq!(commit.groups(), 1);
+
+        let appender = commit.appender();
+        let mut journal = commit.shutdown();
+        assert_eq!(journal.len(), 8);
+        assert_eq!(journal.last_seq(&3), Ok(1));
+        assert!(appender.append(3, Incremented {}).is_err());
+
+        // A failed group aborts the steps, which leave the states unchanged
+        journal.fail_appends(true);
+        let commit = GroupCommit::spawn(journal, Duration::ZERO, 8);
+        let mut runner = Runner::<u32, u32, (), Counter>::new(());
+        runner.insert(0, 0);
+        let result = runner.send_with(&0, &Increment {}, &mut commit.appender());
+        assert!(matches!(result, Err(RunError::Aborted(_))));
+        assert_eq!(runner.state(&0), Some(&0));
+    }
+}
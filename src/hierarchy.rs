@@ -0,0 +1,160 @@
+//! Hierarchical machines, where a child region is a view of the parent
+//! state reached through a `Lens`. Events are applied to the child region
+//! in the usual way. When the region arrives at a final state a synthetic
+//! `Completed` event is fired at the parent, in the manner of statecharts,
+//! so the parent can transition on completion without polling the child.
+//!
+//! The completion event is derived from the child event and so need not be
+//! logged. Replaying the child events reproduces it.
+
+use std::marker::PhantomData;
+
+use crate::command_and_event_traits::{Event, Fsm, Lens};
+
+/// A state of a child region that may be final.
+pub trait Final {
+    fn is_final(&self) -> bool;
+}
+
+/// The synthetic event fired at the parent when the child region
+/// with state T reaches its final state.
+#[derive(Debug, PartialEq)]
+pub struct Completed<T>(PhantomData<T>);
+
+impl<T> Completed<T> {
+    pub fn new() -> Self {
+        Completed(PhantomData)
+    }
+}
+
+impl<T> Default for Completed<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How a child region responded to an event.
+#[derive(Debug, PartialEq)]
+pub enum ChildTransition {
+    /// The region stayed in its existing state
+    Same,
+    /// The region transitioned to a new, non-final state
+    Next,
+    /// The region transitioned to a final state and the parent was notified
+    Completed,
+}
+
+/// Extends an FSM with child regions. This is implemented for every `Fsm`.
+pub trait Hierarchy<S, H>: Fsm<S, H> {
+    /// Apply an event to the child region T of a state, in place. If the
+    /// region arrives at a final state then `Completed<T>` is applied to
+    /// the whole state.
+    fn for_child_event<E, T>(state: &mut S, event: &E) -> ChildTransition
+    where
+        E: Event<T>,
+        S: Lens<T>,
+        T: Final,
+        Completed<T>: Event<S>,
+    {
+        if !Self::for_event_mut(state, event) {
+            ChildTransition::Same
+        } else if <S as Lens<T>>::extract(state).is_final() {
+            Self::for_event_mut::<_, S>(state, &Completed::<T>::new());
+            ChildTransition::Completed
+        } else {
+            ChildTransition::Next
+        }
+    }
+}
+
+impl<F, S, H> Hierarchy<S, H> for F where F: Fsm<S, H> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::Transition;
+
+    #[test]
+    fn test_for_child_event() {
+        // A job with a child region that works through two phases
+
+        #[derive(Debug, PartialEq)]
+        enum Phase {
+            Preparing,
+            Running,
+            Finished,
+        }
+
+        impl Final for Phase {
+            fn is_final(&self) -> bool {
+                *self == Phase::Finished
+            }
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Job {
+            phase: Phase,
+            done: bool,
+        }
+
+        impl Lens<Phase> for Job {
+            fn extract(&self) -> &Phase {
+                &self.phase
+            }
+
+            fn inject_mut(&mut self, view: Phase) {
+                self.phase = view;
+            }
+        }
+
+        struct Advanced {}
+
+        impl Event<Phase> for Advanced {
+            fn fire(&self, s: &Phase) -> Transition<Phase> {
+                match s {
+                    Phase::Preparing => Transition::Next(Phase::Running),
+                    Phase::Running => Transition::Next(Phase::Finished),
+                    Phase::Finished => Transition::Same,
+                }
+            }
+        }
+
+        // The parent transitions when its child completes
+
+        impl Event<Job> for Completed<Phase> {
+            fn fire(&self, s: &Job) -> Transition<Job> {
+                Transition::Next(Job {
+                    phase: Phase::Finished,
+                    done: !s.done,
+                })
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<Job, ()> for MyFsm {}
+
+        let mut job = Job {
+            phase: Phase::Preparing,
+            done: false,
+        };
+
+        assert_eq!(
+            MyFsm::for_child_event(&mut job, &Advanced {}),
+            ChildTransition::Next
+        );
+        assert!(!job.done);
+
+        assert_eq!(
+            MyFsm::for_child_event(&mut job, &Advanced {}),
+            ChildTransition::Completed
+        );
+        assert!(job.done);
+
+        assert_eq!(
+            MyFsm::for_child_event(&mut job, &Advanced {}),
+            ChildTransition::Same
+        );
+        assert!(job.done);
+    }
+}
@@ -0,0 +1,174 @@
+//! Effects performed at most once, even when commands are redelivered
+//! after a crash. An `IdempotentHandler` wraps an effect handler, and a
+//! command performs each side effect, such as sending an email, through
+//! `once`, naming it by entity, sequence number and an effect id. The
+//! effect is recorded in an `EffectLog` before it is performed, and an
+//! effect already recorded is skipped.
+//!
+//! The sequence number is whatever identifies the step being retried,
+//! typically the sequence number the command's event will be journalled at.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+
+/// Identifies an effect of a step of an entity.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EffectId<K> {
+    pub entity: K,
+    pub seq: u64,
+    pub effect: String,
+}
+
+/// A durable record of the effects that have been performed.
+pub trait EffectLog<K> {
+    fn contains(&self, id: &EffectId<K>) -> bool;
+
+    fn record(&mut self, id: EffectId<K>) -> Result<(), String>;
+}
+
+/// An effect log held in memory.
+impl<K> EffectLog<K> for HashSet<EffectId<K>>
+where
+    K: Eq + Hash,
+{
+    fn contains(&self, id: &EffectId<K>) -> bool {
+        HashSet::contains(self, id)
+    }
+
+    fn record(&mut self, id: EffectId<K>) -> Result<(), String> {
+        self.insert(id);
+        Ok(())
+    }
+}
+
+/// An effect handler H whose effects are recorded in a log L.
+pub struct IdempotentHandler<H, L> {
+    inner: H,
+    log: L,
+}
+
+/// What became of an effect.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Once<R> {
+    Performed(R),
+    /// The effect had already been recorded
+    Skipped,
+    /// The effect could not be recorded, so it was not performed
+    Failed(String),
+}
+
+impl<R> Once<R> {
+    pub fn performed(self) -> Option<R> {
+        match self {
+            Once::Performed(r) => Some(r),
+            _ => None,
+        }
+    }
+}
+
+impl<H, L> IdempotentHandler<H, L> {
+    pub fn new(inner: H, log: L) -> Self {
+        Self { inner, log }
+    }
+
+    pub fn inner(&self) -> &H {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut H {
+        &mut self.inner
+    }
+
+    pub fn log(&self) -> &L {
+        &self.log
+    }
+
+    pub fn into_inner(self) -> (H, L) {
+        (self.inner, self.log)
+    }
+
+    /// Perform an effect with the inner handler, unless it was recorded before.
+    pub fn once<K, R>(
+        &mut self,
+        entity: K,
+        seq: u64,
+        effect: &str,
+        perform: impl FnOnce(&mut H) -> R,
+    ) -> Once<R>
+    where
+        L: EffectLog<K>,
+    {
+        let id = EffectId {
+            entity,
+            seq,
+            effect: effect.to_string(),
+        };
+        if self.log.contains(&id) {
+            return Once::Skipped;
+        }
+        match self.log.record(id) {
+            Ok(()) => Once::Performed(perform(&mut self.inner)),
+            Err(reason) => Once::Failed(reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+
+    #[test]
+    fn test_idempotent_handler() {
+        // Orders which email the customer when placed
+
+        type Mailer = IdempotentHandler<Vec<String>, HashSet<EffectId<&'static str>>>;
+
+        struct Place {
+            order: &'static str,
+            seq: u64,
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Placed {}
+
+        impl Command<bool, Mailer> for Place {
+            type Output = Placed;
+            fn execute(&self, _s: &bool, h: &mut Mailer) -> Option<Placed> {
+                h.once(self.order, self.seq, "email", |sent| {
+                    sent.push(format!("order {} placed", self.order))
+                });
+                Some(Placed {})
+            }
+        }
+
+        impl Validate<bool> for Place {}
+
+        impl Event<bool> for Placed {
+            fn fire(&self, _s: &bool) -> Transition<bool> {
+                Transition::Next(true)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<bool, Mailer> for MyFsm {}
+
+        let mut handler = IdempotentHandler::new(Vec::new(), HashSet::new());
+        let place = Place {
+            order: "o1",
+            seq: 1,
+        };
+
+        // The command is redelivered after a crash, but the email is sent once
+        let (e, _) = MyFsm::step(&false, &place, &mut handler);
+        assert_eq!(e, Some(Placed {}));
+        let (e, _) = MyFsm::step(&false, &place, &mut handler);
+        assert_eq!(e, Some(Placed {}));
+        assert_eq!(handler.inner(), &vec!["order o1 placed".to_string()]);
+
+        assert_eq!(handler.once("o1", 1, "email", |_| ()), Once::Skipped);
+        assert_eq!(handler.once("o1", 2, "email", |_| 7).performed(), Some(7));
+        assert_eq!(handler.log().len(), 2);
+    }
+}
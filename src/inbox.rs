@@ -0,0 +1,265 @@
+//! The inbox pattern, mirroring the outbox: incoming command envelopes
+//! are stored before they are executed, with duplicates detected by
+//! message id. A broker that redelivers a message, because its
+//! acknowledgement was lost, has the duplicate absorbed here rather than
+//! relying on every command being idempotent.
+//!
+//! An envelope stays pending until its command has been stepped, or
+//! rejected, since a rejected command would be rejected again. A command
+//! that failed for another reason, such as an entity not yet known or a
+//! journal that could not be written, stays pending to be retried. After
+//! a crash, `process_pending` steps the envelopes that were stored but not
+//! processed.
+//!
+//! Stepping a command and marking its envelope processed are separate
+//! writes. A crash between the two leaves an envelope pending whose
+//! command was stepped, and it is stepped again on recovery, so at that
+//! point delivery is at least once.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::command_and_event_traits::{Command, Fsm, Validate};
+use crate::runner::{RunError, Runner};
+
+/// A command for an entity, as received with a message id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope<K, C> {
+    pub message_id: String,
+    pub key: K,
+    pub command: C,
+}
+
+/// Stored envelopes of commands of type C for entities with keys K.
+pub trait Inbox<K, C> {
+    /// Store an envelope, answering false if its message id was seen before.
+    fn store(&mut self, envelope: &Envelope<K, C>) -> Result<bool, String>;
+
+    /// The envelopes stored but not yet processed, in the order received.
+    fn pending(&self) -> Result<Vec<Envelope<K, C>>, String>;
+
+    fn mark_processed(&mut self, message_id: &str) -> Result<(), String>;
+}
+
+/// An inbox held in memory.
+#[derive(Debug)]
+pub struct MemInbox<K, C> {
+    seen: HashMap<String, bool>,
+    pending: Vec<Envelope<K, C>>,
+}
+
+impl<K, C> Default for MemInbox<K, C> {
+    fn default() -> Self {
+        Self {
+            seen: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl<K, C> MemInbox<K, C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a message has been processed.
+    pub fn is_processed(&self, message_id: &str) -> bool {
+        self.seen.get(message_id).copied().unwrap_or(false)
+    }
+}
+
+impl<K, C> Inbox<K, C> for MemInbox<K, C>
+where
+    K: Clone,
+    C: Clone,
+{
+    fn store(&mut self, envelope: &Envelope<K, C>) -> Result<bool, String> {
+        if self.seen.contains_key(&envelope.message_id) {
+            return Ok(false);
+        }
+        self.seen.insert(envelope.message_id.clone(), false);
+        self.pending.push(envelope.clone());
+        Ok(true)
+    }
+
+    fn pending(&self) -> Result<Vec<Envelope<K, C>>, String> {
+        Ok(self.pending.clone())
+    }
+
+    fn mark_processed(&mut self, message_id: &str) -> Result<(), String> {
+        self.seen.insert(message_id.to_string(), true);
+        self.pending.retain(|e| e.message_id != message_id);
+        Ok(())
+    }
+}
+
+/// What became of a delivered envelope.
+#[derive(Debug, PartialEq)]
+pub enum Delivery<E> {
+    /// The command was stepped, emitting an event or not
+    Stepped(Option<E>),
+    /// The message id had been seen before, so the command was not stepped
+    Duplicate,
+}
+
+/// Store an envelope in the inbox and, unless it is a duplicate, step its
+/// command. A rejected command is still marked processed, as redelivering
+/// it would be rejected again.
+pub fn deliver<K, S, H, F, C, I>(
+    inbox: &mut I,
+    runner: &mut Runner<K, S, H, F>,
+    envelope: &Envelope<K, C>,
+) -> Result<Delivery<C::Output>, RunError>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    I: Inbox<K, C>,
+{
+    if !inbox.store(envelope).map_err(RunError::Failed)? {
+        return Ok(Delivery::Duplicate);
+    }
+    process(inbox, runner, envelope).map(Delivery::Stepped)
+}
+
+/// The result of sending a command.
+pub type Sent<E> = Result<Option<E>, RunError>;
+
+/// Step the commands of envelopes stored but not processed, for example
+/// after a crash, returning the results in order.
+pub fn process_pending<K, S, H, F, C, I>(
+    inbox: &mut I,
+    runner: &mut Runner<K, S, H, F>,
+) -> Result<Vec<Sent<C::Output>>, RunError>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    I: Inbox<K, C>,
+{
+    let pending = inbox.pending().map_err(RunError::Failed)?;
+    Ok(pending
+        .iter()
+        .map(|envelope| process(inbox, runner, envelope))
+        .collect())
+}
+
+fn process<K, S, H, F, C, I>(
+    inbox: &mut I,
+    runner: &mut Runner<K, S, H, F>,
+    envelope: &Envelope<K, C>,
+) -> Sent<C::Output>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    I: Inbox<K, C>,
+{
+    let result = runner.send(&envelope.key, &envelope.command);
+    let settled = match &result {
+        Ok(_) => true,
+        Err(e) => e.rejection().is_some(),
+    };
+    if settled {
+        inbox
+            .mark_processed(&envelope.message_id)
+            .map_err(RunError::Failed)?;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Transition};
+    use crate::fixtures::{Counter, Increment, Incremented};
+
+    #[test]
+    fn test_inbox() {
+        // Deposits to an account, which must not be applied twice
+
+        #[derive(Debug, Clone)]
+        struct Deposit(u32);
+
+        #[derive(Debug, PartialEq)]
+        struct Deposited(u32);
+
+        impl Command<u32, ()> for Deposit {
+            type Output = Deposited;
+            fn execute(&self, _s: &u32, _h: &mut ()) -> Option<Deposited> {
+                Some(Deposited(self.0))
+            }
+        }
+
+        impl Validate<u32> for Deposit {}
+
+        impl Event<u32> for Deposited {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s + self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, ()> for MyFsm {}
+
+        let mut runner = Runner::<&str, u32, (), MyFsm>::new(());
+        runner.insert("a", 0);
+        let mut inbox = MemInbox::new();
+        let envelope = |id: &str, amount| Envelope {
+            message_id: id.to_string(),
+            key: "a",
+            command: Deposit(amount),
+        };
+
+        assert_eq!(
+            deliver(&mut inbox, &mut runner, &envelope("m1", 5)),
+            Ok(Delivery::Stepped(Some(Deposited(5))))
+        );
+        assert_eq!(
+            deliver(&mut inbox, &mut runner, &envelope("m1", 5)),
+            Ok(Delivery::Duplicate)
+        );
+        assert_eq!(runner.state(&"a"), Some(&5));
+        assert!(inbox.is_processed("m1"));
+
+        // An envelope stored before a crash is processed on recovery, once
+        assert_eq!(inbox.store(&envelope("m2", 3)), Ok(true));
+        assert_eq!(
+            process_pending(&mut inbox, &mut runner),
+            Ok(vec![Ok(Some(Deposited(3)))])
+        );
+        assert_eq!(process_pending(&mut inbox, &mut runner), Ok(vec![]));
+        assert_eq!(
+            deliver(&mut inbox, &mut runner, &envelope("m2", 3)),
+            Ok(Delivery::Duplicate)
+        );
+        assert_eq!(runner.state(&"a"), Some(&8));
+    }
+
+    #[test]
+    fn test_unknown_entity_retried() {
+        // A command for an entity not yet created is not lost
+        let mut runner = Runner::<&str, u32, (), Counter>::new(());
+        let mut inbox = MemInbox::new();
+        let envelope = Envelope {
+            message_id: "m1".to_string(),
+            key: "a",
+            command: Increment {},
+        };
+
+        assert_eq!(
+            deliver(&mut inbox, &mut runner, &envelope),
+            Err(RunError::Unknown)
+        );
+        assert!(!inbox.is_processed("m1"));
+
+        runner.insert("a", 0);
+        assert_eq!(
+            process_pending(&mut inbox, &mut runner),
+            Ok(vec![Ok(Some(Incremented {}))])
+        );
+        assert!(inbox.is_processed("m1"));
+        assert_eq!(runner.state(&"a"), Some(&1));
+    }
+}
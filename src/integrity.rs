@@ -0,0 +1,420 @@
+//! State hashes recorded in the journal, to check the integrity of replay.
+//! Every so often, `HashRecorder` journals an event together with the
+//! hash of the state it produced. `replay_verified` replays an entity's
+//! events and compares the state it computes with each recorded hash. A
+//! divergence points to a `fire` that is not deterministic, or to
+//! corrupted data, and is reported with the sequence number where it
+//! was first detected.
+//!
+//! Replicas of the same entities can be compared in the same way. Each
+//! takes the `fingerprints` of its entities, their sequence numbers and
+//! state hashes, which are exchanged and checked with `compare`. Entities
+//! at the same sequence number with different hashes have diverged.
+//!
+//! Hashes must be the same between runs, so `stable_hash` uses FNV-1a
+//! rather than the standard library's randomly keyed hasher, and writes
+//! integers little endian at a fixed width, so that they are the same on
+//! every platform. The bytes hashed are those written by the values' `Hash`
+//! impls, which the standard library does not promise to keep between Rust
+//! versions, so recorded hashes and replicas should be compared only across
+//! builds with the same toolchain.
+
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+
+use crate::command_and_event_traits::Fsm;
+use crate::command_and_event_traits::{Event, Transition};
+use crate::journal::{paged, Journal, JournalError};
+use crate::runner::{EventListener, Runner};
+
+/// States with a hash that is the same between runs and platforms.
+pub trait StateHash {
+    fn state_hash(&self) -> u64;
+}
+
+/// A 64 bit FNV-1a hasher, which is not keyed, writing integers as little
+/// endian and `usize` and `isize` as 64 bits.
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes())
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64)
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as i64 as u64)
+    }
+}
+
+/// The FNV-1a hash of a value, for implementing `StateHash`.
+pub fn stable_hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = StableHasher::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// A journalled event, with the hash of the state it produced if one was recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hashed<E> {
+    pub event: E,
+    pub hash: Option<u64>,
+}
+
+impl<S, E> Event<S> for Hashed<E>
+where
+    E: Event<S>,
+{
+    fn fire(&self, state: &S) -> Transition<S> {
+        self.event.fire(state)
+    }
+}
+
+/// A listener journalling events, with a state hash every `every` events of an entity.
+pub struct HashRecorder<J> {
+    journal: J,
+    every: u64,
+}
+
+impl<J> HashRecorder<J> {
+    pub fn new(journal: J, every: u64) -> Self {
+        Self {
+            journal,
+            every: every.max(1),
+        }
+    }
+
+    pub fn journal(&self) -> &J {
+        &self.journal
+    }
+}
+
+impl<K, S, E, J> EventListener<K, S, E> for HashRecorder<J>
+where
+    J: Journal<K, Hashed<E>>,
+    S: StateHash,
+    E: Event<S> + Clone,
+{
+    fn on_event(&mut self, key: &K, state: &S, event: &E) -> Result<(), String> {
+        let seq = self.journal.last_seq(key).map_err(|e| format!("{e:?}"))? + 1;
+        let hash = (seq % self.every == 0).then(|| match event.fire(state) {
+            Transition::Next(s) => s.state_hash(),
+            Transition::Same => state.state_hash(),
+        });
+        let hashed = Hashed {
+            event: event.clone(),
+            hash,
+        };
+        self.journal
+            .append(key, hashed)
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+/// A replayed state whose hash differs from the one recorded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub seq: u64,
+    pub recorded: u64,
+    pub computed: u64,
+}
+
+/// Why a verified replay failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplayError {
+    Journal(JournalError),
+    Diverged(Divergence),
+}
+
+/// The number of records read at a time during a verified replay.
+const REPLAY_BATCH: usize = 1000;
+
+/// Replay an entity's journalled events from an initial state, checking
+/// each recorded hash, and return the state with the last sequence number.
+pub fn replay_verified<K, S, E, J>(
+    journal: &J,
+    key: &K,
+    initial: S,
+) -> Result<(S, u64), ReplayError>
+where
+    J: Journal<K, Hashed<E>>,
+    S: StateHash,
+    E: Event<S>,
+{
+    let mut state = initial;
+    let mut seq = 0;
+    for record in paged(journal, key, 0, REPLAY_BATCH) {
+        let record = record.map_err(ReplayError::Journal)?;
+        if let Transition::Next(s) = record.event.fire(&state) {
+            state = s;
+        }
+        seq = record.seq;
+        if let Some(recorded) = record.event.hash {
+            let computed = state.state_hash();
+            if computed != recorded {
+                return Err(ReplayError::Diverged(Divergence {
+                    seq,
+                    recorded,
+                    computed,
+                }));
+            }
+        }
+    }
+    Ok((state, seq))
+}
+
+/// The sequence number and state hash of an entity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub seq: u64,
+    pub hash: u64,
+}
+
+/// The fingerprints of the live entities of a runner, with their
+/// sequence numbers from its journal.
+pub fn fingerprints<K, S, H, F, J, E>(
+    runner: &Runner<K, S, H, F>,
+    journal: &J,
+) -> Result<BTreeMap<K, Fingerprint>, JournalError>
+where
+    K: Eq + Hash + Ord + Clone,
+    S: StateHash,
+    F: Fsm<S, H>,
+    J: Journal<K, E>,
+{
+    runner
+        .live_states()
+        .map(|(key, state)| {
+            let seq = journal.last_seq(key)?;
+            let hash = state.state_hash();
+            Ok((key.clone(), Fingerprint { seq, hash }))
+        })
+        .collect()
+}
+
+/// How an entity differs between our replica and theirs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mismatch {
+    /// At the same sequence number, the states differ
+    Diverged {
+        seq: u64,
+        ours: u64,
+        theirs: u64,
+    },
+    /// One replica is behind the other, so they cannot yet be compared
+    Lagging {
+        ours: u64,
+        theirs: u64,
+    },
+    OnlyOurs,
+    OnlyTheirs,
+}
+
+/// The entities which differ between two sets of fingerprints, in key order.
+pub fn compare<K>(
+    ours: &BTreeMap<K, Fingerprint>,
+    theirs: &BTreeMap<K, Fingerprint>,
+) -> Vec<(K, Mismatch)>
+where
+    K: Ord + Clone,
+{
+    let mut mismatches: Vec<_> = ours
+        .iter()
+        .filter_map(|(key, a)| {
+            let mismatch = match theirs.get(key) {
+                None => Mismatch::OnlyOurs,
+                Some(b) if a.seq != b.seq => Mismatch::Lagging {
+                    ours: a.seq,
+                    theirs: b.seq,
+                },
+                Some(b) if a.hash != b.hash => Mismatch::Diverged {
+                    seq: a.seq,
+                    ours: a.hash,
+                    theirs: b.hash,
+                },
+                Some(_) => return None,
+            };
+            Some((key.clone(), mismatch))
+        })
+        .collect();
+    mismatches.extend(
+        theirs
+            .keys()
+            .filter(|k| !ours.contains_key(k))
+            .map(|k| (k.clone(), Mismatch::OnlyTheirs)),
+    );
+    mismatches.sort_by(|a, b| a.0.cmp(&b.0));
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Command, Fsm, Validate, Veto};
+    use crate::journal::MemJournal;
+    use crate::runner::Runner;
+
+    #[test]
+    fn test_replay_verified() {
+        // A running total, hashed every second event
+
+        struct Add(u32);
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct Added(u32);
+
+        impl Command<Total, ()> for Add {
+            type Output = Added;
+            fn execute(&self, _s: &Total, _h: &mut ()) -> Option<Added> {
+                Some(Added(self.0))
+            }
+        }
+
+        impl Validate<Total> for Add {}
+
+        impl Event<Total> for Added {
+            fn fire(&self, s: &Total) -> Transition<Total> {
+                Transition::Next(Total(s.0 + self.0))
+            }
+        }
+
+        #[derive(Debug, Clone, PartialEq, Hash)]
+        struct Total(u32);
+
+        impl StateHash for Total {
+            fn state_hash(&self) -> u64 {
+                stable_hash(self)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<Total, ()> for MyFsm {}
+
+        let mut runner = Runner::<&str, Total, (), MyFsm>::new(());
+        runner.insert("a", Total(0));
+        let mut recorder = HashRecorder::new(MemJournal::new(), 2);
+        for n in [1, 2, 3, 4, 5] {
+            runner.send_with(&"a", &Add(n), &mut recorder).unwrap();
+        }
+        let journal = recorder.journal();
+        let hashes: Vec<_> = journal
+            .read(&"a", 0)
+            .unwrap()
+            .iter()
+            .map(|r| r.event.hash.is_some())
+            .collect();
+        assert_eq!(hashes, vec![false, true, false, true, false]);
+        assert_eq!(replay_verified(journal, &"a", Total(0)), Ok((Total(15), 5)));
+
+        // Replaying from the wrong initial state diverges at the first hash
+        assert_eq!(
+            replay_verified(journal, &"a", Total(1)),
+            Err(ReplayError::Diverged(Divergence {
+                seq: 2,
+                recorded: stable_hash(&Total(3)),
+                computed: stable_hash(&Total(4)),
+            }))
+        );
+
+        // An event vetoed by the entry hook is not recorded, so replay
+        // still agrees with the recorded hashes
+
+        struct Capped {}
+
+        impl Fsm<Total, ()> for Capped {
+            fn on_entry(_old: &Total, new: &Total, _h: &mut ()) -> Result<(), Veto> {
+                match new.0 {
+                    0..=10 => Ok(()),
+                    _ => Err(Veto("over the cap".to_string())),
+                }
+            }
+        }
+
+        let mut capped = Runner::<&str, Total, (), Capped>::new(());
+        capped.insert("a", Total(0));
+        let mut capped_recorder = HashRecorder::new(MemJournal::new(), 2);
+        for n in [4, 5, 6, 1] {
+            let _ = capped.send_with(&"a", &Add(n), &mut capped_recorder);
+        }
+        assert_eq!(capped.state(&"a"), Some(&Total(10)));
+        assert_eq!(
+            replay_verified(capped_recorder.journal(), &"a", Total(0)),
+            Ok((Total(10), 3))
+        );
+
+        // A replica whose entity took a different path diverges
+        let mut replica = Runner::<&str, Total, (), MyFsm>::new(());
+        replica.insert("a", Total(0));
+        replica.insert("b", Total(0));
+        let mut replica_recorder = HashRecorder::new(MemJournal::new(), 2);
+        for n in [1, 2, 3, 4, 6] {
+            replica
+                .send_with(&"a", &Add(n), &mut replica_recorder)
+                .unwrap();
+        }
+        let ours = fingerprints(&runner, recorder.journal()).unwrap();
+        let theirs = fingerprints(&replica, replica_recorder.journal()).unwrap();
+        assert_eq!(
+            compare(&ours, &theirs),
+            vec![
+                (
+                    "a",
+                    Mismatch::Diverged {
+                        seq: 5,
+                        ours: stable_hash(&Total(15)),
+                        theirs: stable_hash(&Total(16)),
+                    }
+                ),
+                ("b", Mismatch::OnlyTheirs),
+            ]
+        );
+        assert_eq!(compare(&ours, &ours), vec![]);
+    }
+
+    #[test]
+    fn test_stable_hasher() {
+        // Integers hash the same at any width of usize and endianness, as
+        // the bytes FNV-1a sees are fixed
+        let mut hasher = StableHasher::default();
+        hasher.write(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(stable_hash(&1usize), hasher.finish());
+        assert_eq!(stable_hash(&1u64), hasher.finish());
+        assert_eq!(stable_hash(&-1isize), stable_hash(&u64::MAX));
+        let mut hasher = StableHasher::default();
+        hasher.write(&[7, 0, 0, 0]);
+        assert_eq!(stable_hash(&7u32), hasher.finish());
+    }
+}
@@ -0,0 +1,173 @@
+//! Adapters for machines defined in the style of other FSM libraries,
+//! so they can be driven through this crate's `Fsm`, `Command` and `Event`
+//! traits, for example behind one runner while migrating.
+//!
+//! `MealyMachine` has the shape of `rust-fsm`'s `StateMachineImpl`: a
+//! transition function and an output function over a state and an input.
+//! Implementing it for a `rust-fsm` machine is a matter of delegating to
+//! that machine's functions. Each input becomes a command, and an input
+//! the machine accepts becomes an event carrying the input and any output,
+//! so that it can be replayed.
+//!
+//! Hierarchical `statig` machines hold their state themselves and handle
+//! events by mutation. They fit as an effect handler rather than as a
+//! state, with inputs passed to it from commands.
+
+use std::fmt::{self, Debug};
+
+use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+
+/// A machine defined by transition and output functions.
+pub trait MealyMachine {
+    type Input: Clone;
+    type State;
+    type Output;
+
+    /// The next state for an input, if the input is accepted.
+    fn transition(state: &Self::State, input: &Self::Input) -> Option<Self::State>;
+
+    /// The output for an input, if any.
+    fn output(state: &Self::State, input: &Self::Input) -> Option<Self::Output>;
+}
+
+/// A command submitting an input to a machine, M.
+pub struct Input<M: MealyMachine>(pub M::Input);
+
+/// The event of an input accepted by a machine, M.
+pub struct Accepted<M: MealyMachine> {
+    pub input: M::Input,
+    pub output: Option<M::Output>,
+}
+
+impl<M> Debug for Accepted<M>
+where
+    M: MealyMachine,
+    M::Input: Debug,
+    M::Output: Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Accepted")
+            .field("input", &self.input)
+            .field("output", &self.output)
+            .finish()
+    }
+}
+
+impl<M> PartialEq for Accepted<M>
+where
+    M: MealyMachine,
+    M::Input: PartialEq,
+    M::Output: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.input == other.input && self.output == other.output
+    }
+}
+
+impl<M, H> Command<M::State, H> for Input<M>
+where
+    M: MealyMachine,
+{
+    type Output = Accepted<M>;
+    fn execute(&self, state: &M::State, _handler: &mut H) -> Option<Accepted<M>> {
+        M::transition(state, &self.0).map(|_| Accepted {
+            input: self.0.clone(),
+            output: M::output(state, &self.0),
+        })
+    }
+}
+
+impl<M> Validate<M::State> for Input<M> where M: MealyMachine {}
+
+impl<M> Event<M::State> for Accepted<M>
+where
+    M: MealyMachine,
+{
+    fn fire(&self, state: &M::State) -> Transition<M::State> {
+        match M::transition(state, &self.input) {
+            Some(s) => Transition::Next(s),
+            None => Transition::Same,
+        }
+    }
+}
+
+/// The FSM of a machine, M.
+pub struct Mealy<M>(std::marker::PhantomData<M>);
+
+impl<M, H> Fsm<M::State, H> for Mealy<M> where M: MealyMachine {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mealy() {
+        // A circuit breaker in the style of rust-fsm
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Breaker {
+            Closed,
+            Open,
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Signal {
+            Failure,
+            Reset,
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Alarm;
+
+        struct CircuitBreaker;
+
+        impl MealyMachine for CircuitBreaker {
+            type Input = Signal;
+            type State = Breaker;
+            type Output = Alarm;
+
+            fn transition(state: &Breaker, input: &Signal) -> Option<Breaker> {
+                match (state, input) {
+                    (Breaker::Closed, Signal::Failure) => Some(Breaker::Open),
+                    (Breaker::Open, Signal::Reset) => Some(Breaker::Closed),
+                    _ => None,
+                }
+            }
+
+            fn output(state: &Breaker, input: &Signal) -> Option<Alarm> {
+                match (state, input) {
+                    (Breaker::Closed, Signal::Failure) => Some(Alarm),
+                    _ => None,
+                }
+            }
+        }
+
+        type MyFsm = Mealy<CircuitBreaker>;
+
+        let input = Input::<CircuitBreaker>(Signal::Failure);
+        let (e, t) = MyFsm::step(&Breaker::Closed, &input, &mut ());
+        assert_eq!(
+            e,
+            Some(Accepted {
+                input: Signal::Failure,
+                output: Some(Alarm)
+            })
+        );
+        assert_eq!(t, Ok(Transition::Next(Breaker::Open)));
+
+        let (e, t) = MyFsm::step(&Breaker::Open, &input, &mut ());
+        assert_eq!(e, None);
+        assert_eq!(t, Ok(Transition::Same));
+
+        let input = Input::<CircuitBreaker>(Signal::Reset);
+        let (e, t) = MyFsm::step(&Breaker::Open, &input, &mut ());
+        assert_eq!(
+            e,
+            Some(Accepted {
+                input: Signal::Reset,
+                output: None
+            })
+        );
+        assert_eq!(t, Ok(Transition::Next(Breaker::Closed)));
+    }
+}
@@ -0,0 +1,515 @@
+//! A journal records the events of entities, in order, so that their
+//! states can be reconstituted by replaying them.
+//!
+//! Each record has an offset, its position among all records, and a
+//! sequence number, its position among the records of its entity.
+//! Offsets start at 0 and sequence numbers at 1.
+//!
+//! An entity with a long history can be read a page at a time with
+//! `paged`, so that recovering it never holds more than a page of records.
+//! Pages are read only as the records are consumed, so a slow consumer
+//! holds back the reads.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::command_and_event_traits::Rejection;
+
+/// An event recorded for an entity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record<K, E> {
+    pub offset: u64,
+    pub key: K,
+    pub seq: u64,
+    pub event: E,
+}
+
+/// Where a record was appended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub offset: u64,
+    pub seq: u64,
+}
+
+/// Why a journal operation failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JournalError {
+    /// The journal's storage failed
+    Failed(String),
+    /// The entity's last sequence number was not the one expected
+    Conflict { expected: u64, actual: u64 },
+}
+
+impl From<JournalError> for Rejection {
+    fn from(error: JournalError) -> Self {
+        match error {
+            JournalError::Failed(reason) => Rejection::EffectFailed(reason),
+            JournalError::Conflict { expected, actual } => {
+                Rejection::ConcurrencyConflict { expected, actual }
+            }
+        }
+    }
+}
+
+/// Records events of type E for entities with keys K.
+pub trait Journal<K, E> {
+    /// Append an event for an entity.
+    fn append(&mut self, key: &K, event: E) -> Result<Position, JournalError>;
+
+    /// The records of an entity, from a sequence number.
+    fn read(&self, key: &K, from_seq: u64) -> Result<Vec<Record<K, E>>, JournalError>;
+
+    /// All records, from an offset.
+    fn read_all(&self, from_offset: u64) -> Result<Vec<Record<K, E>>, JournalError>;
+
+    /// At most `limit` records of an entity, from a sequence number. A
+    /// backend should override this to avoid reading the rest.
+    fn read_page(
+        &self,
+        key: &K,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Record<K, E>>, JournalError> {
+        let mut records = self.read(key, from_seq)?;
+        records.truncate(limit);
+        Ok(records)
+    }
+
+    /// The sequence number of an entity's last record, or 0 if none.
+    fn last_seq(&self, key: &K) -> Result<u64, JournalError> {
+        Ok(self.read(key, 0)?.last().map_or(0, |r| r.seq))
+    }
+
+    /// Remove an entity's records up to and including a sequence number,
+    /// once they are covered by a snapshot, where supported. Sequence
+    /// numbers and offsets are unchanged.
+    fn prune(&mut self, _key: &K, _up_to_seq: u64) -> Result<(), JournalError> {
+        Err(JournalError::Failed("pruning is not supported".to_string()))
+    }
+
+    /// Remove the records from an offset onwards, where supported.
+    fn truncate(&mut self, _from_offset: u64) -> Result<(), JournalError> {
+        Err(JournalError::Failed(
+            "truncation is not supported".to_string(),
+        ))
+    }
+
+    /// Append several events for an entity. The default appends them in
+    /// turn and, if an append fails, truncates those already appended, so
+    /// the batch is all or nothing if the journal supports truncation.
+    /// Otherwise they remain. Either way the error is the failed append's.
+    /// A backend that can append a batch at once should override this.
+    fn append_batch(&mut self, key: &K, events: Vec<E>) -> Result<Vec<Position>, JournalError> {
+        let mut positions: Vec<Position> = Vec::with_capacity(events.len());
+        for event in events {
+            match self.append(key, event) {
+                Ok(p) => positions.push(p),
+                Err(e) => {
+                    if let Some(first) = positions.first() {
+                        let _ = self.truncate(first.offset);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(positions)
+    }
+
+    /// Append an event for an entity whose last sequence number is
+    /// expected to be as given, for optimistic concurrency.
+    fn append_expected(
+        &mut self,
+        key: &K,
+        expected: u64,
+        event: E,
+    ) -> Result<Position, JournalError> {
+        let actual = self.last_seq(key)?;
+        if actual != expected {
+            return Err(JournalError::Conflict { expected, actual });
+        }
+        self.append(key, event)
+    }
+
+    /// Append events for several entities together, answering for each in
+    /// order. A backend should override this to make the whole group
+    /// durable at once rather than each event in turn.
+    fn append_group(&mut self, events: Vec<(K, E)>) -> Vec<Result<Position, JournalError>> {
+        events
+            .into_iter()
+            .map(|(key, event)| self.append(&key, event))
+            .collect()
+    }
+}
+
+/// The records of an entity, read a page at a time as they are consumed.
+pub struct Paged<'a, J, K, E> {
+    journal: &'a J,
+    key: &'a K,
+    next_seq: u64,
+    batch: usize,
+    page: std::vec::IntoIter<Record<K, E>>,
+    done: bool,
+}
+
+/// Read an entity's records from a sequence number in pages of `batch` records.
+pub fn paged<'a, J, K, E>(
+    journal: &'a J,
+    key: &'a K,
+    from_seq: u64,
+    batch: usize,
+) -> Paged<'a, J, K, E> {
+    Paged {
+        journal,
+        key,
+        next_seq: from_seq,
+        batch: batch.max(1),
+        page: Vec::new().into_iter(),
+        done: false,
+    }
+}
+
+impl<J, K, E> Iterator for Paged<'_, J, K, E>
+where
+    J: Journal<K, E>,
+{
+    type Item = Result<Record<K, E>, JournalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(record) = self.page.next() {
+            self.next_seq = record.seq + 1;
+            return Some(Ok(record));
+        }
+        if self.done {
+            return None;
+        }
+        match self.journal.read_page(self.key, self.next_seq, self.batch) {
+            Ok(page) => {
+                self.done = page.len() < self.batch;
+                self.page = page.into_iter();
+                let record = self.page.next()?;
+                self.next_seq = record.seq + 1;
+                Some(Ok(record))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A shared journal, locked for each operation.
+impl<K, E, J> Journal<K, E> for Arc<Mutex<J>>
+where
+    J: Journal<K, E>,
+{
+    fn append(&mut self, key: &K, event: E) -> Result<Position, JournalError> {
+        lock(self).append(key, event)
+    }
+
+    fn read(&self, key: &K, from_seq: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        lock(self).read(key, from_seq)
+    }
+
+    fn read_all(&self, from_offset: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        lock(self).read_all(from_offset)
+    }
+
+    fn read_page(
+        &self,
+        key: &K,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Record<K, E>>, JournalError> {
+        lock(self).read_page(key, from_seq, limit)
+    }
+
+    fn last_seq(&self, key: &K) -> Result<u64, JournalError> {
+        lock(self).last_seq(key)
+    }
+
+    fn prune(&mut self, key: &K, up_to_seq: u64) -> Result<(), JournalError> {
+        lock(self).prune(key, up_to_seq)
+    }
+
+    fn truncate(&mut self, from_offset: u64) -> Result<(), JournalError> {
+        lock(self).truncate(from_offset)
+    }
+
+    fn append_batch(&mut self, key: &K, events: Vec<E>) -> Result<Vec<Position>, JournalError> {
+        lock(self).append_batch(key, events)
+    }
+
+    fn append_expected(
+        &mut self,
+        key: &K,
+        expected: u64,
+        event: E,
+    ) -> Result<Position, JournalError> {
+        lock(self).append_expected(key, expected, event)
+    }
+
+    fn append_group(&mut self, events: Vec<(K, E)>) -> Vec<Result<Position, JournalError>> {
+        lock(self).append_group(events)
+    }
+}
+
+fn lock<J>(journal: &Mutex<J>) -> MutexGuard<'_, J> {
+    journal.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A journal held in memory.
+#[derive(Debug)]
+pub struct MemJournal<K, E> {
+    records: Vec<Record<K, E>>,
+    seqs: HashMap<K, u64>,
+    next_offset: u64,
+    fail_appends: bool,
+    fail_reads: bool,
+}
+
+impl<K, E> Default for MemJournal<K, E> {
+    fn default() -> Self {
+        Self {
+            records: Vec::new(),
+            seqs: HashMap::new(),
+            next_offset: 0,
+            fail_appends: false,
+            fail_reads: false,
+        }
+    }
+}
+
+impl<K, E> MemJournal<K, E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of records held.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Make appends fail, or succeed again, e.g. to test error handling.
+    pub fn fail_appends(&mut self, fail: bool) {
+        self.fail_appends = fail;
+    }
+
+    /// Make reads fail, or succeed again, e.g. to test recovery.
+    pub fn fail_reads(&mut self, fail: bool) {
+        self.fail_reads = fail;
+    }
+
+    fn check(&self, fail: bool, operation: &str) -> Result<(), JournalError> {
+        if fail {
+            Err(JournalError::Failed(format!("{operation} failed")))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<K, E> Journal<K, E> for MemJournal<K, E>
+where
+    K: Eq + Hash + Clone,
+    E: Clone,
+{
+    fn append(&mut self, key: &K, event: E) -> Result<Position, JournalError> {
+        self.check(self.fail_appends, "append")?;
+        let seq = self.seqs.entry(key.clone()).or_insert(0);
+        *seq += 1;
+        let position = Position {
+            offset: self.next_offset,
+            seq: *seq,
+        };
+        self.next_offset += 1;
+        self.records.push(Record {
+            offset: position.offset,
+            key: key.clone(),
+            seq: position.seq,
+            event,
+        });
+        Ok(position)
+    }
+
+    fn read(&self, key: &K, from_seq: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.check(self.fail_reads, "read")?;
+        Ok(self
+            .records
+            .iter()
+            .filter(|r| r.key == *key && r.seq >= from_seq)
+            .cloned()
+            .collect())
+    }
+
+    fn read_page(
+        &self,
+        key: &K,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.check(self.fail_reads, "read")?;
+        Ok(self
+            .records
+            .iter()
+            .filter(|r| r.key == *key && r.seq >= from_seq)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn read_all(&self, from_offset: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        self.check(self.fail_reads, "read")?;
+        let from = self.records.partition_point(|r| r.offset < from_offset);
+        Ok(self.records[from..].to_vec())
+    }
+
+    fn last_seq(&self, key: &K) -> Result<u64, JournalError> {
+        self.check(self.fail_reads, "read")?;
+        Ok(self.seqs.get(key).copied().unwrap_or(0))
+    }
+
+    fn truncate(&mut self, from_offset: u64) -> Result<(), JournalError> {
+        let from = self.records.partition_point(|r| r.offset < from_offset);
+        for r in self.records.drain(from..).rev() {
+            if let Some(seq) = self.seqs.get_mut(&r.key) {
+                *seq = r.seq - 1;
+            }
+        }
+        self.next_offset = self.next_offset.min(from_offset);
+        Ok(())
+    }
+
+    fn prune(&mut self, key: &K, up_to_seq: u64) -> Result<(), JournalError> {
+        self.check(self.fail_appends, "prune")?;
+        self.records.retain(|r| r.key != *key || r.seq > up_to_seq);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mem_journal() {
+        let mut journal = MemJournal::new();
+        assert_eq!(journal.append(&"a", 1), Ok(Position { offset: 0, seq: 1 }));
+        assert_eq!(journal.append(&"b", 2), Ok(Position { offset: 1, seq: 1 }));
+        assert_eq!(journal.append(&"a", 3), Ok(Position { offset: 2, seq: 2 }));
+
+        let events = |records: Vec<Record<&str, u32>>| -> Vec<u32> {
+            records.into_iter().map(|r| r.event).collect()
+        };
+        assert_eq!(events(journal.read(&"a", 1).unwrap()), vec![1, 3]);
+        assert_eq!(events(journal.read(&"a", 2).unwrap()), vec![3]);
+        assert_eq!(events(journal.read_all(1).unwrap()), vec![2, 3]);
+        assert_eq!(events(journal.read_all(5).unwrap()), vec![]);
+
+        assert_eq!(
+            journal.append_expected(&"a", 1, 4),
+            Err(JournalError::Conflict {
+                expected: 1,
+                actual: 2
+            })
+        );
+        assert_eq!(
+            journal.append_expected(&"a", 2, 4),
+            Ok(Position { offset: 3, seq: 3 })
+        );
+
+        // Injected failures leave the records as they were
+        journal.fail_appends(true);
+        assert_eq!(
+            journal.append(&"b", 5),
+            Err(JournalError::Failed("append failed".to_string()))
+        );
+        journal.fail_appends(false);
+        journal.fail_reads(true);
+        assert!(journal.read(&"b", 0).is_err());
+        assert!(journal.read_all(0).is_err());
+        assert!(journal.last_seq(&"b").is_err());
+        journal.fail_reads(false);
+        assert_eq!(events(journal.read(&"b", 0).unwrap()), vec![2]);
+
+        // Reading "a" two records at a time
+        assert_eq!(events(journal.read_page(&"a", 2, 1).unwrap()), vec![3]);
+        let pages: Result<Vec<_>, _> = paged(&journal, &"a", 1, 2)
+            .map(|r| r.map(|r| r.event))
+            .collect();
+        assert_eq!(pages, Ok(vec![1, 3, 4]));
+        assert_eq!(paged(&journal, &"c", 1, 2).count(), 0);
+    }
+
+    /// Fails appends after a number of them, and truncates if it may.
+    struct Failing {
+        inner: MemJournal<&'static str, u32>,
+        appends: usize,
+        truncates: bool,
+    }
+
+    impl Journal<&'static str, u32> for Failing {
+        fn append(&mut self, key: &&'static str, event: u32) -> Result<Position, JournalError> {
+            match self.appends {
+                0 => Err(JournalError::Conflict {
+                    expected: 0,
+                    actual: 1,
+                }),
+                _ => {
+                    self.appends -= 1;
+                    self.inner.append(key, event)
+                }
+            }
+        }
+
+        fn read(
+            &self,
+            key: &&'static str,
+            from_seq: u64,
+        ) -> Result<Vec<Record<&'static str, u32>>, JournalError> {
+            self.inner.read(key, from_seq)
+        }
+
+        fn read_all(
+            &self,
+            from_offset: u64,
+        ) -> Result<Vec<Record<&'static str, u32>>, JournalError> {
+            self.inner.read_all(from_offset)
+        }
+
+        fn truncate(&mut self, from_offset: u64) -> Result<(), JournalError> {
+            match self.truncates {
+                true => self.inner.truncate(from_offset),
+                false => Err(JournalError::Failed(
+                    "truncation is not supported".to_string(),
+                )),
+            }
+        }
+    }
+
+    #[test]
+    fn test_failed_batch() {
+        // A batch whose third append fails, with the append's own error
+        let conflict = Err(JournalError::Conflict {
+            expected: 0,
+            actual: 1,
+        });
+        for truncates in [true, false] {
+            let mut journal = Failing {
+                inner: MemJournal::new(),
+                appends: 3,
+                truncates,
+            };
+            journal.append(&"a", 0).unwrap();
+            assert_eq!(journal.append_batch(&"a", vec![1, 2, 3]), conflict);
+            // Rolled back if the journal truncates, and partly applied if not
+            let expected = if truncates { 1 } else { 3 };
+            assert_eq!(journal.last_seq(&"a"), Ok(expected));
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! JSON Schemas for the commands and events of a machine, so that API
+//! clients and validation middleware agree with the code. Types with a
+//! `JsonSchema` give the schema of their JSON form, and a `MachineSchemas`
+//! collects those of a machine's commands and events under the names its
+//! `MachineDescriptor` declares, reporting any it lacks.
+//!
+//! The schemas are JSON Schema 2020-12, gathered in a document's `$defs`
+//! with `oneOf` references to all commands and all events. `validate`
+//! checks a value against a schema, for the subset of keywords written
+//! here, so middleware can refuse malformed commands before decoding
+//! them.
+
+use crate::descriptor::MachineDescriptor;
+use crate::json::Json;
+use crate::observer::type_label;
+
+/// Types whose JSON form has a schema.
+pub trait JsonSchema {
+    /// The name of the schema, by default that of the type.
+    fn schema_name() -> String {
+        type_label::<Self>()
+    }
+
+    fn json_schema() -> Json;
+}
+
+fn text(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        members
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+/// The schema of values of a JSON type, such as `"string"`.
+pub fn typed(json_type: &str) -> Json {
+    object(vec![("type", text(json_type))])
+}
+
+/// The schema of an object with properties, all required unless optional.
+pub fn object_schema(properties: Vec<(&str, Json)>, optional: &[&str]) -> Json {
+    let required = properties
+        .iter()
+        .filter(|(name, _)| !optional.contains(name))
+        .map(|(name, _)| text(name))
+        .collect();
+    object(vec![
+        ("type", text("object")),
+        ("properties", object(properties)),
+        ("required", Json::Array(required)),
+        ("additionalProperties", Json::Bool(false)),
+    ])
+}
+
+/// The schema of one variant of a message tagged by a `"type"` property,
+/// as the JSON journals and the interop formats write them.
+pub fn tagged_schema(tag: &str, mut properties: Vec<(&str, Json)>, optional: &[&str]) -> Json {
+    properties.insert(0, ("type", object(vec![("const", text(tag))])));
+    object_schema(properties, optional)
+}
+
+impl JsonSchema for bool {
+    fn json_schema() -> Json {
+        typed("boolean")
+    }
+}
+
+macro_rules! integer_schema {
+    ($($t:ty),*) => {
+        $(
+            impl JsonSchema for $t {
+                fn json_schema() -> Json {
+                    typed("integer")
+                }
+            }
+        )*
+    };
+}
+
+integer_schema!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+impl JsonSchema for f32 {
+    fn json_schema() -> Json {
+        typed("number")
+    }
+}
+
+impl JsonSchema for f64 {
+    fn json_schema() -> Json {
+        typed("number")
+    }
+}
+
+impl JsonSchema for String {
+    fn json_schema() -> Json {
+        typed("string")
+    }
+}
+
+impl<T: JsonSchema> JsonSchema for Vec<T> {
+    fn json_schema() -> Json {
+        object(vec![("type", text("array")), ("items", T::json_schema())])
+    }
+}
+
+/// An optional value is its value or null.
+impl<T: JsonSchema> JsonSchema for Option<T> {
+    fn json_schema() -> Json {
+        object(vec![(
+            "anyOf",
+            Json::Array(vec![T::json_schema(), typed("null")]),
+        )])
+    }
+}
+
+/// Any JSON value.
+impl JsonSchema for Json {
+    fn json_schema() -> Json {
+        Json::Object(Vec::new())
+    }
+}
+
+/// The schemas of the commands and events of a machine.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MachineSchemas {
+    pub name: String,
+    pub commands: Vec<(String, Json)>,
+    pub events: Vec<(String, Json)>,
+}
+
+impl MachineSchemas {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Add the schema of a command, under its schema name.
+    pub fn command<C: JsonSchema>(mut self) -> Self {
+        self.commands.push((C::schema_name(), C::json_schema()));
+        self
+    }
+
+    /// Add the schema of an event, under its schema name.
+    pub fn event<E: JsonSchema>(mut self) -> Self {
+        self.events.push((E::schema_name(), E::json_schema()));
+        self
+    }
+
+    /// The schema of a command or event by name.
+    pub fn schema(&self, name: &str) -> Option<&Json> {
+        self.commands
+            .iter()
+            .chain(&self.events)
+            .find(|(n, _)| n == name)
+            .map(|(_, s)| s)
+    }
+
+    /// The commands and events a descriptor declares which have no
+    /// schema here.
+    pub fn missing(&self, descriptor: &MachineDescriptor) -> Vec<String> {
+        let has = |names: &[(String, Json)], name: &String| names.iter().any(|(n, _)| n == name);
+        let commands = descriptor
+            .commands
+            .iter()
+            .filter(|c| !has(&self.commands, c));
+        let events = descriptor.events.iter().filter(|e| !has(&self.events, e));
+        commands.chain(events).cloned().collect()
+    }
+
+    /// A JSON Schema document with each command and event in `$defs`,
+    /// whose `commands` and `events` definitions are one of them.
+    pub fn document(&self) -> Json {
+        let one_of = |names: &[(String, Json)]| {
+            let refs = names
+                .iter()
+                .map(|(n, _)| object(vec![("$ref", text(&format!("#/$defs/{n}")))]))
+                .collect();
+            object(vec![("oneOf", Json::Array(refs))])
+        };
+        let mut defs: Vec<(String, Json)> =
+            self.commands.iter().chain(&self.events).cloned().collect();
+        defs.push(("commands".to_string(), one_of(&self.commands)));
+        defs.push(("events".to_string(), one_of(&self.events)));
+        object(vec![
+            (
+                "$schema",
+                text("https://json-schema.org/draft/2020-12/schema"),
+            ),
+            ("title", text(&self.name)),
+            ("$defs", Json::Object(defs)),
+        ])
+    }
+
+    /// The definitions of the document, with their references pointing
+    /// at the schemas of an OpenAPI or AsyncAPI document's components.
+    pub fn components(&self) -> Vec<(String, Json)> {
+        match self.document().get("$defs") {
+            Some(Json::Object(defs)) => defs.iter().map(|(k, v)| (k.clone(), rebase(v))).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Point `$ref`s into `$defs` at `components/schemas` instead.
+fn rebase(schema: &Json) -> Json {
+    match schema {
+        Json::Object(members) => Json::Object(
+            members
+                .iter()
+                .map(|(k, v)| match (k.as_str(), v) {
+                    ("$ref", Json::String(r)) => match r.strip_prefix("#/$defs/") {
+                        Some(name) => (k.clone(), text(&format!("#/components/schemas/{name}"))),
+                        None => (k.clone(), v.clone()),
+                    },
+                    _ => (k.clone(), rebase(v)),
+                })
+                .collect(),
+        ),
+        Json::Array(items) => Json::Array(items.iter().map(rebase).collect()),
+        other => other.clone(),
+    }
+}
+
+/// Check a value against a schema, resolving `$ref`s into the `$defs`
+/// of a document. The keywords checked are `type`, `const`, `enum`,
+/// `properties`, `required`, `additionalProperties`, `items`, `oneOf`,
+/// `anyOf` and `$ref`, and others are ignored.
+pub fn validate(document: &Json, schema: &Json, value: &Json) -> Result<(), String> {
+    validate_at(document, schema, value, "$")
+}
+
+fn validate_at(document: &Json, schema: &Json, value: &Json, path: &str) -> Result<(), String> {
+    if let Some(reference) = schema.get("$ref").and_then(Json::as_str) {
+        let target = reference
+            .strip_prefix("#/$defs/")
+            .and_then(|name| document.get("$defs")?.get(name))
+            .ok_or(format!("{path}: {reference} is not defined"))?;
+        validate_at(document, target, value, path)?;
+    }
+    if let Some(Json::String(expected)) = schema.get("type") {
+        let actual = match value {
+            Json::Null => "null",
+            Json::Bool(_) => "boolean",
+            Json::Number(n) if expected == "integer" && n.fract() == 0.0 => "integer",
+            Json::Number(_) => "number",
+            Json::String(_) => "string",
+            Json::Array(_) => "array",
+            Json::Object(_) => "object",
+        };
+        if actual != expected {
+            return Err(format!("{path}: expected {expected}, found {actual}"));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return Err(format!("{path}: expected {constant}"));
+        }
+    }
+    if let Some(Json::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return Err(format!("{path}: {value} is not allowed"));
+        }
+    }
+    if let (Some(Json::Object(properties)), Json::Object(members)) =
+        (schema.get("properties"), value)
+    {
+        for (name, v) in members {
+            match properties.iter().find(|(n, _)| n == name) {
+                Some((_, s)) => validate_at(document, s, v, &format!("{path}.{name}"))?,
+                None if schema.get("additionalProperties") == Some(&Json::Bool(false)) => {
+                    return Err(format!("{path}: {name} is not allowed"));
+                }
+                None => {}
+            }
+        }
+    }
+    if let (Some(Json::Array(required)), Json::Object(_)) = (schema.get("required"), value) {
+        for name in required.iter().filter_map(Json::as_str) {
+            if value.get(name).is_none() {
+                return Err(format!("{path}: {name} is required"));
+            }
+        }
+    }
+    if let (Some(items), Json::Array(values)) = (schema.get("items"), value) {
+        for (i, v) in values.iter().enumerate() {
+            validate_at(document, items, v, &format!("{path}[{i}]"))?;
+        }
+    }
+    if let Some(Json::Array(branches)) = schema.get("oneOf") {
+        let matched = branches
+            .iter()
+            .filter(|b| validate_at(document, b, value, path).is_ok())
+            .count();
+        if matched != 1 {
+            return Err(format!("{path}: matches {matched} of oneOf"));
+        }
+    }
+    if let Some(Json::Array(branches)) = schema.get("anyOf") {
+        if !branches
+            .iter()
+            .any(|b| validate_at(document, b, value, path).is_ok())
+        {
+            return Err(format!("{path}: matches none of anyOf"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_machine_schemas() {
+        // An account's deposits, whose schemas are checked against its
+        // descriptor and used to validate incoming commands
+
+        struct Deposit {}
+
+        struct Deposited {}
+
+        impl JsonSchema for Deposit {
+            fn json_schema() -> Json {
+                tagged_schema(
+                    "Deposit",
+                    vec![
+                        ("amount", u64::json_schema()),
+                        ("memo", Option::<String>::json_schema()),
+                    ],
+                    &["memo"],
+                )
+            }
+        }
+
+        impl JsonSchema for Deposited {
+            fn json_schema() -> Json {
+                tagged_schema("Deposited", vec![("amount", u64::json_schema())], &[])
+            }
+        }
+
+        let descriptor = MachineDescriptor::new("account")
+            .transition("Open", "Deposit", "Deposited", "Open")
+            .transition("Open", "Close", "Closed", "Closed");
+        let schemas = MachineSchemas::new("account")
+            .command::<Deposit>()
+            .event::<Deposited>();
+        assert_eq!(schemas.missing(&descriptor), vec!["Close", "Closed"]);
+
+        let document = schemas.document();
+        let commands = &document.get("$defs").unwrap().get("commands").unwrap();
+        let valid = |text: &str| validate(&document, commands, &Json::parse(text).unwrap());
+        assert_eq!(valid(r#"{"type":"Deposit","amount":5}"#), Ok(()));
+        assert_eq!(
+            valid(r#"{"type":"Deposit","amount":5,"memo":null}"#),
+            Ok(())
+        );
+        assert!(valid(r#"{"type":"Deposit","amount":5.5}"#).is_err());
+        assert!(valid(r#"{"type":"Deposit"}"#).is_err());
+        assert!(valid(r#"{"type":"Deposit","amount":5,"extra":1}"#).is_err());
+        assert!(valid(r#"{"type":"Deposited","amount":5}"#).is_err());
+        assert_eq!(
+            schemas.schema("Deposited").and_then(|s| s.get("required")),
+            Some(&Json::Array(vec![text("type"), text("amount")]))
+        );
+    }
+}
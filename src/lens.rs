@@ -0,0 +1,256 @@
+//! Lenses over collections of sub-states. A machine whose state is a
+//! collection of sub-machines can route a command or event to just one
+//! of them by focusing the lens on it. Commands then execute against the
+//! focused entry via `Lens::extract`, and events are applied to it in
+//! place via `Lens::inject_mut`, so the collection is never cloned.
+//!
+//! A lens focused on an entry that does not exist extracts a default
+//! value. What injecting does then is a matter of policy for maps. For
+//! vectors, an index out of bounds is never injected, so the collection
+//! stays the same.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::command_and_event_traits::{Event, Lens, Transition};
+
+/// What to do when injecting into a missing entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Missing {
+    /// Create the entry
+    CreateDefault,
+    /// Leave the collection as it is
+    Ignore,
+}
+
+/// A map of sub-states, focused on one key.
+#[derive(Debug)]
+pub struct MapLens<K, V> {
+    entries: HashMap<K, V>,
+    focus: Option<K>,
+    missing: Missing,
+    vacant: V,
+}
+
+impl<K, V> MapLens<K, V>
+where
+    K: Eq + Hash,
+    V: Default,
+{
+    pub fn new(entries: HashMap<K, V>, missing: Missing) -> Self {
+        Self {
+            entries,
+            focus: None,
+            missing,
+            vacant: V::default(),
+        }
+    }
+
+    /// Focus on the entry with a key.
+    pub fn focus(&mut self, key: K) -> &mut Self {
+        self.focus = Some(key);
+        self
+    }
+
+    pub fn key(&self) -> Option<&K> {
+        self.focus.as_ref()
+    }
+
+    /// The focused entry, if it exists.
+    pub fn get(&self) -> Option<&V> {
+        self.entries.get(self.focus.as_ref()?)
+    }
+
+    pub fn entries(&self) -> &HashMap<K, V> {
+        &self.entries
+    }
+
+    pub fn into_entries(self) -> HashMap<K, V> {
+        self.entries
+    }
+
+    /// The transition of the focused entry for an event, which is `Same`
+    /// when the entry is missing and would be ignored.
+    pub fn fire<E>(&self, event: &E) -> Transition<V>
+    where
+        E: Event<V>,
+    {
+        match self.get() {
+            None if self.missing == Missing::Ignore => Transition::Same,
+            entry => event.fire(entry.unwrap_or(&self.vacant)),
+        }
+    }
+}
+
+impl<K, V> Lens<V> for MapLens<K, V>
+where
+    K: Eq + Hash + Clone,
+{
+    fn extract(&self) -> &V {
+        self.focus
+            .as_ref()
+            .and_then(|k| self.entries.get(k))
+            .unwrap_or(&self.vacant)
+    }
+
+    fn inject_mut(&mut self, view: V) {
+        let Some(key) = &self.focus else {
+            return;
+        };
+        match self.entries.get_mut(key) {
+            Some(entry) => *entry = view,
+            None if self.missing == Missing::CreateDefault => {
+                self.entries.insert(key.clone(), view);
+            }
+            None => (),
+        }
+    }
+}
+
+/// A vector of sub-states, focused on one index.
+#[derive(Debug)]
+pub struct IndexLens<V> {
+    slots: Vec<V>,
+    focus: usize,
+    vacant: V,
+}
+
+impl<V> IndexLens<V>
+where
+    V: Default,
+{
+    pub fn new(slots: Vec<V>) -> Self {
+        Self {
+            slots,
+            focus: 0,
+            vacant: V::default(),
+        }
+    }
+
+    /// Focus on the slot at an index.
+    pub fn focus(&mut self, index: usize) -> &mut Self {
+        self.focus = index;
+        self
+    }
+
+    pub fn index(&self) -> usize {
+        self.focus
+    }
+
+    /// The focused slot, if it is in bounds.
+    pub fn get(&self) -> Option<&V> {
+        self.slots.get(self.focus)
+    }
+
+    pub fn slots(&self) -> &[V] {
+        &self.slots
+    }
+
+    pub fn into_slots(self) -> Vec<V> {
+        self.slots
+    }
+
+    /// The transition of the focused slot for an event, which is `Same`
+    /// when the index is out of bounds.
+    pub fn fire<E>(&self, event: &E) -> Transition<V>
+    where
+        E: Event<V>,
+    {
+        match self.get() {
+            Some(slot) => event.fire(slot),
+            None => Transition::Same,
+        }
+    }
+}
+
+impl<V> Lens<V> for IndexLens<V> {
+    fn extract(&self) -> &V {
+        self.slots.get(self.focus).unwrap_or(&self.vacant)
+    }
+
+    fn inject_mut(&mut self, view: V) {
+        if let Some(slot) = self.slots.get_mut(self.focus) {
+            *slot = view;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Command, Event, Fsm, Transition};
+
+    // Per room light switches
+
+    #[derive(Debug, Default, PartialEq)]
+    enum Switch {
+        #[default]
+        Off,
+        On,
+    }
+
+    struct Toggle {}
+
+    struct Toggled {}
+
+    impl Command<Switch, ()> for Toggle {
+        type Output = Toggled;
+        fn execute(&self, _s: &Switch, _h: &mut ()) -> Option<Toggled> {
+            Some(Toggled {})
+        }
+    }
+
+    impl Event<Switch> for Toggled {
+        fn fire(&self, s: &Switch) -> Transition<Switch> {
+            match s {
+                Switch::Off => Transition::Next(Switch::On),
+                Switch::On => Transition::Next(Switch::Off),
+            }
+        }
+    }
+
+    struct MyFsm {}
+
+    impl<S> Fsm<S, ()> for MyFsm {}
+
+    #[test]
+    fn test_map_lens() {
+        let toggle = |lens: &mut MapLens<&str, Switch>, key| {
+            lens.focus(key);
+            let event = MyFsm::for_command(lens, &Toggle {}, &mut ()).unwrap();
+            MyFsm::for_event_mut(lens, &event)
+        };
+
+        let rooms = HashMap::from([("hall", Switch::Off)]);
+        let mut lens = MapLens::new(rooms, Missing::CreateDefault);
+        assert!(toggle(&mut lens, "hall"));
+        assert!(toggle(&mut lens, "attic"));
+        assert_eq!(lens.get(), Some(&Switch::On));
+        assert_eq!(lens.entries().len(), 2);
+
+        let rooms = HashMap::from([("hall", Switch::Off)]);
+        let mut lens = MapLens::new(rooms, Missing::Ignore);
+        toggle(&mut lens, "attic");
+        assert_eq!(lens.get(), None);
+        assert_eq!(lens.fire(&Toggled {}), Transition::Same);
+        toggle(&mut lens, "hall");
+        assert_eq!(lens.into_entries(), HashMap::from([("hall", Switch::On)]));
+    }
+
+    #[test]
+    fn test_index_lens() {
+        let mut lens = IndexLens::new(vec![Switch::Off, Switch::Off]);
+        lens.focus(1);
+        let event = MyFsm::for_command(&lens, &Toggle {}, &mut ()).unwrap();
+        assert!(MyFsm::for_event_mut(&mut lens, &event));
+        assert_eq!(lens.get(), Some(&Switch::On));
+
+        // Out of bounds is the same as no transition
+
+        lens.focus(2);
+        assert_eq!(lens.get(), None);
+        assert_eq!(lens.fire(&event), Transition::Same);
+        MyFsm::for_event_mut(&mut lens, &event);
+        assert_eq!(lens.into_slots(), vec![Switch::Off, Switch::On]);
+    }
+}
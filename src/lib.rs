@@ -1 +1,76 @@
+pub mod aggregate;
+pub mod async_handler;
+pub mod async_runner;
+#[cfg(feature = "schemars")]
+pub mod asyncapi;
+pub mod audit;
+pub mod authorization;
+pub mod avro;
+pub mod backfill;
+pub mod batch;
+pub mod bincode;
+pub mod canary;
+pub mod chaos;
+pub mod codec;
 pub mod command_and_event_traits;
+pub mod compaction;
+pub mod compression;
+pub mod context;
+pub mod correlation;
+pub mod derived;
+pub mod descriptor;
+pub mod diff;
+pub mod envelope;
+pub mod export;
+pub mod fallible;
+pub mod file_journal;
+#[cfg(test)]
+mod fixtures;
+pub mod flags;
+pub mod group_commit;
+pub mod hierarchy;
+pub mod idempotent;
+pub mod inbox;
+pub mod integrity;
+pub mod interop;
+pub mod journal;
+pub mod json;
+#[cfg(feature = "schemars")]
+pub mod json_schema;
+pub mod lens;
+pub mod machine;
+pub mod mailbox;
+pub mod migrate;
+pub mod msgpack;
+pub mod observer;
+#[cfg(feature = "schemars")]
+pub mod openapi;
+pub mod outbox;
+pub mod patch;
+pub mod pipeline;
+pub mod projection;
+pub mod published;
+pub mod rate_limit;
+pub mod read_only;
+pub mod recovery;
+pub mod redaction;
+pub mod runner;
+pub mod shadow;
+pub mod shard;
+pub mod simulation;
+pub mod snapshot;
+pub mod soak;
+pub mod sse;
+pub mod stats;
+pub mod subscription;
+pub mod supervisor;
+pub mod table;
+pub mod tail;
+pub mod tenant;
+pub mod testing;
+pub mod thread_runner;
+pub mod timer;
+pub mod trace;
+pub mod unit_of_work;
+pub mod verification;
+pub mod xstate;
@@ -0,0 +1,421 @@
+//! An owning wrapper for a single machine. The `Fsm` trait is all static,
+//! leaving the caller to keep the state and handler and to write back
+//! each new state. A `Machine` holds both and does that bookkeeping, for
+//! the simple case of one entity.
+//!
+//! A `SharedMachine` is a handle to a machine driven from several
+//! threads. Sends are serialized by a lock on the handler, while the
+//! state is behind a read-write lock that is only held for writing while
+//! a new state is stored, so reading the state is cheap. A panic during
+//! a step leaves the previous state in place, and the locks are not
+//! treated as poisoned, although the handler may have been part way
+//! through an effect.
+//!
+//! Whether a machine can cross threads follows from its state and handler:
+//! a `Machine` is `Send` when both are, and a `SharedMachine` is `Send`
+//! and `Sync` when its state is `Send + Sync` and its handler `Send`. The
+//! FSM type itself never matters. The marker newtypes make the intent
+//! explicit: a `LocalMachine` is never `Send` or `Sync`, so it may hold
+//! `Rc`s or other thread-bound values and will not be moved by mistake,
+//! while a `SyncMachine` can only be built from parts that make it
+//! `Send + Sync`, so the bounds fail where it is made rather than where
+//! it is first shared.
+
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+use crate::command_and_event_traits::{Command, Fsm, StepOutcome, Transition, Validate};
+
+/// A state S and handler H, stepped by the FSM F.
+pub struct Machine<S, H, F> {
+    state: S,
+    handler: H,
+    fsm: PhantomData<fn() -> F>,
+}
+
+impl<S, H, F> Machine<S, H, F>
+where
+    F: Fsm<S, H>,
+{
+    pub fn new(state: S, handler: H) -> Self {
+        Self {
+            state,
+            handler,
+            fsm: PhantomData,
+        }
+    }
+
+    /// Step the machine with a command, moving to the new state if there
+    /// is a transition. The outcome is that of `Fsm::step`.
+    pub fn send<C>(&mut self, command: &C) -> StepOutcome<C::Output, S>
+    where
+        C: Command<S, H> + Validate<S>,
+        S: Clone,
+    {
+        let (event, trans) = F::step(&self.state, command, &mut self.handler);
+        if let Ok(Transition::Next(s)) = &trans {
+            self.state = s.clone();
+        }
+        (event, trans)
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Whether the current state satisfies a predicate, e.g.
+    /// `machine.is_in(|s| matches!(s, State::Idle))`.
+    pub fn is_in(&self, pred: impl FnOnce(&S) -> bool) -> bool {
+        pred(&self.state)
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn into_parts(self) -> (S, H) {
+        (self.state, self.handler)
+    }
+}
+
+/// A machine shared between threads.
+pub struct SharedMachine<S, H, F> {
+    state: Arc<RwLock<S>>,
+    handler: Arc<Mutex<H>>,
+    fsm: PhantomData<fn() -> F>,
+}
+
+impl<S, H, F> Clone for SharedMachine<S, H, F> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            handler: self.handler.clone(),
+            fsm: PhantomData,
+        }
+    }
+}
+
+impl<S, H, F> SharedMachine<S, H, F>
+where
+    F: Fsm<S, H>,
+{
+    pub fn new(state: S, handler: H) -> Self {
+        Self {
+            state: Arc::new(RwLock::new(state)),
+            handler: Arc::new(Mutex::new(handler)),
+            fsm: PhantomData,
+        }
+    }
+
+    /// Step the machine with a command, after any sends in progress on
+    /// other threads, as `Machine::send`.
+    pub fn send<C>(&self, command: &C) -> StepOutcome<C::Output, S>
+    where
+        C: Command<S, H> + Validate<S>,
+        S: Clone,
+    {
+        let mut handler = self.lock_handler();
+        let (event, trans) = {
+            let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+            F::step(&state, command, &mut handler)
+        };
+        if let Ok(Transition::Next(s)) = &trans {
+            *self.state.write().unwrap_or_else(|e| e.into_inner()) = s.clone();
+        }
+        (event, trans)
+    }
+
+    /// Read the current state, without waiting for a send in progress.
+    pub fn read_state<R>(&self, read: impl FnOnce(&S) -> R) -> R {
+        read(&self.state.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// A copy of the current state.
+    pub fn state(&self) -> S
+    where
+        S: Clone,
+    {
+        self.read_state(S::clone)
+    }
+
+    /// Use the handler, once any send in progress completes.
+    pub fn with_handler<R>(&self, f: impl FnOnce(&mut H) -> R) -> R {
+        f(&mut self.lock_handler())
+    }
+
+    fn lock_handler(&self) -> MutexGuard<'_, H> {
+        self.handler.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<S, H, F> From<Machine<S, H, F>> for SharedMachine<S, H, F>
+where
+    F: Fsm<S, H>,
+{
+    fn from(machine: Machine<S, H, F>) -> Self {
+        let (state, handler) = machine.into_parts();
+        Self::new(state, handler)
+    }
+}
+
+/// A machine confined to the thread that made it.
+///
+/// ```compile_fail
+/// # use fsm_laboratory::command_and_event_traits::Fsm;
+/// # use fsm_laboratory::machine::LocalMachine;
+/// struct MyFsm {}
+/// impl Fsm<u32, ()> for MyFsm {}
+/// let machine = LocalMachine::<u32, (), MyFsm>::new(0, ());
+/// std::thread::spawn(move || machine.state().clone());
+/// ```
+pub struct LocalMachine<S, H, F> {
+    machine: Machine<S, H, F>,
+    local: PhantomData<*const ()>,
+}
+
+impl<S, H, F> LocalMachine<S, H, F>
+where
+    F: Fsm<S, H>,
+{
+    pub fn new(state: S, handler: H) -> Self {
+        Self {
+            machine: Machine::new(state, handler),
+            local: PhantomData,
+        }
+    }
+
+    /// Step the machine, as `Machine::send`.
+    pub fn send<C>(&mut self, command: &C) -> StepOutcome<C::Output, S>
+    where
+        C: Command<S, H> + Validate<S>,
+        S: Clone,
+    {
+        self.machine.send(command)
+    }
+
+    pub fn state(&self) -> &S {
+        self.machine.state()
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Machine<S, H, F> {
+        &mut self.machine
+    }
+
+    pub fn into_inner(self) -> Machine<S, H, F> {
+        self.machine
+    }
+}
+
+/// A shared machine that is always `Send + Sync`.
+pub struct SyncMachine<S, H, F> {
+    shared: SharedMachine<S, H, F>,
+}
+
+impl<S, H, F> Clone for SyncMachine<S, H, F> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S, H, F> SyncMachine<S, H, F>
+where
+    S: Send + Sync,
+    H: Send,
+    F: Fsm<S, H>,
+{
+    pub fn new(state: S, handler: H) -> Self {
+        Self {
+            shared: SharedMachine::new(state, handler),
+        }
+    }
+
+    /// Step the machine, as `SharedMachine::send`.
+    pub fn send<C>(&self, command: &C) -> StepOutcome<C::Output, S>
+    where
+        C: Command<S, H> + Validate<S>,
+        S: Clone,
+    {
+        self.shared.send(command)
+    }
+
+    pub fn read_state<R>(&self, read: impl FnOnce(&S) -> R) -> R {
+        self.shared.read_state(read)
+    }
+
+    pub fn shared(&self) -> &SharedMachine<S, H, F> {
+        &self.shared
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, StepError, ValidationError};
+    use crate::runner::Runner;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_machine() {
+        // A turnstile that must be paid before it turns
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum Turnstile {
+            Locked,
+            Unlocked,
+        }
+
+        enum Input {
+            Coin,
+            Push,
+        }
+
+        #[derive(Debug, PartialEq)]
+        enum Output {
+            Unlocked,
+            Turned,
+        }
+
+        impl Command<Turnstile, u32> for Input {
+            type Output = Output;
+            fn execute(&self, s: &Turnstile, takings: &mut u32) -> Option<Output> {
+                match (s, self) {
+                    (Turnstile::Locked, Input::Coin) => {
+                        *takings += 1;
+                        Some(Output::Unlocked)
+                    }
+                    (Turnstile::Unlocked, Input::Push) => Some(Output::Turned),
+                    _ => None,
+                }
+            }
+        }
+
+        impl Validate<Turnstile> for Input {
+            fn validate(&self, s: &Turnstile) -> Result<(), ValidationError> {
+                match (s, self) {
+                    (Turnstile::Unlocked, Input::Coin) => {
+                        Err(ValidationError("already paid".to_string()))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        impl Event<Turnstile> for Output {
+            fn fire(&self, _s: &Turnstile) -> Transition<Turnstile> {
+                match self {
+                    Output::Unlocked => Transition::Next(Turnstile::Unlocked),
+                    Output::Turned => Transition::Next(Turnstile::Locked),
+                }
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<Turnstile, u32> for MyFsm {}
+
+        let mut machine = Machine::<_, _, MyFsm>::new(Turnstile::Locked, 0);
+        assert_eq!(machine.send(&Input::Push), (None, Ok(Transition::Same)));
+        assert_eq!(
+            machine.send(&Input::Coin),
+            (
+                Some(Output::Unlocked),
+                Ok(Transition::Next(Turnstile::Unlocked))
+            )
+        );
+        assert!(machine.is_in(|s| *s == Turnstile::Unlocked));
+        assert_eq!(
+            machine.send(&Input::Coin),
+            (
+                None,
+                Err(StepError::Invalid(ValidationError(
+                    "already paid".to_string()
+                )))
+            )
+        );
+        assert_eq!(machine.send(&Input::Push).0, Some(Output::Turned));
+        assert_eq!(machine.state(), &Turnstile::Locked);
+        assert_eq!(machine.into_parts(), (Turnstile::Locked, 1));
+    }
+
+    #[test]
+    fn test_shared_machine() {
+        // A counter incremented from several threads, with a handler that
+        // panics on its 500th call
+
+        struct Increment {}
+
+        struct Incremented {}
+
+        impl Command<u32, u32> for Increment {
+            type Output = Incremented;
+            fn execute(&self, _s: &u32, calls: &mut u32) -> Option<Incremented> {
+                *calls += 1;
+                assert!(*calls != 500, "unlucky");
+                Some(Incremented {})
+            }
+        }
+
+        impl Validate<u32> for Increment {}
+
+        impl Event<u32> for Incremented {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s + 1)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, u32> for MyFsm {}
+
+        let machine = SharedMachine::from(Machine::<u32, u32, MyFsm>::new(0, 0));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let machine = machine.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..250 {
+                        let _ = std::panic::catch_unwind(|| machine.send(&Increment {}));
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+
+        // One send panicked, and the next carried on from the same state
+        assert_eq!(machine.state(), 999);
+        assert_eq!(machine.with_handler(|calls| *calls), 1000);
+        assert!(machine.read_state(|s| *s > 0));
+    }
+
+    #[test]
+    fn test_thread_safety() {
+        // Which machines can cross threads, checked at compile time
+
+        fn send<T: Send>() {}
+        fn send_sync<T: Send + Sync>() {}
+
+        struct MyFsm {}
+
+        impl Fsm<u32, Vec<u8>> for MyFsm {}
+
+        impl Fsm<Rc<u32>, ()> for MyFsm {}
+
+        send::<Machine<u32, Vec<u8>, MyFsm>>();
+        send_sync::<Machine<u32, Vec<u8>, MyFsm>>();
+        send_sync::<SharedMachine<u32, Vec<u8>, MyFsm>>();
+        send_sync::<SyncMachine<u32, Vec<u8>, MyFsm>>();
+        send::<Runner<u32, u32, Vec<u8>, MyFsm>>();
+
+        // A local machine may hold thread-bound state
+        let mut local = LocalMachine::<Rc<u32>, (), MyFsm>::new(Rc::new(1), ());
+        assert_eq!(**local.state(), 1);
+        assert_eq!(**local.machine_mut().state(), 1);
+    }
+}
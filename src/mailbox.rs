@@ -0,0 +1,196 @@
+//! Channels for sending commands to, and replies from, tasks that run
+//! machines asynchronously. They are independent of any async runtime.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+struct Queue<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiving: bool,
+    waker: Option<Waker>,
+}
+
+struct Shared<T>(Mutex<Queue<T>>);
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, Queue<T>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Create an unbounded mailbox with one receiver and any number of senders.
+pub fn mailbox<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared(Mutex::new(Queue {
+        items: VecDeque::new(),
+        senders: 1,
+        receiving: true,
+        waker: None,
+    })));
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+/// Sends items to a mailbox.
+pub struct Sender<T>(Arc<Shared<T>>);
+
+impl<T> Sender<T> {
+    /// Send an item, which is returned if the receiver has gone.
+    pub fn send(&self, item: T) -> Result<(), T> {
+        let mut queue = self.0.lock();
+        if !queue.receiving {
+            return Err(item);
+        }
+        queue.items.push_back(item);
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.lock().senders += 1;
+        Sender(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut queue = self.0.lock();
+        queue.senders -= 1;
+        if queue.senders == 0 {
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Receives items from a mailbox.
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+impl<T> Receiver<T> {
+    /// Receive the next item, or `None` once all senders have gone
+    /// and the mailbox is empty.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv(self)
+    }
+
+    /// Receive an item if one is waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.0.lock().items.pop_front()
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.0.lock().receiving = false;
+    }
+}
+
+/// The future of a `Receiver::recv`.
+pub struct Recv<'a, T>(&'a mut Receiver<T>);
+
+impl<T> Future for Recv<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut queue = (self.0).0.lock();
+        match queue.items.pop_front() {
+            Some(item) => Poll::Ready(Some(item)),
+            None if queue.senders == 0 => Poll::Ready(None),
+            None => {
+                queue.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Create a channel for a single reply.
+pub fn reply<T>() -> (ReplySender<T>, ReplyReceiver<T>) {
+    let (sender, receiver) = mailbox();
+    (ReplySender(sender), ReplyReceiver(receiver))
+}
+
+/// Sends a single reply.
+pub struct ReplySender<T>(Sender<T>);
+
+impl<T> ReplySender<T> {
+    /// Send the reply, which is discarded if no one is waiting for it.
+    pub fn send(self, value: T) {
+        let _ = self.0.send(value);
+    }
+}
+
+/// Resolves to a reply, or `None` if the sender went without replying.
+pub struct ReplyReceiver<T>(Receiver<T>);
+
+impl<T> Future for ReplyReceiver<T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Pin::new(&mut self.0.recv()).poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_runner::block_on;
+    use std::thread;
+
+    #[test]
+    fn test_closed() {
+        // Items queued before the last sender goes are still received,
+        // and then the mailbox ends
+        let (sender, mut receiver) = mailbox();
+        let other = sender.clone();
+        sender.send(1).unwrap();
+        drop(sender);
+        other.send(2).unwrap();
+        drop(other);
+        block_on(async {
+            assert_eq!(receiver.recv().await, Some(1));
+            assert_eq!(receiver.recv().await, Some(2));
+            assert_eq!(receiver.recv().await, None);
+        });
+
+        // An item sent after the receiver has gone comes back
+        let (sender, receiver) = mailbox();
+        drop(receiver);
+        assert_eq!(sender.send(3), Err(3));
+    }
+
+    #[test]
+    fn test_woken() {
+        // A receiver waiting on an empty mailbox is woken by a send from
+        // another thread, and by the last sender going
+        let (sender, mut receiver) = mailbox();
+        let sending = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(20));
+            sender.send("late").unwrap();
+        });
+        assert_eq!(block_on(receiver.recv()), Some("late"));
+        sending.join().unwrap();
+        assert_eq!(block_on(receiver.recv()), None);
+    }
+
+    #[test]
+    fn test_unanswered_reply() {
+        // A reply sender dropped without replying resolves the reply to
+        // None, as when a runner's task stops with requests in flight
+        let (reply_to, replied) = reply::<u32>();
+        drop(reply_to);
+        assert_eq!(block_on(replied), None);
+
+        // A reply no one waits for is discarded
+        let (reply_to, replied) = reply();
+        drop(replied);
+        reply_to.send(1);
+    }
+}
@@ -0,0 +1,99 @@
+//! Copying a journal from one backend or codec to another, upcasting
+//! events along the way, so that storage decisions can be revisited. The
+//! records are copied in order, keeping each entity's sequence numbers,
+//! and the copy stops at the first record that cannot be upcast or
+//! written.
+//!
+//! The `modular-fsm-migrate` binary, built with the `migrate` feature,
+//! does this for journal files.
+
+use crate::journal::{Journal, JournalError, Record};
+
+/// Why a migration stopped, and at which record of the source.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrateError {
+    Read(JournalError),
+    Upcast { offset: u64, reason: String },
+    Write { offset: u64, error: JournalError },
+}
+
+/// Copy the records of a source journal from an offset to a target,
+/// converting each event with `upcast`, and return the number copied.
+/// The target must not already have records for the entities copied.
+pub fn migrate<K, E, T, A, B, U>(
+    source: &A,
+    target: &mut B,
+    from_offset: u64,
+    mut upcast: U,
+) -> Result<usize, MigrateError>
+where
+    A: Journal<K, E>,
+    B: Journal<K, T>,
+    U: FnMut(&Record<K, E>) -> Result<T, String>,
+{
+    let records = source.read_all(from_offset).map_err(MigrateError::Read)?;
+    for record in &records {
+        let event = upcast(record).map_err(|reason| MigrateError::Upcast {
+            offset: record.offset,
+            reason,
+        })?;
+        // Keeping sequence numbers means the previous one must be in place
+        target
+            .append_expected(&record.key, record.seq - 1, event)
+            .map_err(|error| MigrateError::Write {
+                offset: record.offset,
+                error,
+            })?;
+    }
+    Ok(records.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::MemJournal;
+
+    #[test]
+    fn test_migrate() {
+        // Version 1 events were amounts in pounds, version 2 in pence
+
+        let mut source = MemJournal::new();
+        source.append(&"a", 1.5).unwrap();
+        source.append(&"b", 2.0).unwrap();
+        source.append(&"a", -0.25).unwrap();
+
+        let mut target = MemJournal::new();
+        let pence = |r: &Record<&str, f64>| Ok((r.event * 100.0) as i64);
+        assert_eq!(migrate(&source, &mut target, 0, pence), Ok(3));
+        let events: Vec<_> = target
+            .read(&"a", 0)
+            .unwrap()
+            .into_iter()
+            .map(|r| (r.seq, r.event))
+            .collect();
+        assert_eq!(events, vec![(1, 150), (2, -25)]);
+
+        // Copying again would not keep the sequence numbers
+        assert_eq!(
+            migrate(&source, &mut target, 0, pence),
+            Err(MigrateError::Write {
+                offset: 0,
+                error: JournalError::Conflict {
+                    expected: 0,
+                    actual: 2
+                }
+            })
+        );
+
+        let refuse = |r: &Record<&str, f64>| -> Result<i64, String> {
+            Err(format!("cannot upcast {}", r.event))
+        };
+        assert_eq!(
+            migrate(&source, &mut MemJournal::new(), 2, refuse),
+            Err(MigrateError::Upcast {
+                offset: 2,
+                reason: "cannot upcast -0.25".to_string()
+            })
+        );
+    }
+}
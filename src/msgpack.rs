@@ -0,0 +1,308 @@
+//! The MessagePack format, for compact events that consumers in other
+//! languages decode with their own MessagePack libraries, as they would
+//! JSON. `MessagePackCodec` encodes `Json` values, so events are converted
+//! to and from `Json` as they are for the JSON codec.
+//!
+//! Whole numbers are written as the smallest MessagePack integer that
+//! holds them, and other numbers as 64 bit floats. Binary and extension
+//! values have no `Json` equivalent and are refused when decoding, as
+//! are arrays and maps nested more than `json::MAX_DEPTH` deep.
+
+use crate::codec::{Codec, CodecError};
+use crate::envelope::CODEC_MESSAGEPACK;
+use crate::json::{Json, MAX_DEPTH};
+
+/// `Json` values in the MessagePack format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+fn write_length(out: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, tags: [u8; 3]) {
+    if len <= fix_max {
+        out.push(fix | len as u8);
+    } else if tags[0] != 0 && len <= u8::MAX as usize {
+        out.push(tags[0]);
+        out.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        out.push(tags[1]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(tags[2]);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn write_number(out: &mut Vec<u8>, n: f64) {
+    let whole = n.fract() == 0.0 && n >= i64::MIN as f64 && n < 2f64.powi(64);
+    if !whole {
+        out.push(0xcb);
+        out.extend_from_slice(&n.to_be_bytes());
+    } else if n >= 0.0 {
+        let n = n as u64;
+        match n {
+            0..=0x7f => out.push(n as u8),
+            0x80..=0xff => out.extend_from_slice(&[0xcc, n as u8]),
+            0x100..=0xffff => {
+                out.push(0xcd);
+                out.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(0xce);
+                out.extend_from_slice(&(n as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xcf);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+        }
+    } else {
+        let n = n as i64;
+        if n >= -32 {
+            out.push(n as i8 as u8);
+        } else if n >= i8::MIN as i64 {
+            out.extend_from_slice(&[0xd0, n as i8 as u8]);
+        } else if n >= i16::MIN as i64 {
+            out.push(0xd1);
+            out.extend_from_slice(&(n as i16).to_be_bytes());
+        } else if n >= i32::MIN as i64 {
+            out.push(0xd2);
+            out.extend_from_slice(&(n as i32).to_be_bytes());
+        } else {
+            out.push(0xd3);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Json) {
+    match value {
+        Json::Null => out.push(0xc0),
+        Json::Bool(false) => out.push(0xc2),
+        Json::Bool(true) => out.push(0xc3),
+        Json::Number(n) => write_number(out, *n),
+        Json::String(s) => write_string(out, s),
+        Json::Array(items) => {
+            write_length(out, items.len(), 0x90, 15, [0, 0xdc, 0xdd]);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        Json::Object(members) => {
+            write_length(out, members.len(), 0x80, 15, [0, 0xde, 0xdf]);
+            for (k, v) in members {
+                write_string(out, k);
+                write_value(out, v);
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    write_length(out, s.len(), 0xa0, 31, [0xd9, 0xda, 0xdb]);
+    out.extend_from_slice(s.as_bytes());
+}
+
+/// Reads values from the start of some bytes, within arrays and maps
+/// nested to a depth.
+struct Reader<'a>(&'a [u8], usize);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < n {
+            return Err(format!("expected {n} more bytes, found {}", self.0.len()));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn nested(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<Json, String>,
+    ) -> Result<Json, String> {
+        if self.1 == MAX_DEPTH {
+            return Err(format!("nested more than {MAX_DEPTH} deep"));
+        }
+        self.1 += 1;
+        let value = read(self);
+        self.1 -= 1;
+        value
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().expect("N bytes were taken"))
+    }
+
+    fn length(&mut self, width: usize) -> Result<usize, String> {
+        Ok(match width {
+            1 => u8::from_be_bytes(self.array()?) as usize,
+            2 => u16::from_be_bytes(self.array()?) as usize,
+            _ => u32::from_be_bytes(self.array()?) as usize,
+        })
+    }
+
+    fn string(&mut self, len: usize) -> Result<String, String> {
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn items(&mut self, len: usize) -> Result<Json, String> {
+        self.nested(|r| {
+            // Each item takes at least a byte, which bounds a corrupt length
+            let mut items = Vec::with_capacity(len.min(r.0.len()));
+            for _ in 0..len {
+                items.push(r.value()?);
+            }
+            Ok(Json::Array(items))
+        })
+    }
+
+    fn members(&mut self, len: usize) -> Result<Json, String> {
+        self.nested(|r| {
+            let mut members = Vec::with_capacity(len.min(r.0.len()));
+            for _ in 0..len {
+                let Json::String(key) = r.value()? else {
+                    return Err("a map key is not a string".to_string());
+                };
+                members.push((key, r.value()?));
+            }
+            Ok(Json::Object(members))
+        })
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        let [tag] = self.array()?;
+        Ok(match tag {
+            0x00..=0x7f => Json::Number(tag as f64),
+            0x80..=0x8f => self.members((tag & 0x0f) as usize)?,
+            0x90..=0x9f => self.items((tag & 0x0f) as usize)?,
+            0xa0..=0xbf => Json::String(self.string((tag & 0x1f) as usize)?),
+            0xc0 => Json::Null,
+            0xc2 => Json::Bool(false),
+            0xc3 => Json::Bool(true),
+            0xca => Json::Number(f32::from_be_bytes(self.array()?) as f64),
+            0xcb => Json::Number(f64::from_be_bytes(self.array()?)),
+            0xcc => Json::Number(u8::from_be_bytes(self.array()?) as f64),
+            0xcd => Json::Number(u16::from_be_bytes(self.array()?) as f64),
+            0xce => Json::Number(u32::from_be_bytes(self.array()?) as f64),
+            0xcf => Json::Number(u64::from_be_bytes(self.array()?) as f64),
+            0xd0 => Json::Number(i8::from_be_bytes(self.array()?) as f64),
+            0xd1 => Json::Number(i16::from_be_bytes(self.array()?) as f64),
+            0xd2 => Json::Number(i32::from_be_bytes(self.array()?) as f64),
+            0xd3 => Json::Number(i64::from_be_bytes(self.array()?) as f64),
+            0xd9 => {
+                let len = self.length(1)?;
+                Json::String(self.string(len)?)
+            }
+            0xda => {
+                let len = self.length(2)?;
+                Json::String(self.string(len)?)
+            }
+            0xdb => {
+                let len = self.length(4)?;
+                Json::String(self.string(len)?)
+            }
+            0xdc => {
+                let len = self.length(2)?;
+                self.items(len)?
+            }
+            0xdd => {
+                let len = self.length(4)?;
+                self.items(len)?
+            }
+            0xde => {
+                let len = self.length(2)?;
+                self.members(len)?
+            }
+            0xdf => {
+                let len = self.length(4)?;
+                self.members(len)?
+            }
+            0xe0..=0xff => Json::Number(tag as i8 as f64),
+            tag => return Err(format!("tag {tag:#04x} has no JSON equivalent")),
+        })
+    }
+}
+
+impl Codec<Json> for MessagePackCodec {
+    fn id(&self) -> u8 {
+        CODEC_MESSAGEPACK
+    }
+
+    fn encode(&self, value: &Json) -> Result<Vec<u8>, CodecError> {
+        let mut out = Vec::new();
+        write_value(&mut out, value);
+        Ok(out)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Json, CodecError> {
+        let mut reader = Reader(bytes, 0);
+        let value = reader.value().map_err(CodecError::Decode)?;
+        if !reader.0.is_empty() {
+            let trailing = format!("{} bytes follow the value", reader.0.len());
+            return Err(CodecError::Decode(trailing));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_pack() {
+        // A deposit, as a Python or Node consumer would receive it
+
+        let event = Json::parse(r#"{"type":"Deposited","amount":5}"#).unwrap();
+        let bytes = MessagePackCodec.encode(&event).unwrap();
+        let mut expected = vec![0x82, 0xa4];
+        expected.extend(b"type");
+        expected.push(0xa9);
+        expected.extend(b"Deposited");
+        expected.push(0xa6);
+        expected.extend(b"amount");
+        expected.push(0x05);
+        assert_eq!(bytes, expected);
+        assert_eq!(MessagePackCodec.decode(&bytes), Ok(event));
+
+        let number = |n: f64| MessagePackCodec.encode(&Json::Number(n)).unwrap();
+        assert_eq!(number(-1.0), [0xff]);
+        assert_eq!(number(-33.0), [0xd0, 0xdf]);
+        assert_eq!(number(300.0), [0xcd, 0x01, 0x2c]);
+        assert_eq!(number(1.5)[0], 0xcb);
+
+        let long = Json::Array(vec![
+            Json::String("x".repeat(300)),
+            Json::Number(-70000.0),
+            Json::Number(1e19),
+            Json::Array((0..20).map(|i| Json::Number(i as f64)).collect()),
+            Json::Null,
+            Json::Bool(true),
+        ]);
+        let bytes = MessagePackCodec.encode(&long).unwrap();
+        assert_eq!(MessagePackCodec.decode(&bytes), Ok(long));
+
+        assert!(MessagePackCodec.decode(&[0xc4, 1, 0]).is_err());
+        assert!(MessagePackCodec.decode(&[0x81, 0x01, 0x01]).is_err());
+        assert!(MessagePackCodec.decode(&[0x92, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_depth() {
+        // Arrays nested to the limit decode, and deeper ones are refused
+        // rather than overflowing the stack
+        let mut nested = vec![0x91; MAX_DEPTH - 1];
+        nested.push(0x90);
+        assert!(MessagePackCodec.decode(&nested).is_ok());
+        nested.insert(0, 0x91);
+        assert_eq!(
+            MessagePackCodec.decode(&nested),
+            Err(CodecError::Decode(format!(
+                "nested more than {MAX_DEPTH} deep"
+            )))
+        );
+        assert!(MessagePackCodec.decode(&[0x91; 10_000]).is_err());
+        assert!(MessagePackCodec
+            .decode(&[0x81, 0xa1, b'a'].repeat(10_000))
+            .is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Observers are notified as a runner steps its entities, for
+//! recording what happens without involving the FSM itself.
+//! Commands and events are described by their type names, without
+//! module paths.
+
+use std::any::type_name;
+use std::fmt::Debug;
+use std::io::Write;
+
+use crate::runner::RunError;
+
+/// The name of a type without module paths e.g. `Outcome<Connected, ConnectFailed>`.
+pub fn type_label<T: ?Sized>() -> String {
+    let mut label = String::new();
+    let mut segment = String::new();
+    let mut chars = type_name::<T>().chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            segment.clear();
+        } else if c.is_alphanumeric() || c == '_' {
+            segment.push(c);
+        } else {
+            label.push_str(&segment);
+            segment.clear();
+            label.push(c);
+        }
+    }
+    label.push_str(&segment);
+    label
+}
+
+/// Notified of what happens to each entity of a runner.
+pub trait Observer<K, S> {
+    /// An entity was added, or replaced, with a state.
+    fn inserted(&mut self, _key: &K, _state: &S) {}
+
+    /// An entity was removed.
+    fn removed(&mut self, _key: &K) {}
+
+    /// An event transitioned an entity to a new state.
+    fn transitioned(&mut self, _key: &K, _from: &S, _event: &str, _to: &S) {}
+
+    /// A command emitted no event.
+    fn ignored(&mut self, _key: &K, _state: &S, _command: &str) {}
+
+    /// A command could not be stepped.
+    fn rejected(&mut self, _key: &K, _command: &str, _error: &RunError) {}
+}
+
+/// A lightweight observer writing log lines: transitions at debug level
+/// and ignored or rejected commands at warn level.
+pub struct LogObserver<W> {
+    out: W,
+    debug: bool,
+}
+
+impl<W> LogObserver<W>
+where
+    W: Write,
+{
+    pub fn new(out: W) -> Self {
+        Self { out, debug: true }
+    }
+
+    /// Write only the warn level lines.
+    pub fn warn_only(mut self) -> Self {
+        self.debug = false;
+        self
+    }
+}
+
+impl<K, S, W> Observer<K, S> for LogObserver<W>
+where
+    K: Debug,
+    S: Debug,
+    W: Write,
+{
+    fn transitioned(&mut self, key: &K, from: &S, event: &str, to: &S) {
+        if self.debug {
+            let _ = writeln!(self.out, "DEBUG {key:?}: {from:?} -> {to:?} on {event}");
+        }
+    }
+
+    fn ignored(&mut self, key: &K, state: &S, command: &str) {
+        let _ = writeln!(self.out, "WARN {key:?}: {command} ignored in {state:?}");
+    }
+
+    fn rejected(&mut self, key: &K, command: &str, error: &RunError) {
+        let _ = writeln!(self.out, "WARN {key:?}: {command} rejected {error:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+    use crate::runner::Runner;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_type_label() {
+        assert_eq!(type_label::<u32>(), "u32");
+        assert_eq!(type_label::<Vec<String>>(), "Vec<String>");
+        assert_eq!(type_label::<RunError>(), "RunError");
+    }
+
+    #[test]
+    fn test_log_observer() {
+        // A latch that can only be set once
+
+        struct Set {}
+
+        struct WasSet {}
+
+        impl Command<bool, ()> for Set {
+            type Output = WasSet;
+            fn execute(&self, s: &bool, _h: &mut ()) -> Option<WasSet> {
+                if *s {
+                    None
+                } else {
+                    Some(WasSet {})
+                }
+            }
+        }
+
+        impl Validate<bool> for Set {}
+
+        impl Event<bool> for WasSet {
+            fn fire(&self, _s: &bool) -> Transition<bool> {
+                Transition::Next(true)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<bool, ()> for MyFsm {}
+
+        // Capture the log lines
+
+        #[derive(Clone, Default)]
+        struct Lines(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Lines {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let lines = Lines::default();
+        let mut runner = Runner::<u32, bool, (), MyFsm>::new(())
+            .with_observer(Box::new(LogObserver::new(lines.clone())));
+        runner.insert(1, false);
+
+        assert!(runner.send(&1, &Set {}).is_ok());
+        assert!(runner.send(&1, &Set {}).is_ok());
+        assert!(runner.send(&2, &Set {}).is_err());
+
+        let text = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+        let text: Vec<_> = text
+            .lines()
+            .map(|l| l.replace("fsm_laboratory::observer::tests::test_log_observer::", ""))
+            .collect();
+        assert_eq!(
+            text,
+            vec![
+                "DEBUG 1: false -> true on WasSet",
+                "WARN 1: Set ignored in true",
+                "WARN 2: Set rejected Unknown",
+            ]
+        );
+    }
+}
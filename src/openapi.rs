@@ -0,0 +1,304 @@
+//! An OpenAPI document for serving a machine over HTTP, generated from
+//! its `MachineSchemas` so that clients can generate SDKs. The routes,
+//! under a base path for the machine's entities, are:
+//!
+//! ```text
+//! POST {base}/{id}/commands   step the entity with a command
+//! GET  {base}/{id}            the entity's state
+//! GET  {base}/{id}/events     the entity's events, as served by `sse`
+//! ```
+//!
+//! A command answers with its event, or no content if it emitted none,
+//! and a refused command with the status of its `RunError`, from
+//! `status_of`, and a JSON body with the reason. The document is OpenAPI
+//! 3.1, whose schemas are JSON Schema 2020-12, so the command and event
+//! schemas are its components as they are.
+
+use crate::json::Json;
+use crate::json_schema::MachineSchemas;
+use crate::runner::RunError;
+
+/// The HTTP status a route answers with for a refused command.
+pub fn status_of(error: &RunError) -> u16 {
+    match error {
+        RunError::Denied(_) | RunError::FeatureDisabled(_) => 403,
+        RunError::Unknown => 404,
+        RunError::Conflict { .. } => 409,
+        RunError::Step(_) => 422,
+        RunError::RateLimited | RunError::Dropped => 429,
+        RunError::Stopped => 503,
+        RunError::TimedOut => 504,
+        RunError::Failed(_)
+        | RunError::Panicked(_)
+        | RunError::Aborted(_)
+        | RunError::Cancelled => 500,
+    }
+}
+
+fn text(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        members
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+    )
+}
+
+fn reference(name: &str) -> Json {
+    object(vec![(
+        "$ref",
+        text(&format!("#/components/schemas/{name}")),
+    )])
+}
+
+/// Content of a JSON body with a schema.
+fn json_content(schema: Json) -> Json {
+    object(vec![("application/json", object(vec![("schema", schema)]))])
+}
+
+fn response(description: &str, schema: Option<Json>) -> Json {
+    let mut members = vec![("description", text(description))];
+    if let Some(schema) = schema {
+        members.push(("content", json_content(schema)));
+    }
+    object(members)
+}
+
+/// An OpenAPI document for a machine's routes.
+pub struct OpenApi<'a> {
+    schemas: &'a MachineSchemas,
+    base: String,
+    version: String,
+    state: Json,
+}
+
+impl<'a> OpenApi<'a> {
+    /// The routes of a machine's entities, under `/{name}`, with any state.
+    pub fn new(schemas: &'a MachineSchemas) -> Self {
+        Self {
+            schemas,
+            base: format!("/{}", schemas.name),
+            version: "1".to_string(),
+            state: Json::Object(Vec::new()),
+        }
+    }
+
+    /// Serve the entities under another base path.
+    pub fn with_base(mut self, base: &str) -> Self {
+        self.base = base.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// The version of the API, as given in the document's info.
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// The schema of an entity's state.
+    pub fn with_state(mut self, state: Json) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// The OpenAPI document.
+    pub fn document(&self) -> Json {
+        let name = &self.schemas.name;
+        let id = object(vec![
+            ("name", text("id")),
+            ("in", text("path")),
+            ("required", Json::Bool(true)),
+            ("schema", object(vec![("type", text("string"))])),
+        ]);
+        let refused = |description: &str| response(description, Some(reference("Error")));
+        let send = object(vec![
+            ("operationId", text(&format!("send_{name}_command"))),
+            ("parameters", Json::Array(vec![id.clone()])),
+            (
+                "requestBody",
+                object(vec![
+                    ("required", Json::Bool(true)),
+                    ("content", json_content(reference("commands"))),
+                ]),
+            ),
+            (
+                "responses",
+                object(vec![
+                    (
+                        "200",
+                        response("The event the command emitted", Some(reference("events"))),
+                    ),
+                    ("204", response("The command emitted no event", None)),
+                    ("403", refused("The caller may not send the command")),
+                    ("404", refused("There is no such entity")),
+                    ("409", refused("The entity was not at the expected version")),
+                    (
+                        "422",
+                        refused("The command was not allowed, invalid or vetoed"),
+                    ),
+                    ("429", refused("The command exceeded a rate limit")),
+                ]),
+            ),
+        ]);
+        let state = object(vec![
+            ("operationId", text(&format!("get_{name}_state"))),
+            ("parameters", Json::Array(vec![id.clone()])),
+            (
+                "responses",
+                object(vec![
+                    (
+                        "200",
+                        response("The entity's state", Some(reference("State"))),
+                    ),
+                    ("404", refused("There is no such entity")),
+                ]),
+            ),
+        ]);
+        let last_event_id = object(vec![
+            ("name", text("Last-Event-ID")),
+            ("in", text("header")),
+            ("required", Json::Bool(false)),
+            ("schema", object(vec![("type", text("integer"))])),
+        ]);
+        let stream = object(vec![(
+            "text/event-stream",
+            object(vec![("schema", object(vec![("type", text("string"))]))]),
+        )]);
+        let streamed =
+            "The entity's events after the last event id, with their sequence numbers as ids";
+        let events = object(vec![
+            ("operationId", text(&format!("stream_{name}_events"))),
+            ("parameters", Json::Array(vec![id, last_event_id])),
+            (
+                "responses",
+                object(vec![(
+                    "200",
+                    object(vec![("description", text(streamed)), ("content", stream)]),
+                )]),
+            ),
+        ]);
+
+        let mut components = self.schemas.components();
+        components.push(("State".to_string(), self.state.clone()));
+        components.push((
+            "Error".to_string(),
+            object(vec![
+                ("type", text("object")),
+                (
+                    "properties",
+                    object(vec![("error", object(vec![("type", text("string"))]))]),
+                ),
+                ("required", Json::Array(vec![text("error")])),
+            ]),
+        ));
+
+        let base = &self.base;
+        object(vec![
+            ("openapi", text("3.1.0")),
+            (
+                "info",
+                object(vec![
+                    ("title", text(name)),
+                    ("version", text(&self.version)),
+                ]),
+            ),
+            (
+                "paths",
+                Json::Object(vec![
+                    (
+                        format!("{base}/{{id}}/commands"),
+                        object(vec![("post", send)]),
+                    ),
+                    (format!("{base}/{{id}}"), object(vec![("get", state)])),
+                    (
+                        format!("{base}/{{id}}/events"),
+                        object(vec![("get", events)]),
+                    ),
+                ]),
+            ),
+            (
+                "components",
+                object(vec![("schemas", Json::Object(components))]),
+            ),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{StepError, ValidationError};
+    use crate::json_schema::{tagged_schema, JsonSchema};
+
+    #[test]
+    fn test_openapi() {
+        // An account API, its deposits documented for client generators
+
+        struct Deposit {}
+
+        struct Deposited {}
+
+        impl JsonSchema for Deposit {
+            fn json_schema() -> Json {
+                tagged_schema("Deposit", vec![("amount", u64::json_schema())], &[])
+            }
+        }
+
+        impl JsonSchema for Deposited {
+            fn json_schema() -> Json {
+                tagged_schema("Deposited", vec![("amount", u64::json_schema())], &[])
+            }
+        }
+
+        let schemas = MachineSchemas::new("account")
+            .command::<Deposit>()
+            .event::<Deposited>();
+        let document = OpenApi::new(&schemas)
+            .with_base("/accounts/")
+            .with_state(u64::json_schema())
+            .document();
+
+        assert_eq!(
+            document.get("openapi").and_then(Json::as_str),
+            Some("3.1.0")
+        );
+        let paths = document.get("paths").unwrap();
+        let send = paths
+            .get("/accounts/{id}/commands")
+            .and_then(|p| p.get("post"))
+            .unwrap();
+        assert_eq!(
+            send.get("requestBody")
+                .and_then(|b| b.get("content"))
+                .and_then(|c| c.get("application/json"))
+                .and_then(|j| j.get("schema")),
+            Some(&reference("commands"))
+        );
+        assert!(send.get("responses").and_then(|r| r.get("422")).is_some());
+        assert!(paths.get("/accounts/{id}/events").is_some());
+
+        // References between schemas point into the components
+        let schemas = document
+            .get("components")
+            .and_then(|c| c.get("schemas"))
+            .unwrap();
+        let commands = schemas.get("commands").unwrap().to_string();
+        assert_eq!(
+            commands,
+            r##"{"oneOf":[{"$ref":"#/components/schemas/Deposit"}]}"##
+        );
+        assert_eq!(schemas.get("State"), Some(&u64::json_schema()));
+
+        assert_eq!(status_of(&RunError::Unknown), 404);
+        assert_eq!(
+            status_of(&RunError::Step(StepError::Invalid(ValidationError(
+                "no".to_string()
+            )))),
+            422
+        );
+    }
+}
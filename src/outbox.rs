@@ -0,0 +1,364 @@
+//! The relay half of the outbox pattern. Events enlisted in a unit of work
+//! are stored as outbox rows in the same commit as the journal, and a
+//! `Relay` delivers them afterwards to a `Publisher`, such as a broker
+//! client, marking each row delivered once the publisher confirms it.
+//!
+//! Delivery is at least once. A row whose delivery fails is retried
+//! with exponential backoff, and later rows for the same entity wait for
+//! it, so that each entity's events are published in order. A relay can
+//! be polled directly or run on its own thread with `spawn`.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// An event waiting in the outbox to be published.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxRow<K, E> {
+    pub id: u64,
+    pub key: K,
+    pub event: E,
+}
+
+/// Stored events for entities with keys K, waiting to be published.
+pub trait Outbox<K, E> {
+    /// The oldest rows not yet delivered, in the order they were added.
+    fn unpublished(&self, limit: usize) -> Result<Vec<OutboxRow<K, E>>, String>;
+
+    fn mark_delivered(&mut self, id: u64) -> Result<(), String>;
+}
+
+/// An outbox shared with a relay on another thread.
+impl<K, E, O> Outbox<K, E> for Arc<Mutex<O>>
+where
+    O: Outbox<K, E>,
+{
+    fn unpublished(&self, limit: usize) -> Result<Vec<OutboxRow<K, E>>, String> {
+        self.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .unpublished(limit)
+    }
+
+    fn mark_delivered(&mut self, id: u64) -> Result<(), String> {
+        self.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .mark_delivered(id)
+    }
+}
+
+/// An outbox held in memory.
+#[derive(Debug)]
+pub struct MemOutbox<K, E> {
+    rows: Vec<OutboxRow<K, E>>,
+    next: u64,
+}
+
+impl<K, E> Default for MemOutbox<K, E> {
+    fn default() -> Self {
+        Self {
+            rows: Vec::new(),
+            next: 0,
+        }
+    }
+}
+
+impl<K, E> MemOutbox<K, E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an event, returning the id of its row.
+    pub fn push(&mut self, key: K, event: E) -> u64 {
+        let id = self.next;
+        self.next += 1;
+        self.rows.push(OutboxRow { id, key, event });
+        id
+    }
+
+    /// The number of rows not yet delivered.
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The events not yet delivered.
+    pub fn pending(&self) -> Vec<(K, E)>
+    where
+        K: Clone,
+        E: Clone,
+    {
+        self.rows
+            .iter()
+            .map(|r| (r.key.clone(), r.event.clone()))
+            .collect()
+    }
+}
+
+impl<K, E> Outbox<K, E> for MemOutbox<K, E>
+where
+    K: Clone,
+    E: Clone,
+{
+    fn unpublished(&self, limit: usize) -> Result<Vec<OutboxRow<K, E>>, String> {
+        Ok(self.rows.iter().take(limit).cloned().collect())
+    }
+
+    fn mark_delivered(&mut self, id: u64) -> Result<(), String> {
+        self.rows.retain(|r| r.id != id);
+        Ok(())
+    }
+}
+
+/// Delivers events, confirming each by returning `Ok`.
+pub trait Publisher<K, E> {
+    fn publish(&mut self, key: &K, event: &E) -> Result<(), String>;
+}
+
+impl<K, E, F> Publisher<K, E> for F
+where
+    F: FnMut(&K, &E) -> Result<(), String>,
+{
+    fn publish(&mut self, key: &K, event: &E) -> Result<(), String> {
+        self(key, event)
+    }
+}
+
+/// Exponential backoff between attempts to deliver a row.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay after a number of failed attempts, at least one.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Counts of the relay's work.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RelayMetrics {
+    pub delivered: u64,
+    pub failures: u64,
+    /// Rows waiting for their backoff to elapse
+    pub retrying: usize,
+}
+
+struct Retry {
+    failures: u32,
+    at: Instant,
+}
+
+/// Delivers rows from an outbox O to a publisher P.
+pub struct Relay<O, P> {
+    outbox: O,
+    publisher: P,
+    batch: usize,
+    backoff: Backoff,
+    retries: HashMap<u64, Retry>,
+    metrics: RelayMetrics,
+}
+
+impl<O, P> Relay<O, P> {
+    pub fn new(outbox: O, publisher: P) -> Self {
+        Self {
+            outbox,
+            publisher,
+            batch: 100,
+            backoff: Backoff::default(),
+            retries: HashMap::new(),
+            metrics: RelayMetrics::default(),
+        }
+    }
+
+    /// The most rows considered in each poll.
+    pub fn with_batch(mut self, batch: usize) -> Self {
+        self.batch = batch.max(1);
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn outbox(&self) -> &O {
+        &self.outbox
+    }
+
+    pub fn publisher(&self) -> &P {
+        &self.publisher
+    }
+
+    pub fn metrics(&self) -> RelayMetrics {
+        RelayMetrics {
+            retrying: self.retries.len(),
+            ..self.metrics
+        }
+    }
+
+    /// Deliver the rows that are due, returning the number delivered.
+    pub fn poll<K, E>(&mut self, now: Instant) -> Result<usize, String>
+    where
+        O: Outbox<K, E>,
+        P: Publisher<K, E>,
+        K: Eq + Hash,
+    {
+        let mut delivered = 0;
+        let mut blocked = HashSet::new();
+        for row in self.outbox.unpublished(self.batch)? {
+            if blocked.contains(&row.key) {
+                continue;
+            }
+            if self.retries.get(&row.id).is_some_and(|r| r.at > now) {
+                blocked.insert(row.key);
+                continue;
+            }
+            match self.publisher.publish(&row.key, &row.event) {
+                Ok(()) => {
+                    self.outbox.mark_delivered(row.id)?;
+                    self.retries.remove(&row.id);
+                    self.metrics.delivered += 1;
+                    delivered += 1;
+                }
+                Err(_) => {
+                    let retry = self.retries.entry(row.id).or_insert(Retry {
+                        failures: 0,
+                        at: now,
+                    });
+                    retry.failures += 1;
+                    retry.at = now + self.backoff.delay(retry.failures);
+                    self.metrics.failures += 1;
+                    blocked.insert(row.key);
+                }
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Poll on a thread of its own, at an interval, until stopped.
+    pub fn spawn<K, E>(self, interval: Duration) -> RelayThread<O, P>
+    where
+        O: Outbox<K, E> + Send + 'static,
+        P: Publisher<K, E> + Send + 'static,
+        K: Eq + Hash,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut relay = self;
+            loop {
+                let _ = relay.poll(Instant::now());
+                if stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+                    return relay;
+                }
+            }
+        });
+        RelayThread { stop, thread }
+    }
+}
+
+/// A relay running on its own thread.
+pub struct RelayThread<O, P> {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<Relay<O, P>>,
+}
+
+impl<O, P> RelayThread<O, P> {
+    /// Stop polling and return the relay.
+    pub fn stop(self) -> Relay<O, P> {
+        let _ = self.stop.send(());
+        match self.thread.join() {
+            Ok(relay) => relay,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relay() {
+        // A broker which is down for its first two deliveries
+
+        let mut outbox = MemOutbox::new();
+        outbox.push("a", 1);
+        outbox.push("a", 2);
+        outbox.push("b", 3);
+
+        let mut down = 2;
+        let broker = move |key: &&str, event: &u32| {
+            if down > 0 {
+                down -= 1;
+                Err("broker unavailable".to_string())
+            } else {
+                assert!(!key.is_empty() && *event > 0);
+                Ok(())
+            }
+        };
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(3),
+        };
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(2));
+        assert_eq!(backoff.delay(5), Duration::from_secs(3));
+        let mut relay = Relay::new(outbox, broker).with_backoff(backoff);
+
+        // Event 2 waits behind event 1 of the same entity
+        let start = Instant::now();
+        assert_eq!(relay.poll(start), Ok(0));
+        assert_eq!(relay.outbox().pending(), vec![("a", 1), ("a", 2), ("b", 3)]);
+        assert_eq!(relay.poll(start), Ok(0));
+        assert_eq!(relay.poll(start + Duration::from_millis(500)), Ok(0));
+        assert_eq!(
+            relay.metrics(),
+            RelayMetrics {
+                delivered: 0,
+                failures: 2,
+                retrying: 2
+            }
+        );
+
+        assert_eq!(relay.poll(start + Duration::from_secs(1)), Ok(3));
+        assert!(relay.outbox().is_empty());
+        assert_eq!(
+            relay.metrics(),
+            RelayMetrics {
+                delivered: 3,
+                failures: 2,
+                retrying: 0
+            }
+        );
+
+        // On its own thread, with a shared outbox
+        let outbox = Arc::new(Mutex::new(MemOutbox::new()));
+        let (sent, published) = mpsc::channel();
+        let relay = Relay::new(outbox.clone(), move |key: &&'static str, event: &u32| {
+            sent.send((*key, *event)).map_err(|e| e.to_string())
+        })
+        .spawn(Duration::from_millis(5));
+        outbox.lock().unwrap().push("c", 4);
+        assert_eq!(published.recv_timeout(Duration::from_secs(5)), Ok(("c", 4)));
+        assert_eq!(relay.stop().metrics().delivered, 1);
+    }
+}
@@ -0,0 +1,330 @@
+//! Events which are JSON patches, as described in RFC 6902, for
+//! machines whose state is semi-structured `Json` rather than a closed
+//! type, and for generic admin tooling. A `PatchEvent` is a list of
+//! operations applied in order; if any operation fails, including a
+//! `test`, the patch as a whole is not applied and the state is the same.
+//!
+//! Paths are JSON pointers, as described in RFC 6901. Values are compared
+//! as JSON, so a `test` of an object passes whatever the order of its
+//! members.
+
+use crate::command_and_event_traits::{Event, Transition};
+use crate::json::Json;
+
+/// An operation of a patch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchOp {
+    Add { path: String, value: Json },
+    Remove { path: String },
+    Replace { path: String, value: Json },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Json },
+}
+
+/// Why a patch could not be read or applied, and at which operation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchError {
+    pub index: usize,
+    pub message: String,
+}
+
+/// An event applying a patch to the state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatchEvent(pub Vec<PatchOp>);
+
+impl PatchEvent {
+    /// Read a patch document, an array of operation objects.
+    pub fn parse(doc: &Json) -> Result<Self, PatchError> {
+        let error = |index, message: &str| PatchError {
+            index,
+            message: message.to_string(),
+        };
+        let items = doc
+            .as_array()
+            .ok_or_else(|| error(0, "a patch must be an array"))?;
+        let mut ops = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            let member = |key| {
+                item.get(key)
+                    .ok_or_else(|| error(i, &format!("missing member {key}")))
+            };
+            let text = |key| {
+                member(key)?
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| error(i, &format!("member {key} must be a string")))
+            };
+            let op = match text("op")?.as_str() {
+                "add" => PatchOp::Add {
+                    path: text("path")?,
+                    value: member("value")?.clone(),
+                },
+                "remove" => PatchOp::Remove {
+                    path: text("path")?,
+                },
+                "replace" => PatchOp::Replace {
+                    path: text("path")?,
+                    value: member("value")?.clone(),
+                },
+                "move" => PatchOp::Move {
+                    from: text("from")?,
+                    path: text("path")?,
+                },
+                "copy" => PatchOp::Copy {
+                    from: text("from")?,
+                    path: text("path")?,
+                },
+                "test" => PatchOp::Test {
+                    path: text("path")?,
+                    value: member("value")?.clone(),
+                },
+                other => return Err(error(i, &format!("unknown op {other}"))),
+            };
+            ops.push(op);
+        }
+        Ok(Self(ops))
+    }
+
+    /// The patch document, as read by `parse`.
+    pub fn to_json(&self) -> Json {
+        let text = |s: &str| Json::String(s.to_string());
+        let op = |op: &str, members: &[(&str, Json)]| {
+            let mut object = vec![("op".to_string(), text(op))];
+            object.extend(members.iter().map(|(k, v)| (k.to_string(), v.clone())));
+            Json::Object(object)
+        };
+        Json::Array(
+            self.0
+                .iter()
+                .map(|o| match o {
+                    PatchOp::Add { path, value } => {
+                        op("add", &[("path", text(path)), ("value", value.clone())])
+                    }
+                    PatchOp::Remove { path } => op("remove", &[("path", text(path))]),
+                    PatchOp::Replace { path, value } => {
+                        op("replace", &[("path", text(path)), ("value", value.clone())])
+                    }
+                    PatchOp::Move { from, path } => {
+                        op("move", &[("from", text(from)), ("path", text(path))])
+                    }
+                    PatchOp::Copy { from, path } => {
+                        op("copy", &[("from", text(from)), ("path", text(path))])
+                    }
+                    PatchOp::Test { path, value } => {
+                        op("test", &[("path", text(path)), ("value", value.clone())])
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Apply the patch to a document, returning the patched document.
+    pub fn apply(&self, doc: &Json) -> Result<Json, PatchError> {
+        let mut doc = doc.clone();
+        for (index, op) in self.0.iter().enumerate() {
+            apply_op(&mut doc, op).map_err(|message| PatchError { index, message })?;
+        }
+        Ok(doc)
+    }
+}
+
+impl Event<Json> for PatchEvent {
+    fn fire(&self, state: &Json) -> Transition<Json> {
+        match self.apply(state) {
+            Ok(doc) if !doc.equivalent(state) => Transition::Next(doc),
+            _ => Transition::Same,
+        }
+    }
+}
+
+fn apply_op(doc: &mut Json, op: &PatchOp) -> Result<(), String> {
+    match op {
+        PatchOp::Add { path, value } => add(doc, &pointer(path)?, value.clone()),
+        PatchOp::Remove { path } => remove(doc, &pointer(path)?).map(|_| ()),
+        PatchOp::Replace { path, value } => {
+            let target = get_mut(doc, &pointer(path)?).ok_or(format!("{path} not found"))?;
+            *target = value.clone();
+            Ok(())
+        }
+        PatchOp::Move { from, path } => {
+            if path.starts_with(&format!("{from}/")) {
+                return Err(format!("cannot move {from} into itself"));
+            }
+            let value = remove(doc, &pointer(from)?)?;
+            add(doc, &pointer(path)?, value)
+        }
+        PatchOp::Copy { from, path } => {
+            let value = get_mut(doc, &pointer(from)?)
+                .ok_or(format!("{from} not found"))?
+                .clone();
+            add(doc, &pointer(path)?, value)
+        }
+        PatchOp::Test { path, value } => match get_mut(doc, &pointer(path)?) {
+            Some(target) if target.equivalent(value) => Ok(()),
+            _ => Err(format!("test of {path} failed")),
+        },
+    }
+}
+
+/// The reference tokens of a JSON pointer.
+fn pointer(path: &str) -> Result<Vec<String>, String> {
+    if path.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = path
+        .strip_prefix('/')
+        .ok_or(format!("{path} is not a JSON pointer"))?;
+    Ok(rest
+        .split('/')
+        .map(|t| t.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn index(token: &str, len: usize) -> Option<usize> {
+    let leading_zero = token.len() > 1 && token.starts_with('0');
+    if leading_zero || !token.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    token.parse().ok().filter(|i| *i < len)
+}
+
+fn get_mut<'a>(doc: &'a mut Json, tokens: &[String]) -> Option<&'a mut Json> {
+    tokens.iter().try_fold(doc, |value, token| match value {
+        Json::Object(members) => members.iter_mut().find(|(k, _)| k == token).map(|(_, v)| v),
+        Json::Array(items) => {
+            let i = index(token, items.len())?;
+            items.get_mut(i)
+        }
+        _ => None,
+    })
+}
+
+fn add(doc: &mut Json, tokens: &[String], value: Json) -> Result<(), String> {
+    let Some((last, parent)) = tokens.split_last() else {
+        *doc = value;
+        return Ok(());
+    };
+    match get_mut(doc, parent) {
+        Some(Json::Object(members)) => {
+            match members.iter_mut().find(|(k, _)| k == last) {
+                Some((_, v)) => *v = value,
+                None => members.push((last.clone(), value)),
+            }
+            Ok(())
+        }
+        Some(Json::Array(items)) => {
+            let i = if last == "-" {
+                items.len()
+            } else {
+                index(last, items.len() + 1).ok_or(format!("invalid index {last}"))?
+            };
+            items.insert(i, value);
+            Ok(())
+        }
+        _ => Err(format!("no container for {last}")),
+    }
+}
+
+fn remove(doc: &mut Json, tokens: &[String]) -> Result<Json, String> {
+    let (last, parent) = tokens
+        .split_last()
+        .ok_or("cannot remove the whole document")?;
+    match get_mut(doc, parent) {
+        Some(Json::Object(members)) => {
+            let i = members
+                .iter()
+                .position(|(k, _)| k == last)
+                .ok_or(format!("{last} not found"))?;
+            Ok(members.remove(i).1)
+        }
+        Some(Json::Array(items)) => {
+            let i = index(last, items.len()).ok_or(format!("invalid index {last}"))?;
+            Ok(items.remove(i))
+        }
+        _ => Err(format!("no container for {last}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patch_event() {
+        // A customer record edited by an admin tool
+
+        let state = Json::parse(r#"{"name": "ann", "tags": ["new"], "a/b": {"~": 1}}"#).unwrap();
+        let patch = PatchEvent::parse(
+            &Json::parse(
+                r#"[
+                    {"op": "test", "path": "/name", "value": "ann"},
+                    {"op": "replace", "path": "/name", "value": "Ann"},
+                    {"op": "add", "path": "/tags/0", "value": "vip"},
+                    {"op": "add", "path": "/tags/-", "value": "late"},
+                    {"op": "remove", "path": "/tags/1"},
+                    {"op": "copy", "from": "/a~1b/~0", "path": "/count"},
+                    {"op": "move", "from": "/a~1b", "path": "/extra"}
+                ]"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let expected = Json::parse(
+            r#"{"name": "Ann", "tags": ["vip", "late"], "count": 1, "extra": {"~": 1}}"#,
+        )
+        .unwrap();
+        assert_eq!(patch.fire(&state), Transition::Next(expected.clone()));
+        assert_eq!(PatchEvent::parse(&patch.to_json()), Ok(patch.clone()));
+
+        // A failed test leaves the state as it was
+        let stale = PatchEvent(vec![
+            PatchOp::Remove {
+                path: "/tags".to_string(),
+            },
+            PatchOp::Test {
+                path: "/name".to_string(),
+                value: Json::String("bob".to_string()),
+            },
+        ]);
+        assert_eq!(
+            stale.apply(&state),
+            Err(PatchError {
+                index: 1,
+                message: "test of /name failed".to_string()
+            })
+        );
+        assert_eq!(stale.fire(&state), Transition::Same);
+
+        assert!(PatchEvent::parse(&Json::parse(r#"[{"op": "frob"}]"#).unwrap()).is_err());
+        let bad_index = PatchEvent(vec![PatchOp::Remove {
+            path: "/tags/01".to_string(),
+        }]);
+        assert!(bad_index.apply(&state).is_err());
+    }
+
+    #[test]
+    fn test_member_order() {
+        // Objects equal but for the order of their members test equal,
+        // and a patch that only reorders them is no transition
+        let state = Json::parse(r#"{"address": {"city": "Oslo", "zip": "0150"}}"#).unwrap();
+        let test = PatchEvent(vec![PatchOp::Test {
+            path: "/address".to_string(),
+            value: Json::parse(r#"{"zip": "0150", "city": "Oslo"}"#).unwrap(),
+        }]);
+        assert_eq!(test.apply(&state), Ok(state.clone()));
+
+        let reorder = PatchEvent(vec![
+            PatchOp::Remove {
+                path: "/address/city".to_string(),
+            },
+            PatchOp::Add {
+                path: "/address/city".to_string(),
+                value: Json::String("Oslo".to_string()),
+            },
+        ]);
+        assert_ne!(reorder.apply(&state), Ok(state.clone()));
+        assert_eq!(reorder.fire(&state), Transition::Same);
+    }
+}
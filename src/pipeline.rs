@@ -0,0 +1,179 @@
+//! Pipelines wire the events of one machine into the commands of another.
+//! Each event emitted upstream is mapped, by a user function, to any
+//! number of commands for downstream entities, which are sent in order
+//! before the next upstream command. An order machine can feed a shipment
+//! machine this way, for example.
+//!
+//! Runners and pipelines are both `Dispatch`, so pipelines can be chained.
+
+use std::hash::Hash;
+
+use crate::command_and_event_traits::{Command, Fsm, Validate};
+use crate::runner::{RunError, Runner};
+
+/// Something that commands of type C can be sent to, for entities with
+/// keys K.
+pub trait Dispatch<K, C> {
+    type Output;
+    fn dispatch(&mut self, key: &K, command: &C) -> Result<Option<Self::Output>, RunError>;
+}
+
+impl<K, S, H, F, C> Dispatch<K, C> for Runner<K, S, H, F>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+{
+    type Output = C::Output;
+    fn dispatch(&mut self, key: &K, command: &C) -> Result<Option<C::Output>, RunError> {
+        self.send(key, command)
+    }
+}
+
+/// Two stages, A and B, with a function M mapping the events of A into
+/// commands for B.
+pub struct Pipeline<A, B, M> {
+    upstream: A,
+    downstream: B,
+    map: M,
+    failures: Vec<RunError>,
+}
+
+impl<A, B, M> Pipeline<A, B, M> {
+    pub fn new(upstream: A, downstream: B, map: M) -> Self {
+        Self {
+            upstream,
+            downstream,
+            map,
+            failures: Vec::new(),
+        }
+    }
+
+    pub fn upstream(&self) -> &A {
+        &self.upstream
+    }
+
+    pub fn downstream(&self) -> &B {
+        &self.downstream
+    }
+
+    /// Take the errors of commands sent downstream since last taken.
+    pub fn take_failures(&mut self) -> Vec<RunError> {
+        std::mem::take(&mut self.failures)
+    }
+}
+
+/// Sending to a pipeline sends upstream, then sends the commands mapped
+/// from the upstream event downstream. The upstream event is returned.
+/// The downstream events are discarded and their errors kept as failures,
+/// since the upstream event has already been applied.
+impl<A, B, M, KA, CA, KB, CB> Dispatch<KA, CA> for Pipeline<A, B, M>
+where
+    A: Dispatch<KA, CA>,
+    B: Dispatch<KB, CB>,
+    M: FnMut(&KA, &A::Output) -> Vec<(KB, CB)>,
+{
+    type Output = A::Output;
+    fn dispatch(&mut self, key: &KA, command: &CA) -> Result<Option<A::Output>, RunError> {
+        let event = self.upstream.dispatch(key, command)?;
+        if let Some(event) = &event {
+            for (k, c) in (self.map)(key, event) {
+                if let Err(e) = self.downstream.dispatch(&k, &c) {
+                    self.failures.push(e);
+                }
+            }
+        }
+        Ok(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Transition};
+
+    #[test]
+    fn test_pipeline() {
+        // Orders that, once paid, create shipments
+
+        #[derive(Debug, PartialEq)]
+        enum Order {
+            Open,
+            Paid,
+        }
+
+        struct Pay {}
+
+        #[derive(Debug, PartialEq)]
+        struct Paid {}
+
+        impl Command<Order, ()> for Pay {
+            type Output = Paid;
+            fn execute(&self, s: &Order, _h: &mut ()) -> Option<Paid> {
+                (*s == Order::Open).then_some(Paid {})
+            }
+        }
+
+        impl Validate<Order> for Pay {}
+
+        impl Event<Order> for Paid {
+            fn fire(&self, _s: &Order) -> Transition<Order> {
+                Transition::Next(Order::Paid)
+            }
+        }
+
+        struct OrderFsm {}
+
+        impl Fsm<Order, ()> for OrderFsm {}
+
+        #[derive(Debug, PartialEq)]
+        enum Shipment {
+            Pending,
+            Packing,
+        }
+
+        struct Pack {}
+
+        #[derive(Debug, PartialEq)]
+        struct Packing {}
+
+        impl Command<Shipment, ()> for Pack {
+            type Output = Packing;
+            fn execute(&self, s: &Shipment, _h: &mut ()) -> Option<Packing> {
+                (*s == Shipment::Pending).then_some(Packing {})
+            }
+        }
+
+        impl Validate<Shipment> for Pack {}
+
+        impl Event<Shipment> for Packing {
+            fn fire(&self, _s: &Shipment) -> Transition<Shipment> {
+                Transition::Next(Shipment::Packing)
+            }
+        }
+
+        struct ShipmentFsm {}
+
+        impl Fsm<Shipment, ()> for ShipmentFsm {}
+
+        let mut orders = Runner::<u32, Order, (), OrderFsm>::new(());
+        orders.insert(1, Order::Open);
+        orders.insert(2, Order::Open);
+        let mut shipments = Runner::<u32, Shipment, (), ShipmentFsm>::new(());
+        shipments.insert(1, Shipment::Pending);
+
+        let mut pipeline = Pipeline::new(orders, shipments, |order: &u32, _: &Paid| {
+            vec![(*order, Pack {})]
+        });
+
+        assert_eq!(pipeline.dispatch(&1, &Pay {}), Ok(Some(Paid {})));
+        assert_eq!(pipeline.downstream().state(&1), Some(&Shipment::Packing));
+        assert_eq!(pipeline.dispatch(&1, &Pay {}), Ok(None));
+
+        // A missing shipment is a downstream failure, after the order is paid
+
+        assert_eq!(pipeline.dispatch(&2, &Pay {}), Ok(Some(Paid {})));
+        assert_eq!(pipeline.upstream().state(&2), Some(&Order::Paid));
+        assert_eq!(pipeline.take_failures(), vec![RunError::Unknown]);
+    }
+}
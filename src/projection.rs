@@ -0,0 +1,517 @@
+//! Projections are read models maintained from events. Each event is
+//! applied with its sequence number, the offset of its record in the
+//! journal, so a projection can tell how far it has got.
+//!
+//! A `Projector` attaches projections to a runner. As an event listener,
+//! it journals each event as it is produced and applies it to the
+//! projections. A projection can also be rebuilt from a journal with
+//! `rebuild_from`, or with `rebuild_parallel`, which partitions the
+//! journal by entity, folds the partitions into separate projections on
+//! several threads, and merges them. Each entity's events are still
+//! applied in order, so this suits projections whose merge does not
+//! depend on the order of events across entities.
+//!
+//! A long rebuild can be checkpointed with `rebuild_checkpointed`, which
+//! saves the projection and its offset to a `SnapshotStore`, under the
+//! projection's name, every so many records, so that a crash part way
+//! through resumes from the last checkpoint.
+//!
+//! Alternatively, `catch_up` maintains a projection from an event bus on
+//! its own thread. Each projection is named and has a high-water mark,
+//! the offset it has applied up to, kept in `Marks`. It first replays the
+//! records it has missed from the journal and then tails the live records,
+//! reporting its progress as it goes.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use crate::integrity::stable_hash;
+use crate::journal::{Journal, JournalError, Record};
+use crate::runner::EventListener;
+use crate::snapshot::SnapshotStore;
+use crate::subscription::EventBus;
+
+/// A read model maintained from events of type E.
+pub trait Projection<E> {
+    fn apply(&mut self, event: &E, seq: u64);
+}
+
+/// A shared projection, so that it can be read while it is maintained.
+impl<E, P> Projection<E> for Arc<Mutex<P>>
+where
+    P: Projection<E>,
+{
+    fn apply(&mut self, event: &E, seq: u64) {
+        self.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .apply(event, seq)
+    }
+}
+
+/// Apply the records of a journal from an offset to a projection,
+/// returning the offset to continue from.
+pub fn rebuild_from<K, E, J, P>(
+    journal: &J,
+    projection: &mut P,
+    from_offset: u64,
+) -> Result<u64, JournalError>
+where
+    J: Journal<K, E>,
+    P: Projection<E>,
+{
+    let mut next = from_offset;
+    for record in journal.read_all(from_offset)? {
+        projection.apply(&record.event, record.offset);
+        next = record.offset + 1;
+    }
+    Ok(next)
+}
+
+/// Why a checkpointed rebuild stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RebuildError {
+    Journal(JournalError),
+    Checkpoint(String),
+}
+
+/// Rebuild a named projection from its last checkpoint, or from `empty`
+/// and the start of the journal, saving a checkpoint of the projection
+/// and the offset to continue from after every `every` records and at
+/// the end. Returns the projection and the offset to continue from.
+pub fn rebuild_checkpointed<K, E, J, P, C>(
+    journal: &J,
+    checkpoints: &mut C,
+    name: &str,
+    empty: impl FnOnce() -> P,
+    every: usize,
+) -> Result<(P, u64), RebuildError>
+where
+    J: Journal<K, E>,
+    P: Projection<E>,
+    C: SnapshotStore<String, P>,
+{
+    let name = name.to_string();
+    let (mut next, mut projection) =
+        match checkpoints.load(&name).map_err(RebuildError::Checkpoint)? {
+            Some(checkpoint) => checkpoint,
+            None => (0, empty()),
+        };
+    let every = every.max(1);
+    let records = journal.read_all(next).map_err(RebuildError::Journal)?;
+    for (i, record) in records.iter().enumerate() {
+        projection.apply(&record.event, record.offset);
+        next = record.offset + 1;
+        if (i + 1) % every == 0 {
+            checkpoints
+                .save(&name, next, &projection)
+                .map_err(RebuildError::Checkpoint)?;
+        }
+    }
+    if records.len() % every != 0 {
+        checkpoints
+            .save(&name, next, &projection)
+            .map_err(RebuildError::Checkpoint)?;
+    }
+    Ok((projection, next))
+}
+
+/// The number of records a partition applies between progress reports.
+const PROGRESS_EVERY: usize = 1000;
+
+/// How far a parallel rebuild has got, in records applied of the total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebuildProgress {
+    pub applied: usize,
+    pub total: usize,
+}
+
+/// Rebuild a projection from all the records of a journal, partitioned by
+/// entity across `parallelism` threads. Each partition is applied to a
+/// projection from `empty`, and the results are merged with `merge`.
+/// Progress is reported on the calling thread. Returns the projection
+/// and the offset to continue from.
+pub fn rebuild_parallel<K, E, J, P>(
+    journal: &J,
+    parallelism: usize,
+    empty: impl Fn() -> P + Sync,
+    mut merge: impl FnMut(P, P) -> P,
+    mut progress: impl FnMut(RebuildProgress),
+) -> Result<(P, u64), JournalError>
+where
+    K: Hash + Send,
+    E: Send,
+    J: Journal<K, E>,
+    P: Projection<E> + Send,
+{
+    let records = journal.read_all(0)?;
+    let next = records.last().map_or(0, |r| r.offset + 1);
+    let total = records.len();
+    let parallelism = parallelism.max(1);
+    let mut partitions: Vec<Vec<Record<K, E>>> = (0..parallelism).map(|_| Vec::new()).collect();
+    for record in records {
+        partitions[(stable_hash(&record.key) % parallelism as u64) as usize].push(record);
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let parts: Vec<P> = thread::scope(|scope| {
+        let threads: Vec<_> = partitions
+            .into_iter()
+            .map(|partition| {
+                let sender = sender.clone();
+                let empty = &empty;
+                scope.spawn(move || {
+                    let mut part = empty();
+                    for (i, record) in partition.iter().enumerate() {
+                        part.apply(&record.event, record.offset);
+                        if (i + 1) % PROGRESS_EVERY == 0 {
+                            let _ = sender.send(PROGRESS_EVERY);
+                        }
+                    }
+                    let _ = sender.send(partition.len() % PROGRESS_EVERY);
+                    part
+                })
+            })
+            .collect();
+        drop(sender);
+        let mut applied = 0;
+        for n in receiver {
+            applied += n;
+            progress(RebuildProgress { applied, total });
+        }
+        threads
+            .into_iter()
+            .map(|t| t.join().unwrap_or_else(|p| std::panic::resume_unwind(p)))
+            .collect()
+    });
+    let merged = parts.into_iter().reduce(&mut merge).unwrap_or_else(empty);
+    Ok((merged, next))
+}
+
+/// Journals events as they are produced and applies them to projections.
+pub struct Projector<J, E> {
+    journal: J,
+    projections: Vec<Box<dyn Projection<E> + Send>>,
+}
+
+impl<J, E> Projector<J, E> {
+    pub fn new(journal: J) -> Self {
+        Self {
+            journal,
+            projections: Vec::new(),
+        }
+    }
+
+    /// Maintain a projection, which should already be up to date with
+    /// the journal.
+    pub fn with_projection(mut self, projection: Box<dyn Projection<E> + Send>) -> Self {
+        self.projections.push(projection);
+        self
+    }
+
+    pub fn journal(&self) -> &J {
+        &self.journal
+    }
+}
+
+impl<K, S, E, J> EventListener<K, S, E> for Projector<J, E>
+where
+    J: Journal<K, E>,
+    E: Clone,
+{
+    fn on_event(&mut self, key: &K, _state: &S, event: &E) -> Result<(), String> {
+        let position = self
+            .journal
+            .append(key, event.clone())
+            .map_err(|e| format!("{e:?}"))?;
+        for p in self.projections.iter_mut() {
+            p.apply(event, position.offset);
+        }
+        Ok(())
+    }
+}
+
+/// The high-water marks of named projections: the offsets they
+/// continue from. These can be saved and restored with `get` and `set`.
+#[derive(Debug, Clone, Default)]
+pub struct Marks(Arc<Mutex<HashMap<String, u64>>>);
+
+impl Marks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The offset a projection continues from, 0 if it has none.
+    pub fn get(&self, name: &str) -> u64 {
+        self.lock().get(name).copied().unwrap_or(0)
+    }
+
+    pub fn set(&self, name: &str, offset: u64) {
+        self.lock().insert(name.to_string(), offset);
+    }
+}
+
+/// How far a projection has caught up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub name: String,
+    /// The offset it continues from
+    pub mark: u64,
+    /// The journalled records it has replayed, and the number it must
+    pub replayed: usize,
+    pub backlog: usize,
+}
+
+impl Progress {
+    /// Whether the projection has replayed its backlog and is tailing
+    /// live records.
+    pub fn is_live(&self) -> bool {
+        self.replayed >= self.backlog
+    }
+}
+
+/// A projection being maintained on its own thread.
+pub struct CatchUp<P> {
+    projection: Arc<Mutex<P>>,
+    thread: JoinHandle<()>,
+}
+
+impl<P> CatchUp<P> {
+    /// The projection, which can be read while it is maintained.
+    pub fn projection(&self) -> &Arc<Mutex<P>> {
+        &self.projection
+    }
+
+    /// Wait for the bus to go, when the projection is complete.
+    pub fn join(self) -> Arc<Mutex<P>> {
+        let _ = self.thread.join();
+        self.projection
+    }
+}
+
+/// Maintain a named projection from an event bus, continuing from its
+/// high-water mark, and reporting progress after each record.
+pub fn catch_up<J, K, E, P>(
+    name: &str,
+    bus: &EventBus<J, K, E>,
+    projection: P,
+    marks: &Marks,
+    mut progress: impl FnMut(&Progress) + Send + 'static,
+) -> Result<CatchUp<P>, JournalError>
+where
+    J: Journal<K, E>,
+    K: Clone + Send + 'static,
+    E: Clone + Send + 'static,
+    P: Projection<E> + Send + 'static,
+{
+    let subscription = bus.subscribe_from(marks.get(name))?;
+    let projection = Arc::new(Mutex::new(projection));
+    let mut state = Progress {
+        name: name.to_string(),
+        mark: marks.get(name),
+        replayed: 0,
+        backlog: subscription.backlog(),
+    };
+    let marks = marks.clone();
+    let mut target = projection.clone();
+    let thread = thread::spawn(move || {
+        for record in subscription {
+            target.apply(&record.event, record.offset);
+            state.mark = record.offset + 1;
+            marks.set(&state.name, state.mark);
+            if !state.is_live() {
+                state.replayed += 1;
+            }
+            progress(&state);
+        }
+    });
+    Ok(CatchUp { projection, thread })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Counter, Increment, Incremented};
+    use crate::journal::MemJournal;
+    use crate::runner::Runner;
+    use crate::snapshot::MemSnapshotStore;
+
+    #[test]
+    fn test_projection() {
+        // Counters, and a read model of the total count
+
+        #[derive(Debug, Default, PartialEq)]
+        struct Total {
+            count: u32,
+            seq: Option<u64>,
+        }
+
+        impl Projection<Incremented> for Total {
+            fn apply(&mut self, _e: &Incremented, seq: u64) {
+                self.count += 1;
+                self.seq = Some(seq);
+            }
+        }
+
+        let mut runner = Runner::<&str, u32, (), Counter>::new(());
+        runner.insert("a", 0);
+        runner.insert("b", 0);
+        let total = Arc::new(Mutex::new(Total::default()));
+        let mut projector =
+            Projector::new(MemJournal::new()).with_projection(Box::new(total.clone()));
+
+        for key in ["a", "b", "a"] {
+            runner
+                .send_with(&key, &Increment {}, &mut projector)
+                .unwrap();
+        }
+        assert_eq!(
+            *total.lock().unwrap(),
+            Total {
+                count: 3,
+                seq: Some(2)
+            }
+        );
+
+        let mut rebuilt = Total::default();
+        assert_eq!(rebuild_from(projector.journal(), &mut rebuilt, 1), Ok(3));
+        assert_eq!(
+            rebuilt,
+            Total {
+                count: 2,
+                seq: Some(2)
+            }
+        );
+    }
+
+    #[test]
+    fn test_catch_up() {
+        // A projection collecting events, restarted part way through
+
+        #[derive(Debug, Default)]
+        struct Seen(Vec<(u64, u32)>);
+
+        impl Projection<u32> for Seen {
+            fn apply(&mut self, e: &u32, seq: u64) {
+                self.0.push((seq, *e));
+            }
+        }
+
+        let bus = EventBus::new(MemJournal::new());
+        for n in 0..4 {
+            bus.publish(&"a", n).unwrap();
+        }
+        let marks = Marks::new();
+        marks.set("seen", 2);
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let log = reports.clone();
+        let seen = catch_up("seen", &bus, Seen::default(), &marks, move |p| {
+            log.lock().unwrap().push((p.mark, p.is_live()))
+        })
+        .unwrap();
+        bus.publish(&"a", 4).unwrap();
+        drop(bus);
+
+        let seen = seen.join();
+        assert_eq!(seen.lock().unwrap().0, vec![(2, 2), (3, 3), (4, 4)]);
+        assert_eq!(marks.get("seen"), 5);
+        assert_eq!(
+            *reports.lock().unwrap(),
+            vec![(3, false), (4, true), (5, true)]
+        );
+    }
+
+    #[test]
+    fn test_rebuild_parallel() {
+        // Counts of events per entity, merged by adding
+
+        #[derive(Debug, Default, PartialEq)]
+        struct Counts(HashMap<u32, usize>);
+
+        impl Projection<u32> for Counts {
+            fn apply(&mut self, key: &u32, _seq: u64) {
+                *self.0.entry(*key).or_default() += 1;
+            }
+        }
+
+        let mut journal = MemJournal::new();
+        for i in 0..2500u32 {
+            let key = i % 7;
+            journal.append(&key, key).unwrap();
+        }
+
+        let mut reports = Vec::new();
+        let (counts, next) = rebuild_parallel(
+            &journal,
+            4,
+            Counts::default,
+            |mut a, b| {
+                for (k, n) in b.0 {
+                    *a.0.entry(k).or_default() += n;
+                }
+                a
+            },
+            |p| reports.push(p),
+        )
+        .unwrap();
+
+        assert_eq!(next, 2500);
+        assert_eq!(counts.0.values().sum::<usize>(), 2500);
+        assert_eq!(counts.0[&0], 358);
+        assert_eq!(
+            reports.last(),
+            Some(&RebuildProgress {
+                applied: 2500,
+                total: 2500
+            })
+        );
+        assert!(reports.windows(2).all(|w| w[0].applied <= w[1].applied));
+    }
+
+    #[test]
+    fn test_rebuild_checkpointed() {
+        // A sum of events rebuilt in two goes, the first stopped by a
+        // failing journal once checkpointed
+
+        #[derive(Debug, Clone, Default, PartialEq)]
+        struct Sum(u32);
+
+        impl Projection<u32> for Sum {
+            fn apply(&mut self, e: &u32, _seq: u64) {
+                self.0 += e;
+            }
+        }
+
+        let mut journal = MemJournal::new();
+        for i in 1..=10u32 {
+            journal.append(&"a", i).unwrap();
+        }
+        let mut checkpoints = MemSnapshotStore::new();
+        let first = rebuild_checkpointed(&journal, &mut checkpoints, "sum", Sum::default, 4);
+        assert_eq!(first, Ok((Sum(55), 10)));
+
+        // More records arrive, and the rebuild resumes from the checkpoint
+        for i in 11..=12u32 {
+            journal.append(&"a", i).unwrap();
+        }
+        assert_eq!(
+            checkpoints.load(&"sum".to_string()),
+            Ok(Some((10, Sum(55))))
+        );
+        let resumed = rebuild_checkpointed(&journal, &mut checkpoints, "sum", Sum::default, 4);
+        assert_eq!(resumed, Ok((Sum(78), 12)));
+
+        journal.fail_reads(true);
+        assert!(matches!(
+            rebuild_checkpointed(&journal, &mut checkpoints, "sum", Sum::default, 4),
+            Err(RebuildError::Journal(_))
+        ));
+    }
+}
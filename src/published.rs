@@ -0,0 +1,182 @@
+//! States published for readers, for read-heavy workloads. `Published`
+//! is attached to a runner as an observer, and publishes a copy of each
+//! entity's state, behind an `Arc`, whenever it is inserted or
+//! transitions. Any number of readers, on any threads, load a consistent
+//! snapshot of an entity's state without waiting for the runner's steps.
+//!
+//! Each entity's snapshot is held in an `ArcCell`, an `RwLock` around the
+//! `Arc`. Reads are not lock-free: a load takes the read lock, but only
+//! for as long as it takes to clone the `Arc`, and a store takes the write
+//! lock only to replace it, never while stepping. A reader may keep an
+//! entity's cell and load from it directly.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::observer::Observer;
+
+/// A shared value that is replaced as a whole.
+pub struct ArcCell<T> {
+    value: RwLock<Arc<T>>,
+}
+
+impl<T> ArcCell<T> {
+    pub fn new(value: Arc<T>) -> Self {
+        Self {
+            value: RwLock::new(value),
+        }
+    }
+
+    /// The current value.
+    pub fn load(&self) -> Arc<T> {
+        self.value.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replace the value. Readers holding the old one keep it.
+    pub fn store(&self, value: Arc<T>) {
+        *self.value.write().unwrap_or_else(|e| e.into_inner()) = value;
+    }
+}
+
+type Cells<K, S> = HashMap<K, Arc<ArcCell<S>>>;
+
+/// The published states S of entities with keys K.
+pub struct Published<K, S> {
+    cells: Arc<RwLock<Cells<K, S>>>,
+}
+
+impl<K, S> Clone for Published<K, S> {
+    fn clone(&self) -> Self {
+        Self {
+            cells: self.cells.clone(),
+        }
+    }
+}
+
+impl<K, S> Default for Published<K, S> {
+    fn default() -> Self {
+        Self {
+            cells: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K, S> Published<K, S>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The latest published state of an entity.
+    pub fn load(&self, key: &K) -> Option<Arc<S>> {
+        self.read().get(key).map(|cell| cell.load())
+    }
+
+    /// The cell an entity's states are published to.
+    pub fn cell(&self, key: &K) -> Option<Arc<ArcCell<S>>> {
+        self.read().get(key).cloned()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Cells<K, S>> {
+        self.cells.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Cells<K, S>> {
+        self.cells.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K, S> Observer<K, S> for Published<K, S>
+where
+    K: Eq + Hash + Clone,
+    S: Clone,
+{
+    fn inserted(&mut self, key: &K, state: &S) {
+        let cell = Arc::new(ArcCell::new(Arc::new(state.clone())));
+        self.write().insert(key.clone(), cell);
+    }
+
+    fn removed(&mut self, key: &K) {
+        self.write().remove(key);
+    }
+
+    fn transitioned(&mut self, key: &K, _from: &S, _event: &str, to: &S) {
+        if let Some(cell) = self.cell(key) {
+            cell.store(Arc::new(to.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+    use crate::runner::Runner;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    #[test]
+    fn test_published() {
+        // A pair of counters that are always incremented together, read
+        // from other threads while they are stepped
+
+        struct Increment {}
+
+        struct Incremented {}
+
+        impl Command<(u32, u32), ()> for Increment {
+            type Output = Incremented;
+            fn execute(&self, _s: &(u32, u32), _h: &mut ()) -> Option<Incremented> {
+                Some(Incremented {})
+            }
+        }
+
+        impl Validate<(u32, u32)> for Increment {}
+
+        impl Event<(u32, u32)> for Incremented {
+            fn fire(&self, s: &(u32, u32)) -> Transition<(u32, u32)> {
+                Transition::Next((s.0 + 1, s.1 + 1))
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<(u32, u32), ()> for MyFsm {}
+
+        let published = Published::new();
+        let mut runner =
+            Runner::<&str, _, _, MyFsm>::new(()).with_observer(Box::new(published.clone()));
+        runner.insert("a", (0, 0));
+
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let cell = published.cell(&"a").unwrap();
+                let done = done.clone();
+                thread::spawn(move || {
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let state = cell.load();
+                        assert_eq!(state.0, state.1);
+                        assert!(state.0 >= last);
+                        last = state.0;
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..1000 {
+            runner.send(&"a", &Increment {}).unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        assert_eq!(published.load(&"a").as_deref(), Some(&(1000, 1000)));
+        runner.remove(&"a");
+        assert!(published.load(&"a").is_none());
+    }
+}
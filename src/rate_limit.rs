@@ -0,0 +1,597 @@
+//! Rate limits on the commands sent to a runner, to protect the targets
+//! of effects such as third party APIs. Limits are token buckets, one
+//! shared by all entities and one for each entity, either of which may
+//! be omitted. A command is admitted when both buckets hold a token.
+//!
+//! What happens to a command that is not admitted is up to the `Overflow`
+//! policy: it can wait for a token, be rejected or be dropped. Buckets
+//! that could never admit a command, or only after longer than a
+//! `Duration` can express, are refused when they are configured. A
+//! rejected or dropped command is reported to the runner's observers.
+//!
+//! The limits are applied by `RateLimited` for a runner on the caller's
+//! thread, and inside the task or thread of a `ThreadRunner`,
+//! `AsyncRunner` or `AsyncEffectRunner` spawned with `spawn_rate_limited`.
+//! A `RateLimited` sender waiting for a token sleeps. The spawned runners
+//! return a waiting command to its sender, which waits, with the
+//! executor's `Sleep` for the async runners, and sends it again, so
+//! that the runner steps other commands in the meantime.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::hash::Hash;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::async_handler::{AsyncCommand, AsyncEffectRunner, AsyncHandler};
+use crate::async_runner::{AsyncRunner, Executor, Paced};
+use crate::command_and_event_traits::{Command, Event, Fsm, Validate};
+use crate::runner::{RunError, Runner};
+use crate::thread_runner::ThreadRunner;
+
+/// Why a bucket cannot be configured.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LimitError {
+    /// A bucket holding no tokens would never admit a command
+    ZeroCapacity,
+    /// The refill rate is not finite and positive, or so slow the wait for
+    /// a token cannot be expressed
+    InvalidRate(f64),
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::ZeroCapacity => write!(f, "a bucket must hold at least one token"),
+            LimitError::InvalidRate(rate) => write!(f, "invalid refill rate {rate} per second"),
+        }
+    }
+}
+
+/// A bucket of up to `capacity` tokens, refilled at a steady rate.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(capacity: u32, per_second: f64) -> Result<Self, LimitError> {
+        Self::check(capacity, per_second)?;
+        Ok(Self::full_at(capacity, per_second, Instant::now()))
+    }
+
+    fn check(capacity: u32, per_second: f64) -> Result<(), LimitError> {
+        if capacity == 0 {
+            return Err(LimitError::ZeroCapacity);
+        }
+        // The longest wait for a token is the time to refill one
+        match per_second.is_finite()
+            && per_second > 0.0
+            && Duration::try_from_secs_f64(1.0 / per_second).is_ok()
+        {
+            true => Ok(()),
+            false => Err(LimitError::InvalidRate(per_second)),
+        }
+    }
+
+    fn full_at(capacity: u32, per_second: f64, now: Instant) -> Self {
+        Self {
+            capacity: capacity as f64,
+            per_second,
+            tokens: capacity as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        self.updated = self.updated.max(now);
+    }
+
+    /// How long until a token is available, which is zero if one is.
+    pub fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.per_second)
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// Take a token if one is available.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let available = self.wait(now).is_zero();
+        if available {
+            self.take();
+        }
+        available
+    }
+}
+
+/// What to do with a command that exceeds a rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Wait until it is admitted
+    Queue,
+    /// Fail it with `RunError::RateLimited`
+    Reject,
+    /// Discard it, failing it with `RunError::Dropped`, for commands
+    /// worth sending only while within the limit
+    Drop,
+}
+
+/// Global and per entity rate limits.
+#[derive(Debug, Clone)]
+pub struct RateLimit<K> {
+    overflow: Overflow,
+    global: Option<TokenBucket>,
+    per_entity: Option<(u32, f64)>,
+    entities: HashMap<K, TokenBucket>,
+    evict_at: usize,
+}
+
+/// How many entity buckets there may be before full ones are evicted.
+const EVICT_AT: usize = 64;
+
+impl<K> RateLimit<K>
+where
+    K: Eq + Hash + Clone,
+{
+    /// No limits, yet.
+    pub fn new(overflow: Overflow) -> Self {
+        Self {
+            overflow,
+            global: None,
+            per_entity: None,
+            entities: HashMap::new(),
+            evict_at: EVICT_AT,
+        }
+    }
+
+    /// Limit the commands sent to all entities.
+    pub fn with_global(mut self, capacity: u32, per_second: f64) -> Result<Self, LimitError> {
+        self.global = Some(TokenBucket::new(capacity, per_second)?);
+        Ok(self)
+    }
+
+    /// Limit the commands sent to each entity.
+    pub fn with_per_entity(mut self, capacity: u32, per_second: f64) -> Result<Self, LimitError> {
+        TokenBucket::check(capacity, per_second)?;
+        self.per_entity = Some((capacity, per_second));
+        Ok(self)
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    /// Admit a command for an entity, taking a token from each bucket,
+    /// or answer how long until it could be admitted.
+    pub fn try_admit(&mut self, key: &K, now: Instant) -> Result<(), Duration> {
+        if self.per_entity.is_some() && self.entities.len() >= self.evict_at {
+            self.evict(now);
+        }
+        let global = self.global.as_mut();
+        let entity = match self.per_entity {
+            Some((capacity, per_second)) => Some(
+                self.entities
+                    .entry(key.clone())
+                    .or_insert_with(|| TokenBucket::full_at(capacity, per_second, now)),
+            ),
+            None => None,
+        };
+        let mut buckets: Vec<&mut TokenBucket> = global.into_iter().chain(entity).collect();
+        let wait = buckets
+            .iter_mut()
+            .map(|b| b.wait(now))
+            .max()
+            .unwrap_or(Duration::ZERO);
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        for b in buckets {
+            b.take();
+        }
+        Ok(())
+    }
+
+    /// Admit a command for an entity now, or refuse it according to the
+    /// overflow policy, reporting the refusal to the runner's observers,
+    /// or answer how long to wait if the command is to be queued.
+    fn admit<S, H, F>(
+        &mut self,
+        runner: &mut Runner<K, S, H, F>,
+        key: &K,
+        command: &str,
+    ) -> Paced<Result<(), RunError>>
+    where
+        F: Fsm<S, H>,
+    {
+        let Err(wait) = self.try_admit(key, Instant::now()) else {
+            return Ok(Ok(()));
+        };
+        match self.overflow {
+            Overflow::Queue => Err(wait),
+            Overflow::Reject => Ok(Err(runner.reject(key, command, RunError::RateLimited))),
+            Overflow::Drop => Ok(Err(runner.reject(key, command, RunError::Dropped))),
+        }
+    }
+
+    /// Forget the buckets of entities that have been idle long enough to
+    /// refill them, which are the same as new ones. This is done as the
+    /// buckets grow, doubling the number kept before the next time.
+    fn evict(&mut self, now: Instant) {
+        self.entities.retain(|_, bucket| {
+            bucket.refill(now);
+            !bucket.is_full()
+        });
+        self.evict_at = EVICT_AT.max(2 * self.entities.len());
+    }
+}
+
+/// A runner whose commands are rate limited.
+pub struct RateLimited<K, S, H, F> {
+    runner: Runner<K, S, H, F>,
+    limit: RateLimit<K>,
+}
+
+impl<K, S, H, F> RateLimited<K, S, H, F>
+where
+    K: Eq + Hash + Clone,
+    F: Fsm<S, H>,
+{
+    pub fn new(runner: Runner<K, S, H, F>, limit: RateLimit<K>) -> Self {
+        Self { runner, limit }
+    }
+
+    pub fn runner(&self) -> &Runner<K, S, H, F> {
+        &self.runner
+    }
+
+    pub fn runner_mut(&mut self) -> &mut Runner<K, S, H, F> {
+        &mut self.runner
+    }
+
+    pub fn into_inner(self) -> Runner<K, S, H, F> {
+        self.runner
+    }
+
+    /// Step an entity with a command once it is admitted by the limits.
+    pub fn send<C>(&mut self, key: &K, command: &C) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+    {
+        let name = command.name();
+        let admitted = loop {
+            match self.limit.admit(&mut self.runner, key, &name) {
+                Ok(admitted) => break admitted,
+                Err(wait) => thread::sleep(wait),
+            }
+        };
+        admitted?;
+        self.runner.send(key, command)
+    }
+}
+
+impl<K, S, H, F, C> ThreadRunner<K, S, H, F, C>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    S: Send + 'static,
+    H: Send + 'static,
+    F: Fsm<S, H> + 'static,
+    C: Command<S, H> + Validate<S> + Send + 'static,
+    C::Output: Send + 'static,
+{
+    /// Start a thread owning the runner, whose commands are rate limited.
+    pub fn spawn_rate_limited(runner: Runner<K, S, H, F>, limit: RateLimit<K>) -> Self {
+        let mut limit = limit;
+        Self::spawn_paced(runner, move |runner, key, command| {
+            let admitted = limit.admit(runner, key, &command.name())?;
+            Ok(admitted.and_then(|_| runner.send(key, command)))
+        })
+    }
+}
+
+impl<K, C, E> AsyncRunner<K, C, E>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawn a task owning the runner, whose commands are rate limited.
+    pub fn spawn_rate_limited<S, H, F, X>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        limit: RateLimit<K>,
+    ) -> Self
+    where
+        S: Send + 'static,
+        H: Send + 'static,
+        F: Fsm<S, H> + 'static,
+        C: Command<S, H, Output = E> + Validate<S>,
+        X: Executor + Send + Sync + 'static,
+    {
+        let mut limit = limit;
+        Self::spawn_paced(runner, executor, move |runner, key, command| {
+            let admitted = limit.admit(runner, key, &command.name())?;
+            Ok(admitted.and_then(|_| runner.send(key, command)))
+        })
+    }
+}
+
+impl<K, C, E> AsyncEffectRunner<K, C, E>
+where
+    K: Eq + Hash + Clone + Send + 'static,
+    C: Send + 'static,
+    E: Send + 'static,
+{
+    /// Spawn a task owning the runner, whose commands are rate limited
+    /// before their effects start.
+    pub fn spawn_rate_limited<S, H, F, X>(
+        runner: Runner<K, S, H, F>,
+        executor: X,
+        limit: RateLimit<K>,
+    ) -> Self
+    where
+        S: Send + 'static,
+        H: AsyncHandler,
+        F: Fsm<S, H> + 'static,
+        C: AsyncCommand<S, H, Output = E> + Validate<S>,
+        E: Event<S>,
+        X: Executor + Send + Sync + 'static,
+    {
+        let mut limit = limit;
+        Self::spawn_paced(runner, executor, move |runner, key, command| {
+            limit.admit(runner, key, &command.name())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::async_handler::{CancellationToken, Effect};
+    use crate::async_runner::{block_on, ThreadExecutor};
+    use crate::command_and_event_traits::Rejection;
+    use crate::fixtures::{Counter, Increment, Incremented};
+    use crate::observer::Observer;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limit = RateLimit::new(Overflow::Reject)
+            .with_global(3, 1.0)
+            .and_then(|l| l.with_per_entity(2, 0.5))
+            .unwrap();
+        let start = Instant::now();
+
+        // Each entity has a burst of two, and all share a burst of three
+
+        assert_eq!(limit.try_admit(&"a", start), Ok(()));
+        assert_eq!(limit.try_admit(&"a", start), Ok(()));
+        assert_eq!(limit.try_admit(&"a", start), Err(Duration::from_secs(2)));
+        assert_eq!(limit.try_admit(&"b", start), Ok(()));
+        assert_eq!(limit.try_admit(&"b", start), Err(Duration::from_secs(1)));
+
+        // Refilled after a while
+
+        let later = start + Duration::from_secs(2);
+        assert_eq!(limit.try_admit(&"a", later), Ok(()));
+        assert_eq!(limit.try_admit(&"b", later), Ok(()));
+        assert_eq!(limit.try_admit(&"c", later), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_invalid_limits() {
+        // Buckets that would never admit a command, or would wait longer
+        // than a Duration can hold, are refused rather than left to hang
+        // a queueing sender
+        assert_eq!(
+            TokenBucket::new(0, 1.0).err(),
+            Some(LimitError::ZeroCapacity)
+        );
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e-300] {
+            let limit = RateLimit::<&str>::new(Overflow::Queue).with_per_entity(1, rate);
+            assert!(matches!(limit, Err(LimitError::InvalidRate(_))));
+        }
+        assert!(RateLimit::<&str>::new(Overflow::Queue)
+            .with_global(1, 1e-6)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_eviction() {
+        // Buckets of entities idle long enough to refill are evicted once
+        // there are many, while those still waiting on a refill are kept
+        let mut limit = RateLimit::new(Overflow::Reject)
+            .with_per_entity(1, 1.0)
+            .unwrap();
+        let start = Instant::now();
+        for key in 0..EVICT_AT as u32 {
+            assert_eq!(limit.try_admit(&key, start), Ok(()));
+        }
+        assert_eq!(limit.entities.len(), EVICT_AT);
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(limit.try_admit(&0, later), Ok(()));
+        assert_eq!(limit.entities.len(), 1);
+        assert_eq!(limit.try_admit(&0, later), Err(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        // A counter incremented by commands
+
+        let limited = |overflow| {
+            let mut runner = Runner::<&str, u32, (), Counter>::new(());
+            runner.insert("a", 0);
+            RateLimited::new(
+                runner,
+                RateLimit::new(overflow).with_per_entity(1, 50.0).unwrap(),
+            )
+        };
+
+        let mut rejecting = limited(Overflow::Reject);
+        assert_eq!(
+            rejecting.send(&"a", &Increment {}),
+            Ok(Some(Incremented {}))
+        );
+        assert_eq!(
+            rejecting.send(&"a", &Increment {}),
+            Err(RunError::RateLimited)
+        );
+
+        let mut dropping = limited(Overflow::Drop);
+        assert_eq!(dropping.send(&"a", &Increment {}), Ok(Some(Incremented {})));
+        assert_eq!(dropping.send(&"a", &Increment {}), Err(RunError::Dropped));
+        assert_eq!(dropping.runner().state(&"a"), Some(&1));
+
+        let mut queueing = limited(Overflow::Queue);
+        for _ in 0..3 {
+            assert_eq!(queueing.send(&"a", &Increment {}), Ok(Some(Incremented {})));
+        }
+        assert_eq!(queueing.runner().state(&"a"), Some(&3));
+    }
+
+    #[derive(Clone, Default)]
+    struct Refusals(Arc<Mutex<Vec<String>>>);
+
+    impl<S> Observer<&'static str, S> for Refusals {
+        fn rejected(&mut self, key: &&'static str, command: &str, error: &RunError) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{key} {command} {error:?}"));
+        }
+    }
+
+    fn counter(refusals: &Refusals) -> Runner<&'static str, u32, (), Counter> {
+        let mut runner = Runner::new(()).with_observer(Box::new(refusals.clone()));
+        runner.insert("a", 0);
+        runner
+    }
+
+    fn one_per(overflow: Overflow, millis: u64) -> RateLimit<&'static str> {
+        RateLimit::new(overflow)
+            .with_per_entity(1, 1000.0 / millis as f64)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_refusals_observed() {
+        // Rejected and dropped commands reach the observers, as rate limits
+        let refusals = Refusals::default();
+        let mut limited = RateLimited::new(counter(&refusals), one_per(Overflow::Reject, 1000));
+        limited.send(&"a", &Increment {}).unwrap();
+        let refused = limited.send(&"a", &Increment {}).unwrap_err();
+        assert_eq!(refused.rejection(), Some(Rejection::RateLimited));
+        assert_eq!(RunError::Dropped.rejection(), Some(Rejection::RateLimited));
+        assert_eq!(*refusals.0.lock().unwrap(), vec!["a Increment RateLimited"]);
+    }
+
+    #[test]
+    fn test_thread_rate_limited() {
+        let refusals = Refusals::default();
+        let threaded =
+            ThreadRunner::spawn_rate_limited(counter(&refusals), one_per(Overflow::Drop, 1000));
+        assert_eq!(threaded.send("a", Increment {}), Ok(Some(Incremented {})));
+        assert_eq!(threaded.send("a", Increment {}), Err(RunError::Dropped));
+        assert_eq!(threaded.shutdown().state(&"a"), Some(&1));
+        assert_eq!(*refusals.0.lock().unwrap(), vec!["a Increment Dropped"]);
+
+        // Queued commands wait on the sender's thread
+        let threaded =
+            ThreadRunner::spawn_rate_limited(counter(&refusals), one_per(Overflow::Queue, 20));
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(threaded.send("a", Increment {}), Ok(Some(Incremented {})));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(threaded.shutdown().state(&"a"), Some(&3));
+    }
+
+    #[test]
+    fn test_async_rate_limited() {
+        // A queued command waits for its token while the runner steps a
+        // command for another entity
+        let refusals = Refusals::default();
+        let mut runner = counter(&refusals);
+        runner.insert("b", 0);
+        let handle =
+            AsyncRunner::spawn_rate_limited(runner, ThreadExecutor, one_per(Overflow::Queue, 200));
+        block_on(handle.send("a", Increment {})).unwrap();
+
+        let other = handle.clone();
+        let waiting = std::thread::spawn(move || block_on(other.send("a", Increment {})));
+        std::thread::sleep(Duration::from_millis(20));
+        let start = Instant::now();
+        assert_eq!(
+            block_on(handle.send("b", Increment {})),
+            Ok(Some(Incremented {}))
+        );
+        assert!(start.elapsed() < Duration::from_millis(100));
+        assert_eq!(waiting.join().unwrap(), Ok(Some(Incremented {})));
+        assert!(refusals.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_effect_rate_limited() {
+        // Increments whose effects are counted, refused before they start
+
+        #[derive(Clone, Default)]
+        struct Effects(Arc<Mutex<u32>>);
+
+        struct Call {}
+
+        impl AsyncCommand<u32, Effects> for Call {
+            type Output = Incremented;
+            fn execute(
+                &self,
+                _s: &u32,
+                effects: Effects,
+                _c: CancellationToken,
+            ) -> Effect<Incremented> {
+                Box::pin(async move {
+                    *effects.0.lock().unwrap() += 1;
+                    Some(Incremented {})
+                })
+            }
+        }
+
+        impl Validate<u32> for Call {}
+
+        struct Api {}
+
+        impl Fsm<u32, Effects> for Api {}
+
+        let effects = Effects::default();
+        let refusals = Refusals::default();
+        let mut runner = Runner::<&str, u32, Effects, Api>::new(effects.clone())
+            .with_observer(Box::new(refusals.clone()));
+        runner.insert("a", 0);
+        let handle = AsyncEffectRunner::spawn_rate_limited(
+            runner,
+            ThreadExecutor,
+            one_per(Overflow::Reject, 1000),
+        );
+        block_on(async {
+            assert_eq!(handle.send("a", Call {}).await, Ok(Some(Incremented {})));
+            assert_eq!(handle.send("a", Call {}).await, Err(RunError::RateLimited));
+        });
+        assert_eq!(*effects.0.lock().unwrap(), 1);
+        assert_eq!(*refusals.0.lock().unwrap(), vec!["a Call RateLimited"]);
+    }
+}
@@ -0,0 +1,120 @@
+//! Commands that only read the effect handler. A read-only command is
+//! executed with `&H` rather than `&mut H`, which makes it plain that it
+//! performs no effects and lets a runner execute read-only commands for
+//! different entities concurrently. The `ReadOnly` adapter turns one into
+//! an ordinary `Command` for use with `step`.
+
+use crate::command_and_event_traits::{Command, Event, Validate, ValidationError};
+use crate::observer::type_label;
+
+/// A command that needs only read access to the effect handler.
+pub trait ReadCommand<S, H> {
+    type Output: Event<S>;
+    fn execute_read(&self, state: &S, handler: &H) -> Option<Self::Output>;
+}
+
+impl<S, H, C> ReadCommand<S, H> for &C
+where
+    C: ReadCommand<S, H>,
+{
+    type Output = C::Output;
+    fn execute_read(&self, state: &S, handler: &H) -> Option<Self::Output> {
+        (*self).execute_read(state, handler)
+    }
+}
+
+/// Adapts a read-only command to a `Command`.
+pub struct ReadOnly<C>(pub C);
+
+impl<S, H, C> Command<S, H> for ReadOnly<C>
+where
+    C: ReadCommand<S, H>,
+{
+    type Output = C::Output;
+    fn execute(&self, state: &S, handler: &mut H) -> Option<Self::Output> {
+        self.0.execute_read(state, handler)
+    }
+
+    /// The name of the read-only command, through any reference.
+    fn name(&self) -> String {
+        type_label::<C>().trim_start_matches('&').to_string()
+    }
+}
+
+impl<S, C> Validate<S> for ReadOnly<C>
+where
+    C: Validate<S>,
+{
+    fn validate(&self, state: &S) -> Result<(), ValidationError> {
+        self.0.validate(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Fsm, Transition};
+    use crate::runner::{RunError, Runner};
+
+    #[test]
+    fn test_read_only() {
+        // Thermostats that copy a shared target temperature
+
+        struct Follow {}
+
+        #[derive(Debug, PartialEq)]
+        struct Followed(i32);
+
+        struct Settings {
+            target: i32,
+        }
+
+        impl ReadCommand<i32, Settings> for Follow {
+            type Output = Followed;
+            fn execute_read(&self, s: &i32, settings: &Settings) -> Option<Followed> {
+                (*s != settings.target).then_some(Followed(settings.target))
+            }
+        }
+
+        impl Validate<i32> for Follow {}
+
+        impl Event<i32> for Followed {
+            fn fire(&self, _s: &i32) -> Transition<i32> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<i32, Settings> for MyFsm {}
+
+        let mut settings = Settings { target: 20 };
+        let (e, t) = MyFsm::step(&18, &ReadOnly(Follow {}), &mut settings);
+        assert_eq!(e, Some(Followed(20)));
+        assert_eq!(t, Ok(Transition::Next(20)));
+
+        let mut runner = Runner::<&str, i32, Settings, MyFsm>::new(settings);
+        runner.insert("a", 18);
+        runner.insert("b", 20);
+        runner.insert("c", 25);
+
+        let results = runner.send_read_only(&[
+            ("a", Follow {}),
+            ("b", Follow {}),
+            ("c", Follow {}),
+            ("d", Follow {}),
+            ("a", Follow {}),
+        ]);
+        assert_eq!(
+            results,
+            vec![
+                Ok(Some(Followed(20))),
+                Ok(None),
+                Ok(Some(Followed(20))),
+                Err(RunError::Unknown),
+                Ok(None)
+            ]
+        );
+        assert_eq!(runner.state(&"c"), Some(&20));
+    }
+}
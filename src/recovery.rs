@@ -0,0 +1,301 @@
+//! Recovering many entities at startup. Each entity is restored from its
+//! latest snapshot, if any, and the journalled events after it are
+//! replayed. Entities are independent, so `recover_all` recovers them on
+//! a bounded number of threads, each taking the next entity as it
+//! finishes one, which keeps all threads busy when some entities have
+//! far longer histories than others.
+//!
+//! A long replay can be checkpointed with `recover_checkpointed`, which
+//! saves the state to the snapshot store every so many events, so that a
+//! crash part way through resumes from the last checkpoint rather than
+//! from the start.
+
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use crate::command_and_event_traits::{Event, Fsm, Transition};
+use crate::journal::{paged, Journal, JournalError};
+use crate::runner::Runner;
+use crate::snapshot::SnapshotStore;
+
+/// The number of records read at a time while replaying an entity.
+const REPLAY_BATCH: usize = 1000;
+
+/// Why an entity could not be recovered.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecoverError {
+    Snapshot(String),
+    Journal(JournalError),
+}
+
+/// A recovered state, the sequence number it reflects, and the number of
+/// events replayed after the snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recovered<S> {
+    pub state: S,
+    pub seq: u64,
+    pub replayed: usize,
+}
+
+/// Recover one entity from its snapshot and the events after it, or from
+/// `initial` and all its events if it has no snapshot.
+pub fn recover<K, S, E, J, St>(
+    journal: &J,
+    snapshots: &St,
+    key: &K,
+    initial: impl FnOnce(&K) -> S,
+) -> Result<Recovered<S>, RecoverError>
+where
+    J: Journal<K, E>,
+    St: SnapshotStore<K, S>,
+    E: Event<S>,
+{
+    let (seq, mut state) = match snapshots.load(key).map_err(RecoverError::Snapshot)? {
+        Some(snapshot) => snapshot,
+        None => (0, initial(key)),
+    };
+    let mut recovered_seq = seq;
+    let mut replayed = 0;
+    for record in paged(journal, key, seq + 1, REPLAY_BATCH) {
+        let record = record.map_err(RecoverError::Journal)?;
+        if let Transition::Next(s) = record.event.fire(&state) {
+            state = s;
+        }
+        recovered_seq = record.seq;
+        replayed += 1;
+    }
+    Ok(Recovered {
+        state,
+        seq: recovered_seq,
+        replayed,
+    })
+}
+
+/// Recover one entity, as `recover`, saving the state to the snapshot
+/// store after every `every` events replayed and once the replay is done.
+pub fn recover_checkpointed<K, S, E, J, St>(
+    journal: &J,
+    snapshots: &mut St,
+    key: &K,
+    initial: impl FnOnce(&K) -> S,
+    every: usize,
+) -> Result<Recovered<S>, RecoverError>
+where
+    J: Journal<K, E>,
+    St: SnapshotStore<K, S>,
+    E: Event<S>,
+{
+    let (seq, mut state) = match snapshots.load(key).map_err(RecoverError::Snapshot)? {
+        Some(snapshot) => snapshot,
+        None => (0, initial(key)),
+    };
+    let every = every.max(1);
+    let mut recovered_seq = seq;
+    let mut replayed = 0;
+    for record in paged(journal, key, seq + 1, REPLAY_BATCH) {
+        let record = record.map_err(RecoverError::Journal)?;
+        if let Transition::Next(s) = record.event.fire(&state) {
+            state = s;
+        }
+        recovered_seq = record.seq;
+        replayed += 1;
+        if replayed % every == 0 {
+            snapshots
+                .save(key, recovered_seq, &state)
+                .map_err(RecoverError::Snapshot)?;
+        }
+    }
+    if replayed % every != 0 {
+        snapshots
+            .save(key, recovered_seq, &state)
+            .map_err(RecoverError::Snapshot)?;
+    }
+    Ok(Recovered {
+        state,
+        seq: recovered_seq,
+        replayed,
+    })
+}
+
+/// Recover several entities on at most `parallelism` threads, answering
+/// in the order of the keys given.
+pub fn recover_all<K, S, E, J, St>(
+    journal: &J,
+    snapshots: &St,
+    keys: &[K],
+    initial: impl Fn(&K) -> S + Sync,
+    parallelism: usize,
+) -> Vec<Result<Recovered<S>, RecoverError>>
+where
+    K: Sync,
+    S: Send,
+    J: Journal<K, E> + Sync,
+    St: SnapshotStore<K, S> + Sync,
+    E: Event<S>,
+{
+    let next = AtomicUsize::new(0);
+    let results: Vec<_> = keys.iter().map(|_| Mutex::new(None)).collect();
+    thread::scope(|scope| {
+        for _ in 0..parallelism.clamp(1, keys.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(key) = keys.get(i) else {
+                    break;
+                };
+                let result = recover(journal, snapshots, key, &initial);
+                *results[i].lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|r| {
+            r.into_inner()
+                .unwrap_or_else(|e| e.into_inner())
+                .expect("every entity is recovered")
+        })
+        .collect()
+}
+
+/// Recover several entities into a runner, as `recover_all`, returning
+/// those that could not be recovered.
+pub fn recover_into<K, S, H, F, E, J, St>(
+    runner: &mut Runner<K, S, H, F>,
+    journal: &J,
+    snapshots: &St,
+    keys: &[K],
+    initial: impl Fn(&K) -> S + Sync,
+    parallelism: usize,
+) -> Vec<(K, RecoverError)>
+where
+    K: Eq + Hash + Clone + Sync,
+    S: Send,
+    F: Fsm<S, H>,
+    J: Journal<K, E> + Sync,
+    St: SnapshotStore<K, S> + Sync,
+    E: Event<S>,
+{
+    let mut failed = Vec::new();
+    let results = recover_all(journal, snapshots, keys, initial, parallelism);
+    for (key, result) in keys.iter().zip(results) {
+        match result {
+            Ok(recovered) => runner.insert(key.clone(), recovered.state),
+            Err(e) => failed.push((key.clone(), e)),
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::MemJournal;
+    use crate::snapshot::MemSnapshotStore;
+
+    #[test]
+    fn test_recover_all() {
+        // Counters journalled as increments, one with a snapshot
+
+        #[derive(Clone)]
+        struct Added(u64);
+
+        impl Event<u64> for Added {
+            fn fire(&self, s: &u64) -> Transition<u64> {
+                Transition::Next(s + self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u64, ()> for MyFsm {}
+
+        let mut journal = MemJournal::new();
+        let mut snapshots = MemSnapshotStore::new();
+        let keys: Vec<u32> = (0..100).collect();
+        for key in &keys {
+            for n in 1..=*key as u64 {
+                journal.append(key, Added(n)).unwrap();
+            }
+        }
+        // Entity 10 was snapshotted after 4 events, which summed to 10
+        snapshots.save(&10, 4, &10).unwrap();
+
+        let results = recover_all(&journal, &snapshots, &keys, |_| 0, 8);
+        assert_eq!(results.len(), 100);
+        assert_eq!(
+            results[10],
+            Ok(Recovered {
+                state: 55,
+                seq: 10,
+                replayed: 6
+            })
+        );
+        for (key, result) in keys.iter().zip(&results) {
+            let k = *key as u64;
+            assert_eq!(result.as_ref().map(|r| r.state), Ok(k * (k + 1) / 2));
+        }
+
+        let mut runner = Runner::<u32, u64, (), MyFsm>::new(());
+        snapshots.fail_loads(true);
+        let failed = recover_into(&mut runner, &journal, &snapshots, &keys[..3], |_| 0, 2);
+        assert_eq!(failed.len(), 3);
+        snapshots.fail_loads(false);
+        let failed = recover_into(&mut runner, &journal, &snapshots, &keys, |_| 0, 2);
+        assert!(failed.is_empty());
+        assert_eq!(runner.state(&99), Some(&4950));
+    }
+
+    #[test]
+    fn test_recover_checkpointed() {
+        // A long history whose replay fails part way, then resumes
+
+        #[derive(Clone)]
+        struct Added(u64);
+
+        impl Event<u64> for Added {
+            fn fire(&self, s: &u64) -> Transition<u64> {
+                Transition::Next(s + self.0)
+            }
+        }
+
+        let mut journal = MemJournal::new();
+        for _ in 0..25 {
+            journal.append(&"a", Added(1)).unwrap();
+        }
+        let mut snapshots = MemSnapshotStore::new();
+
+        // The checkpoint after 20 events fails, which stops the replay
+        struct FailAt<'a>(&'a mut MemSnapshotStore<&'static str, u64>, u64);
+
+        impl SnapshotStore<&'static str, u64> for FailAt<'_> {
+            fn save(&mut self, key: &&'static str, seq: u64, state: &u64) -> Result<(), String> {
+                if seq == self.1 {
+                    Err("crashed".to_string())
+                } else {
+                    self.0.save(key, seq, state)
+                }
+            }
+            fn load(&self, key: &&'static str) -> Result<Option<(u64, u64)>, String> {
+                self.0.load(key)
+            }
+        }
+
+        let crashed =
+            recover_checkpointed(&journal, &mut FailAt(&mut snapshots, 20), &"a", |_| 0, 10);
+        assert_eq!(crashed, Err(RecoverError::Snapshot("crashed".to_string())));
+        assert_eq!(snapshots.load(&"a"), Ok(Some((10, 10))));
+
+        let resumed = recover_checkpointed(&journal, &mut snapshots, &"a", |_| 0, 10);
+        assert_eq!(
+            resumed,
+            Ok(Recovered {
+                state: 25,
+                seq: 25,
+                replayed: 15
+            })
+        );
+        assert_eq!(snapshots.load(&"a"), Ok(Some((25, 25))));
+    }
+}
@@ -0,0 +1,277 @@
+//! Public representations of events, for publication outside the
+//! service. A `Transform` maps an internal event to the event published,
+//! or withholds it, and is applied only on the way out, so what is
+//! journalled is unchanged and a transform can be revised without
+//! migrating the journal.
+//!
+//! `Redacted` applies a transform to an outbound path: as a `Publisher`
+//! for an outbox `Relay`, where withheld events are confirmed without
+//! being delivered, and as an iterator over a `Subscription`, where they
+//! are skipped. Adapters that take records, such as `sse::stream`, apply
+//! one with `Transform::record`.
+//!
+//! `JsonRedaction` is a transform of JSON events that removes or masks
+//! fields holding personal data, wherever they appear in the event.
+
+use crate::journal::Record;
+use crate::json::Json;
+use crate::outbox::Publisher;
+use crate::subscription::Subscription;
+
+/// Maps events E of entities with keys K to their public representation.
+pub trait Transform<K, E> {
+    type Public;
+
+    /// The public representation of an event, or `None` to withhold it.
+    fn public(&self, key: &K, event: &E) -> Option<Self::Public>;
+
+    /// A record with its event in public.
+    fn record(&self, record: &Record<K, E>) -> Option<Record<K, Self::Public>>
+    where
+        K: Clone,
+    {
+        Some(Record {
+            offset: record.offset,
+            key: record.key.clone(),
+            seq: record.seq,
+            event: self.public(&record.key, &record.event)?,
+        })
+    }
+}
+
+impl<K, E, P, F> Transform<K, E> for F
+where
+    F: Fn(&K, &E) -> Option<P>,
+{
+    type Public = P;
+
+    fn public(&self, key: &K, event: &E) -> Option<P> {
+        self(key, event)
+    }
+}
+
+/// An outbound path I whose events are transformed by T.
+pub struct Redacted<I, T> {
+    inner: I,
+    transform: T,
+}
+
+impl<I, T> Redacted<I, T> {
+    pub fn new(inner: I, transform: T) -> Self {
+        Self { inner, transform }
+    }
+
+    pub fn inner(&self) -> &I {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+}
+
+/// Publishes the public representation of each event, confirming those
+/// withheld without publishing them.
+impl<K, E, P, T> Publisher<K, E> for Redacted<P, T>
+where
+    T: Transform<K, E>,
+    P: Publisher<K, T::Public>,
+{
+    fn publish(&mut self, key: &K, event: &E) -> Result<(), String> {
+        match self.transform.public(key, event) {
+            Some(public) => self.inner.publish(key, &public),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The records of a subscription in public, skipping those withheld.
+impl<K, E, T> Iterator for Redacted<Subscription<K, E>, T>
+where
+    K: Clone,
+    T: Transform<K, E>,
+{
+    type Item = Record<K, T::Public>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Ends when the subscription does
+        loop {
+            let record = self.inner.recv()?;
+            if let Some(public) = self.transform.record(&record) {
+                return Some(public);
+            }
+        }
+    }
+}
+
+/// Removes or masks fields of JSON events, at any depth.
+#[derive(Debug, Clone, Default)]
+pub struct JsonRedaction {
+    removed: Vec<String>,
+    masked: Vec<String>,
+    withheld: Vec<String>,
+}
+
+/// The value of a masked field.
+pub const MASK: &str = "[redacted]";
+
+impl JsonRedaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove a field wherever it appears.
+    pub fn remove(mut self, field: &str) -> Self {
+        self.removed.push(field.to_string());
+        self
+    }
+
+    /// Replace the value of a field with `MASK` wherever it appears, so
+    /// consumers see that it was present.
+    pub fn mask(mut self, field: &str) -> Self {
+        self.masked.push(field.to_string());
+        self
+    }
+
+    /// Withhold events of a type, named by their `"type"` field, as the
+    /// JSON journals write them.
+    pub fn withhold(mut self, event_type: &str) -> Self {
+        self.withheld.push(event_type.to_string());
+        self
+    }
+
+    /// The redacted value.
+    pub fn apply(&self, value: &Json) -> Json {
+        match value {
+            Json::Object(members) => Json::Object(
+                members
+                    .iter()
+                    .filter(|(k, _)| !self.removed.contains(k))
+                    .map(|(k, v)| match self.masked.contains(k) {
+                        true => (k.clone(), Json::String(MASK.to_string())),
+                        false => (k.clone(), self.apply(v)),
+                    })
+                    .collect(),
+            ),
+            Json::Array(items) => Json::Array(items.iter().map(|v| self.apply(v)).collect()),
+            other => other.clone(),
+        }
+    }
+}
+
+impl<K> Transform<K, Json> for JsonRedaction {
+    type Public = Json;
+
+    fn public(&self, _key: &K, event: &Json) -> Option<Json> {
+        match event.get("type").and_then(Json::as_str) {
+            Some(t) if self.withheld.iter().any(|w| w == t) => None,
+            _ => Some(self.apply(event)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::{Journal, MemJournal};
+    use crate::outbox::{MemOutbox, Relay};
+    use crate::subscription::EventBus;
+    use std::time::Instant;
+
+    // Customer events published without their personal data, and
+    // without the internal notes kept on the account
+
+    fn redaction() -> JsonRedaction {
+        JsonRedaction::new()
+            .remove("email")
+            .mask("name")
+            .withhold("NoteAdded")
+    }
+
+    fn registered() -> Json {
+        Json::parse(
+            r#"{"type":"Registered","name":"Ann","email":"ann@example.com",
+                "addresses":[{"city":"Oslo","email":"work@example.com"}]}"#,
+        )
+        .unwrap()
+    }
+
+    fn note() -> Json {
+        Json::parse(r#"{"type":"NoteAdded","text":"called twice"}"#).unwrap()
+    }
+
+    #[test]
+    fn test_redaction() {
+        // Fields are removed or masked at any depth, and withheld types
+        // have no public representation
+        assert_eq!(
+            redaction().public(&1, &registered()).map(|e| e.to_string()),
+            Some(
+                r#"{"type":"Registered","name":"[redacted]","addresses":[{"city":"Oslo"}]}"#
+                    .to_string()
+            )
+        );
+        assert_eq!(redaction().public(&1, &note()), None);
+
+        // Events without a type, or of other types, are only redacted
+        let untyped = Json::parse(r#"[{"name":"Bo"}, 3]"#).unwrap();
+        assert_eq!(
+            redaction().public(&1, &untyped).map(|e| e.to_string()),
+            Some(r#"[{"name":"[redacted]"},3]"#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_relay_confirms_withheld() {
+        // The relay publishes the public events and confirms the rest
+        let mut outbox = MemOutbox::new();
+        outbox.push(1, registered());
+        outbox.push(1, note());
+        let mut published = Vec::new();
+        let publisher = |_: &u32, e: &Json| {
+            published.push(e.get("type").and_then(Json::as_str).unwrap().to_string());
+            Ok(())
+        };
+        let mut relay = Relay::new(outbox, Redacted::new(publisher, redaction()));
+        assert_eq!(relay.poll(Instant::now()), Ok(2));
+        assert!(relay.outbox().is_empty());
+        drop(relay);
+        assert_eq!(published, vec!["Registered"]);
+    }
+
+    #[test]
+    fn test_subscription_skips_withheld() {
+        // Subscribers see the public events, while the journal keeps all
+        let bus = EventBus::new(MemJournal::new());
+        bus.publish(&1, note()).unwrap();
+        bus.publish(&1, registered()).unwrap();
+        let mut public = Redacted::new(bus.subscribe_from(0).unwrap(), redaction());
+        let record = public.next().unwrap();
+        assert_eq!((record.offset, record.seq), (1, 2));
+        assert!(record.event.get("email").is_none());
+        let journalled = bus.with_journal(|j| j.read_all(0)).unwrap();
+        assert_eq!(journalled[1].event, registered());
+        assert_eq!(journalled.len(), 2);
+
+        // The subscription, and so the redacted one, ends with the bus
+        drop(bus);
+        assert!(public.next().is_none());
+    }
+
+    #[test]
+    fn test_closure_transform() {
+        // A closure is a transform, here to a public event type
+        let amounts = |_: &u32, e: &(String, u64)| (e.0 != "internal").then_some(e.1);
+        let record = |event: (&str, u64)| Record {
+            offset: 3,
+            key: 1,
+            seq: 2,
+            event: (event.0.to_string(), event.1),
+        };
+        assert_eq!(
+            amounts.record(&record(("deposit", 5))).map(|r| r.event),
+            Some(5)
+        );
+        assert!(amounts.record(&record(("internal", 5))).is_none());
+    }
+}
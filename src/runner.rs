@@ -0,0 +1,922 @@
+//! A runner is the custodian of state for many entities of the same FSM.
+//! Each entity is identified by a key and has its own state. All entities
+//! share one effect handler. Commands are sent to an entity by key and
+//! stepped through the FSM, with the entity's state updated in place.
+//!
+//! Optionally, a panic while stepping an entity can be isolated to that
+//! entity. The entity is then marked as failed and subsequent commands
+//! to it are refused, but the runner and its other entities continue.
+//! Note that the effect handler may have been part way through an effect
+//! when the panic occurred.
+//!
+//! Observers may be attached to a runner to follow what happens to its
+//! entities.
+//!
+//! A runner is `Send` when its keys, states and handler are, so that it
+//! can be moved to a thread of its own, as `ThreadRunner` does. It is not
+//! `Sync`, as observers need only be `Send`. To share one between threads,
+//! put it behind a lock or use a `ThreadSender`.
+//!
+//! An event listener may be given when sending a command. It is invoked
+//! once the event is produced and accepted by the entry hook, and before
+//! it is applied to the entity, which makes it the place to persist or
+//! publish events. A vetoed event never reaches the listener. If the
+//! listener fails the transition is aborted: the entity's state is
+//! unchanged, and neither the transition hook nor `after_event` is called.
+//!
+//! Each live entity has a version, which starts at zero and is incremented
+//! by every transition. A command can be sent with the version its sender
+//! last saw, and is refused with a conflict if the entity has moved on,
+//! which suits optimistic updates from a user interface.
+
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+
+use crate::command_and_event_traits::{
+    Command, Event, Fsm, Query, Rejection, StepError, StepOutcome, Transition, Validate,
+    ValidationError, Veto,
+};
+use crate::descriptor::Describe;
+use crate::observer::{type_label, Observer};
+use crate::read_only::{ReadCommand, ReadOnly};
+
+/// Why a runner could not step an entity.
+#[derive(Debug, PartialEq)]
+pub enum RunError {
+    /// There is no entity with the key
+    Unknown,
+    /// The entity failed earlier and is no longer stepped
+    Failed(String),
+    /// The runner is no longer running
+    Stopped,
+    /// Stepping the entity panicked, which has now failed it
+    Panicked(String),
+    /// The step produced no transition
+    Step(StepError),
+    /// An event listener failed, so the event was not applied
+    Aborted(String),
+    /// The command's effect was cancelled before its event was applied
+    Cancelled,
+    /// The command's effect overran its deadline
+    TimedOut,
+    /// The command exceeded a rate limit
+    RateLimited,
+    /// The command exceeded a rate limit that discards such commands
+    Dropped,
+    /// The entity was not at the version the command expected
+    Conflict { expected: u64, actual: u64 },
+    /// The caller is not authorized to send the command, with the reason
+    Denied(String),
+    /// The command needs a feature flag that is off, named
+    FeatureDisabled(String),
+}
+
+impl RunError {
+    /// The reason the command was rejected, if it was.
+    pub fn rejection(&self) -> Option<Rejection> {
+        match self {
+            RunError::Step(StepError::Invalid(ValidationError(reason))) => {
+                Some(Rejection::ValidationFailed(reason.clone()))
+            }
+            RunError::Step(StepError::Vetoed(Veto(reason))) => {
+                Some(Rejection::Vetoed(reason.clone()))
+            }
+            RunError::Step(StepError::NotAllowed(_)) => Some(Rejection::NotAllowedInState),
+            RunError::Aborted(reason) => Some(Rejection::EffectFailed(reason.clone())),
+            RunError::Denied(reason) => Some(Rejection::Unauthorized(reason.clone())),
+            RunError::RateLimited | RunError::Dropped => Some(Rejection::RateLimited),
+            RunError::FeatureDisabled(flag) => Some(Rejection::FeatureDisabled(flag.clone())),
+            RunError::Conflict { expected, actual } => Some(Rejection::ConcurrencyConflict {
+                expected: *expected,
+                actual: *actual,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Invoked with each event produced for an entity and accepted by its
+/// entry hook, before it is applied.
+pub trait EventListener<K, S, E> {
+    /// Accept an event, or fail it with a reason.
+    fn on_event(&mut self, key: &K, state: &S, event: &E) -> Result<(), String>;
+}
+
+/// No listener.
+impl<K, S, E> EventListener<K, S, E> for () {
+    fn on_event(&mut self, _key: &K, _state: &S, _event: &E) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The outcome of stepping an entity, or the payload of a panic.
+type Stepped<E, S> = Result<StepOutcome<E, S>, Box<dyn Any + Send>>;
+
+/// The outcome of executing a read-only command, or the payload of a panic.
+type Executed<E> = Result<Result<Option<E>, StepError>, Box<dyn Any + Send>>;
+
+enum Entity<S> {
+    Live(S, u64),
+    Failed(String),
+}
+
+/// Runs an FSM, F, for many entities with keys K and states S,
+/// sharing an effect handler H.
+pub struct Runner<K, S, H, F> {
+    entities: HashMap<K, Entity<S>>,
+    handler: H,
+    isolate_panics: bool,
+    observers: Vec<Box<dyn Observer<K, S> + Send>>,
+    fsm: PhantomData<fn() -> F>,
+}
+
+impl<K, S, H, F> Runner<K, S, H, F>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H>,
+{
+    pub fn new(handler: H) -> Self {
+        Self {
+            entities: HashMap::new(),
+            handler,
+            isolate_panics: false,
+            observers: Vec::new(),
+            fsm: PhantomData,
+        }
+    }
+
+    /// Catch panics while stepping an entity, failing just that entity.
+    pub fn with_panic_isolation(mut self) -> Self {
+        self.isolate_panics = true;
+        self
+    }
+
+    /// Notify an observer as entities are stepped.
+    pub fn with_observer(mut self, observer: Box<dyn Observer<K, S> + Send>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Add an entity, or replace one including a failed entity.
+    pub fn insert(&mut self, key: K, state: S) {
+        for o in self.observers.iter_mut() {
+            o.inserted(&key, &state);
+        }
+        self.entities.insert(key, Entity::Live(state, 0));
+    }
+
+    /// Remove an entity, returning its state unless it had failed.
+    pub fn remove(&mut self, key: &K) -> Option<S> {
+        for o in self.observers.iter_mut() {
+            o.removed(key);
+        }
+        match self.entities.remove(key) {
+            Some(Entity::Live(s, _)) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The state of a live entity.
+    pub fn state(&self, key: &K) -> Option<&S> {
+        match self.entities.get(key) {
+            Some(Entity::Live(s, _)) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The version of a live entity's state.
+    pub fn version(&self, key: &K) -> Option<u64> {
+        match self.entities.get(key) {
+            Some(Entity::Live(_, v)) => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// The keys and states of the live entities, in no particular order.
+    pub fn live_states(&self) -> impl Iterator<Item = (&K, &S)> {
+        self.entities.iter().filter_map(|(k, e)| match e {
+            Entity::Live(s, _) => Some((k, s)),
+            Entity::Failed(_) => None,
+        })
+    }
+
+    /// The reason an entity failed, if it has.
+    pub fn failure(&self, key: &K) -> Option<&str> {
+        match self.entities.get(key) {
+            Some(Entity::Failed(reason)) => Some(reason),
+            _ => None,
+        }
+    }
+
+    /// Answer a query against the current state of a live entity.
+    pub fn query<Q>(&self, key: &K, query: &Q) -> Result<Q::Reply, RunError>
+    where
+        Q: Query<S>,
+    {
+        live(&self.entities, key).map(|s| query.answer(s))
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Step an entity with a command, returning the event emitted, if any.
+    pub fn send<C>(&mut self, key: &K, command: &C) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+    {
+        self.send_with(key, command, &mut ())
+    }
+
+    /// Step an entity with a command, provided its state is still at the
+    /// version expected.
+    pub fn send_expected<C>(
+        &mut self,
+        key: &K,
+        expected: u64,
+        command: &C,
+    ) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+    {
+        match self.version(key) {
+            Some(actual) if actual != expected => {
+                let e = RunError::Conflict { expected, actual };
+                for o in self.observers.iter_mut() {
+                    o.rejected(key, &command.name(), &e);
+                }
+                Err(e)
+            }
+            _ => self.send(key, command),
+        }
+    }
+
+    /// Step an entity with a command, passing its event to a listener
+    /// before it is applied.
+    pub fn send_with<C, L>(
+        &mut self,
+        key: &K,
+        command: &C,
+        listener: &mut L,
+    ) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+        L: EventListener<K, S, C::Output>,
+    {
+        let result = self.step_entity(key, command, listener);
+        if let Err(e) = &result {
+            let name = command.name();
+            for o in self.observers.iter_mut() {
+                o.rejected(key, &name, e);
+            }
+        }
+        result
+    }
+
+    /// Report a command refused before it reached the runner, for example
+    /// by a wrapper checking access, to the observers.
+    pub fn reject(&mut self, key: &K, command: &str, error: RunError) -> RunError {
+        for o in self.observers.iter_mut() {
+            o.rejected(key, command, &error);
+        }
+        error
+    }
+
+    /// Apply an event produced outside the runner to an entity, as though
+    /// a command had emitted it.
+    pub fn apply<E>(&mut self, key: &K, event: E) -> Result<Option<E>, RunError>
+    where
+        E: Event<S>,
+    {
+        let label = type_label::<E>();
+        let result = self.apply_executed(key, &label, Ok(Ok(Ok(Some(event)))));
+        if let Err(e) = &result {
+            for o in self.observers.iter_mut() {
+                o.rejected(key, &label, e);
+            }
+        }
+        result
+    }
+
+    fn apply_executed<E>(
+        &mut self,
+        key: &K,
+        command: &str,
+        executed: Result<Executed<E>, RunError>,
+    ) -> Result<Option<E>, RunError>
+    where
+        E: Event<S>,
+    {
+        let stepped = match executed? {
+            Ok(Ok(Some(event))) => {
+                let state = live(&self.entities, key)?;
+                let handler = &mut self.handler;
+                let mut step = || F::step_event(state, &event, handler);
+                let trans = if self.isolate_panics {
+                    panic::catch_unwind(AssertUnwindSafe(step))
+                } else {
+                    Ok(step())
+                };
+                trans.map(|t| (Some(event), t))
+            }
+            Ok(Ok(None)) => Ok((None, Ok(Transition::Same))),
+            Ok(Err(e)) => Ok((None, Err(e))),
+            Err(payload) => Err(payload),
+        };
+        self.settle(key, command, stepped)
+    }
+
+    fn step_entity<C, L>(
+        &mut self,
+        key: &K,
+        command: &C,
+        listener: &mut L,
+    ) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+        L: EventListener<K, S, C::Output>,
+    {
+        let state = live(&self.entities, key)?;
+        let handler = &mut self.handler;
+        // As `Fsm::step`, but with the listener between the entry hook
+        // accepting the event and the rest of the hooks
+        let mut step = || match F::step_command(state, command, handler) {
+            Ok(Some(event)) => {
+                let trans = match F::enter_event(state, &event, handler) {
+                    Ok(trans) => trans,
+                    Err(e) => return Ok((Some(event), Err(e))),
+                };
+                listener.on_event(key, state, &event)?;
+                F::complete_event(state, &event, &trans, handler);
+                Ok((Some(event), Ok(trans)))
+            }
+            Ok(None) => Ok((None, Ok(Transition::Same))),
+            Err(e) => Ok((None, Err(e))),
+        };
+        let stepped = if self.isolate_panics {
+            panic::catch_unwind(AssertUnwindSafe(step))
+        } else {
+            Ok(step())
+        };
+        let stepped = match stepped {
+            Ok(Err(reason)) => return Err(RunError::Aborted(reason)),
+            Ok(Ok(outcome)) => Ok(outcome),
+            Err(payload) => Err(payload),
+        };
+        self.settle(key, &command.name(), stepped)
+    }
+
+    /// Apply the outcome of stepping a live entity.
+    fn settle<E>(
+        &mut self,
+        key: &K,
+        command: &str,
+        stepped: Stepped<E, S>,
+    ) -> Result<Option<E>, RunError> {
+        let entity = self.entities.get_mut(key).ok_or(RunError::Unknown)?;
+        let (state, version) = match entity {
+            Entity::Live(s, v) => (s, v),
+            Entity::Failed(reason) => return Err(RunError::Failed(reason.clone())),
+        };
+        match stepped {
+            Ok((event, Ok(Transition::Next(s)))) => {
+                for o in self.observers.iter_mut() {
+                    o.transitioned(key, state, &type_label::<E>(), &s);
+                }
+                *state = s;
+                *version += 1;
+                Ok(event)
+            }
+            Ok((event, Ok(Transition::Same))) => {
+                if event.is_none() {
+                    for o in self.observers.iter_mut() {
+                        o.ignored(key, state, command);
+                    }
+                }
+                Ok(event)
+            }
+            Ok((_, Err(e))) => Err(RunError::Step(e)),
+            Err(payload) => {
+                let reason = panic_reason(payload.as_ref());
+                *entity = Entity::Failed(reason.clone());
+                Err(RunError::Panicked(reason))
+            }
+        }
+    }
+}
+
+impl<K, S, H, F> Runner<K, S, H, F>
+where
+    K: Eq + Hash + Sync,
+    S: Sync,
+    H: Sync,
+    F: Fsm<S, H>,
+{
+    /// Step several entities with read-only commands. The commands for
+    /// different entities are executed concurrently, sharing the handler,
+    /// then their events are applied in order. Commands for an entity
+    /// that appears more than once are executed in turn.
+    pub fn send_read_only<C>(
+        &mut self,
+        commands: &[(K, C)],
+    ) -> Vec<Result<Option<C::Output>, RunError>>
+    where
+        C: ReadCommand<S, H> + Validate<S> + Sync,
+        C::Output: Send,
+    {
+        let mut results = Vec::with_capacity(commands.len());
+        let mut rest = commands;
+        while !rest.is_empty() {
+            let mut keys = HashSet::new();
+            let n = rest.iter().take_while(|(k, _)| keys.insert(k)).count();
+            let (round, tail) = rest.split_at(n);
+            results.extend(self.read_round(round));
+            rest = tail;
+        }
+        results
+    }
+
+    /// Step distinct entities with read-only commands.
+    fn read_round<C>(&mut self, round: &[(K, C)]) -> Vec<Result<Option<C::Output>, RunError>>
+    where
+        C: ReadCommand<S, H> + Validate<S> + Sync,
+        C::Output: Send,
+    {
+        for (key, command) in round {
+            if let Some(Entity::Live(state, _)) = self.entities.get(key) {
+                F::before_command(state, &ReadOnly(command), &mut self.handler);
+            }
+        }
+
+        // Validate and execute concurrently
+
+        let executed: Vec<_> = thread::scope(|scope| {
+            let (entities, handler) = (&self.entities, &self.handler);
+            let isolate_panics = self.isolate_panics;
+            let threads: Vec<_> = round
+                .iter()
+                .map(|(key, command)| {
+                    scope.spawn(move || {
+                        let state = live(entities, key)?;
+                        let execute = || {
+                            let name = ReadOnly(command).name();
+                            if !F::allows(state, &name) {
+                                return Err(StepError::NotAllowed(name));
+                            }
+                            match command.validate(state) {
+                                Ok(()) => Ok(command.execute_read(state, handler)),
+                                Err(invalid) => Err(StepError::Invalid(invalid)),
+                            }
+                        };
+                        Ok(if isolate_panics {
+                            panic::catch_unwind(AssertUnwindSafe(execute))
+                        } else {
+                            Ok(execute())
+                        })
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|t| t.join().unwrap_or_else(|p| panic::resume_unwind(p)))
+                .collect()
+        });
+
+        // Apply in order
+
+        round
+            .iter()
+            .zip(executed)
+            .map(|((key, command), executed)| {
+                let label = ReadOnly(command).name();
+                let result = self.apply_executed(key, &label, executed);
+                if let Err(e) = &result {
+                    for o in self.observers.iter_mut() {
+                        o.rejected(key, &label, e);
+                    }
+                }
+                result
+            })
+            .collect()
+    }
+}
+
+impl<K, S, H, F> Runner<K, S, H, F>
+where
+    K: Eq + Hash,
+    F: Fsm<S, H> + Describe<S>,
+{
+    /// The commands that are valid for a live entity in its current state.
+    pub fn valid_commands(&self, key: &K) -> Option<Vec<String>> {
+        self.state(key).map(F::valid_commands)
+    }
+}
+
+fn live<'a, K: Eq + Hash, S>(
+    entities: &'a HashMap<K, Entity<S>>,
+    key: &K,
+) -> Result<&'a S, RunError> {
+    match entities.get(key) {
+        Some(Entity::Live(s, _)) => Ok(s),
+        Some(Entity::Failed(reason)) => Err(RunError::Failed(reason.clone())),
+        None => Err(RunError::Unknown),
+    }
+}
+
+fn panic_reason(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "panicked".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::correlation::{Correlated, Correlation};
+    use crate::descriptor::MachineDescriptor;
+    use crate::fixtures::{Counter, Increment, Incremented};
+    use crate::journal::{Journal, MemJournal};
+    use crate::subscription::EventBus;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_panic_isolation() {
+        // A counter whose increment panics on reaching a limit
+
+        struct CappedIncrement {}
+
+        impl Command<u32, ()> for CappedIncrement {
+            type Output = Incremented;
+            fn execute(&self, s: &u32, _h: &mut ()) -> Option<Incremented> {
+                if *s == 2 {
+                    panic!("limit reached");
+                }
+                Some(Incremented {})
+            }
+        }
+
+        impl Validate<u32> for CappedIncrement {}
+
+        let mut runner = Runner::<&str, u32, (), Counter>::new(()).with_panic_isolation();
+        runner.insert("a", 0);
+        runner.insert("b", 2);
+
+        assert_eq!(
+            runner.send(&"a", &CappedIncrement {}),
+            Ok(Some(Incremented {}))
+        );
+        assert_eq!(runner.state(&"a"), Some(&1));
+
+        assert_eq!(
+            runner.send(&"b", &CappedIncrement {}),
+            Err(RunError::Panicked("limit reached".to_string()))
+        );
+        assert_eq!(runner.failure(&"b"), Some("limit reached"));
+        assert_eq!(
+            runner.send(&"b", &CappedIncrement {}),
+            Err(RunError::Failed("limit reached".to_string()))
+        );
+
+        // Other entities are unaffected
+
+        assert_eq!(
+            runner.send(&"a", &CappedIncrement {}),
+            Ok(Some(Incremented {}))
+        );
+        assert_eq!(runner.state(&"a"), Some(&2));
+        assert_eq!(
+            runner.send(&"c", &CappedIncrement {}),
+            Err(RunError::Unknown)
+        );
+
+        // Queries are answered without stepping
+
+        struct Remaining {}
+
+        impl Query<u32> for Remaining {
+            type Reply = u32;
+            fn answer(&self, s: &u32) -> u32 {
+                2 - s
+            }
+        }
+
+        assert_eq!(runner.query(&"a", &Remaining {}), Ok(0));
+        assert_eq!(
+            runner.query(&"b", &Remaining {}),
+            Err(RunError::Failed("limit reached".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_event_listener() {
+        // A counter whose events are persisted, with a store that fills up
+
+        struct Store(Vec<(u32, Incremented)>);
+
+        impl EventListener<&str, u32, Incremented> for Store {
+            fn on_event(&mut self, _key: &&str, s: &u32, e: &Incremented) -> Result<(), String> {
+                if self.0.len() == 2 {
+                    return Err("store full".to_string());
+                }
+                self.0.push((*s, e.clone()));
+                Ok(())
+            }
+        }
+
+        let mut runner = Runner::<&str, u32, (), Counter>::new(());
+        runner.insert("a", 0);
+        let mut store = Store(Vec::new());
+
+        for _ in 0..2 {
+            assert_eq!(
+                runner.send_with(&"a", &Increment {}, &mut store),
+                Ok(Some(Incremented {}))
+            );
+        }
+        let aborted = runner.send_with(&"a", &Increment {}, &mut store);
+        assert_eq!(aborted, Err(RunError::Aborted("store full".to_string())));
+        assert_eq!(
+            aborted.unwrap_err().rejection(),
+            Some(Rejection::EffectFailed("store full".to_string()))
+        );
+        assert_eq!(runner.state(&"a"), Some(&2));
+        assert_eq!(store.0, vec![(0, Incremented {}), (1, Incremented {})]);
+    }
+
+    #[test]
+    fn test_aborted_event_has_no_effects() {
+        // A light whose entry and after-event hooks are logged, with a
+        // listener that refuses to persist the event
+
+        struct Toggle {}
+
+        #[derive(Debug, PartialEq)]
+        struct Toggled {}
+
+        impl Command<bool, Vec<&'static str>> for Toggle {
+            type Output = Toggled;
+            fn execute(&self, _s: &bool, log: &mut Vec<&'static str>) -> Option<Toggled> {
+                log.push("execute");
+                Some(Toggled {})
+            }
+        }
+
+        impl Validate<bool> for Toggle {}
+
+        impl Event<bool> for Toggled {
+            fn fire(&self, s: &bool) -> Transition<bool> {
+                Transition::Next(!s)
+            }
+        }
+
+        struct Light {}
+
+        impl Fsm<bool, Vec<&'static str>> for Light {
+            fn on_entry(_old: &bool, _new: &bool, log: &mut Vec<&'static str>) -> Result<(), Veto> {
+                log.push("entry");
+                Ok(())
+            }
+
+            fn on_transition(_old: &bool, _new: &bool, log: &mut Vec<&'static str>) {
+                log.push("transition");
+            }
+
+            fn after_event<E>(_s: &bool, _e: &E, _t: &Transition<bool>, log: &mut Vec<&'static str>)
+            where
+                E: Event<bool>,
+            {
+                log.push("after");
+            }
+        }
+
+        struct Refuse {}
+
+        impl EventListener<u8, bool, Toggled> for Refuse {
+            fn on_event(&mut self, _k: &u8, _s: &bool, _e: &Toggled) -> Result<(), String> {
+                Err("disk full".to_string())
+            }
+        }
+
+        let mut runner = Runner::<u8, bool, _, Light>::new(Vec::new()).with_panic_isolation();
+        runner.insert(1, false);
+        assert_eq!(
+            runner.send_with(&1, &Toggle {}, &mut Refuse {}),
+            Err(RunError::Aborted("disk full".to_string()))
+        );
+        assert_eq!(runner.state(&1), Some(&false));
+        assert_eq!(runner.version(&1), Some(0));
+        assert_eq!(*runner.handler(), vec!["execute", "entry"]);
+
+        assert_eq!(
+            runner.send_with(&1, &Toggle {}, &mut ()),
+            Ok(Some(Toggled {}))
+        );
+        assert_eq!(
+            *runner.handler(),
+            vec![
+                "execute",
+                "entry",
+                "execute",
+                "entry",
+                "transition",
+                "after"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vetoed_event_not_listened() {
+        // A counter that is full, with its events journalled by a bus: the
+        // vetoed event is not journalled, so replay agrees with the state
+
+        struct Full {}
+
+        impl Fsm<u32, ()> for Full {
+            fn on_entry(_old: &u32, _new: &u32, _h: &mut ()) -> Result<(), Veto> {
+                Err(Veto("full".to_string()))
+            }
+        }
+
+        let mut runner = Runner::<&str, u32, (), Full>::new(());
+        runner.insert("a", 0);
+        let mut bus = EventBus::new(MemJournal::new());
+
+        assert_eq!(
+            runner.send_with(&"a", &Increment {}, &mut bus),
+            Err(RunError::Step(StepError::Vetoed(Veto("full".to_string()))))
+        );
+        assert_eq!(runner.state(&"a"), Some(&0));
+        assert_eq!(bus.with_journal(|j| j.last_seq(&"a")), Ok(0));
+    }
+
+    #[test]
+    fn test_send_expected() {
+        // A title edited from two browser tabs
+
+        struct Rename(&'static str);
+
+        #[derive(Debug, PartialEq)]
+        struct Renamed(&'static str);
+
+        impl Command<&'static str, ()> for Rename {
+            type Output = Renamed;
+            fn execute(&self, _s: &&'static str, _h: &mut ()) -> Option<Renamed> {
+                Some(Renamed(self.0))
+            }
+        }
+
+        impl Validate<&'static str> for Rename {}
+
+        impl Event<&'static str> for Renamed {
+            fn fire(&self, s: &&'static str) -> Transition<&'static str> {
+                if *s == self.0 {
+                    Transition::Same
+                } else {
+                    Transition::Next(self.0)
+                }
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<&'static str, ()> for MyFsm {}
+
+        let mut runner = Runner::<u32, _, (), MyFsm>::new(());
+        runner.insert(1, "draft");
+        assert_eq!(runner.version(&1), Some(0));
+
+        // Both tabs saw version 0, and the first to save wins
+        assert_eq!(
+            runner.send_expected(&1, 0, &Rename("final")),
+            Ok(Some(Renamed("final")))
+        );
+        let conflict = runner.send_expected(&1, 0, &Rename("other")).unwrap_err();
+        assert_eq!(
+            conflict,
+            RunError::Conflict {
+                expected: 0,
+                actual: 1
+            }
+        );
+        assert_eq!(
+            conflict.rejection(),
+            Some(Rejection::ConcurrencyConflict {
+                expected: 0,
+                actual: 1
+            })
+        );
+
+        // Only transitions move the version on
+        runner.send(&1, &Rename("final")).unwrap();
+        assert_eq!(runner.version(&1), Some(1));
+        assert_eq!(runner.version(&2), None);
+    }
+
+    #[test]
+    fn test_allowed_commands() {
+        // A door whose commands need not check the state, because its
+        // descriptor declares where each is allowed
+
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        enum Door {
+            Open,
+            Closed,
+        }
+
+        struct Open {}
+
+        struct Close {}
+
+        #[derive(Debug, PartialEq)]
+        struct Moved(Door);
+
+        impl Command<Door, u32> for Open {
+            type Output = Moved;
+            fn execute(&self, _s: &Door, creaks: &mut u32) -> Option<Moved> {
+                *creaks += 1;
+                Some(Moved(Door::Open))
+            }
+        }
+
+        impl Command<Door, u32> for Close {
+            type Output = Moved;
+            fn execute(&self, _s: &Door, _h: &mut u32) -> Option<Moved> {
+                Some(Moved(Door::Closed))
+            }
+        }
+
+        impl Validate<Door> for Open {}
+
+        impl Validate<Door> for Close {}
+
+        impl Event<Door> for Moved {
+            fn fire(&self, _s: &Door) -> Transition<Door> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Describe<Door> for MyFsm {
+            fn descriptor() -> MachineDescriptor {
+                MachineDescriptor::new("door")
+                    .transition("Closed", "Open", "Moved", "Open")
+                    .transition("Open", "Close", "Moved", "Closed")
+            }
+
+            fn state_name(state: &Door) -> String {
+                format!("{state:?}")
+            }
+        }
+
+        impl Fsm<Door, u32> for MyFsm {
+            fn allows(state: &Door, command: &str) -> bool {
+                Self::declares(state, command)
+            }
+        }
+
+        let mut runner = Runner::<u32, _, _, MyFsm>::new(0);
+        runner.insert(1, Door::Closed);
+        let refused = runner.send(&1, &Close {}).unwrap_err();
+        assert_eq!(
+            refused,
+            RunError::Step(StepError::NotAllowed("Close".to_string()))
+        );
+        assert_eq!(refused.rejection(), Some(Rejection::NotAllowedInState));
+
+        assert_eq!(runner.send(&1, &Open {}), Ok(Some(Moved(Door::Open))));
+        assert!(runner.send(&1, &Open {}).is_err());
+        assert_eq!(*runner.handler(), 1);
+
+        // Wrapped commands are allowed, and refused, as the commands they
+        // wrap, and observers see them by those names
+
+        struct Refused(Arc<Mutex<Vec<String>>>);
+
+        impl Observer<u32, Door> for Refused {
+            fn rejected(&mut self, _key: &u32, command: &str, _error: &RunError) {
+                self.0.lock().unwrap().push(command.to_string());
+            }
+        }
+
+        let refused = Arc::new(Mutex::new(Vec::new()));
+        let mut runner = runner.with_observer(Box::new(Refused(refused.clone())));
+        let traced = Correlated::new(Correlation::new("m1"), Close {});
+        assert!(runner.send(&1, &traced).is_ok());
+        assert_eq!(runner.state(&1), Some(&Door::Closed));
+        assert!(runner.send(&1, &traced).is_err());
+        assert_eq!(*refused.lock().unwrap(), vec!["Close"]);
+    }
+}
@@ -0,0 +1,271 @@
+//! Running a new version of a machine in shadow of the live one, to
+//! de-risk a rollout. Every command sent to the primary runner is also
+//! sent to the shadow runner, which keeps its own states for the same
+//! entities. The events, outcomes and states are compared, and each
+//! divergence is counted and logged. The caller only ever sees the
+//! primary's result.
+//!
+//! The shadow's effect handler should suppress effects, or record them
+//! for inspection, since the primary has already performed them. Panics
+//! in the shadow are isolated, failing only the shadow entity. Where the
+//! states diverge, the shadow entity is reset from the primary so that
+//! one difference is not reported again for every later command.
+
+use std::fmt::Debug;
+use std::hash::Hash;
+
+use crate::command_and_event_traits::{Command, Fsm, Validate};
+use crate::observer::type_label;
+use crate::runner::{RunError, Runner};
+use crate::testing::Diverged;
+
+/// A difference between the primary and the shadow, for a command sent
+/// to an entity. The values are written with their `Debug`
+/// representations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowDivergence<K> {
+    pub key: K,
+    pub command: String,
+    pub diverged: Diverged,
+    pub primary: String,
+    pub shadow: String,
+}
+
+/// Counts of the commands shadowed and the divergences found.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShadowMetrics {
+    pub compared: u64,
+    pub diverged: u64,
+}
+
+/// A primary runner, of FSM F with states S, shadowed by a runner of
+/// FSM G with states T.
+pub struct Shadowed<K, S, H, F, T, I, G> {
+    primary: Runner<K, S, H, F>,
+    shadow: Runner<K, T, I, G>,
+    convert: Box<dyn Fn(&S) -> T + Send>,
+    metrics: ShadowMetrics,
+    divergences: Vec<ShadowDivergence<K>>,
+}
+
+impl<K, S, H, F, T, I, G> Shadowed<K, S, H, F, T, I, G>
+where
+    K: Eq + Hash + Clone,
+    F: Fsm<S, H>,
+    G: Fsm<T, I>,
+    S: Debug,
+    T: Debug,
+{
+    /// Shadow a primary runner, converting primary states to shadow
+    /// states with `convert`. Entities already in the primary are added
+    /// to the shadow.
+    pub fn new(
+        primary: Runner<K, S, H, F>,
+        shadow: Runner<K, T, I, G>,
+        convert: impl Fn(&S) -> T + Send + 'static,
+    ) -> Self {
+        let mut shadow = shadow.with_panic_isolation();
+        for (key, state) in primary.live_states() {
+            shadow.insert(key.clone(), convert(state));
+        }
+        Self {
+            primary,
+            shadow,
+            convert: Box::new(convert),
+            metrics: ShadowMetrics::default(),
+            divergences: Vec::new(),
+        }
+    }
+
+    /// Add an entity to the primary and the shadow.
+    pub fn insert(&mut self, key: K, state: S) {
+        self.shadow.insert(key.clone(), (self.convert)(&state));
+        self.primary.insert(key, state);
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<S> {
+        self.shadow.remove(key);
+        self.primary.remove(key)
+    }
+
+    pub fn primary(&self) -> &Runner<K, S, H, F> {
+        &self.primary
+    }
+
+    pub fn shadow(&self) -> &Runner<K, T, I, G> {
+        &self.shadow
+    }
+
+    pub fn metrics(&self) -> ShadowMetrics {
+        self.metrics
+    }
+
+    /// Take the divergences logged so far.
+    pub fn take_divergences(&mut self) -> Vec<ShadowDivergence<K>> {
+        std::mem::take(&mut self.divergences)
+    }
+
+    /// Step an entity with a command in the primary, then in the shadow,
+    /// returning the primary's result.
+    pub fn send<C>(
+        &mut self,
+        key: &K,
+        command: &C,
+    ) -> Result<Option<<C as Command<S, H>>::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S> + Command<T, I> + Validate<T>,
+        <C as Command<S, H>>::Output: PartialEq<<C as Command<T, I>>::Output> + Debug,
+        <C as Command<T, I>>::Output: Debug,
+        S: PartialEq<T>,
+    {
+        let primary = self.primary.send(key, command);
+        if matches!(primary, Err(RunError::Unknown)) {
+            return primary;
+        }
+        let shadow = self.shadow.send(key, command);
+        self.metrics.compared += 1;
+
+        let diverged = match (&primary, &shadow) {
+            (Ok(p), Ok(s)) => {
+                let same = match (p, s) {
+                    (Some(p), Some(s)) => p == s,
+                    (None, None) => true,
+                    _ => false,
+                };
+                (!same).then(|| (Diverged::Event, format!("{p:?}"), format!("{s:?}")))
+            }
+            (Err(p), Err(s)) if p == s => None,
+            (p, s) => Some((
+                Diverged::Outcome,
+                format!("{:?}", p.as_ref().err()),
+                format!("{:?}", s.as_ref().err()),
+            )),
+        };
+        let diverged =
+            diverged.or_else(|| match (self.primary.state(key), self.shadow.state(key)) {
+                (Some(p), Some(s)) if p == s => None,
+                (p, s) => Some((Diverged::State, format!("{p:?}"), format!("{s:?}"))),
+            });
+
+        if let Some((diverged, p, s)) = diverged {
+            self.metrics.diverged += 1;
+            self.divergences.push(ShadowDivergence {
+                key: key.clone(),
+                command: type_label::<C>(),
+                diverged,
+                primary: p,
+                shadow: s,
+            });
+            if let Some(state) = self.primary.state(key) {
+                self.shadow.insert(key.clone(), (self.convert)(state));
+            }
+        }
+        primary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Transition};
+
+    #[test]
+    fn test_shadowed() {
+        // A counter, reworked to keep a history, with a bug in the rework
+        // where adding zero is ignored
+
+        #[derive(Debug, PartialEq)]
+        struct Count(u32);
+
+        struct Add(u32);
+
+        #[derive(Debug, PartialEq)]
+        struct Added(u32);
+
+        impl Command<Count, ()> for Add {
+            type Output = Added;
+            fn execute(&self, _s: &Count, _h: &mut ()) -> Option<Added> {
+                Some(Added(self.0))
+            }
+        }
+
+        impl Validate<Count> for Add {}
+
+        impl Event<Count> for Added {
+            fn fire(&self, s: &Count) -> Transition<Count> {
+                Transition::Next(Count(s.0 + self.0))
+            }
+        }
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct History(Vec<u32>);
+
+        impl PartialEq<History> for Count {
+            fn eq(&self, other: &History) -> bool {
+                self.0 == other.0.iter().sum::<u32>()
+            }
+        }
+
+        impl Command<History, Vec<u32>> for Add {
+            type Output = Added;
+            fn execute(&self, _s: &History, recorded: &mut Vec<u32>) -> Option<Added> {
+                recorded.push(self.0);
+                (self.0 > 0).then_some(Added(self.0))
+            }
+        }
+
+        impl Validate<History> for Add {}
+
+        impl Event<History> for Added {
+            fn fire(&self, s: &History) -> Transition<History> {
+                let mut h = s.0.clone();
+                h.push(self.0);
+                Transition::Next(History(h))
+            }
+        }
+
+        struct Old {}
+
+        impl Fsm<Count, ()> for Old {}
+
+        struct New {}
+
+        impl Fsm<History, Vec<u32>> for New {}
+
+        let mut primary = Runner::<&str, Count, (), Old>::new(());
+        primary.insert("a", Count(5));
+        let mut shadowed = Shadowed::new(
+            primary,
+            Runner::<_, _, _, New>::new(Vec::new()),
+            |s: &Count| History(vec![s.0]),
+        );
+
+        assert_eq!(shadowed.send(&"a", &Add(2)), Ok(Some(Added(2))));
+        assert_eq!(shadowed.send(&"a", &Add(0)), Ok(Some(Added(0))));
+        assert_eq!(shadowed.send(&"a", &Add(1)), Ok(Some(Added(1))));
+        assert_eq!(shadowed.send(&"b", &Add(1)), Err(RunError::Unknown));
+
+        assert_eq!(
+            shadowed.metrics(),
+            ShadowMetrics {
+                compared: 3,
+                diverged: 1
+            }
+        );
+        assert_eq!(
+            shadowed.take_divergences(),
+            vec![ShadowDivergence {
+                key: "a",
+                command: "Add".to_string(),
+                diverged: Diverged::Event,
+                primary: "Some(Added(0))".to_string(),
+                shadow: "None".to_string(),
+            }]
+        );
+
+        // The shadow's effects were recorded, and it was reset
+        assert_eq!(shadowed.shadow().handler(), &vec![2, 0, 1]);
+        assert_eq!(shadowed.shadow().state(&"a"), Some(&History(vec![7, 1])));
+        assert_eq!(shadowed.primary().state(&"a"), Some(&Count(8)));
+    }
+}
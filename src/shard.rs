@@ -0,0 +1,246 @@
+//! Partitioning entities across shards. Commands that name their entity
+//! implement `ShardKey`, so they can be routed without the caller knowing
+//! the key separately. `shard_of` assigns a key to a shard by the FNV-1a
+//! hash of its `PartitionKey` bytes, an explicit encoding rather than its
+//! `Hash`, so every router, and any broker partitioning by the same rule,
+//! agrees on where an entity's commands go, whatever its platform or
+//! toolchain.
+//!
+//! `Shards` holds one of something per shard: a `Runner`, or a
+//! `ThreadSender` for a runner on its own thread.
+
+use std::hash::{Hash, Hasher};
+
+use crate::command_and_event_traits::{Command, Fsm, Validate};
+use crate::integrity::StableHasher;
+use crate::runner::{RunError, Runner};
+use crate::thread_runner::ThreadSender;
+
+/// Commands which name the entity they are for.
+pub trait ShardKey<K> {
+    fn shard_key(&self) -> K;
+}
+
+/// Keys with the bytes they are partitioned by. Strings are their UTF-8
+/// bytes and integers big endian at their width, with `usize` and
+/// `isize` as 64 bits.
+pub trait PartitionKey {
+    fn partition_bytes(&self) -> Vec<u8>;
+}
+
+impl PartitionKey for str {
+    fn partition_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl PartitionKey for String {
+    fn partition_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl PartitionKey for [u8] {
+    fn partition_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+impl PartitionKey for Vec<u8> {
+    fn partition_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+}
+
+impl<T: PartitionKey + ?Sized> PartitionKey for &T {
+    fn partition_bytes(&self) -> Vec<u8> {
+        (**self).partition_bytes()
+    }
+}
+
+macro_rules! partition_int {
+    ($($t:ty => $as:ty),*) => {$(
+        impl PartitionKey for $t {
+            fn partition_bytes(&self) -> Vec<u8> {
+                (*self as $as).to_be_bytes().to_vec()
+            }
+        }
+    )*};
+}
+
+partition_int!(
+    u8 => u8, u16 => u16, u32 => u32, u64 => u64, u128 => u128, usize => u64,
+    i8 => i8, i16 => i16, i32 => i32, i64 => i64, i128 => i128, isize => i64
+);
+
+/// The shard, out of a number of shards, for a key.
+pub fn shard_of<K: PartitionKey + ?Sized>(key: &K, shards: usize) -> usize {
+    let mut hasher = StableHasher::default();
+    hasher.write(&key.partition_bytes());
+    (hasher.finish() % shards.max(1) as u64) as usize
+}
+
+/// One of T for each shard.
+pub struct Shards<T> {
+    shards: Vec<T>,
+}
+
+impl<T> Shards<T> {
+    /// Shards, of which there must be at least one.
+    pub fn new(shards: Vec<T>) -> Self {
+        assert!(!shards.is_empty(), "there must be at least one shard");
+        Self { shards }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.is_empty()
+    }
+
+    pub fn shards(&self) -> &[T] {
+        &self.shards
+    }
+
+    /// The shard for a key.
+    pub fn for_key<K: PartitionKey>(&self, key: &K) -> &T {
+        &self.shards[shard_of(key, self.shards.len())]
+    }
+
+    pub fn for_key_mut<K: PartitionKey>(&mut self, key: &K) -> &mut T {
+        let i = shard_of(key, self.shards.len());
+        &mut self.shards[i]
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.shards
+    }
+}
+
+impl<K, S, H, F> Shards<Runner<K, S, H, F>>
+where
+    K: Eq + Hash + PartitionKey,
+    F: Fsm<S, H>,
+{
+    /// Add an entity to its shard.
+    pub fn insert(&mut self, key: K, state: S) {
+        self.for_key_mut(&key).insert(key, state)
+    }
+
+    pub fn state(&self, key: &K) -> Option<&S> {
+        self.for_key(key).state(key)
+    }
+
+    /// Step the entity named by a command, on its shard.
+    pub fn send<C>(&mut self, command: &C) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S> + ShardKey<K>,
+    {
+        let key = command.shard_key();
+        self.for_key_mut(&key).send(&key, command)
+    }
+}
+
+impl<K, C, E> Shards<ThreadSender<K, C, E>>
+where
+    K: PartitionKey,
+    C: ShardKey<K>,
+{
+    /// Send a command to the thread of the shard for the entity it names.
+    pub fn send(&self, command: C) -> Result<Option<E>, RunError> {
+        let key = command.shard_key();
+        self.for_key(&key).send(key, command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Transition};
+    use crate::thread_runner::ThreadRunner;
+
+    #[test]
+    fn test_shards() {
+        // Counters, where each command names its counter
+
+        struct Increment(u32);
+
+        #[derive(Debug, PartialEq)]
+        struct Incremented {}
+
+        impl Command<u32, ()> for Increment {
+            type Output = Incremented;
+            fn execute(&self, _s: &u32, _h: &mut ()) -> Option<Incremented> {
+                Some(Incremented {})
+            }
+        }
+
+        impl Validate<u32> for Increment {}
+
+        impl ShardKey<u32> for Increment {
+            fn shard_key(&self) -> u32 {
+                self.0
+            }
+        }
+
+        impl Event<u32> for Incremented {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s + 1)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, ()> for MyFsm {}
+
+        assert_eq!(shard_of(&7u32, 4), shard_of(&7u32, 4));
+        assert_eq!(shard_of(&7u32, 0), 0);
+
+        let mut shards = Shards::new(
+            (0..3)
+                .map(|_| Runner::<u32, u32, (), MyFsm>::new(()))
+                .collect(),
+        );
+        for key in 0..10 {
+            shards.insert(key, 0);
+        }
+        for key in [1, 2, 2, 9] {
+            assert_eq!(shards.send(&Increment(key)), Ok(Some(Incremented {})));
+        }
+        assert_eq!(shards.state(&2), Some(&2));
+        assert_eq!(shards.send(&Increment(10)), Err(RunError::Unknown));
+
+        // Each entity is only in the runner of its shard
+        for (i, runner) in shards.shards().iter().enumerate() {
+            let keys: Vec<_> = runner.live_states().map(|(k, _)| *k).collect();
+            assert!(keys.iter().all(|k| shard_of(k, 3) == i));
+        }
+
+        // The same routing with a thread per shard
+        let threads: Vec<_> = shards
+            .into_inner()
+            .into_iter()
+            .map(ThreadRunner::<u32, u32, (), MyFsm, Increment>::spawn)
+            .collect();
+        let senders = Shards::new(threads.iter().map(|t| t.sender()).collect());
+        assert_eq!(senders.send(Increment(2)), Ok(Some(Incremented {})));
+        let runners: Vec<_> = threads.into_iter().map(|t| t.shutdown()).collect();
+        assert_eq!(runners[shard_of(&2u32, 3)].state(&2), Some(&3));
+    }
+
+    #[test]
+    fn test_partition_bytes() {
+        // Shards follow from the key's bytes alone, so the values here
+        // hold on any platform, and a broker hashing the same bytes with
+        // FNV-1a agrees
+        assert_eq!(7u32.partition_bytes(), vec![0, 0, 0, 7]);
+        assert_eq!(7usize.partition_bytes(), 7u64.partition_bytes());
+        assert_eq!((-1isize).partition_bytes(), vec![0xff; 8]);
+        assert_eq!("order-1".partition_bytes(), b"order-1".to_vec());
+        assert_eq!(shard_of("order-1", 4), shard_of(&"order-1".to_string(), 4));
+        assert_eq!(shard_of("", 1 << 20), 0xcbf2_9ce4_8422_2325 % (1 << 20));
+        assert_eq!(shard_of("a", 1 << 20), 0xaf63_dc4c_8601_ec8c % (1 << 20));
+    }
+}
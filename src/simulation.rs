@@ -0,0 +1,238 @@
+//! Deterministic simulation of delivery and effect latency. A
+//! `Simulation` keeps virtual time and a queue of messages in flight,
+//! each delivered after a latency drawn from its channel's distribution
+//! with a seeded generator. Time advances only as messages are delivered
+//! or deadlines pass, so a run takes no real time and is the same for the
+//! same seed, and races such as a reply arriving after its timeout fired
+//! are reproduced rather than left to chance.
+//!
+//! Timers are driven by the simulation's clock: `next_until` is given the
+//! next deadline of a `Timers`, `Watchdog` or `StateTimer`, and answers
+//! the next message due before it, or `None` once the clock has reached
+//! the deadline, when the timers' `due` or `fire_due` is called with
+//! `sim.now()`.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::soak::Rng;
+
+/// A distribution of latencies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Evenly spread between a minimum and maximum
+    Uniform(Duration, Duration),
+    /// A minimum plus an exponentially distributed delay with a mean,
+    /// giving the long tail of a network
+    Exponential {
+        min: Duration,
+        mean: Duration,
+    },
+}
+
+impl Latency {
+    pub fn sample(&self, rng: &mut Rng) -> Duration {
+        match self {
+            Latency::Fixed(d) => *d,
+            Latency::Uniform(min, max) => {
+                let spread = max.saturating_sub(*min).as_nanos() as u64;
+                *min + Duration::from_nanos(rng.below(spread + 1))
+            }
+            Latency::Exponential { min, mean } => {
+                // 1 - u is in (0, 1], so its log is finite
+                let u = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+                *min + mean.mul_f64(-(1.0 - u).ln())
+            }
+        }
+    }
+}
+
+/// Messages M in flight on channels with latencies, in virtual time.
+pub struct Simulation<M> {
+    rng: Rng,
+    start: Instant,
+    now: Duration,
+    latencies: HashMap<String, Latency>,
+    default: Latency,
+    queue: BinaryHeap<Reverse<(Duration, u64)>>,
+    messages: HashMap<u64, M>,
+    sent: u64,
+}
+
+impl<M> Simulation<M> {
+    /// A simulation at time zero, delivering on any channel without
+    /// latency until configured.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            start: Instant::now(),
+            now: Duration::ZERO,
+            latencies: HashMap::new(),
+            default: Latency::Fixed(Duration::ZERO),
+            queue: BinaryHeap::new(),
+            messages: HashMap::new(),
+            sent: 0,
+        }
+    }
+
+    /// The latency of a channel, such as an effect or a route between nodes.
+    pub fn with_channel(mut self, channel: &str, latency: Latency) -> Self {
+        self.latencies.insert(channel.to_string(), latency);
+        self
+    }
+
+    /// The latency of channels not otherwise configured.
+    pub fn with_default(mut self, latency: Latency) -> Self {
+        self.default = latency;
+        self
+    }
+
+    /// The virtual time.
+    pub fn now(&self) -> Instant {
+        self.start + self.now
+    }
+
+    /// The virtual time elapsed since the simulation began.
+    pub fn elapsed(&self) -> Duration {
+        self.now
+    }
+
+    /// Send a message on a channel, to be delivered after its latency.
+    /// Answers when it will be delivered.
+    pub fn send(&mut self, channel: &str, message: M) -> Instant {
+        let latency = self.latencies.get(channel).unwrap_or(&self.default);
+        let delay = latency.sample(&mut self.rng);
+        self.schedule(delay, message)
+    }
+
+    /// Deliver a message after a delay, such as a command a test injects.
+    pub fn schedule(&mut self, delay: Duration, message: M) -> Instant {
+        let at = self.now + delay;
+        // Messages due at the same time are delivered in the order sent
+        self.queue.push(Reverse((at, self.sent)));
+        self.messages.insert(self.sent, message);
+        self.sent += 1;
+        self.start + at
+    }
+
+    /// The number of messages in flight.
+    pub fn in_flight(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Deliver the next message due no later than a deadline, advancing
+    /// the clock to it. Answers `None`, with the clock advanced to the
+    /// deadline, if there is none. Without a deadline, answers `None`
+    /// only when no message is in flight.
+    pub fn next_until(&mut self, deadline: Option<Instant>) -> Option<M> {
+        let deadline = deadline.map(|d| d.saturating_duration_since(self.start));
+        match self.queue.peek() {
+            Some(Reverse((at, _))) if deadline.is_none_or(|d| *at <= d) => {
+                let Reverse((at, id)) = self.queue.pop()?;
+                self.now = self.now.max(at);
+                self.messages.remove(&id)
+            }
+            _ => {
+                if let Some(d) = deadline {
+                    self.now = self.now.max(d);
+                }
+                None
+            }
+        }
+    }
+
+    /// Deliver the next message in flight.
+    pub fn deliver(&mut self) -> Option<M> {
+        self.next_until(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer::Timers;
+
+    #[test]
+    fn test_simulation() {
+        // A client that calls a service with a 100ms timeout, where the
+        // reply sometimes arrives after the timeout has fired
+
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        enum Call {
+            Waiting,
+            Answered,
+            TimedOut,
+        }
+
+        enum Message {
+            Request(u32),
+            Reply(u32),
+        }
+
+        // The calls made and how each ended, with the replies that came late
+        let run = |seed, latency: Latency| {
+            let mut sim = Simulation::new(seed)
+                .with_channel("request", Latency::Fixed(Duration::from_millis(10)))
+                .with_channel("reply", latency);
+            let mut timers = Timers::new();
+            let mut calls = HashMap::new();
+            let mut late = 0;
+            for id in 0..20 {
+                calls.insert(id, Call::Waiting);
+                sim.send("request", Message::Request(id));
+                timers.arm(id, sim.now() + Duration::from_millis(100), ());
+            }
+            loop {
+                match sim.next_until(timers.next_deadline()) {
+                    Some(Message::Request(id)) => {
+                        sim.send("reply", Message::Reply(id));
+                    }
+                    Some(Message::Reply(id)) => match calls[&id] {
+                        Call::Waiting => {
+                            timers.disarm(&id);
+                            calls.insert(id, Call::Answered);
+                        }
+                        _ => late += 1,
+                    },
+                    None if timers.next_deadline().is_some() => {
+                        for (id, ()) in timers.due(sim.now()) {
+                            calls.insert(id, Call::TimedOut);
+                        }
+                    }
+                    None => break,
+                }
+            }
+            let ended = |call| calls.values().filter(|c| **c == call).count();
+            (
+                ended(Call::Answered),
+                ended(Call::TimedOut),
+                late,
+                sim.elapsed(),
+            )
+        };
+
+        // A slow service always times out, and its replies arrive late
+        let slow = Latency::Fixed(Duration::from_millis(150));
+        assert_eq!(run(0, slow), (0, 20, 20, Duration::from_millis(160)));
+
+        // A jittery one sometimes does, the same way for the same seed
+        let jittery = Latency::Uniform(Duration::from_millis(40), Duration::from_millis(140));
+        let (answered, timed_out, late, _) = run(5, jittery.clone());
+        assert_eq!(answered + timed_out, 20);
+        assert_eq!(late, timed_out);
+        assert!(answered > 0 && timed_out > 0);
+        assert_eq!(run(5, jittery.clone()), run(5, jittery));
+
+        let mut rng = Rng::new(1);
+        let tail = Latency::Exponential {
+            min: Duration::from_millis(5),
+            mean: Duration::from_millis(20),
+        };
+        let samples: Vec<Duration> = (0..1000).map(|_| tail.sample(&mut rng)).collect();
+        assert!(samples.iter().all(|d| *d >= Duration::from_millis(5)));
+        let mean = samples.iter().sum::<Duration>() / 1000;
+        assert!(mean > Duration::from_millis(20) && mean < Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,569 @@
+//! Snapshot stores keep the latest state of each entity, with the journal
+//! sequence number it reflects, so that recovery replays only the events
+//! after it.
+//!
+//! `DeltaSnapshots` saves most snapshots as deltas against the previous
+//! one, with a full snapshot every so often, to cut storage for large
+//! states that change a little with each transition. The chain is kept in
+//! a `BlobStore`, and loading rebuilds the state from the last full
+//! snapshot and the deltas after it, so it survives a restart.
+//!
+//! `BlobSnapshots` keeps each snapshot as an object in a `BlobStore`, so
+//! that large states can live in cheap blob storage, such as S3, while
+//! the journal lives in a database. `DirBlobStore` keeps the objects as
+//! files in a directory.
+
+use std::collections::HashMap;
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::PathBuf;
+
+use crate::json::Json;
+use crate::patch::{PatchEvent, PatchOp};
+
+/// The latest snapshots of entities with keys K and states S.
+pub trait SnapshotStore<K, S> {
+    /// Save the state of an entity, as of a journal sequence number.
+    fn save(&mut self, key: &K, seq: u64, state: &S) -> Result<(), String>;
+
+    /// The latest snapshot of an entity and its sequence number.
+    fn load(&self, key: &K) -> Result<Option<(u64, S)>, String>;
+}
+
+/// A snapshot store held in memory.
+pub struct MemSnapshotStore<K, S> {
+    snapshots: HashMap<K, (u64, S)>,
+    fail_saves: bool,
+    fail_loads: bool,
+}
+
+impl<K, S> Default for MemSnapshotStore<K, S> {
+    fn default() -> Self {
+        Self {
+            snapshots: HashMap::new(),
+            fail_saves: false,
+            fail_loads: false,
+        }
+    }
+}
+
+impl<K, S> MemSnapshotStore<K, S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make saves fail, or succeed again, e.g. to test error handling.
+    pub fn fail_saves(&mut self, fail: bool) {
+        self.fail_saves = fail;
+    }
+
+    /// Make loads fail, or succeed again, e.g. to test recovery.
+    pub fn fail_loads(&mut self, fail: bool) {
+        self.fail_loads = fail;
+    }
+}
+
+impl<K, S> SnapshotStore<K, S> for MemSnapshotStore<K, S>
+where
+    K: Eq + Hash + Clone,
+    S: Clone,
+{
+    fn save(&mut self, key: &K, seq: u64, state: &S) -> Result<(), String> {
+        if self.fail_saves {
+            return Err("save failed".to_string());
+        }
+        self.snapshots.insert(key.clone(), (seq, state.clone()));
+        Ok(())
+    }
+
+    fn load(&self, key: &K) -> Result<Option<(u64, S)>, String> {
+        if self.fail_loads {
+            return Err("load failed".to_string());
+        }
+        Ok(self.snapshots.get(key).cloned())
+    }
+}
+
+/// Named objects of bytes, such as an S3 bucket.
+pub trait BlobStore {
+    fn put(&mut self, name: &str, bytes: Vec<u8>) -> Result<(), String>;
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String>;
+}
+
+/// Objects held in memory.
+impl BlobStore for HashMap<String, Vec<u8>> {
+    fn put(&mut self, name: &str, bytes: Vec<u8>) -> Result<(), String> {
+        self.insert(name.to_string(), bytes);
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        Ok(HashMap::get(self, name).cloned())
+    }
+}
+
+/// Objects kept as files in a directory.
+pub struct DirBlobStore {
+    dir: PathBuf,
+}
+
+impl DirBlobStore {
+    /// Objects in a directory, which is created if need be.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+}
+
+impl BlobStore for DirBlobStore {
+    /// The object is written to a temporary file and renamed into place,
+    /// so a reader never sees part of it.
+    fn put(&mut self, name: &str, bytes: Vec<u8>) -> Result<(), String> {
+        let path = self.dir.join(name);
+        let partial = self.dir.join(format!("{name}.partial"));
+        fs::write(&partial, bytes)
+            .and_then(|_| fs::rename(&partial, &path))
+            .map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    fn get(&self, name: &str) -> Result<Option<Vec<u8>>, String> {
+        let path = self.dir.join(name);
+        match fs::read(&path) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("{}: {e}", path.display())),
+        }
+    }
+}
+
+type Encode<S> = Box<dyn Fn(&S) -> Vec<u8> + Send>;
+type Decode<S> = Box<dyn Fn(&[u8]) -> Result<S, String> + Send>;
+
+/// A snapshot store keeping each entity's snapshot as an object in a
+/// blob store B, its sequence number followed by the encoded state.
+pub struct BlobSnapshots<B, K, S> {
+    blobs: B,
+    name: Box<dyn Fn(&K) -> String + Send>,
+    encode: Encode<S>,
+    decode: Decode<S>,
+}
+
+impl<B, K, S> BlobSnapshots<B, K, S> {
+    /// Snapshots named after their keys, with states encoded and decoded
+    /// by the given functions.
+    pub fn new(
+        blobs: B,
+        name: impl Fn(&K) -> String + Send + 'static,
+        encode: impl Fn(&S) -> Vec<u8> + Send + 'static,
+        decode: impl Fn(&[u8]) -> Result<S, String> + Send + 'static,
+    ) -> Self {
+        Self {
+            blobs,
+            name: Box::new(name),
+            encode: Box::new(encode),
+            decode: Box::new(decode),
+        }
+    }
+
+    pub fn blobs(&self) -> &B {
+        &self.blobs
+    }
+}
+
+impl<B, K, S> SnapshotStore<K, S> for BlobSnapshots<B, K, S>
+where
+    B: BlobStore,
+{
+    fn save(&mut self, key: &K, seq: u64, state: &S) -> Result<(), String> {
+        let mut bytes = seq.to_be_bytes().to_vec();
+        bytes.extend((self.encode)(state));
+        self.blobs.put(&(self.name)(key), bytes)
+    }
+
+    fn load(&self, key: &K) -> Result<Option<(u64, S)>, String> {
+        let name = (self.name)(key);
+        let Some(bytes) = self.blobs.get(&name)? else {
+            return Ok(None);
+        };
+        let (seq, state) = bytes
+            .split_first_chunk::<8>()
+            .ok_or(format!("snapshot {name} is truncated"))?;
+        let state = (self.decode)(state).map_err(|e| format!("snapshot {name}: {e}"))?;
+        Ok(Some((u64::from_be_bytes(*seq), state)))
+    }
+}
+
+/// States which can be encoded as a delta against a previous state.
+pub trait Delta: Sized {
+    type Delta;
+
+    /// The delta from a previous state to this one.
+    fn delta(&self, previous: &Self) -> Self::Delta;
+
+    /// This state updated by a delta.
+    fn apply_delta(&self, delta: &Self::Delta) -> Result<Self, String>;
+}
+
+/// A stored snapshot, in full or as a delta against the one before.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stored<S, D> {
+    Full(S),
+    Delta(D),
+}
+
+/// Json snapshots are encoded as `{"full": state}` or `{"delta": patch}`.
+impl Stored<Json, PatchEvent> {
+    pub fn encode(&self) -> Vec<u8> {
+        let member = match self {
+            Stored::Full(state) => ("full".to_string(), state.clone()),
+            Stored::Delta(patch) => ("delta".to_string(), patch.to_json()),
+        };
+        Json::Object(vec![member]).to_string().into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let text = std::str::from_utf8(bytes).map_err(|e| e.to_string())?;
+        let doc = Json::parse(text).map_err(|e| format!("offset {}: {}", e.offset, e.message))?;
+        match (doc.get("full"), doc.get("delta")) {
+            (Some(state), None) => Ok(Stored::Full(state.clone())),
+            (None, Some(patch)) => PatchEvent::parse(patch)
+                .map(Stored::Delta)
+                .map_err(|e| format!("patch operation {}: {}", e.index, e.message)),
+            _ => Err("expected a full snapshot or a delta".to_string()),
+        }
+    }
+}
+
+type Chain<S> = Vec<(u64, Stored<S, <S as Delta>::Delta>)>;
+
+/// A snapshot store saving deltas, with a full snapshot every `full_every`
+/// saves, as objects in a blob store B.
+///
+/// Each entity has a head object, named after its key, giving the length
+/// of its chain and which of two slots holds it, and an object per stored
+/// snapshot, named `{name}.{slot}.{index}`. A new chain is written to the
+/// other slot before the head moves to it, so a failed save leaves the
+/// previous chain whole.
+pub struct DeltaSnapshots<B, K, S: Delta> {
+    blobs: B,
+    name: Box<dyn Fn(&K) -> String + Send>,
+    encode: Encode<Stored<S, S::Delta>>,
+    decode: Decode<Stored<S, S::Delta>>,
+    full_every: usize,
+}
+
+impl<B, K, S> DeltaSnapshots<B, K, S>
+where
+    B: BlobStore,
+    S: Delta + Clone,
+{
+    /// A store saving a full snapshot, then deltas until there are 16
+    /// snapshots in the chain, then a full snapshot again. Snapshots are
+    /// named after their keys and encoded and decoded by the given functions.
+    pub fn new(
+        blobs: B,
+        name: impl Fn(&K) -> String + Send + 'static,
+        encode: impl Fn(&Stored<S, S::Delta>) -> Vec<u8> + Send + 'static,
+        decode: impl Fn(&[u8]) -> Result<Stored<S, S::Delta>, String> + Send + 'static,
+    ) -> Self {
+        Self {
+            blobs,
+            name: Box::new(name),
+            encode: Box::new(encode),
+            decode: Box::new(decode),
+            full_every: 16,
+        }
+    }
+
+    /// Bound chains to `full_every` snapshots, the first in full.
+    pub fn with_full_every(mut self, full_every: usize) -> Self {
+        self.full_every = full_every.max(1);
+        self
+    }
+
+    pub fn blobs(&self) -> &B {
+        &self.blobs
+    }
+
+    /// The stored snapshots of an entity since its last full snapshot,
+    /// read from the blob store.
+    pub fn stored(&self, key: &K) -> Result<Chain<S>, String> {
+        let name = (self.name)(key);
+        let Some((slot, len)) = self.head(&name)? else {
+            return Ok(Vec::new());
+        };
+        (0..len)
+            .map(|i| {
+                let link = format!("{name}.{slot}.{i}");
+                let bytes = self
+                    .blobs
+                    .get(&link)?
+                    .ok_or(format!("snapshot {link} is missing"))?;
+                let (seq, stored) = bytes
+                    .split_first_chunk::<8>()
+                    .ok_or(format!("snapshot {link} is truncated"))?;
+                let stored = (self.decode)(stored).map_err(|e| format!("snapshot {link}: {e}"))?;
+                Ok((u64::from_be_bytes(*seq), stored))
+            })
+            .collect()
+    }
+
+    /// The slot and length of an entity's chain.
+    fn head(&self, name: &str) -> Result<Option<(u8, u32)>, String> {
+        let Some(bytes) = self.blobs.get(name)? else {
+            return Ok(None);
+        };
+        match bytes.split_first() {
+            Some((&slot, len)) if slot < 2 && len.len() == 4 => {
+                Ok(Some((slot, u32::from_be_bytes(len.try_into().unwrap()))))
+            }
+            _ => Err(format!("snapshot {name} has a corrupt head")),
+        }
+    }
+
+    fn rebuild(chain: Chain<S>) -> Result<Option<(u64, S)>, String> {
+        let mut loaded: Option<(u64, S)> = None;
+        for (seq, stored) in chain {
+            let state = match (stored, &loaded) {
+                (Stored::Full(state), _) => state,
+                (Stored::Delta(delta), Some((_, previous))) => previous
+                    .apply_delta(&delta)
+                    .map_err(|e| format!("snapshot {seq}: {e}"))?,
+                (Stored::Delta(_), None) => {
+                    return Err(format!("snapshot {seq}: delta without a full snapshot"))
+                }
+            };
+            loaded = Some((seq, state));
+        }
+        Ok(loaded)
+    }
+}
+
+impl<B, K, S> SnapshotStore<K, S> for DeltaSnapshots<B, K, S>
+where
+    B: BlobStore,
+    S: Delta + Clone,
+{
+    /// The previous state is rebuilt from the chain to compute the delta,
+    /// so nothing is held in memory between saves.
+    fn save(&mut self, key: &K, seq: u64, state: &S) -> Result<(), String> {
+        let name = (self.name)(key);
+        let head = self.head(&name)?;
+        let (slot, index, stored) = match head {
+            Some((slot, len)) if (len as usize) < self.full_every => {
+                match Self::rebuild(self.stored(key)?)? {
+                    Some((_, previous)) => (slot, len, Stored::Delta(state.delta(&previous))),
+                    None => (slot ^ 1, 0, Stored::Full(state.clone())),
+                }
+            }
+            Some((slot, _)) => (slot ^ 1, 0, Stored::Full(state.clone())),
+            None => (0, 0, Stored::Full(state.clone())),
+        };
+        let mut bytes = seq.to_be_bytes().to_vec();
+        bytes.extend((self.encode)(&stored));
+        self.blobs.put(&format!("{name}.{slot}.{index}"), bytes)?;
+        let mut head = vec![slot];
+        head.extend((index + 1).to_be_bytes());
+        self.blobs.put(&name, head)
+    }
+
+    fn load(&self, key: &K) -> Result<Option<(u64, S)>, String> {
+        Self::rebuild(self.stored(key)?)
+    }
+}
+
+/// Json states are encoded as patches, member by member.
+impl Delta for Json {
+    type Delta = PatchEvent;
+
+    fn delta(&self, previous: &Self) -> PatchEvent {
+        let mut ops = Vec::new();
+        json_delta(previous, self, "", &mut ops);
+        PatchEvent(ops)
+    }
+
+    fn apply_delta(&self, delta: &PatchEvent) -> Result<Self, String> {
+        delta
+            .apply(self)
+            .map_err(|e| format!("patch operation {}: {}", e.index, e.message))
+    }
+}
+
+fn json_delta(from: &Json, to: &Json, path: &str, ops: &mut Vec<PatchOp>) {
+    match (from, to) {
+        _ if from == to => {}
+        (Json::Object(before), Json::Object(after)) => {
+            for (k, _) in before {
+                if to.get(k).is_none() {
+                    ops.push(PatchOp::Remove {
+                        path: member_path(path, k),
+                    });
+                }
+            }
+            for (k, v) in after {
+                let path = member_path(path, k);
+                match from.get(k) {
+                    Some(u) => json_delta(u, v, &path, ops),
+                    None => ops.push(PatchOp::Add {
+                        path,
+                        value: v.clone(),
+                    }),
+                }
+            }
+        }
+        (Json::Array(before), Json::Array(after)) if before.len() == after.len() => {
+            for (i, (u, v)) in before.iter().zip(after).enumerate() {
+                json_delta(u, v, &format!("{path}/{i}"), ops);
+            }
+        }
+        _ => ops.push(PatchOp::Replace {
+            path: path.to_string(),
+            value: to.clone(),
+        }),
+    }
+}
+
+fn member_path(path: &str, member: &str) -> String {
+    format!("{path}/{}", member.replace('~', "~0").replace('/', "~1"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_snapshots() {
+        // A large document where one counter changes with each transition
+
+        let mut doc = Json::parse(r#"{"big": [1, 2, 3, 4, 5], "a/b": {"n": 0}}"#).unwrap();
+        let blobs: HashMap<String, Vec<u8>> = HashMap::new();
+        let name = |key: &&str| key.to_string();
+        let mut store =
+            DeltaSnapshots::new(blobs, name, Stored::encode, Stored::decode).with_full_every(3);
+        let mut plain = MemSnapshotStore::new();
+
+        for seq in 1..=4 {
+            if let Json::Object(members) = &mut doc {
+                members[1].1 = Json::parse(&format!(r#"{{"n": {seq}, "m": true}}"#)).unwrap();
+            }
+            store.save(&"doc", seq, &doc).unwrap();
+            plain.save(&"doc", seq, &doc).unwrap();
+            assert_eq!(store.load(&"doc"), plain.load(&"doc"));
+        }
+
+        // The fourth snapshot starts a new chain
+        let stored = store.stored(&"doc").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert!(matches!(stored[0], (4, Stored::Full(_))));
+
+        store.save(&"doc", 5, &doc).unwrap();
+        store
+            .save(&"doc", 6, &Json::parse(r#"{"big": []}"#).unwrap())
+            .unwrap();
+        let stored = store.stored(&"doc").unwrap();
+        assert_eq!(stored[1], (5, Stored::Delta(PatchEvent(vec![]))));
+        assert_eq!(
+            stored[2],
+            (
+                6,
+                Stored::Delta(PatchEvent(vec![
+                    PatchOp::Remove {
+                        path: "/a~1b".to_string()
+                    },
+                    PatchOp::Replace {
+                        path: "/big".to_string(),
+                        value: Json::Array(vec![])
+                    }
+                ]))
+            )
+        );
+        assert_eq!(
+            store.load(&"doc"),
+            Ok(Some((6, Json::parse(r#"{"big": []}"#).unwrap())))
+        );
+        assert_eq!(store.load(&"other"), Ok(None));
+
+        // The objects are the head and at most two chains, the previous
+        // one being overwritten by the next, and a store over the same
+        // objects after a restart loads the same state
+        assert_eq!(store.blobs().len(), 7);
+        let restarted =
+            DeltaSnapshots::new(store.blobs().clone(), name, Stored::encode, Stored::decode);
+        assert_eq!(restarted.load(&"doc"), store.load(&"doc"));
+
+        // Injected failures leave the snapshot as it was
+        plain.fail_saves(true);
+        assert_eq!(plain.save(&"doc", 5, &doc), Err("save failed".to_string()));
+        plain.fail_saves(false);
+        plain.fail_loads(true);
+        assert_eq!(plain.load(&"doc"), Err("load failed".to_string()));
+        plain.fail_loads(false);
+        assert_eq!(plain.load(&"doc").unwrap().map(|(seq, _)| seq), Some(4));
+    }
+
+    #[test]
+    fn test_blob_snapshots() {
+        // Json states as files in a directory, and in memory
+
+        let dir = std::env::temp_dir().join(format!("fsm-snapshots-{}", std::process::id()));
+        let encode = |s: &Json| s.to_string().into_bytes();
+        let decode = |b: &[u8]| {
+            let text = std::str::from_utf8(b).map_err(|e| e.to_string())?;
+            Json::parse(text).map_err(|e| e.message)
+        };
+        let state = Json::parse(r#"{"n": 1}"#).unwrap();
+
+        let blobs = DirBlobStore::new(&dir).unwrap();
+        let mut store = BlobSnapshots::new(blobs, |k: &&str| format!("{k}.json"), encode, decode);
+        assert_eq!(store.load(&"a"), Ok(None));
+        store.save(&"a", 7, &state).unwrap();
+        assert_eq!(store.load(&"a"), Ok(Some((7, state.clone()))));
+        assert_eq!(fs::read(dir.join("a.json")).unwrap()[8..], *br#"{"n":1}"#);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut store =
+            BlobSnapshots::new(HashMap::new(), |k: &&str| k.to_string(), encode, decode);
+        store.save(&"a", 2, &state).unwrap();
+        assert_eq!(store.load(&"a"), Ok(Some((2, state))));
+        store.blobs.put("b", vec![0, 1]).unwrap();
+        assert_eq!(store.load(&"b"), Err("snapshot b is truncated".to_string()));
+    }
+
+    #[test]
+    fn test_corrupt_snapshots() {
+        // A missing or garbled object fails the load, rather than yield an
+        // older state or a partial one
+        let name = |key: &&str| key.to_string();
+        let doc = Json::parse(r#"{"n": 1}"#).unwrap();
+        let mut store = DeltaSnapshots::new(HashMap::new(), name, Stored::encode, Stored::decode);
+        store.save(&"doc", 1, &doc).unwrap();
+        store.save(&"doc", 2, &doc).unwrap();
+
+        let mut missing = store.blobs().clone();
+        missing.remove("doc.0.1");
+        let missing = DeltaSnapshots::new(missing, name, Stored::encode, Stored::decode);
+        assert_eq!(
+            missing.load(&"doc"),
+            Err("snapshot doc.0.1 is missing".to_string())
+        );
+
+        let mut garbled = store.blobs().clone();
+        garbled.insert("doc".to_string(), vec![7]);
+        let garbled = DeltaSnapshots::new(garbled, name, Stored::encode, Stored::decode);
+        assert_eq!(
+            garbled.load(&"doc"),
+            Err("snapshot doc has a corrupt head".to_string())
+        );
+
+        let mut blobs = HashMap::new();
+        blobs.insert("doc".to_string(), vec![1, 2, 3]);
+        let plain = BlobSnapshots::new(blobs, name, |_: &Json| vec![], |_| Ok(Json::Null));
+        assert_eq!(
+            plain.load(&"doc"),
+            Err("snapshot doc is truncated".to_string())
+        );
+    }
+}
@@ -0,0 +1,396 @@
+//! Soak testing of machine designs. A `Soak` steps a machine through a
+//! long random walk of commands, chosen in each state with weights that
+//! make the walk a Markov chain over the machine's states, and checks its
+//! invariants after every step. It runs for a number of steps or for a
+//! length of time, and answers the coverage of the states and transitions
+//! it reached, which `Coverage::uncovered` compares with the machine's
+//! descriptor.
+//!
+//! The walk restarts from the initial state when it reaches a state with
+//! no commands to choose, or after a number of steps, if an episode length
+//! is given. Walks are seeded, so a failure is reproduced by running again
+//! with the seed it reports, or by stepping its script.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::command_and_event_traits::{Command, Fsm, Transition, Validate};
+use crate::descriptor::{MachineDescriptor, TransitionDescriptor};
+
+/// A small, seeded pseudo random number generator, SplitMix64, so that
+/// simulations are reproducible.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`, or 0 if n is 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+
+    /// True with probability p.
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+type Invariant<'a, S> = (String, Box<dyn Fn(&S) -> bool + 'a>);
+
+/// A random walk of commands C through a machine over states S.
+pub struct Soak<'a, S, C> {
+    label: Box<dyn Fn(&S) -> String + 'a>,
+    commands: Vec<(String, C, u32)>,
+    weights: HashMap<(String, String), u32>,
+    invariants: Vec<Invariant<'a, S>>,
+    seed: u64,
+    steps: u64,
+    duration: Option<Duration>,
+    episode: Option<u64>,
+}
+
+impl<'a, S, C> Soak<'a, S, C> {
+    /// A walk of 1000 steps, seeded with 0, where states are named by the
+    /// given function, for example `Describe::state_name`.
+    pub fn new(label: impl Fn(&S) -> String + 'a) -> Self {
+        Self {
+            label: Box::new(label),
+            commands: Vec::new(),
+            weights: HashMap::new(),
+            invariants: Vec::new(),
+            seed: 0,
+            steps: 1000,
+            duration: None,
+            episode: None,
+        }
+    }
+
+    /// A command to choose, by name, with weight 1 in every state.
+    pub fn command(self, name: &str, command: C) -> Self {
+        self.weighted(name, command, 1)
+    }
+
+    /// A command to choose, by name, with a weight in every state.
+    pub fn weighted(mut self, name: &str, command: C, weight: u32) -> Self {
+        self.commands.push((name.to_string(), command, weight));
+        self
+    }
+
+    /// The weight of a command in one state, where 0 never chooses it.
+    pub fn weight(mut self, state: &str, command: &str, weight: u32) -> Self {
+        self.weights
+            .insert((state.to_string(), command.to_string()), weight);
+        self
+    }
+
+    /// A property that holds in every state reached.
+    pub fn invariant(mut self, name: &str, holds: impl Fn(&S) -> bool + 'a) -> Self {
+        self.invariants.push((name.to_string(), Box::new(holds)));
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Run for a number of steps.
+    pub fn steps(mut self, steps: u64) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// Run for a length of time, rather than a number of steps.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.steps = u64::MAX;
+        self.duration = Some(duration);
+        self
+    }
+
+    /// Restart from the initial state after a number of steps.
+    pub fn episode(mut self, steps: u64) -> Self {
+        self.episode = Some(steps);
+        self
+    }
+
+    fn choose(&self, state: &str, rng: &mut Rng) -> Option<usize> {
+        let weights: Vec<u64> = self
+            .commands
+            .iter()
+            .map(|(name, _, weight)| {
+                let key = (state.to_string(), name.clone());
+                *self.weights.get(&key).unwrap_or(weight) as u64
+            })
+            .collect();
+        let mut pick = rng.below(weights.iter().sum());
+        for (i, weight) in weights.into_iter().enumerate() {
+            if pick < weight {
+                return Some(i);
+            }
+            pick -= weight;
+        }
+        None
+    }
+
+    /// Walk an FSM, F, from an initial state, answering the coverage of
+    /// the walk or the first invariant broken.
+    pub fn run<F, H>(&self, initial: &S, handler: &mut H) -> Result<Coverage, SoakFailure>
+    where
+        F: Fsm<S, H>,
+        C: Command<S, H> + Validate<S>,
+        S: Clone,
+    {
+        let started = Instant::now();
+        let mut rng = Rng::new(self.seed);
+        let mut coverage = Coverage::default();
+        let mut state = initial.clone();
+        let mut script = Vec::new();
+        let mut label = (self.label)(&state);
+        self.check(&state, &label, &script)?;
+        *coverage.states.entry(label.clone()).or_default() += 1;
+        coverage.episodes = 1;
+
+        while coverage.steps < self.steps && self.duration.is_none_or(|d| started.elapsed() < d) {
+            let restart = self.episode.is_some_and(|n| script.len() as u64 >= n);
+            let choice = match restart {
+                true => None,
+                false => self.choose(&label, &mut rng),
+            };
+            let Some(i) = choice else {
+                if script.is_empty() {
+                    break;
+                }
+                state = initial.clone();
+                label = (self.label)(&state);
+                script.clear();
+                coverage.episodes += 1;
+                continue;
+            };
+
+            let (name, command, _) = &self.commands[i];
+            script.push(name.clone());
+            coverage.steps += 1;
+            let (event, trans) = F::step(&state, command, handler);
+            match (event, trans) {
+                (Some(_), Ok(trans)) => {
+                    if let Transition::Next(s) = trans {
+                        state = s;
+                    }
+                    let to = (self.label)(&state);
+                    let path = (label, name.clone(), to.clone());
+                    *coverage.transitions.entry(path).or_default() += 1;
+                    *coverage.states.entry(to.clone()).or_default() += 1;
+                    label = to;
+                }
+                (None, _) => coverage.ignored += 1,
+                (Some(_), Err(_)) => coverage.rejected += 1,
+            }
+            self.check(&state, &label, &script)?;
+        }
+        Ok(coverage)
+    }
+
+    fn check(&self, state: &S, label: &str, script: &[String]) -> Result<(), SoakFailure> {
+        match self.invariants.iter().find(|(_, holds)| !holds(state)) {
+            Some((name, _)) => Err(SoakFailure {
+                seed: self.seed,
+                invariant: name.clone(),
+                state: label.to_string(),
+                script: script.to_vec(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The states and transitions a walk reached, with how often.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Coverage {
+    pub steps: u64,
+    pub episodes: u64,
+    /// Commands that emitted no event.
+    pub ignored: u64,
+    /// Commands whose event was invalid or vetoed.
+    pub rejected: u64,
+    pub states: BTreeMap<String, u64>,
+    /// Transitions by from-state, command and to-state.
+    pub transitions: BTreeMap<(String, String, String), u64>,
+}
+
+impl Coverage {
+    /// The transitions a descriptor declares which the walk never took.
+    pub fn uncovered<'d>(
+        &self,
+        descriptor: &'d MachineDescriptor,
+    ) -> Vec<&'d TransitionDescriptor> {
+        descriptor
+            .transitions
+            .iter()
+            .filter(|t| {
+                let path = (t.from.clone(), t.command.clone(), t.to.clone());
+                !self.transitions.contains_key(&path)
+            })
+            .collect()
+    }
+
+    /// The states a descriptor declares which the walk never reached.
+    pub fn unvisited<'d>(&self, descriptor: &'d MachineDescriptor) -> Vec<&'d str> {
+        descriptor
+            .states
+            .iter()
+            .filter(|s| !self.states.contains_key(*s))
+            .map(String::as_str)
+            .collect()
+    }
+}
+
+/// An invariant broken by a walk, with the commands by which the episode
+/// reached the state that broke it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakFailure {
+    pub seed: u64,
+    pub invariant: String,
+    pub state: String,
+    pub script: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::Event;
+
+    #[test]
+    fn test_soak() {
+        // A bounded counter, soaked with more increments than decrements,
+        // whose reset is never chosen once it is full
+
+        #[derive(Debug, Clone, PartialEq)]
+        struct Count(u32);
+
+        #[derive(Clone)]
+        enum Op {
+            Inc,
+            Dec,
+            Reset,
+        }
+
+        struct Changed(i32);
+
+        impl Command<Count, ()> for Op {
+            type Output = Changed;
+            fn execute(&self, s: &Count, _h: &mut ()) -> Option<Changed> {
+                match self {
+                    Op::Inc if s.0 < 5 => Some(Changed(1)),
+                    Op::Dec if s.0 > 0 => Some(Changed(-1)),
+                    Op::Reset => Some(Changed(-(s.0 as i32))),
+                    _ => None,
+                }
+            }
+        }
+
+        impl Validate<Count> for Op {}
+
+        impl Event<Count> for Changed {
+            fn fire(&self, s: &Count) -> Transition<Count> {
+                match self.0 {
+                    0 => Transition::Same,
+                    n => Transition::Next(Count((s.0 as i32 + n) as u32)),
+                }
+            }
+        }
+
+        struct Counter {}
+
+        impl Fsm<Count, ()> for Counter {}
+
+        let label = |s: &Count| match s.0 {
+            0 => "Empty".to_string(),
+            5 => "Full".to_string(),
+            _ => "Partial".to_string(),
+        };
+        let soak = || {
+            Soak::new(label)
+                .weighted("Inc", Op::Inc, 3)
+                .command("Dec", Op::Dec)
+                .command("Reset", Op::Reset)
+                .weight("Full", "Reset", 0)
+                .invariant("at most 5", |s: &Count| s.0 <= 5)
+                .seed(7)
+                .steps(500)
+        };
+
+        let coverage = soak().run::<Counter, _>(&Count(0), &mut ()).unwrap();
+        assert_eq!(coverage.steps, 500);
+        assert_eq!(
+            coverage,
+            soak().run::<Counter, _>(&Count(0), &mut ()).unwrap()
+        );
+        assert!(coverage.states.contains_key("Full"));
+        assert!(coverage.ignored > 0);
+        let reset_when_full = ("Full".to_string(), "Reset".to_string(), "Empty".to_string());
+        assert!(!coverage.transitions.contains_key(&reset_when_full));
+
+        let descriptor = MachineDescriptor::new("counter")
+            .transition("Partial", "Inc", "Changed", "Full")
+            .transition("Full", "Reset", "Changed", "Empty")
+            .state("Overflowed");
+        let uncovered: Vec<_> = coverage
+            .uncovered(&descriptor)
+            .iter()
+            .map(|t| t.from.as_str())
+            .collect();
+        assert_eq!(uncovered, vec!["Full"]);
+        assert_eq!(coverage.unvisited(&descriptor), vec!["Overflowed"]);
+
+        // Short episodes restart from empty
+        let coverage = soak()
+            .episode(10)
+            .run::<Counter, _>(&Count(0), &mut ())
+            .unwrap();
+        assert_eq!(coverage.episodes, 50);
+
+        // A tighter invariant fails with a reproducible script
+        let failure = soak()
+            .invariant("at most 3", |s: &Count| s.0 <= 3)
+            .run::<Counter, _>(&Count(0), &mut ())
+            .unwrap_err();
+        assert_eq!(failure.invariant, "at most 3");
+        assert_eq!(failure.seed, 7);
+        let mut state = Count(0);
+        for name in &failure.script {
+            let op = match name.as_str() {
+                "Inc" => Op::Inc,
+                "Dec" => Op::Dec,
+                _ => Op::Reset,
+            };
+            if let (Some(_), Ok(Transition::Next(s))) = Counter::step(&state, &op, &mut ()) {
+                state = s;
+            }
+        }
+        assert_eq!(state, Count(4));
+
+        // A walk for a length of time
+        let coverage = soak()
+            .duration(Duration::from_millis(20))
+            .run::<Counter, _>(&Count(0), &mut ())
+            .unwrap();
+        assert!(coverage.steps > 500);
+    }
+}
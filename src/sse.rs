@@ -0,0 +1,149 @@
+//! Server-Sent Events, for streaming an entity's events to simple web
+//! clients as JSON. Each event is sent with its journal sequence number
+//! as its id, so a client that reconnects with a `Last-Event-ID` header
+//! resumes from the following event.
+//!
+//! `stream` writes the response to any `Write`, such as an accepted
+//! `TcpStream`, leaving routing and the HTTP request itself to the caller.
+
+use std::io::{self, Write};
+
+use crate::journal::{Journal, JournalError, Record};
+use crate::json::Json;
+use crate::subscription::EventBus;
+
+/// The response headers of an event stream.
+pub const HEADERS: &str = "HTTP/1.1 200 OK\r\n\
+    Content-Type: text/event-stream\r\n\
+    Cache-Control: no-cache\r\n\
+    Connection: keep-alive\r\n\r\n";
+
+/// The frame of one event, with a type and JSON data.
+pub fn frame(id: u64, event: &str, data: &Json) -> String {
+    let mut frame = format!("id: {id}\nevent: {event}\n");
+    for line in data.to_string().lines() {
+        frame.push_str("data: ");
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame.push('\n');
+    frame
+}
+
+/// The `Last-Event-ID` of a request, from its header lines.
+pub fn last_event_id(headers: &str) -> Option<u64> {
+    headers.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("last-event-id") {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// Write an event stream of an entity's events, from the one after the
+/// last event the client saw, until the client goes away. Events are
+/// named and converted to JSON by `describe`.
+pub fn stream<J, K, E, W>(
+    bus: &EventBus<J, K, E>,
+    key: &K,
+    last_event_id: Option<u64>,
+    describe: impl Fn(&E) -> (String, Json),
+    mut out: W,
+) -> Result<(), JournalError>
+where
+    J: Journal<K, E>,
+    K: Clone + PartialEq + Send + 'static,
+    E: Clone,
+    W: Write,
+{
+    let from_seq = last_event_id.map_or(1, |id| id + 1);
+    let subscription = bus.subscribe_entity(key, from_seq)?;
+    let send = |out: &mut W, record: Record<K, E>| -> io::Result<()> {
+        let (name, data) = describe(&record.event);
+        out.write_all(frame(record.seq, &name, &data).as_bytes())?;
+        out.flush()
+    };
+    if out.write_all(HEADERS.as_bytes()).is_ok() {
+        for record in subscription {
+            if send(&mut out, record).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::MemJournal;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn test_sse() {
+        // A client resuming after event 1 of entity "a", then disconnecting
+
+        struct Client {
+            received: Arc<Mutex<String>>,
+            frames: usize,
+        }
+
+        impl Write for Client {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.frames == 0 {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                self.received
+                    .lock()
+                    .unwrap()
+                    .push_str(std::str::from_utf8(buf).unwrap());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.frames = self.frames.saturating_sub(1);
+                Ok(())
+            }
+        }
+
+        assert_eq!(last_event_id("Host: x\r\nlast-event-id: 1\r\n"), Some(1));
+        assert_eq!(last_event_id("Host: x\r\n"), None);
+
+        let bus = EventBus::new(MemJournal::new());
+        bus.publish(&"a", 10).unwrap();
+        bus.publish(&"a", 20).unwrap();
+        bus.publish(&"b", 30).unwrap();
+
+        let received = Arc::new(Mutex::new(String::new()));
+        let client = Client {
+            received: received.clone(),
+            frames: 2,
+        };
+        let streaming = {
+            let bus = bus.clone();
+            thread::spawn(move || {
+                let describe = |e: &u32| ("Added".to_string(), Json::Number(*e as f64));
+                stream(&bus, &"a", Some(1), describe, client)
+            })
+        };
+        while received.lock().unwrap().matches("id: ").count() < 1 {
+            thread::yield_now();
+        }
+        bus.publish(&"b", 40).unwrap();
+        bus.publish(&"a", 50).unwrap();
+        bus.publish(&"a", 60).unwrap();
+        assert_eq!(streaming.join().unwrap(), Ok(()));
+
+        let received = received.lock().unwrap();
+        assert_eq!(
+            received.strip_prefix(HEADERS),
+            Some(
+                "id: 2\nevent: Added\ndata: 20\n\n\
+                 id: 3\nevent: Added\ndata: 50\n\n"
+            )
+        );
+    }
+}
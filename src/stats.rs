@@ -0,0 +1,164 @@
+//! Runtime statistics of the paths taken through a machine. A
+//! `TransitionStats` is attached to a runner as an observer, and counts
+//! each (from-state, event, to-state) path along with when it last
+//! occurred. It is a handle, so a clone can be kept for queries, for
+//! example from a dashboard on another thread.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::observer::Observer;
+
+/// A path through the machine.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Path {
+    pub from: String,
+    pub event: String,
+    pub to: String,
+}
+
+/// How often a path has been taken, and when it was last taken.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PathStats {
+    pub count: u64,
+    pub last: SystemTime,
+}
+
+/// Collects statistics of transitions, naming states with a labelling function.
+pub struct TransitionStats<S> {
+    label: Arc<dyn Fn(&S) -> String + Send + Sync>,
+    paths: Arc<Mutex<HashMap<Path, PathStats>>>,
+}
+
+impl<S> Clone for TransitionStats<S> {
+    fn clone(&self) -> Self {
+        Self {
+            label: self.label.clone(),
+            paths: self.paths.clone(),
+        }
+    }
+}
+
+impl<S> TransitionStats<S> {
+    /// Statistics where states are named by the given function, for
+    /// example `Describe::state_name`.
+    pub fn new<L>(label: L) -> Self
+    where
+        L: Fn(&S) -> String + Send + Sync + 'static,
+    {
+        Self {
+            label: Arc::new(label),
+            paths: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The statistics for one path.
+    pub fn get(&self, from: &str, event: &str, to: &str) -> Option<PathStats> {
+        let path = Path {
+            from: from.to_string(),
+            event: event.to_string(),
+            to: to.to_string(),
+        };
+        self.lock().get(&path).copied()
+    }
+
+    /// The statistics of all paths taken, most frequent first.
+    pub fn paths(&self) -> Vec<(Path, PathStats)> {
+        let mut paths: Vec<_> = self.lock().iter().map(|(p, s)| (p.clone(), *s)).collect();
+        paths.sort_by(|(p1, s1), (p2, s2)| {
+            s2.count
+                .cmp(&s1.count)
+                .then_with(|| (&p1.from, &p1.event).cmp(&(&p2.from, &p2.event)))
+        });
+        paths
+    }
+
+    /// Forget all statistics.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Path, PathStats>> {
+        // The statistics remain consistent even if a holder of the lock panicked
+        self.paths.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K, S> Observer<K, S> for TransitionStats<S> {
+    fn transitioned(&mut self, _key: &K, from: &S, event: &str, to: &S) {
+        let path = Path {
+            from: (self.label)(from),
+            event: event.to_string(),
+            to: (self.label)(to),
+        };
+        let now = SystemTime::now();
+        self.lock()
+            .entry(path)
+            .and_modify(|s| {
+                s.count += 1;
+                s.last = now;
+            })
+            .or_insert(PathStats {
+                count: 1,
+                last: now,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+    use crate::runner::Runner;
+
+    #[test]
+    fn test_transition_stats() {
+        // A switch that is toggled by a command
+
+        struct Toggle {}
+
+        struct Toggled {}
+
+        impl Command<bool, ()> for Toggle {
+            type Output = Toggled;
+            fn execute(&self, _s: &bool, _h: &mut ()) -> Option<Toggled> {
+                Some(Toggled {})
+            }
+        }
+
+        impl Validate<bool> for Toggle {}
+
+        impl Event<bool> for Toggled {
+            fn fire(&self, s: &bool) -> Transition<bool> {
+                Transition::Next(!s)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<bool, ()> for MyFsm {}
+
+        let stats = TransitionStats::new(|s: &bool| if *s { "On" } else { "Off" }.to_string());
+        let mut runner =
+            Runner::<u32, bool, (), MyFsm>::new(()).with_observer(Box::new(stats.clone()));
+        runner.insert(1, false);
+        runner.insert(2, false);
+
+        for _ in 0..3 {
+            runner.send(&1, &Toggle {}).unwrap();
+        }
+        runner.send(&2, &Toggle {}).unwrap();
+
+        assert_eq!(stats.get("Off", "Toggled", "On").map(|s| s.count), Some(3));
+        assert_eq!(stats.get("On", "Toggled", "Off").map(|s| s.count), Some(1));
+        assert_eq!(stats.get("On", "Toggled", "On"), None);
+
+        let paths = stats.paths();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(paths[0].0.from, "Off");
+
+        stats.clear();
+        assert!(stats.paths().is_empty());
+    }
+}
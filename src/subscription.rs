@@ -0,0 +1,275 @@
+//! An event bus journals events and broadcasts them to any number of
+//! subscribers. A subscriber may start from any offset of the journal:
+//! it first receives the journalled records from that offset and then
+//! the live records as they are published, with no gaps or repeats.
+//! This allows a projection to be rebuilt while the machine runs.
+//!
+//! A subscriber may also follow a single entity from a sequence number,
+//! which is what a server streaming an entity's events to a remote
+//! client needs.
+
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, Weak};
+
+use crate::journal::{Journal, JournalError, Position, Record};
+use crate::runner::EventListener;
+
+type Filter<K, E> = Box<dyn Fn(&Record<K, E>) -> bool + Send>;
+
+struct Subscriber<K, E> {
+    sender: mpsc::Sender<Record<K, E>>,
+    filter: Option<Filter<K, E>>,
+    /// Gone when the subscription is dropped, which a send that the filter
+    /// skips would not find out
+    alive: Weak<()>,
+}
+
+impl<K, E> Subscriber<K, E> {
+    /// A subscriber and its subscription, with no backlog.
+    fn new(filter: Option<Filter<K, E>>) -> (Self, Subscription<K, E>) {
+        let (sender, records) = mpsc::channel();
+        let alive = Arc::new(());
+        let subscriber = Self {
+            sender,
+            filter,
+            alive: Arc::downgrade(&alive),
+        };
+        let subscription = Subscription {
+            records,
+            backlog: 0,
+            _alive: alive,
+        };
+        (subscriber, subscription)
+    }
+
+    /// Send a record if it passes the filter, answering whether the
+    /// subscriber is still there.
+    fn send(&self, record: &Record<K, E>) -> bool
+    where
+        K: Clone,
+        E: Clone,
+    {
+        match &self.filter {
+            Some(filter) if !filter(record) => self.alive.strong_count() > 0,
+            _ => self.sender.send(record.clone()).is_ok(),
+        }
+    }
+}
+
+struct Inner<J, K, E> {
+    journal: J,
+    subscribers: Vec<Subscriber<K, E>>,
+}
+
+/// Journals and broadcasts events of type E for entities with keys K.
+/// The bus is a handle that may be cloned and shared between threads.
+pub struct EventBus<J, K, E> {
+    inner: Arc<Mutex<Inner<J, K, E>>>,
+}
+
+impl<J, K, E> Clone for EventBus<J, K, E> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+/// The records received by a subscriber, in order.
+pub struct Subscription<K, E> {
+    records: mpsc::Receiver<Record<K, E>>,
+    backlog: usize,
+    _alive: Arc<()>,
+}
+
+impl<K, E> Subscription<K, E> {
+    /// The number of journalled records replayed before the live records.
+    pub fn backlog(&self) -> usize {
+        self.backlog
+    }
+
+    /// Wait for the next record, or `None` if the bus has gone.
+    pub fn recv(&self) -> Option<Record<K, E>> {
+        self.records.recv().ok()
+    }
+
+    /// The next record if one is waiting.
+    pub fn try_recv(&self) -> Option<Record<K, E>> {
+        self.records.try_recv().ok()
+    }
+}
+
+impl<K, E> Iterator for Subscription<K, E> {
+    type Item = Record<K, E>;
+
+    fn next(&mut self) -> Option<Record<K, E>> {
+        self.recv()
+    }
+}
+
+impl<J, K, E> EventBus<J, K, E>
+where
+    J: Journal<K, E>,
+    K: Clone,
+    E: Clone,
+{
+    pub fn new(journal: J) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                journal,
+                subscribers: Vec::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<J, K, E>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Journal an event and broadcast it to the subscribers.
+    pub fn publish(&self, key: &K, event: E) -> Result<Position, JournalError> {
+        let mut inner = self.lock();
+        let position = inner.journal.append(key, event.clone())?;
+        let record = Record {
+            offset: position.offset,
+            key: key.clone(),
+            seq: position.seq,
+            event,
+        };
+        inner.subscribers.retain(|s| s.send(&record));
+        Ok(position)
+    }
+
+    /// Subscribe to all records from an offset, then to live records.
+    pub fn subscribe_from(&self, offset: u64) -> Result<Subscription<K, E>, JournalError> {
+        let mut inner = self.lock();
+        let backlog = inner.journal.read_all(offset)?;
+        let (subscriber, mut subscription) = Subscriber::new(None);
+        subscription.backlog = backlog.len();
+        for record in backlog {
+            let _ = subscriber.sender.send(record);
+        }
+        inner.subscribers.push(subscriber);
+        Ok(subscription)
+    }
+
+    /// Subscribe to one entity's records from a sequence number, then to
+    /// its live records.
+    pub fn subscribe_entity(
+        &self,
+        key: &K,
+        from_seq: u64,
+    ) -> Result<Subscription<K, E>, JournalError>
+    where
+        K: PartialEq + Send + 'static,
+    {
+        let mut inner = self.lock();
+        let backlog = inner.journal.read(key, from_seq)?;
+        let key = key.clone();
+        let (subscriber, mut subscription) = Subscriber::new(Some(Box::new(move |r| r.key == key)));
+        subscription.backlog = backlog.len();
+        for record in backlog {
+            let _ = subscriber.sender.send(record);
+        }
+        inner.subscribers.push(subscriber);
+        Ok(subscription)
+    }
+
+    /// Subscribe to live records only.
+    pub fn subscribe(&self) -> Subscription<K, E> {
+        let (subscriber, subscription) = Subscriber::new(None);
+        self.lock().subscribers.push(subscriber);
+        subscription
+    }
+
+    /// Subscribe to the live records passing a filter, for example those
+    /// a connected client has asked for.
+    pub fn subscribe_where<P>(&self, filter: P) -> Subscription<K, E>
+    where
+        P: Fn(&Record<K, E>) -> bool + Send + 'static,
+    {
+        let (subscriber, subscription) = Subscriber::new(Some(Box::new(filter)));
+        self.lock().subscribers.push(subscriber);
+        subscription
+    }
+
+    /// Read from the journal.
+    pub fn with_journal<T>(&self, f: impl FnOnce(&J) -> T) -> T {
+        f(&self.lock().journal)
+    }
+}
+
+/// A bus can listen to a runner, journalling and broadcasting each event
+/// before it is applied.
+impl<J, K, S, E> EventListener<K, S, E> for EventBus<J, K, E>
+where
+    J: Journal<K, E>,
+    K: Clone,
+    E: Clone,
+{
+    fn on_event(&mut self, key: &K, _state: &S, event: &E) -> Result<(), String> {
+        self.publish(key, event.clone())
+            .map(|_| ())
+            .map_err(|e| format!("{e:?}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::MemJournal;
+
+    #[test]
+    fn test_subscribe_from() {
+        let bus = EventBus::new(MemJournal::new());
+        bus.publish(&"a", 1).unwrap();
+        bus.publish(&"b", 2).unwrap();
+
+        let live = bus.subscribe();
+        let late = bus.subscribe_from(1).unwrap();
+        assert_eq!(late.backlog(), 1);
+        bus.publish(&"a", 3).unwrap();
+
+        let events = |s: &Subscription<&str, u32>| -> Vec<(u64, u32)> {
+            std::iter::from_fn(|| s.try_recv())
+                .map(|r| (r.offset, r.event))
+                .collect()
+        };
+        assert_eq!(events(&live), vec![(2, 3)]);
+        assert_eq!(events(&late), vec![(1, 2), (2, 3)]);
+
+        // Dropped subscribers are forgotten
+
+        drop(live);
+        bus.publish(&"b", 4).unwrap();
+        assert_eq!(events(&late), vec![(3, 4)]);
+        assert_eq!(bus.with_journal(|j| j.len()), 4);
+
+        // Following one entity from a sequence number
+
+        let follow = bus.subscribe_entity(&"b", 2).unwrap();
+        assert_eq!(follow.backlog(), 1);
+        let large = bus.subscribe_where(|r| r.event > 5);
+        bus.publish(&"a", 5).unwrap();
+        bus.publish(&"b", 6).unwrap();
+        let seqs: Vec<_> = std::iter::from_fn(|| follow.try_recv())
+            .map(|r| (r.seq, r.event))
+            .collect();
+        assert_eq!(seqs, vec![(2, 4), (3, 6)]);
+        assert_eq!(events(&large), vec![(5, 6)]);
+    }
+
+    #[test]
+    fn test_filtered_dropped() {
+        // A client following one entity disconnects, and the bus forgets
+        // it even though no records for that entity are published again
+        let bus = EventBus::new(MemJournal::new());
+        let follow = bus.subscribe_entity(&"a", 1).unwrap();
+        let large = bus.subscribe_where(|r| r.event > 5);
+        let all = bus.subscribe();
+        drop(follow);
+        drop(large);
+        bus.publish(&"b", 1).unwrap();
+        assert_eq!(bus.lock().subscribers.len(), 1);
+        assert_eq!(all.try_recv().map(|r| r.event), Some(1));
+    }
+}
@@ -0,0 +1,272 @@
+//! Supervision of the entities of a runner, in the style of actors. The
+//! entities are children of a parent machine, which receives a
+//! `ChildFailed` event whenever a child fails: when stepping the child
+//! panics, or when the child rejects too many commands in a row. The
+//! parent's state, after the event, then directs what becomes of the
+//! child. It can be restarted in a given state, resumed from its last
+//! snapshot, or stopped.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+use crate::runner::{RunError, Runner};
+
+/// How a child failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Failure {
+    /// Stepping the child panicked, with the reason
+    Panicked(String),
+    /// The child rejected this many commands in a row, the last with the error
+    Rejected { count: u32, last: String },
+}
+
+/// The event synthesized for the parent when a child fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChildFailed<K> {
+    pub key: K,
+    pub failure: Failure,
+}
+
+/// What becomes of a failed child.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive<S> {
+    /// Continue in the given state
+    Restart(S),
+    /// Continue in the state last snapshotted, or stop if there is none
+    ResumeFromSnapshot,
+    /// Remove the child
+    Stop,
+}
+
+/// A parent of children with keys K and states S.
+pub trait Supervise<K, S> {
+    /// What to do with a failed child, having received its failure.
+    fn directive(&self, failed: &ChildFailed<K>) -> Directive<S>;
+}
+
+/// A runner whose entities are supervised by a parent, with state P.
+pub struct Supervisor<K, S, H, F, P> {
+    runner: Runner<K, S, H, F>,
+    parent: P,
+    rejection_limit: u32,
+    rejections: HashMap<K, u32>,
+    snapshots: HashMap<K, S>,
+}
+
+impl<K, S, H, F, P> Supervisor<K, S, H, F, P>
+where
+    K: Eq + Hash + Clone,
+    S: Clone,
+    F: Fsm<S, H>,
+    P: Supervise<K, S>,
+    ChildFailed<K>: Event<P>,
+{
+    /// Supervise the entities of a runner, which is made to isolate panics
+    /// so that a child that panics fails alone and can be restarted.
+    pub fn new(runner: Runner<K, S, H, F>, parent: P) -> Self {
+        Self {
+            runner: runner.with_panic_isolation(),
+            parent,
+            rejection_limit: u32::MAX,
+            rejections: HashMap::new(),
+            snapshots: HashMap::new(),
+        }
+    }
+
+    /// Fail a child when it rejects this many commands in a row.
+    pub fn with_rejection_limit(mut self, limit: u32) -> Self {
+        self.rejection_limit = limit;
+        self
+    }
+
+    pub fn runner(&self) -> &Runner<K, S, H, F> {
+        &self.runner
+    }
+
+    pub fn parent(&self) -> &P {
+        &self.parent
+    }
+
+    /// Add a child, snapshotting its state.
+    pub fn insert(&mut self, key: K, state: S) {
+        self.snapshots.insert(key.clone(), state.clone());
+        self.runner.insert(key, state);
+    }
+
+    /// Snapshot the current state of a live child.
+    pub fn snapshot(&mut self, key: &K) {
+        if let Some(state) = self.runner.state(key) {
+            self.snapshots.insert(key.clone(), state.clone());
+        }
+    }
+
+    /// Step a child with a command, supervising it if it fails.
+    pub fn send<C>(&mut self, key: &K, command: &C) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+    {
+        let result = self.runner.send(key, command);
+        let failure = match &result {
+            Ok(_) => {
+                self.rejections.remove(key);
+                None
+            }
+            Err(RunError::Unknown) | Err(RunError::Failed(_)) => None,
+            Err(RunError::Panicked(reason)) => Some(Failure::Panicked(reason.clone())),
+            Err(e) => {
+                let count = self.rejections.entry(key.clone()).or_insert(0);
+                *count += 1;
+                (*count >= self.rejection_limit).then(|| Failure::Rejected {
+                    count: *count,
+                    last: format!("{e:?}"),
+                })
+            }
+        };
+        if let Some(failure) = failure {
+            self.supervise(ChildFailed {
+                key: key.clone(),
+                failure,
+            });
+        }
+        result
+    }
+
+    fn supervise(&mut self, failed: ChildFailed<K>) {
+        if let Transition::Next(p) = failed.fire(&self.parent) {
+            self.parent = p;
+        }
+        self.rejections.remove(&failed.key);
+        match self.parent.directive(&failed) {
+            Directive::Restart(state) => self.runner.insert(failed.key, state),
+            Directive::ResumeFromSnapshot => match self.snapshots.get(&failed.key) {
+                Some(state) => self.runner.insert(failed.key, state.clone()),
+                None => {
+                    self.runner.remove(&failed.key);
+                }
+            },
+            Directive::Stop => {
+                self.runner.remove(&failed.key);
+                self.snapshots.remove(&failed.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{StepError, ValidationError};
+
+    #[test]
+    fn test_supervisor() {
+        // Counters that panic at 3 and refuse to go below 0, supervised by
+        // a parent that restarts each child once and then stops it
+
+        enum Count {
+            Up,
+            Down,
+        }
+
+        #[derive(Debug, PartialEq)]
+        struct Counted(i32);
+
+        impl Command<i32, ()> for Count {
+            type Output = Counted;
+            fn execute(&self, s: &i32, _h: &mut ()) -> Option<Counted> {
+                match self {
+                    Count::Up if *s == 3 => panic!("overflow"),
+                    Count::Up => Some(Counted(s + 1)),
+                    Count::Down => Some(Counted(s - 1)),
+                }
+            }
+        }
+
+        impl Validate<i32> for Count {
+            fn validate(&self, s: &i32) -> Result<(), ValidationError> {
+                match self {
+                    Count::Down if *s == 0 => Err(ValidationError("at zero".to_string())),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        impl Event<i32> for Counted {
+            fn fire(&self, _s: &i32) -> Transition<i32> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<i32, ()> for MyFsm {}
+
+        #[derive(Debug, PartialEq)]
+        struct Parent {
+            restarted: Vec<(&'static str, Failure)>,
+        }
+
+        impl Event<Parent> for ChildFailed<&'static str> {
+            fn fire(&self, p: &Parent) -> Transition<Parent> {
+                let mut restarted = p.restarted.clone();
+                restarted.push((self.key, self.failure.clone()));
+                Transition::Next(Parent { restarted })
+            }
+        }
+
+        impl Supervise<&'static str, i32> for Parent {
+            fn directive(&self, failed: &ChildFailed<&'static str>) -> Directive<i32> {
+                let failures = self.restarted.iter().filter(|(k, _)| *k == failed.key);
+                match (failures.count(), &failed.failure) {
+                    (1, Failure::Panicked(_)) => Directive::ResumeFromSnapshot,
+                    (1, Failure::Rejected { .. }) => Directive::Restart(1),
+                    _ => Directive::Stop,
+                }
+            }
+        }
+
+        // The supervisor isolates panics itself
+        let runner = Runner::<&str, i32, (), MyFsm>::new(());
+        let parent = Parent { restarted: vec![] };
+        let mut supervisor = Supervisor::new(runner, parent).with_rejection_limit(2);
+        supervisor.insert("a", 2);
+        supervisor.insert("b", 0);
+
+        // A panic resumes from the snapshot, then stops the child
+
+        assert_eq!(supervisor.send(&"a", &Count::Up), Ok(Some(Counted(3))));
+        assert_eq!(
+            supervisor.send(&"a", &Count::Up),
+            Err(RunError::Panicked("overflow".to_string()))
+        );
+        assert_eq!(supervisor.runner().state(&"a"), Some(&2));
+        supervisor.send(&"a", &Count::Up).unwrap();
+        assert!(supervisor.send(&"a", &Count::Up).is_err());
+        assert_eq!(supervisor.runner().state(&"a"), None);
+        assert_eq!(supervisor.send(&"a", &Count::Up), Err(RunError::Unknown));
+
+        // Repeated rejections restart the child
+
+        assert!(supervisor.send(&"b", &Count::Down).is_err());
+        assert_eq!(supervisor.runner().state(&"b"), Some(&0));
+        assert!(supervisor.send(&"b", &Count::Down).is_err());
+        assert_eq!(supervisor.runner().state(&"b"), Some(&1));
+
+        let invalid = ValidationError("at zero".to_string());
+        let error = format!("{:?}", RunError::Step(StepError::Invalid(invalid)));
+        assert_eq!(
+            supervisor.parent().restarted,
+            vec![
+                ("a", Failure::Panicked("overflow".to_string())),
+                ("a", Failure::Panicked("overflow".to_string())),
+                (
+                    "b",
+                    Failure::Rejected {
+                        count: 2,
+                        last: error
+                    }
+                ),
+            ]
+        );
+    }
+}
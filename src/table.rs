@@ -0,0 +1,217 @@
+//! A table driven machine, defined at runtime by a `MachineDescriptor`
+//! rather than by command and event types. States, commands and events
+//! are all named by strings. The table itself is the effect handler,
+//! and each event names the state it transitions to, so events can be
+//! replayed without the table.
+//!
+//! Transitions may have guards and actions, which are named in the table
+//! and bound to closures. Where several transitions are declared for a
+//! command in a state, the first whose guard passes is taken. An unbound
+//! guard never passes, and an unbound action does nothing.
+//!
+//! Tables can be written as text, one transition per line:
+//!
+//! ```text
+//! # A door
+//! machine door
+//! initial Closed
+//! Closed Open   Opened   Open
+//! Open   Close  Closed   Closed
+//! ```
+
+use std::collections::HashMap;
+
+use crate::command_and_event_traits::{Command, Event, Fsm, Transition, Validate};
+use crate::descriptor::{MachineDescriptor, TransitionDescriptor};
+
+/// A guard, given the name of the current state.
+pub type Guard = Box<dyn Fn(&str) -> bool>;
+
+/// An action, given the name of the current state and the event emitted.
+pub type Action = Box<dyn FnMut(&str, &TableEvent)>;
+
+/// The effect handler of a table driven machine.
+pub struct Table {
+    descriptor: MachineDescriptor,
+    rows: HashMap<(String, String), Vec<usize>>,
+    guards: HashMap<String, Guard>,
+    actions: HashMap<String, Action>,
+}
+
+impl Table {
+    pub fn new(descriptor: MachineDescriptor) -> Self {
+        let mut rows: HashMap<_, Vec<_>> = HashMap::new();
+        for (i, t) in descriptor.transitions.iter().enumerate() {
+            rows.entry((t.from.clone(), t.command.clone()))
+                .or_default()
+                .push(i);
+        }
+        Self {
+            descriptor,
+            rows,
+            guards: HashMap::new(),
+            actions: HashMap::new(),
+        }
+    }
+
+    /// Bind a guard to its name.
+    pub fn with_guard(mut self, name: &str, guard: impl Fn(&str) -> bool + 'static) -> Self {
+        self.guards.insert(name.to_string(), Box::new(guard));
+        self
+    }
+
+    /// Bind an action to its name.
+    pub fn with_action(
+        mut self,
+        name: &str,
+        action: impl FnMut(&str, &TableEvent) + 'static,
+    ) -> Self {
+        self.actions.insert(name.to_string(), Box::new(action));
+        self
+    }
+
+    pub fn descriptor(&self) -> &MachineDescriptor {
+        &self.descriptor
+    }
+
+    /// The transition taken by a command in a state, if any.
+    pub fn lookup(&self, state: &str, command: &str) -> Option<&TransitionDescriptor> {
+        self.rows
+            .get(&(state.to_string(), command.to_string()))?
+            .iter()
+            .map(|i| &self.descriptor.transitions[*i])
+            .find(|t| match &t.guard {
+                Some(g) => self.guards.get(g).is_some_and(|guard| guard(state)),
+                None => true,
+            })
+    }
+}
+
+/// A command of a table driven machine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableCommand(pub String);
+
+/// An event of a table driven machine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableEvent {
+    pub name: String,
+    pub to: String,
+}
+
+impl Command<String, Table> for TableCommand {
+    type Output = TableEvent;
+    fn execute(&self, state: &String, table: &mut Table) -> Option<TableEvent> {
+        let t = table.lookup(state, &self.0)?;
+        let event = TableEvent {
+            name: t.event.clone(),
+            to: t.to.clone(),
+        };
+        for name in t.actions.clone() {
+            if let Some(action) = table.actions.get_mut(&name) {
+                action(state, &event);
+            }
+        }
+        Some(event)
+    }
+
+    fn name(&self) -> String {
+        self.0.clone()
+    }
+}
+
+impl Validate<String> for TableCommand {}
+
+impl Event<String> for TableEvent {
+    fn fire(&self, state: &String) -> Transition<String> {
+        if *state == self.to {
+            Transition::Same
+        } else {
+            Transition::Next(self.to.clone())
+        }
+    }
+}
+
+/// The FSM of table driven machines.
+pub struct TableFsm {}
+
+impl Fsm<String, Table> for TableFsm {}
+
+/// A problem with the text of a table.
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// Parse the text form of a table.
+pub fn parse_table(text: &str) -> Result<MachineDescriptor, ParseError> {
+    let mut descriptor = MachineDescriptor::default();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("");
+        let words: Vec<_> = line.split_whitespace().collect();
+        descriptor = match words.as_slice() {
+            [] => descriptor,
+            ["machine", name] => MachineDescriptor {
+                name: name.to_string(),
+                ..descriptor
+            },
+            ["initial", state] => descriptor.initial(state),
+            ["state", state] => descriptor.state(state),
+            [from, command, event, to] => descriptor.transition(from, command, event, to),
+            _ => {
+                return Err(ParseError {
+                    line: i + 1,
+                    message: format!("expected `from command event to`, found {line:?}"),
+                })
+            }
+        };
+    }
+    Ok(descriptor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let descriptor = parse_table(
+            "# A door
+            machine door
+            initial Closed
+            Closed Open  Opened Open
+            Open   Close Closed Closed  # and back again
+            ",
+        )
+        .unwrap();
+        assert_eq!(descriptor.name, "door");
+        assert_eq!(descriptor.initial.as_deref(), Some("Closed"));
+        assert_eq!(descriptor.states, vec!["Closed", "Open"]);
+
+        let mut table = Table::new(descriptor);
+        let state = "Closed".to_string();
+
+        let (e, t) = TableFsm::step(&state, &TableCommand("Open".to_string()), &mut table);
+        assert_eq!(
+            e,
+            Some(TableEvent {
+                name: "Opened".to_string(),
+                to: "Open".to_string()
+            })
+        );
+        assert_eq!(t, Ok(Transition::Next("Open".to_string())));
+
+        let (e, t) = TableFsm::step(&state, &TableCommand("Close".to_string()), &mut table);
+        assert_eq!(e, None);
+        assert_eq!(t, Ok(Transition::Same));
+
+        assert_eq!(
+            parse_table("Closed Open Opened"),
+            Err(ParseError {
+                line: 1,
+                message: "expected `from command event to`, found \"Closed Open Opened\""
+                    .to_string()
+            })
+        );
+    }
+}
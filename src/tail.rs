@@ -0,0 +1,129 @@
+//! Tailing a journal that is written elsewhere, such as a database table
+//! appended to by other processes. A `Tail` reads the records after its
+//! offset, and when there are none it sleeps until it is woken or a
+//! timeout passes, so that readers are prompt without polling hard.
+//!
+//! Writers, or a listener for the database's notifications, wake the
+//! readers through a `Wakeup`. The timeout covers a missed wakeup.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::journal::{Journal, JournalError, Record};
+
+/// Wakes the tails waiting for new records. It is a handle that may be
+/// cloned and shared between threads.
+#[derive(Clone, Default)]
+pub struct Wakeup {
+    inner: Arc<(Mutex<u64>, Condvar)>,
+}
+
+impl Wakeup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wake the waiting tails, as new records have been appended.
+    pub fn notify(&self) {
+        let (count, woken) = &*self.inner;
+        *count.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        woken.notify_all();
+    }
+
+    /// The number of wakeups so far.
+    fn count(&self) -> u64 {
+        *self.inner.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait until there has been a wakeup after the count seen, or a timeout.
+    fn wait(&self, seen: u64, timeout: Duration) {
+        let (count, woken) = &*self.inner;
+        let count = count.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = woken.wait_timeout_while(count, timeout, |c| *c == seen);
+    }
+}
+
+/// Reads the records of a journal J from an offset, as they are appended.
+pub struct Tail<J> {
+    journal: J,
+    offset: u64,
+    wakeup: Wakeup,
+    timeout: Duration,
+}
+
+impl<J> Tail<J> {
+    /// Tail a journal, typically shared as `Arc<Mutex<_>>`, from an offset.
+    pub fn new(journal: J, offset: u64, wakeup: Wakeup) -> Self {
+        Self {
+            journal,
+            offset,
+            wakeup,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// The longest wait for a wakeup before reading again.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The offset of the next record to be read.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The next records, waiting up to the timeout for some to be appended.
+    /// There may be none if the timeout passes.
+    pub fn next_batch<K, E>(&mut self) -> Result<Vec<Record<K, E>>, JournalError>
+    where
+        J: Journal<K, E>,
+    {
+        let seen = self.wakeup.count();
+        let mut records = self.journal.read_all(self.offset)?;
+        if records.is_empty() {
+            self.wakeup.wait(seen, self.timeout);
+            records = self.journal.read_all(self.offset)?;
+        }
+        if let Some(last) = records.last() {
+            self.offset = last.offset + 1;
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::MemJournal;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn test_tail() {
+        // A writer appending to a shared journal, and a tail on another thread
+
+        let journal = Arc::new(Mutex::new(MemJournal::new()));
+        let wakeup = Wakeup::new();
+        let mut tail =
+            Tail::new(journal.clone(), 0, wakeup.clone()).with_timeout(Duration::from_millis(20));
+
+        let start = Instant::now();
+        assert_eq!(tail.next_batch::<&str, u32>(), Ok(vec![]));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let reader = thread::spawn(move || {
+            let mut events = Vec::new();
+            while events.len() < 3 {
+                events.extend(tail.next_batch().unwrap().into_iter().map(|r| r.event));
+            }
+            (events, tail.offset())
+        });
+        let mut writer = journal.clone();
+        for e in [1, 2, 3] {
+            writer.append(&"a", e).unwrap();
+            wakeup.notify();
+        }
+        assert_eq!(reader.join().unwrap(), (vec![1, 2, 3], 3));
+    }
+}
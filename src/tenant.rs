@@ -0,0 +1,279 @@
+//! Hosting the machines of many tenants in one process. Entities are
+//! keyed by `TenantKey`, a tenant identifier and the tenant's own key, so
+//! a single runner and journal can serve every tenant.
+//!
+//! Isolation is kept by the views given to each tenant. A `TenantJournal`
+//! appends and reads only its tenant's records, so replay cannot cross
+//! tenants, and `only_tenant` filters a subscription likewise. `PerTenant`
+//! keeps a separate observer, such as `TransitionStats`, for each tenant,
+//! so that metrics are labelled by tenant.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::journal::{Journal, JournalError, Position, Record};
+use crate::observer::Observer;
+use crate::runner::RunError;
+
+/// The identifier of a tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Tenant(pub String);
+
+impl Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The key of an entity belonging to a tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TenantKey<K> {
+    pub tenant: Tenant,
+    pub key: K,
+}
+
+impl<K> TenantKey<K> {
+    pub fn new(tenant: &str, key: K) -> Self {
+        Self {
+            tenant: Tenant(tenant.to_string()),
+            key,
+        }
+    }
+}
+
+/// One tenant's view of a journal shared by all tenants.
+pub struct TenantJournal<J> {
+    journal: J,
+    tenant: Tenant,
+}
+
+impl<J> TenantJournal<J> {
+    /// A view of a journal, typically shared as `Arc<Mutex<_>>`.
+    pub fn new(journal: J, tenant: &str) -> Self {
+        Self {
+            journal,
+            tenant: Tenant(tenant.to_string()),
+        }
+    }
+
+    pub fn tenant(&self) -> &Tenant {
+        &self.tenant
+    }
+
+    fn key<K: Clone>(&self, key: &K) -> TenantKey<K> {
+        TenantKey {
+            tenant: self.tenant.clone(),
+            key: key.clone(),
+        }
+    }
+}
+
+fn untenanted<K, E>(record: Record<TenantKey<K>, E>) -> Record<K, E> {
+    Record {
+        offset: record.offset,
+        key: record.key.key,
+        seq: record.seq,
+        event: record.event,
+    }
+}
+
+/// Truncation is not offered, as it would remove other tenants' records.
+impl<K, E, J> Journal<K, E> for TenantJournal<J>
+where
+    J: Journal<TenantKey<K>, E>,
+    K: Clone,
+{
+    fn append(&mut self, key: &K, event: E) -> Result<Position, JournalError> {
+        let key = self.key(key);
+        self.journal.append(&key, event)
+    }
+
+    fn read(&self, key: &K, from_seq: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        let records = self.journal.read(&self.key(key), from_seq)?;
+        Ok(records.into_iter().map(untenanted).collect())
+    }
+
+    fn read_page(
+        &self,
+        key: &K,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<Record<K, E>>, JournalError> {
+        let records = self.journal.read_page(&self.key(key), from_seq, limit)?;
+        Ok(records.into_iter().map(untenanted).collect())
+    }
+
+    fn read_all(&self, from_offset: u64) -> Result<Vec<Record<K, E>>, JournalError> {
+        let records = self.journal.read_all(from_offset)?;
+        Ok(only_tenant(records, &self.tenant).collect())
+    }
+
+    fn last_seq(&self, key: &K) -> Result<u64, JournalError> {
+        self.journal.last_seq(&self.key(key))
+    }
+
+    fn prune(&mut self, key: &K, up_to_seq: u64) -> Result<(), JournalError> {
+        let key = self.key(key);
+        self.journal.prune(&key, up_to_seq)
+    }
+
+    /// Delegated, so a batch is all or nothing if it is in the journal of
+    /// all tenants.
+    fn append_batch(&mut self, key: &K, events: Vec<E>) -> Result<Vec<Position>, JournalError> {
+        let key = self.key(key);
+        self.journal.append_batch(&key, events)
+    }
+}
+
+/// The records of one tenant, for example from a subscription, with its keys.
+pub fn only_tenant<'a, K: 'a, E: 'a>(
+    records: impl IntoIterator<Item = Record<TenantKey<K>, E>> + 'a,
+    tenant: &'a Tenant,
+) -> impl Iterator<Item = Record<K, E>> + 'a {
+    records
+        .into_iter()
+        .filter(move |r| r.key.tenant == *tenant)
+        .map(untenanted)
+}
+
+type Observers<O> = Arc<Mutex<HashMap<Tenant, O>>>;
+
+/// An observer for each tenant, made as each tenant is first seen. It is a
+/// handle, so a clone can be kept to get a tenant's observer.
+pub struct PerTenant<O> {
+    make: Arc<dyn Fn(&Tenant) -> O + Send + Sync>,
+    observers: Observers<O>,
+}
+
+impl<O> Clone for PerTenant<O> {
+    fn clone(&self) -> Self {
+        Self {
+            make: self.make.clone(),
+            observers: self.observers.clone(),
+        }
+    }
+}
+
+impl<O> PerTenant<O> {
+    pub fn new<M>(make: M) -> Self
+    where
+        M: Fn(&Tenant) -> O + Send + Sync + 'static,
+    {
+        Self {
+            make: Arc::new(make),
+            observers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Tenant, O>> {
+        self.observers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The observer of a tenant, if it has been seen.
+    pub fn get(&self, tenant: &str) -> Option<O>
+    where
+        O: Clone,
+    {
+        self.lock().get(&Tenant(tenant.to_string())).cloned()
+    }
+
+    fn with<R>(&self, tenant: &Tenant, f: impl FnOnce(&mut O) -> R) -> R {
+        let mut observers = self.lock();
+        let observer = observers
+            .entry(tenant.clone())
+            .or_insert_with(|| (self.make)(tenant));
+        f(observer)
+    }
+}
+
+impl<K, S, O> Observer<TenantKey<K>, S> for PerTenant<O>
+where
+    O: Observer<K, S>,
+{
+    fn inserted(&mut self, key: &TenantKey<K>, state: &S) {
+        self.with(&key.tenant, |o| o.inserted(&key.key, state))
+    }
+
+    fn removed(&mut self, key: &TenantKey<K>) {
+        self.with(&key.tenant, |o| o.removed(&key.key))
+    }
+
+    fn transitioned(&mut self, key: &TenantKey<K>, from: &S, event: &str, to: &S) {
+        self.with(&key.tenant, |o| o.transitioned(&key.key, from, event, to))
+    }
+
+    fn ignored(&mut self, key: &TenantKey<K>, state: &S, command: &str) {
+        self.with(&key.tenant, |o| o.ignored(&key.key, state, command))
+    }
+
+    fn rejected(&mut self, key: &TenantKey<K>, command: &str, error: &RunError) {
+        self.with(&key.tenant, |o| o.rejected(&key.key, command, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Counter, Increment, Incremented};
+    use crate::journal::MemJournal;
+    use crate::projection::Projector;
+    use crate::runner::Runner;
+    use crate::stats::TransitionStats;
+
+    #[test]
+    fn test_tenants() {
+        // Counters for two tenants, both with an entity named "a"
+
+        let stats = PerTenant::new(|_: &Tenant| TransitionStats::new(|s: &u32| s.to_string()));
+        let mut runner = Runner::<TenantKey<&str>, u32, (), Counter>::new(())
+            .with_observer(Box::new(stats.clone()));
+        let acme = TenantKey::new("acme", "a");
+        let globex = TenantKey::new("globex", "a");
+        runner.insert(acme.clone(), 0);
+        runner.insert(globex.clone(), 0);
+
+        let journal = Arc::new(Mutex::new(MemJournal::new()));
+        let mut projector = Projector::new(journal.clone());
+        for key in [&acme, &globex, &acme] {
+            runner
+                .send_with(key, &Increment {}, &mut projector)
+                .unwrap();
+        }
+        assert_eq!(runner.state(&acme), Some(&2));
+        assert_eq!(runner.state(&globex), Some(&1));
+
+        // Each tenant replays only its own records
+        let view = TenantJournal::new(journal.clone(), "globex");
+        let seqs = |records: Vec<Record<&str, Incremented>>| -> Vec<(u64, u64)> {
+            records.iter().map(|r| (r.offset, r.seq)).collect()
+        };
+        assert_eq!(seqs(view.read(&"a", 0).unwrap()), vec![(1, 1)]);
+        assert_eq!(seqs(view.read_all(0).unwrap()), vec![(1, 1)]);
+        assert_eq!(view.last_seq(&"a"), Ok(1));
+
+        let mut acme_view = TenantJournal::new(journal.clone(), "acme");
+        assert!(acme_view.truncate(0).is_err());
+        assert_eq!(acme_view.last_seq(&"a"), Ok(2));
+        acme_view.append(&"b", Incremented {}).unwrap();
+        assert_eq!(view.read_all(0).unwrap().len(), 1);
+        assert_eq!(
+            journal
+                .lock()
+                .unwrap()
+                .last_seq(&TenantKey::new("acme", "b")),
+            Ok(1)
+        );
+
+        // Metrics are kept per tenant
+        let acme_stats = stats.get("acme").unwrap();
+        assert_eq!(
+            acme_stats.get("1", "Incremented", "2").map(|s| s.count),
+            Some(1)
+        );
+        let globex_stats = stats.get("globex").unwrap();
+        assert_eq!(globex_stats.get("1", "Incremented", "2"), None);
+        assert!(stats.get("initech").is_none());
+    }
+}
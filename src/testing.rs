@@ -0,0 +1,474 @@
+//! Helpers for exploring and testing machines.
+//!
+//! Golden traces protect a machine's behaviour against regression. The
+//! transcript of a scripted run is compared with a checked in golden file.
+//! Set `UPDATE_GOLDEN=1` when running tests to regenerate golden files.
+//!
+//! Differential tests validate a rework of a machine by running the same
+//! script through the old and new versions and reporting where they
+//! first differ.
+
+use std::env;
+use std::fmt::{Debug, Write as _};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::command_and_event_traits::{Command, Fsm, Transition, Validate};
+
+/// Interactively step an FSM, F, from a state. Each line of input is parsed
+/// as a command and stepped, and the outcome is pretty printed. A line that
+/// cannot be parsed reports the parser's error. The state reached is returned
+/// when the input ends. For an interactive session, pass `io::stdin().lock()`
+/// and `io::stdout()`.
+pub fn repl<F, S, H, C>(
+    state: S,
+    handler: &mut H,
+    mut parse: impl FnMut(&str) -> Result<C, String>,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> io::Result<S>
+where
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    C::Output: Debug,
+    S: Debug,
+{
+    let mut state = state;
+    writeln!(output, "{state:#?}")?;
+    write!(output, "> ")?;
+    output.flush()?;
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if !line.is_empty() {
+            match parse(line) {
+                Ok(command) => {
+                    let (event, trans) = F::step(&state, &command, handler);
+                    writeln!(output, "{:#?}", (&event, &trans))?;
+                    if let Ok(Transition::Next(s)) = trans {
+                        state = s;
+                    }
+                }
+                Err(e) => writeln!(output, "{e}")?,
+            }
+        }
+        write!(output, "> ")?;
+        output.flush()?;
+    }
+    writeln!(output)?;
+    Ok(state)
+}
+
+/// Run a script of commands through an FSM, F, from a state and produce a
+/// transcript of the commands, events and states in a stable text form.
+/// Values are written with their `Debug` representations.
+pub fn transcript<F, S, H, C>(state: S, handler: &mut H, commands: &[C]) -> String
+where
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S> + Debug,
+    C::Output: Debug,
+    S: Debug,
+{
+    let mut state = state;
+    let mut text = String::new();
+    let _ = writeln!(text, "state {state:?}");
+    for command in commands {
+        let _ = writeln!(text, "command {command:?}");
+        let (event, trans) = F::step(&state, command, handler);
+        if let Some(e) = &event {
+            let _ = writeln!(text, "event {e:?}");
+        }
+        match trans {
+            Ok(Transition::Next(s)) => {
+                state = s;
+                let _ = writeln!(text, "state {state:?}");
+            }
+            Ok(Transition::Same) => {}
+            Err(e) => {
+                let _ = writeln!(text, "error {e:?}");
+            }
+        }
+    }
+    text
+}
+
+/// Compare a transcript with a golden file, panicking with the first
+/// difference if they differ. The golden file is written instead when
+/// the `UPDATE_GOLDEN` environment variable is set.
+pub fn assert_golden(path: impl AsRef<Path>, actual: &str) {
+    check_golden(
+        path.as_ref(),
+        actual,
+        env::var_os("UPDATE_GOLDEN").is_some(),
+    )
+}
+
+fn check_golden(path: &Path, actual: &str, update: bool) {
+    if update {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).expect("cannot create golden file directory");
+        }
+        fs::write(path, actual).expect("cannot write golden file");
+        return;
+    }
+    let expected = fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!(
+            "cannot read golden file {}: {e}, run with UPDATE_GOLDEN=1 to create it",
+            path.display()
+        )
+    });
+    if expected != actual {
+        let mut expected_lines = expected.lines();
+        let mut actual_lines = actual.lines();
+        let mut line = 1;
+        loop {
+            match (expected_lines.next(), actual_lines.next()) {
+                (Some(e), Some(a)) if e == a => line += 1,
+                (e, a) => panic!(
+                    "transcript differs from golden file {} at line {line}\nexpected: {}\n  actual: {}",
+                    path.display(),
+                    e.unwrap_or("<end>"),
+                    a.unwrap_or("<end>"),
+                ),
+            }
+        }
+    }
+}
+
+/// A fuzz target for an FSM, F. Commands are decoded from the fuzzer's data,
+/// consuming bytes as they go until `decode` answers `None`, and stepped from
+/// the initial state. The events emitted are then replayed from the initial
+/// state, which must reproduce the same final state, or this panics. A
+/// `cargo fuzz` target needs only call this, for example:
+///
+/// ```text
+/// fuzz_target!(|data: &[u8]| {
+///     fuzz_replay::<MyFsm, _, _, _>(State::default(), &mut Handler::default(), data, decode)
+/// });
+/// ```
+pub fn fuzz_replay<F, S, H, C>(
+    initial: S,
+    handler: &mut H,
+    data: &[u8],
+    mut decode: impl FnMut(&mut &[u8]) -> Option<C>,
+) where
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    S: Clone + PartialEq + Debug,
+{
+    let mut data = data;
+    let mut state = initial.clone();
+    let mut events = Vec::new();
+    while let Some(command) = decode(&mut data) {
+        let (event, trans) = F::step(&state, &command, handler);
+        if let Ok(trans) = trans {
+            events.extend(event);
+            if let Transition::Next(s) = trans {
+                state = s;
+            }
+        }
+    }
+
+    let mut replayed = initial;
+    for event in &events {
+        if let Transition::Next(s) = F::for_event::<_, S>(&replayed, event) {
+            replayed = s;
+        }
+    }
+    assert_eq!(
+        replayed, state,
+        "replaying events did not reproduce the state"
+    );
+}
+
+/// What differed between two versions of a machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Diverged {
+    /// The events emitted
+    Event,
+    /// Whether the step was refused, and why
+    Outcome,
+    /// The states reached
+    State,
+}
+
+/// The first difference between two versions of a machine, at the index
+/// of a command in the script. The values are written with their `Debug`
+/// representations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub step: usize,
+    pub diverged: Diverged,
+    pub old: String,
+    pub new: String,
+}
+
+/// Run a script of commands through an old FSM, A, and a new one, B, each
+/// from its own state and handler, and report the first step where the
+/// events, outcomes or states differ. The versions may have different
+/// state and event types, provided the old ones compare with the new.
+pub fn compare_versions<A, B, SA, HA, SB, HB, C>(
+    old: SA,
+    old_handler: &mut HA,
+    new: SB,
+    new_handler: &mut HB,
+    commands: &[C],
+) -> Option<Divergence>
+where
+    A: Fsm<SA, HA>,
+    B: Fsm<SB, HB>,
+    C: Command<SA, HA> + Validate<SA> + Command<SB, HB> + Validate<SB>,
+    <C as Command<SA, HA>>::Output: PartialEq<<C as Command<SB, HB>>::Output> + Debug,
+    <C as Command<SB, HB>>::Output: Debug,
+    SA: PartialEq<SB> + Debug,
+    SB: Debug,
+{
+    let mut old = old;
+    let mut new = new;
+    let diverged = |step, diverged, old: &dyn Debug, new: &dyn Debug| Divergence {
+        step,
+        diverged,
+        old: format!("{old:?}"),
+        new: format!("{new:?}"),
+    };
+    for (step, command) in commands.iter().enumerate() {
+        let (old_event, old_trans) = A::step(&old, command, old_handler);
+        let (new_event, new_trans) = B::step(&new, command, new_handler);
+        let same_events = match (&old_event, &new_event) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => true,
+            _ => false,
+        };
+        if !same_events {
+            return Some(diverged(step, Diverged::Event, &old_event, &new_event));
+        }
+        let old_error = old_trans.as_ref().err();
+        let new_error = new_trans.as_ref().err();
+        if old_error != new_error {
+            return Some(diverged(step, Diverged::Outcome, &old_error, &new_error));
+        }
+        if let Ok(Transition::Next(s)) = old_trans {
+            old = s;
+        }
+        if let Ok(Transition::Next(s)) = new_trans {
+            new = s;
+        }
+        if old != new {
+            return Some(diverged(step, Diverged::State, &old, &new));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Event, Veto};
+
+    #[test]
+    fn test_repl() {
+        // A counter that can be incremented by an amount
+
+        struct Add(u32);
+
+        #[derive(Debug)]
+        struct Added(u32);
+
+        impl Command<u32, ()> for Add {
+            type Output = Added;
+            fn execute(&self, _s: &u32, _h: &mut ()) -> Option<Added> {
+                Some(Added(self.0))
+            }
+        }
+
+        impl Validate<u32> for Add {}
+
+        impl Event<u32> for Added {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s + self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u32, ()> for MyFsm {}
+
+        let input = "add 2\n\nadd x\nadd 3\n".as_bytes();
+        let mut output = Vec::new();
+        let state = repl::<MyFsm, _, _, _>(
+            0,
+            &mut (),
+            |line| match line.split_once(' ') {
+                Some(("add", n)) => n.parse().map(Add).map_err(|e| format!("{e}")),
+                _ => Err(format!("unknown command {line}")),
+            },
+            input,
+            &mut output,
+        )
+        .unwrap();
+
+        assert_eq!(state, 5);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Added(\n            2,\n        ),"));
+        assert!(output.contains("invalid digit found in string"));
+    }
+
+    #[test]
+    fn test_golden_transcript() {
+        // A switch with commands that may be ignored
+
+        #[derive(Debug)]
+        enum Switch {
+            On,
+            Off,
+        }
+
+        #[derive(Debug)]
+        struct Switched(bool);
+
+        impl Command<bool, ()> for Switch {
+            type Output = Switched;
+            fn execute(&self, s: &bool, _h: &mut ()) -> Option<Switched> {
+                match (s, self) {
+                    (false, Switch::On) => Some(Switched(true)),
+                    (true, Switch::Off) => Some(Switched(false)),
+                    _ => None,
+                }
+            }
+        }
+
+        impl Validate<bool> for Switch {}
+
+        impl Event<bool> for Switched {
+            fn fire(&self, _s: &bool) -> Transition<bool> {
+                Transition::Next(self.0)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<bool, ()> for MyFsm {}
+
+        let text =
+            transcript::<MyFsm, _, _, _>(false, &mut (), &[Switch::On, Switch::On, Switch::Off]);
+        assert_eq!(
+            text,
+            "state false\n\
+             command On\n\
+             event Switched(true)\n\
+             state true\n\
+             command On\n\
+             command Off\n\
+             event Switched(false)\n\
+             state false\n"
+        );
+
+        // Regenerate a golden file, then check against it
+
+        let path = env::temp_dir()
+            .join(format!("fsm-golden-{}", std::process::id()))
+            .join("switch.txt");
+        check_golden(&path, &text, true);
+        check_golden(&path, &text, false);
+
+        let changed = text.replace("command Off", "command On");
+        let diff = std::panic::catch_unwind(|| check_golden(&path, &changed, false));
+        let message = *diff.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.ends_with("at line 6\nexpected: command Off\n  actual: command On"));
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_fuzz_replay() {
+        // A bounded counter, whose commands are decoded one per byte
+
+        struct Add(u8);
+
+        struct Added(u8);
+
+        impl Command<u8, ()> for Add {
+            type Output = Added;
+            fn execute(&self, s: &u8, _h: &mut ()) -> Option<Added> {
+                s.checked_add(self.0).map(|_| Added(self.0))
+            }
+        }
+
+        impl Validate<u8> for Add {}
+
+        impl Event<u8> for Added {
+            fn fire(&self, s: &u8) -> Transition<u8> {
+                Transition::Next(s.saturating_add(self.0))
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<u8, ()> for MyFsm {}
+
+        let decode = |data: &mut &[u8]| {
+            let (first, rest) = data.split_first()?;
+            *data = rest;
+            Some(Add(*first))
+        };
+        fuzz_replay::<MyFsm, _, _, _>(0, &mut (), &[1, 2, 200, 100, 3], decode);
+        fuzz_replay::<MyFsm, _, _, _>(0, &mut (), &[], decode);
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        // A counter, reworked to refuse counts above a limit
+
+        struct Add(u32);
+
+        #[derive(Debug, PartialEq)]
+        struct Added(u32);
+
+        impl Command<u32, u32> for Add {
+            type Output = Added;
+            fn execute(&self, _s: &u32, _limit: &mut u32) -> Option<Added> {
+                Some(Added(self.0))
+            }
+        }
+
+        impl Validate<u32> for Add {}
+
+        impl Event<u32> for Added {
+            fn fire(&self, s: &u32) -> Transition<u32> {
+                Transition::Next(s + self.0)
+            }
+        }
+
+        struct Old {}
+
+        impl Fsm<u32, u32> for Old {}
+
+        struct New {}
+
+        impl Fsm<u32, u32> for New {
+            fn on_entry(_old_s: &u32, new_s: &u32, limit: &mut u32) -> Result<(), Veto> {
+                if new_s > limit {
+                    Err(Veto("over the limit".to_string()))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+
+        let script = [Add(3), Add(4), Add(5)];
+        assert_eq!(
+            compare_versions::<Old, New, _, _, _, _, _>(0, &mut 20, 0, &mut 20, &script),
+            None
+        );
+        assert_eq!(
+            compare_versions::<Old, New, _, _, _, _, _>(0, &mut 10, 0, &mut 10, &script),
+            Some(Divergence {
+                step: 2,
+                diverged: Diverged::Outcome,
+                old: "None".to_string(),
+                new: "Some(Vetoed(Veto(\"over the limit\")))".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! A threaded runner owns a `Runner` on a dedicated thread, for programs
+//! that are not asynchronous. Commands arrive in a mailbox and are stepped
+//! in order, with callers blocking until their event is returned. Senders
+//! can be cloned and used from any thread.
+//!
+//! Shutting down is graceful: commands already in the mailbox are stepped,
+//! and then the thread is joined and the runner returned to its owner.
+
+use std::hash::Hash;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use crate::async_runner::{Admission, Paced};
+use crate::command_and_event_traits::{Command, Fsm, Validate};
+use crate::runner::{RunError, Runner};
+
+type StepResult<E> = Result<Option<E>, RunError>;
+
+enum Message<K, C, E> {
+    Step(K, C, mpsc::Sender<Admission<K, C, StepResult<E>>>),
+    Stop,
+}
+
+/// Sends commands of type C for entities with keys K to a threaded runner,
+/// answering with events of type E.
+pub struct ThreadSender<K, C, E> {
+    mailbox: mpsc::Sender<Message<K, C, E>>,
+}
+
+impl<K, C, E> Clone for ThreadSender<K, C, E> {
+    fn clone(&self) -> Self {
+        Self {
+            mailbox: self.mailbox.clone(),
+        }
+    }
+}
+
+impl<K, C, E> ThreadSender<K, C, E> {
+    /// Send a command to an entity, blocking until the event emitted, if any,
+    /// is returned.
+    pub fn send(&self, key: K, command: C) -> Result<Option<E>, RunError> {
+        let (mut key, mut command) = (key, command);
+        loop {
+            let (reply, replied) = mpsc::channel();
+            self.mailbox
+                .send(Message::Step(key, command, reply))
+                .map_err(|_| RunError::Stopped)?;
+            match replied.recv() {
+                Ok(Admission::Done(result)) => return result,
+                Ok(Admission::Wait(wait, k, c)) => {
+                    thread::sleep(wait);
+                    (key, command) = (k, c);
+                }
+                Err(_) => return Err(RunError::Stopped),
+            }
+        }
+    }
+}
+
+/// Owns a runner on a dedicated thread.
+pub struct ThreadRunner<K, S, H, F, C>
+where
+    C: Command<S, H>,
+{
+    sender: ThreadSender<K, C, C::Output>,
+    thread: Option<JoinHandle<Runner<K, S, H, F>>>,
+}
+
+impl<K, S, H, F, C> ThreadRunner<K, S, H, F, C>
+where
+    K: Eq + Hash + Send + 'static,
+    S: Send + 'static,
+    H: Send + 'static,
+    F: Fsm<S, H> + 'static,
+    C: Command<S, H> + Validate<S> + Send + 'static,
+    C::Output: Send + 'static,
+{
+    /// Start a thread owning the runner.
+    pub fn spawn(runner: Runner<K, S, H, F>) -> Self {
+        Self::spawn_with(runner, |runner, key, command| runner.send(key, command))
+    }
+
+    /// Start a thread owning the runner, stepping commands with `step`.
+    pub(crate) fn spawn_with(
+        runner: Runner<K, S, H, F>,
+        mut step: impl FnMut(&mut Runner<K, S, H, F>, &K, &C) -> StepResult<C::Output> + Send + 'static,
+    ) -> Self {
+        Self::spawn_paced(runner, move |runner, key, command| {
+            Ok(step(runner, key, command))
+        })
+    }
+
+    /// Start a thread owning the runner, stepping commands with `step`, or
+    /// learning from it how long to wait before a command can be stepped.
+    /// Such a command is returned to its sender, which sends it again
+    /// after waiting, so that the thread is not held up in the meantime.
+    pub(crate) fn spawn_paced(
+        runner: Runner<K, S, H, F>,
+        mut step: impl FnMut(&mut Runner<K, S, H, F>, &K, &C) -> Paced<StepResult<C::Output>>
+            + Send
+            + 'static,
+    ) -> Self {
+        let (mailbox, received) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut runner = runner;
+            while let Ok(Message::Step(key, command, reply)) = received.recv() {
+                let answer = match step(&mut runner, &key, &command) {
+                    Ok(result) => Admission::Done(result),
+                    Err(wait) => Admission::Wait(wait, key, command),
+                };
+                let _ = reply.send(answer);
+            }
+            runner
+        });
+        Self {
+            sender: ThreadSender { mailbox },
+            thread: Some(thread),
+        }
+    }
+
+    /// A sender for use from other threads.
+    pub fn sender(&self) -> ThreadSender<K, C, C::Output> {
+        self.sender.clone()
+    }
+
+    /// Send a command to an entity, blocking until the event emitted, if any,
+    /// is returned.
+    pub fn send(&self, key: K, command: C) -> Result<Option<C::Output>, RunError> {
+        self.sender.send(key, command)
+    }
+
+    /// Step the commands already sent, then stop the thread and return the
+    /// runner. Commands sent afterwards are refused as `RunError::Stopped`.
+    /// If the thread panicked, the panic is resumed here.
+    pub fn shutdown(mut self) -> Runner<K, S, H, F> {
+        self.stop().expect("the runner thread has already stopped")
+    }
+
+    fn stop(&mut self) -> Option<Runner<K, S, H, F>> {
+        let thread = self.thread.take()?;
+        let _ = self.sender.mailbox.send(Message::Stop);
+        match thread.join() {
+            Ok(runner) => Some(runner),
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+impl<K, S, H, F, C> Drop for ThreadRunner<K, S, H, F, C>
+where
+    C: Command<S, H>,
+{
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.sender.mailbox.send(Message::Stop);
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Counter, Increment, Incremented};
+
+    #[test]
+    fn test_thread_runner() {
+        // A counter incremented by commands from several threads
+
+        let mut runner = Runner::<&str, u32, (), Counter>::new(());
+        runner.insert("a", 0);
+        let threaded = ThreadRunner::spawn(runner);
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let sender = threaded.sender();
+                thread::spawn(move || {
+                    for _ in 0..10 {
+                        assert_eq!(sender.send("a", Increment {}), Ok(Some(Incremented {})));
+                    }
+                    sender
+                })
+            })
+            .collect();
+        let senders: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_eq!(threaded.send("b", Increment {}), Err(RunError::Unknown));
+
+        let runner = threaded.shutdown();
+        assert_eq!(runner.state(&"a"), Some(&40));
+        assert_eq!(senders[0].send("a", Increment {}), Err(RunError::Stopped));
+    }
+}
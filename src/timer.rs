@@ -0,0 +1,497 @@
+//! Timers for entities. `Timers` holds at most one deadline for each
+//! entity, with a payload to act upon when it passes. It does not keep
+//! time itself: the owner asks for the timers due at a given instant, and
+//! for the next deadline so it knows how long to wait.
+//!
+//! A `Watchdog` uses timers to give a machine inactivity timeouts. The
+//! machine declares, for each state, how long it may go without a command
+//! and the event then synthesized. The watchdog arms the timer whenever an
+//! entity arrives in such a state or receives a command, and disarms it
+//! when the entity leaves.
+//!
+//! A `StateTimer` gives a machine state timeouts, declared by its
+//! `state_timeouts`. These run from when an entity enters a state,
+//! regardless of commands, and send a command to the entity when they
+//! pass. Neither needs timers to be managed in entry or exit hooks.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use crate::command_and_event_traits::{Command, Event, Fsm, Validate};
+use crate::runner::{RunError, Runner};
+
+/// A deadline for each of some entities, with a payload.
+#[derive(Debug)]
+pub struct Timers<K, T> {
+    entries: HashMap<K, (Instant, T)>,
+}
+
+impl<K, T> Default for Timers<K, T> {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K, T> Timers<K, T>
+where
+    K: Eq + Hash + Clone,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the timer of an entity, replacing any already set.
+    pub fn arm(&mut self, key: K, deadline: Instant, payload: T) {
+        self.entries.insert(key, (deadline, payload));
+    }
+
+    /// Clear the timer of an entity, returning its payload.
+    pub fn disarm(&mut self, key: &K) -> Option<T> {
+        self.entries.remove(key).map(|(_, payload)| payload)
+    }
+
+    pub fn deadline(&self, key: &K) -> Option<Instant> {
+        self.entries.get(key).map(|(deadline, _)| *deadline)
+    }
+
+    /// The earliest deadline of all the timers.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.entries.values().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Clear the timers whose deadlines have passed, returning them in
+    /// the order of their deadlines.
+    pub fn due(&mut self, now: Instant) -> Vec<(K, T)> {
+        let mut keys: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(key, (deadline, _))| (*deadline, key.clone()))
+            .collect();
+        keys.sort_by_key(|(deadline, _)| *deadline);
+        keys.into_iter()
+            .filter_map(|(_, key)| {
+                let (_, payload) = self.entries.remove(&key)?;
+                Some((key, payload))
+            })
+            .collect()
+    }
+}
+
+/// The entities whose timers fired, with the outcome of applying each
+/// one's event.
+pub type Fired<K, E> = Vec<(K, Result<Option<E>, RunError>)>;
+
+/// A machine whose states may time out when no command arrives.
+pub trait Inactivity<S> {
+    type Event: Event<S>;
+
+    /// How long the state may go without a command, and the event to
+    /// synthesize then.
+    fn inactivity(state: &S) -> Option<(Duration, Self::Event)>;
+}
+
+/// A runner whose entities time out when inactive.
+pub struct Watchdog<K, S, H, F>
+where
+    F: Inactivity<S>,
+{
+    runner: Runner<K, S, H, F>,
+    timers: Timers<K, F::Event>,
+}
+
+impl<K, S, H, F> Watchdog<K, S, H, F>
+where
+    K: Eq + Hash + Clone,
+    F: Fsm<S, H> + Inactivity<S>,
+{
+    pub fn new(runner: Runner<K, S, H, F>) -> Self {
+        Self {
+            runner,
+            timers: Timers::new(),
+        }
+    }
+
+    pub fn runner(&self) -> &Runner<K, S, H, F> {
+        &self.runner
+    }
+
+    /// Add an entity, arming its timer.
+    pub fn insert(&mut self, key: K, state: S) {
+        self.runner.insert(key.clone(), state);
+        self.rearm(key, Instant::now());
+    }
+
+    /// Remove an entity, disarming its timer.
+    pub fn remove(&mut self, key: &K) -> Option<S> {
+        self.timers.disarm(key);
+        self.runner.remove(key)
+    }
+
+    /// Step an entity with a command, which rearms its timer.
+    pub fn send<C>(&mut self, key: &K, command: &C) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+    {
+        let result = self.runner.send(key, command);
+        self.rearm(key.clone(), Instant::now());
+        result
+    }
+
+    /// When the next entity will time out.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    /// Apply the events of the entities that have timed out.
+    pub fn fire_due(&mut self, now: Instant) -> Fired<K, F::Event> {
+        let mut fired = Vec::new();
+        for (key, event) in self.timers.due(now) {
+            let result = self.runner.apply(&key, event);
+            self.rearm(key.clone(), now);
+            fired.push((key, result));
+        }
+        fired
+    }
+
+    fn rearm(&mut self, key: K, now: Instant) {
+        match self.runner.state(&key).and_then(F::inactivity) {
+            Some((timeout, event)) => self.timers.arm(key, now + timeout, event),
+            None => {
+                self.timers.disarm(&key);
+            }
+        }
+    }
+}
+
+/// A machine whose states time out, declaratively.
+pub trait StateTimeouts<S, H>: Fsm<S, H> {
+    type Command: Command<S, H> + Validate<S>;
+
+    /// How long an entity may remain in the state after entering it, and
+    /// the command to send it then.
+    fn state_timeouts(state: &S) -> Option<(Duration, Self::Command)>;
+}
+
+/// A runner whose entities time out in states.
+pub struct StateTimer<K, S, H, F>
+where
+    F: StateTimeouts<S, H>,
+{
+    runner: Runner<K, S, H, F>,
+    timers: Timers<K, F::Command>,
+}
+
+impl<K, S, H, F> StateTimer<K, S, H, F>
+where
+    K: Eq + Hash + Clone,
+    F: StateTimeouts<S, H>,
+{
+    pub fn new(runner: Runner<K, S, H, F>) -> Self {
+        Self {
+            runner,
+            timers: Timers::new(),
+        }
+    }
+
+    pub fn runner(&self) -> &Runner<K, S, H, F> {
+        &self.runner
+    }
+
+    /// Add an entity, arming its timer for the state it starts in.
+    pub fn insert(&mut self, key: K, state: S) {
+        match F::state_timeouts(&state) {
+            Some((timeout, command)) => {
+                self.timers
+                    .arm(key.clone(), Instant::now() + timeout, command)
+            }
+            None => {
+                self.timers.disarm(&key);
+            }
+        }
+        self.runner.insert(key, state);
+    }
+
+    /// Remove an entity, disarming its timer.
+    pub fn remove(&mut self, key: &K) -> Option<S> {
+        self.timers.disarm(key);
+        self.runner.remove(key)
+    }
+
+    /// Step an entity with a command, rearming its timer if it enters
+    /// a new state.
+    pub fn send<C>(&mut self, key: &K, command: &C) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+    {
+        self.send_at(key, command, Instant::now())
+    }
+
+    fn send_at<C>(
+        &mut self,
+        key: &K,
+        command: &C,
+        now: Instant,
+    ) -> Result<Option<C::Output>, RunError>
+    where
+        C: Command<S, H> + Validate<S>,
+    {
+        let version = self.runner.version(key);
+        let result = self.runner.send(key, command);
+        if result.is_ok() && self.runner.version(key) != version {
+            self.rearm(key.clone(), now);
+        }
+        result
+    }
+
+    fn rearm(&mut self, key: K, now: Instant) {
+        match self.runner.state(&key).and_then(F::state_timeouts) {
+            Some((timeout, command)) => self.timers.arm(key, now + timeout, command),
+            None => {
+                self.timers.disarm(&key);
+            }
+        }
+    }
+
+    /// When the next entity will time out.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    /// Send the timeout commands of the entities that have timed out.
+    pub fn fire_due(&mut self, now: Instant) -> Fired<K, <F::Command as Command<S, H>>::Output> {
+        let mut fired = Vec::new();
+        for (key, command) in self.timers.due(now) {
+            let result = self.send_at(&key, &command, now);
+            fired.push((key, result));
+        }
+        fired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Transition, Veto};
+    use crate::fixtures::Increment;
+
+    #[test]
+    fn test_watchdog() {
+        // Sessions that expire after a minute without activity
+
+        #[derive(Debug, PartialEq)]
+        enum Session {
+            Active { requests: u32 },
+            Expired,
+        }
+
+        struct Request {}
+
+        #[derive(Debug, PartialEq)]
+        struct Requested {}
+
+        #[derive(Debug, PartialEq)]
+        struct Expired {}
+
+        impl Command<Session, ()> for Request {
+            type Output = Requested;
+            fn execute(&self, s: &Session, _h: &mut ()) -> Option<Requested> {
+                matches!(s, Session::Active { .. }).then_some(Requested {})
+            }
+        }
+
+        impl Validate<Session> for Request {}
+
+        impl Event<Session> for Requested {
+            fn fire(&self, s: &Session) -> Transition<Session> {
+                match s {
+                    Session::Active { requests } => Transition::Next(Session::Active {
+                        requests: requests + 1,
+                    }),
+                    Session::Expired => Transition::Same,
+                }
+            }
+        }
+
+        impl Event<Session> for Expired {
+            fn fire(&self, _s: &Session) -> Transition<Session> {
+                Transition::Next(Session::Expired)
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<Session, ()> for MyFsm {}
+
+        impl Inactivity<Session> for MyFsm {
+            type Event = Expired;
+            fn inactivity(s: &Session) -> Option<(Duration, Expired)> {
+                match s {
+                    Session::Active { .. } => Some((Duration::from_secs(60), Expired {})),
+                    Session::Expired => None,
+                }
+            }
+        }
+
+        let start = Instant::now();
+        let mut watchdog = Watchdog::new(Runner::<&str, Session, (), MyFsm>::new(()));
+        watchdog.insert("a", Session::Active { requests: 0 });
+        watchdog.insert("b", Session::Active { requests: 0 });
+        let minute = Duration::from_secs(60);
+
+        assert!(watchdog.fire_due(start).is_empty());
+        assert!(watchdog.next_deadline().unwrap() >= start + minute);
+
+        // A request to one session keeps it alive beyond the deadline of the other
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(watchdog.send(&"a", &Request {}), Ok(Some(Requested {})));
+        let fired = watchdog.fire_due(start + minute + Duration::from_millis(5));
+        assert_eq!(fired, vec![("b", Ok(Some(Expired {})))]);
+        assert_eq!(watchdog.runner().state(&"b"), Some(&Session::Expired));
+        assert_eq!(
+            watchdog.runner().state(&"a"),
+            Some(&Session::Active { requests: 1 })
+        );
+
+        // The expired session has no timer, the other times out later
+
+        let fired = watchdog.fire_due(start + minute * 2);
+        assert_eq!(fired, vec![("a", Ok(Some(Expired {})))]);
+        assert_eq!(watchdog.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_state_timeouts() {
+        // A traffic light that changes on timeouts, unless held
+
+        #[derive(Debug, PartialEq)]
+        enum Light {
+            Red,
+            Green,
+            Amber,
+        }
+
+        enum Control {
+            Change,
+            Hold,
+        }
+
+        #[derive(Debug, PartialEq)]
+        enum Changed {
+            To(Light),
+            Held,
+        }
+
+        impl Command<Light, ()> for Control {
+            type Output = Changed;
+            fn execute(&self, s: &Light, _h: &mut ()) -> Option<Changed> {
+                match (self, s) {
+                    (Control::Change, Light::Red) => Some(Changed::To(Light::Green)),
+                    (Control::Change, Light::Green) => Some(Changed::To(Light::Amber)),
+                    (Control::Change, Light::Amber) => Some(Changed::To(Light::Red)),
+                    (Control::Hold, _) => Some(Changed::Held),
+                }
+            }
+        }
+
+        impl Validate<Light> for Control {}
+
+        impl Event<Light> for Changed {
+            fn fire(&self, _s: &Light) -> Transition<Light> {
+                match self {
+                    Changed::To(Light::Red) => Transition::Next(Light::Red),
+                    Changed::To(Light::Green) => Transition::Next(Light::Green),
+                    Changed::To(Light::Amber) => Transition::Next(Light::Amber),
+                    Changed::Held => Transition::Same,
+                }
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<Light, ()> for MyFsm {}
+
+        impl StateTimeouts<Light, ()> for MyFsm {
+            type Command = Control;
+            fn state_timeouts(s: &Light) -> Option<(Duration, Control)> {
+                match s {
+                    Light::Red => Some((Duration::from_secs(30), Control::Change)),
+                    Light::Green => Some((Duration::from_secs(20), Control::Change)),
+                    Light::Amber => None,
+                }
+            }
+        }
+
+        let start = Instant::now();
+        let mut timer = StateTimer::new(Runner::<&str, Light, (), MyFsm>::new(()));
+        timer.insert("a", Light::Red);
+        let secs = Duration::from_secs;
+
+        // Commands that stay in the state do not rearm its timeout
+
+        assert_eq!(timer.send(&"a", &Control::Hold), Ok(Some(Changed::Held)));
+        assert!(timer.fire_due(start + secs(29)).is_empty());
+        assert_eq!(
+            timer.fire_due(start + secs(31)),
+            vec![("a", Ok(Some(Changed::To(Light::Green))))]
+        );
+
+        // The next state's timeout starts on entry, and Amber has none
+
+        assert!(timer.fire_due(start + secs(50)).is_empty());
+        assert_eq!(
+            timer.fire_due(start + secs(52)),
+            vec![("a", Ok(Some(Changed::To(Light::Amber))))]
+        );
+        assert_eq!(timer.next_deadline(), None);
+        assert_eq!(
+            timer.send(&"a", &Control::Change),
+            Ok(Some(Changed::To(Light::Red)))
+        );
+        assert!(timer.next_deadline().is_some());
+    }
+
+    #[test]
+    fn test_vetoed_timeout() {
+        // A counter that times out into the next count, but may not pass
+        // one. A vetoed step keeps the timer of the state the entity is in.
+
+        struct Capped {}
+
+        impl Fsm<u32, ()> for Capped {
+            fn on_entry(_old: &u32, new: &u32, _h: &mut ()) -> Result<(), Veto> {
+                match new {
+                    0 | 1 => Ok(()),
+                    _ => Err(Veto("capped".to_string())),
+                }
+            }
+        }
+
+        impl StateTimeouts<u32, ()> for Capped {
+            type Command = Increment;
+            fn state_timeouts(s: &u32) -> Option<(Duration, Increment)> {
+                let secs = match s {
+                    0 => 30,
+                    1 => 10,
+                    _ => 5,
+                };
+                Some((Duration::from_secs(secs), Increment {}))
+            }
+        }
+
+        let start = Instant::now();
+        let secs = Duration::from_secs;
+        let mut timer = StateTimer::new(Runner::<&str, u32, (), Capped>::new(()));
+        timer.insert("a", 0);
+
+        assert!(timer.send_at(&"a", &Increment {}, start).is_ok());
+        assert_eq!(timer.next_deadline(), Some(start + secs(10)));
+        assert!(timer.send_at(&"a", &Increment {}, start + secs(1)).is_err());
+        assert_eq!(timer.runner().state(&"a"), Some(&1));
+        assert_eq!(timer.next_deadline(), Some(start + secs(10)));
+    }
+}
@@ -0,0 +1,291 @@
+//! Distributed tracing with W3C trace context. A command arriving with a
+//! `traceparent` header is wrapped as `Traced`, carrying the remote
+//! context. The `Tracing` dispatch middleware opens a span per step, as
+//! a child of the remote context or as the root of a new trace, and
+//! exports it when the step completes. The event emitted is returned
+//! `Traced` with the step's context, whose `traceparent` is injected
+//! into outbox messages and other requests so that downstream services
+//! join the same trace.
+//!
+//! Spans are exported to a `SpanExporter`, which for OpenTelemetry would
+//! convert each into an SDK span with the same IDs and times.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::command_and_event_traits::{Command, Event, Transition, Validate, ValidationError};
+use crate::observer::type_label;
+use crate::pipeline::Dispatch;
+use crate::runner::RunError;
+
+/// A W3C trace context: the trace, the span within it, and whether the
+/// trace is sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The root of a new, sampled trace.
+    pub fn root() -> Self {
+        Self {
+            trace_id: (random_id() as u128) << 64 | random_id() as u128,
+            span_id: random_id(),
+            sampled: true,
+        }
+    }
+
+    /// A new span in the same trace.
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: random_id(),
+            ..*self
+        }
+    }
+
+    /// Parse a `traceparent` header, of version 00 or a later version
+    /// with the same leading fields.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut fields = traceparent.trim().split('-');
+        let version = hex(fields.next()?, 2)?;
+        let trace_id = hex(fields.next()?, 32)?;
+        let span_id = hex(fields.next()?, 16)?;
+        let flags = hex(fields.next()?, 2)?;
+        if version == 0xff || (version == 0 && fields.next().is_some()) {
+            return None;
+        }
+        if trace_id == 0 || span_id == 0 {
+            return None;
+        }
+        Some(Self {
+            trace_id,
+            span_id: span_id as u64,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    /// The `traceparent` header for this context.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+}
+
+/// A field of lowercase hex digits of an exact length.
+fn hex(field: &str, len: usize) -> Option<u128> {
+    let digits = field
+        .bytes()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    if field.len() != len || !digits {
+        return None;
+    }
+    u128::from_str_radix(field, 16).ok()
+}
+
+/// A random, non-zero ID, from the standard library's randomly keyed hasher.
+fn random_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    hasher.finish().max(1)
+}
+
+/// A command or event, T, with a trace context.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Traced<T> {
+    pub context: Option<TraceContext>,
+    pub body: T,
+}
+
+impl<T> Traced<T> {
+    /// Extract the remote context from a `traceparent` header, if there is
+    /// a valid one.
+    pub fn extract(traceparent: Option<&str>, body: T) -> Self {
+        Self {
+            context: traceparent.and_then(TraceContext::parse),
+            body,
+        }
+    }
+
+    /// The `traceparent` header to inject into a message for this.
+    pub fn traceparent(&self) -> Option<String> {
+        self.context.as_ref().map(TraceContext::traceparent)
+    }
+}
+
+impl<S, H, C> Command<S, H> for Traced<C>
+where
+    C: Command<S, H>,
+{
+    type Output = Traced<C::Output>;
+    fn execute(&self, state: &S, handler: &mut H) -> Option<Self::Output> {
+        let event = self.body.execute(state, handler)?;
+        Some(Traced {
+            context: self.context,
+            body: event,
+        })
+    }
+
+    fn name(&self) -> String {
+        self.body.name()
+    }
+}
+
+impl<S, C> Validate<S> for Traced<C>
+where
+    C: Validate<S>,
+{
+    fn validate(&self, state: &S) -> Result<(), ValidationError> {
+        self.body.validate(state)
+    }
+}
+
+impl<S, E> Event<S> for Traced<E>
+where
+    E: Event<S>,
+{
+    fn fire(&self, state: &S) -> Transition<S> {
+        self.body.fire(state)
+    }
+}
+
+/// A completed step, named for its command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub context: TraceContext,
+    pub parent_span_id: Option<u64>,
+    pub name: String,
+    pub start: SystemTime,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+/// Receives completed spans.
+pub trait SpanExporter {
+    fn export(&mut self, span: Span);
+}
+
+impl<F> SpanExporter for F
+where
+    F: FnMut(Span),
+{
+    fn export(&mut self, span: Span) {
+        self(span)
+    }
+}
+
+/// Dispatch middleware opening a span for each step of D, exported to X.
+pub struct Tracing<D, X> {
+    inner: D,
+    exporter: X,
+}
+
+impl<D, X> Tracing<D, X> {
+    pub fn new(inner: D, exporter: X) -> Self {
+        Self { inner, exporter }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn exporter(&self) -> &X {
+        &self.exporter
+    }
+}
+
+/// The event is returned with the context of the step's span. Spans of
+/// unsampled traces are not exported, but their context is still passed
+/// on.
+impl<D, X, K, C> Dispatch<K, Traced<C>> for Tracing<D, X>
+where
+    D: Dispatch<K, C>,
+    X: SpanExporter,
+{
+    type Output = Traced<D::Output>;
+    fn dispatch(
+        &mut self,
+        key: &K,
+        command: &Traced<C>,
+    ) -> Result<Option<Traced<D::Output>>, RunError> {
+        let context = match &command.context {
+            Some(remote) => remote.child(),
+            None => TraceContext::root(),
+        };
+        let start = SystemTime::now();
+        let timer = Instant::now();
+        let result = self.inner.dispatch(key, &command.body);
+        if context.sampled {
+            self.exporter.export(Span {
+                context,
+                parent_span_id: command.context.map(|c| c.span_id),
+                name: type_label::<C>(),
+                start,
+                duration: timer.elapsed(),
+                error: result.as_ref().err().map(|e| format!("{e:?}")),
+            });
+        }
+        result.map(|event| {
+            event.map(|body| Traced {
+                context: Some(context),
+                body,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Counter, Increment};
+    use crate::runner::Runner;
+
+    #[test]
+    fn test_tracing() {
+        // A counter stepped from requests that may carry a trace
+
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let remote = TraceContext::parse(header).unwrap();
+        assert_eq!(remote.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(remote.traceparent(), header);
+        for bad in [
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+        ] {
+            assert_eq!(TraceContext::parse(bad), None, "{bad}");
+        }
+
+        let mut runner = Runner::<u32, u32, (), Counter>::new(());
+        runner.insert(1, 0);
+        let mut spans = Vec::new();
+        let mut tracing = Tracing::new(runner, |span| spans.push(span));
+
+        let event = tracing
+            .dispatch(&1, &Traced::extract(Some(header), Increment {}))
+            .unwrap()
+            .unwrap();
+        let context = event.context.unwrap();
+        assert_eq!(context.trace_id, remote.trace_id);
+        assert_ne!(context.span_id, remote.span_id);
+
+        // A step without a remote context starts a trace, and a
+        // failure is recorded on the span
+        let _ = tracing.dispatch(&2, &Traced::extract(None, Increment {}));
+        drop(tracing);
+
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].name, "Increment");
+        assert_eq!(spans[0].parent_span_id, Some(remote.span_id));
+        assert_eq!(spans[0].context, context);
+        assert_eq!(spans[0].error, None);
+        assert_eq!(spans[1].parent_span_id, None);
+        assert_ne!(spans[1].context.trace_id, remote.trace_id);
+        assert_eq!(spans[1].error.as_deref(), Some("Unknown"));
+    }
+}
@@ -0,0 +1,211 @@
+//! A unit of work gathers the writes for one step of an entity, namely
+//! the journal append, outbox messages and snapshot update, and commits
+//! them together or not at all. A backend is free to implement it as a
+//! database transaction.
+//!
+//! `Enlist` is an event listener which opens a unit of work from a store
+//! for each step. The event is applied to the entity only once the unit
+//! of work has committed, and a failure to commit aborts the step.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::command_and_event_traits::{Event, Transition};
+use crate::journal::{Journal, MemJournal};
+use crate::outbox::MemOutbox;
+use crate::runner::EventListener;
+
+/// The writes for one step, for an entity with key K, state S and event E.
+pub trait UnitOfWork<K, S, E> {
+    /// Enlist the event to be appended to the journal.
+    fn append(&mut self, key: &K, event: &E) -> Result<(), String>;
+
+    /// Enlist the event to be published through the outbox, if there is one.
+    fn outbox(&mut self, _key: &K, _event: &E) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Enlist the entity's new state to be snapshotted, if snapshots are kept.
+    fn snapshot(&mut self, _key: &K, _state: &S) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Make all of the enlisted writes.
+    fn commit(self) -> Result<(), String>;
+
+    /// Discard all of the enlisted writes.
+    fn rollback(self);
+}
+
+/// Opens units of work.
+pub trait Store<K, S, E> {
+    type Work<'a>: UnitOfWork<K, S, E>
+    where
+        Self: 'a;
+
+    fn begin(&mut self) -> Self::Work<'_>;
+}
+
+/// A listener committing a unit of work for each event.
+pub struct Enlist<'a, T>(pub &'a mut T);
+
+impl<K, S, E, T> EventListener<K, S, E> for Enlist<'_, T>
+where
+    T: Store<K, S, E>,
+    E: Event<S>,
+{
+    fn on_event(&mut self, key: &K, state: &S, event: &E) -> Result<(), String> {
+        let mut work = self.0.begin();
+        let enlisted = work.append(key, event).and_then(|_| {
+            work.outbox(key, event)?;
+            match event.fire(state) {
+                Transition::Next(s) => work.snapshot(key, &s),
+                Transition::Same => Ok(()),
+            }
+        });
+        match enlisted {
+            Ok(()) => work.commit(),
+            Err(e) => {
+                work.rollback();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A store held in memory, with a journal, an outbox and snapshots.
+#[derive(Debug)]
+pub struct MemStore<K, S, E> {
+    pub journal: MemJournal<K, E>,
+    pub outbox: MemOutbox<K, E>,
+    pub snapshots: HashMap<K, S>,
+    fail_commits: bool,
+}
+
+impl<K, S, E> Default for MemStore<K, S, E> {
+    fn default() -> Self {
+        Self {
+            journal: MemJournal::new(),
+            outbox: MemOutbox::new(),
+            snapshots: HashMap::new(),
+            fail_commits: false,
+        }
+    }
+}
+
+impl<K, S, E> MemStore<K, S, E> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make commits fail, or succeed again, e.g. to test rollback.
+    pub fn fail_commits(&mut self, fail: bool) {
+        self.fail_commits = fail;
+    }
+}
+
+/// The unit of work of a `MemStore`, buffering writes until committed.
+pub struct MemWork<'a, K, S, E> {
+    store: &'a mut MemStore<K, S, E>,
+    events: Vec<(K, E)>,
+    outbox: Vec<(K, E)>,
+    snapshots: Vec<(K, S)>,
+}
+
+impl<K, S, E> UnitOfWork<K, S, E> for MemWork<'_, K, S, E>
+where
+    K: Eq + Hash + Clone,
+    S: Clone,
+    E: Clone,
+{
+    fn append(&mut self, key: &K, event: &E) -> Result<(), String> {
+        self.events.push((key.clone(), event.clone()));
+        Ok(())
+    }
+
+    fn outbox(&mut self, key: &K, event: &E) -> Result<(), String> {
+        self.outbox.push((key.clone(), event.clone()));
+        Ok(())
+    }
+
+    fn snapshot(&mut self, key: &K, state: &S) -> Result<(), String> {
+        self.snapshots.push((key.clone(), state.clone()));
+        Ok(())
+    }
+
+    fn commit(self) -> Result<(), String> {
+        if self.store.fail_commits {
+            return Err("commit failed".to_string());
+        }
+        for (key, event) in self.events {
+            self.store
+                .journal
+                .append(&key, event)
+                .map_err(|e| format!("{e:?}"))?;
+        }
+        for (key, event) in self.outbox {
+            self.store.outbox.push(key, event);
+        }
+        self.store.snapshots.extend(self.snapshots);
+        Ok(())
+    }
+
+    fn rollback(self) {}
+}
+
+impl<K, S, E> Store<K, S, E> for MemStore<K, S, E>
+where
+    K: Eq + Hash + Clone,
+    S: Clone,
+    E: Clone,
+{
+    type Work<'a>
+        = MemWork<'a, K, S, E>
+    where
+        Self: 'a;
+
+    fn begin(&mut self) -> MemWork<'_, K, S, E> {
+        MemWork {
+            store: self,
+            events: Vec::new(),
+            outbox: Vec::new(),
+            snapshots: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{Counter, Increment, Incremented};
+    use crate::runner::{RunError, Runner};
+
+    #[test]
+    fn test_unit_of_work() {
+        // A counter whose events and states are stored together
+
+        let mut runner = Runner::<&str, u32, (), Counter>::new(());
+        runner.insert("a", 0);
+        let mut store = MemStore::new();
+
+        assert_eq!(
+            runner.send_with(&"a", &Increment {}, &mut Enlist(&mut store)),
+            Ok(Some(Incremented {}))
+        );
+        assert_eq!(store.journal.len(), 1);
+        assert_eq!(store.outbox.pending(), vec![("a", Incremented {})]);
+        assert_eq!(store.snapshots.get("a"), Some(&1));
+
+        // Nothing is written, and the state is unchanged, if the commit fails
+
+        store.fail_commits(true);
+        assert_eq!(
+            runner.send_with(&"a", &Increment {}, &mut Enlist(&mut store)),
+            Err(RunError::Aborted("commit failed".to_string()))
+        );
+        assert_eq!(runner.state(&"a"), Some(&1));
+        assert_eq!(store.journal.len(), 1);
+        assert_eq!(store.outbox.len(), 1);
+        assert_eq!(store.snapshots.get("a"), Some(&1));
+    }
+}
@@ -0,0 +1,235 @@
+//! Helpers for verifying properties of small machines over bounded
+//! sequences of commands, such as "never reaches state X without event Y".
+//!
+//! A property is a predicate over a state and the events emitted to reach
+//! it. `check_bounded` explores every sequence of commands up to a depth,
+//! which is practical for small machines. `holds_for_script` checks one
+//! sequence, given as indices into the commands. It suits proof harnesses
+//! where a model checker such as Kani chooses the script symbolically:
+//!
+//! ```text
+//! #[cfg(kani)]
+//! #[kani::proof]
+//! #[kani::unwind(5)]
+//! fn never_open_when_locked() {
+//!     let script: [usize; 4] = kani::any();
+//!     kani::assume(script.iter().all(|i| *i < COMMANDS.len()));
+//!     assert!(holds_for_script::<Door, _, _, _>(
+//!         State::Locked, &mut (), &COMMANDS, &script, &property));
+//! }
+//! ```
+
+use crate::command_and_event_traits::{Command, Fsm, Transition, Validate};
+
+/// A sequence of commands, as indices, that violates a property.
+#[derive(Debug, PartialEq)]
+pub struct Counterexample {
+    pub script: Vec<usize>,
+}
+
+/// Step an FSM, F, through a script of commands from a state, answering
+/// whether the property holds in every state reached, including the first.
+pub fn holds_for_script<F, S, H, C>(
+    initial: S,
+    handler: &mut H,
+    commands: &[C],
+    script: &[usize],
+    property: &impl Fn(&S, &[&C::Output]) -> bool,
+) -> bool
+where
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+{
+    let mut state = initial;
+    let mut events = Vec::new();
+    if !property(&state, &[]) {
+        return false;
+    }
+    for i in script {
+        let (event, trans) = F::step(&state, &commands[*i], handler);
+        if let Ok(trans) = trans {
+            events.extend(event);
+            if let Transition::Next(s) = trans {
+                state = s;
+            }
+        }
+        if !property(&state, &events.iter().collect::<Vec<_>>()) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Check a property of an FSM, F, for every sequence of commands up to a
+/// given length. The state and effect handler are cloned for each branch.
+/// Sequences are explored by increasing length, so that any counterexample
+/// found is one of the shortest.
+pub fn check_bounded<F, S, H, C>(
+    initial: S,
+    handler: H,
+    commands: &[C],
+    depth: usize,
+    property: impl Fn(&S, &[&C::Output]) -> bool,
+) -> Result<(), Counterexample>
+where
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    S: Clone,
+    H: Clone,
+{
+    if !property(&initial, &[]) {
+        return Err(Counterexample { script: Vec::new() });
+    }
+    for d in 1..=depth {
+        let mut script = Vec::new();
+        let mut events = Vec::new();
+        if !explore::<F, S, H, C>(
+            &initial,
+            &handler,
+            commands,
+            d,
+            &property,
+            &mut script,
+            &mut events,
+        ) {
+            return Err(Counterexample { script });
+        }
+    }
+    Ok(())
+}
+
+fn explore<F, S, H, C>(
+    state: &S,
+    handler: &H,
+    commands: &[C],
+    depth: usize,
+    property: &impl Fn(&S, &[&C::Output]) -> bool,
+    script: &mut Vec<usize>,
+    events: &mut Vec<C::Output>,
+) -> bool
+where
+    F: Fsm<S, H>,
+    C: Command<S, H> + Validate<S>,
+    S: Clone,
+    H: Clone,
+{
+    if depth == 0 {
+        return true;
+    }
+    for (i, command) in commands.iter().enumerate() {
+        let mut handler = handler.clone();
+        let (event, trans) = F::step(state, command, &mut handler);
+        let (next, emitted) = match trans {
+            Ok(Transition::Next(s)) => (s, event),
+            Ok(Transition::Same) => (state.clone(), event),
+            Err(_) => (state.clone(), None),
+        };
+        script.push(i);
+        let pushed = emitted.is_some();
+        events.extend(emitted);
+        let holds = property(&next, &events.iter().collect::<Vec<_>>())
+            && explore::<F, S, H, C>(
+                &next,
+                &handler,
+                commands,
+                depth - 1,
+                property,
+                script,
+                events,
+            );
+        if !holds {
+            return false;
+        }
+        if pushed {
+            events.pop();
+        }
+        script.pop();
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::Event;
+
+    #[test]
+    fn test_check_bounded() {
+        // A door that should only open after being unlocked
+
+        #[derive(Debug, Clone, PartialEq)]
+        enum State {
+            Locked,
+            Closed,
+            Open,
+        }
+
+        enum Door {
+            Unlock,
+            Open,
+            Barge,
+        }
+
+        #[derive(Debug, PartialEq)]
+        enum Moved {
+            Unlocked,
+            Opened,
+        }
+
+        impl Command<State, ()> for Door {
+            type Output = Moved;
+            fn execute(&self, s: &State, _h: &mut ()) -> Option<Moved> {
+                match (s, self) {
+                    (State::Locked, Door::Unlock) => Some(Moved::Unlocked),
+                    (State::Closed, Door::Open) => Some(Moved::Opened),
+                    (State::Locked, Door::Barge) => Some(Moved::Opened),
+                    _ => None,
+                }
+            }
+        }
+
+        impl Validate<State> for Door {}
+
+        impl Event<State> for Moved {
+            fn fire(&self, _s: &State) -> Transition<State> {
+                match self {
+                    Moved::Unlocked => Transition::Next(State::Closed),
+                    Moved::Opened => Transition::Next(State::Open),
+                }
+            }
+        }
+
+        struct MyFsm {}
+
+        impl Fsm<State, ()> for MyFsm {}
+
+        let property =
+            |s: &State, events: &[&Moved]| *s != State::Open || events.contains(&&Moved::Unlocked);
+
+        let lawful = [Door::Unlock, Door::Open];
+        assert_eq!(
+            check_bounded::<MyFsm, _, _, _>(State::Locked, (), &lawful, 4, property),
+            Ok(())
+        );
+        assert!(holds_for_script::<MyFsm, _, _, _>(
+            State::Locked,
+            &mut (),
+            &lawful,
+            &[0, 1],
+            &property
+        ));
+
+        let all = [Door::Unlock, Door::Open, Door::Barge];
+        assert_eq!(
+            check_bounded::<MyFsm, _, _, _>(State::Locked, (), &all, 4, property),
+            Err(Counterexample { script: vec![2] })
+        );
+        assert!(!holds_for_script::<MyFsm, _, _, _>(
+            State::Locked,
+            &mut (),
+            &all,
+            &[2],
+            &property
+        ));
+    }
+}
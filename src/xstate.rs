@@ -0,0 +1,259 @@
+//! Exchange machine definitions with xstate, so that machines designed in
+//! the Stately editor can be executed by the table driven runtime.
+//!
+//! Importing supports flat machines: the `id`, `initial` and `states`
+//! of the machine, and for each state its `on` transitions. A transition
+//! may be a target, an object with `target`, `actions` and `guard` (or
+//! `cond`), or an array of these to be tried in order. Guards and actions
+//! are imported by name, to be bound with `Table::with_guard` and
+//! `Table::with_action`. An xstate event is imported as a command emitting
+//! an event of the same name. Nested states are not supported.
+//!
+//! Exporting produces xstate JSON for any descriptor, so the machine can be
+//! visualized and simulated in the Stately editor. Commands are exported as
+//! xstate events.
+
+use crate::descriptor::MachineDescriptor;
+use crate::json::{Json, JsonError};
+
+/// Why a machine could not be imported.
+#[derive(Debug, PartialEq)]
+pub enum XstateError {
+    /// The document is not valid JSON
+    Json(JsonError),
+    /// The document is not an xstate machine that can be imported
+    Unsupported(String),
+}
+
+impl From<JsonError> for XstateError {
+    fn from(e: JsonError) -> Self {
+        XstateError::Json(e)
+    }
+}
+
+fn unsupported(message: String) -> XstateError {
+    XstateError::Unsupported(message)
+}
+
+/// Import an xstate machine definition.
+pub fn from_xstate(text: &str) -> Result<MachineDescriptor, XstateError> {
+    let machine = Json::parse(text)?;
+    let id = machine.get("id").and_then(Json::as_str).unwrap_or("");
+    let mut descriptor = MachineDescriptor::new(id);
+    if let Some(initial) = machine.get("initial").and_then(Json::as_str) {
+        descriptor = descriptor.initial(initial);
+    }
+    let states = machine
+        .get("states")
+        .and_then(Json::as_object)
+        .ok_or_else(|| unsupported("expected an object of states".to_string()))?;
+    for (name, state) in states {
+        if state.get("states").is_some() {
+            return Err(unsupported(format!("state {name} has nested states")));
+        }
+        descriptor = descriptor.state(name);
+        let on = match state.get("on") {
+            Some(on) => on
+                .as_object()
+                .ok_or_else(|| unsupported(format!("state {name} expected an object for on")))?,
+            None => continue,
+        };
+        for (event, spec) in on {
+            let specs = match spec {
+                Json::Array(items) => items.as_slice(),
+                spec => std::slice::from_ref(spec),
+            };
+            for spec in specs {
+                descriptor = transition(descriptor, id, name, event, spec)?;
+            }
+        }
+    }
+    Ok(descriptor)
+}
+
+fn transition(
+    descriptor: MachineDescriptor,
+    id: &str,
+    from: &str,
+    event: &str,
+    spec: &Json,
+) -> Result<MachineDescriptor, XstateError> {
+    let (target, guard, actions) = match spec {
+        Json::String(target) => (Some(target.as_str()), None, None),
+        Json::Object(_) => (
+            spec.get("target").and_then(Json::as_str),
+            spec.get("guard").or_else(|| spec.get("cond")),
+            spec.get("actions"),
+        ),
+        _ => {
+            return Err(unsupported(format!(
+                "state {from} has an invalid transition for {event}"
+            )))
+        }
+    };
+    let to = match target {
+        Some(target) => target_name(id, target),
+        None => from,
+    };
+    let mut descriptor = descriptor.transition(from, event, event, to);
+    if let Some(guard) = guard {
+        descriptor = descriptor.guard(&named(guard, from)?);
+    }
+    match actions {
+        Some(Json::Array(actions)) => {
+            for action in actions {
+                descriptor = descriptor.action(&named(action, from)?);
+            }
+        }
+        Some(action) => descriptor = descriptor.action(&named(action, from)?),
+        None => {}
+    }
+    Ok(descriptor)
+}
+
+/// Export a machine definition as xstate JSON.
+pub fn to_xstate(descriptor: &MachineDescriptor) -> String {
+    let mut machine = vec![("id".to_string(), string(&descriptor.name))];
+    if let Some(initial) = descriptor.initial.as_ref().or(descriptor.states.first()) {
+        machine.push(("initial".to_string(), string(initial)));
+    }
+    let states = descriptor
+        .states
+        .iter()
+        .map(|state| {
+            let mut on: Vec<(String, Json)> = Vec::new();
+            for t in descriptor.transitions.iter().filter(|t| t.from == *state) {
+                let spec = if t.guard.is_none() && t.actions.is_empty() {
+                    string(&t.to)
+                } else {
+                    let mut spec = vec![("target".to_string(), string(&t.to))];
+                    if let Some(guard) = &t.guard {
+                        spec.push(("guard".to_string(), string(guard)));
+                    }
+                    if !t.actions.is_empty() {
+                        let actions = t.actions.iter().map(|a| string(a)).collect();
+                        spec.push(("actions".to_string(), Json::Array(actions)));
+                    }
+                    Json::Object(spec)
+                };
+                match on.iter_mut().find(|(command, _)| *command == t.command) {
+                    Some((_, Json::Array(specs))) => specs.push(spec),
+                    Some((_, first)) => *first = Json::Array(vec![first.clone(), spec]),
+                    None => on.push((t.command.clone(), spec)),
+                }
+            }
+            let state_json = if on.is_empty() {
+                Json::Object(Vec::new())
+            } else {
+                Json::Object(vec![("on".to_string(), Json::Object(on))])
+            };
+            (state.clone(), state_json)
+        })
+        .collect();
+    machine.push(("states".to_string(), Json::Object(states)));
+    Json::Object(machine).to_string()
+}
+
+fn string(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+/// The target state, without any reference to the machine by id.
+fn target_name<'a>(id: &str, target: &'a str) -> &'a str {
+    target
+        .strip_prefix('#')
+        .and_then(|t| t.strip_prefix(id))
+        .and_then(|t| t.strip_prefix('.'))
+        .or_else(|| target.strip_prefix('.'))
+        .unwrap_or(target)
+}
+
+/// The name of a guard or action, written as a string or an object with a type.
+fn named(json: &Json, state: &str) -> Result<String, XstateError> {
+    json.as_str()
+        .or_else(|| json.get("type").and_then(Json::as_str))
+        .map(String::from)
+        .ok_or_else(|| unsupported(format!("state {state} has an unnamed guard or action")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_and_event_traits::{Fsm, Transition};
+    use crate::table::{Table, TableCommand, TableFsm};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_from_xstate() {
+        let descriptor = from_xstate(
+            r##"{
+                "id": "door",
+                "initial": "closed",
+                "states": {
+                    "closed": {
+                        "on": {
+                            "OPEN": [
+                                { "target": "open", "guard": "unlocked", "actions": ["chime"] },
+                                { "actions": { "type": "buzz" } }
+                            ]
+                        }
+                    },
+                    "open": { "on": { "CLOSE": "#door.closed" } },
+                    "broken": { "type": "final" }
+                }
+            }"##,
+        )
+        .unwrap();
+        assert_eq!(descriptor.name, "door");
+        assert_eq!(descriptor.initial.as_deref(), Some("closed"));
+        assert_eq!(descriptor.states, vec!["closed", "open", "broken"]);
+        assert_eq!(descriptor.transitions.len(), 3);
+        assert_eq!(descriptor.transitions[2].to, "closed");
+
+        // Bind the guard and actions, then run the machine
+
+        let unlocked = Rc::new(RefCell::new(false));
+        let performed = Rc::new(RefCell::new(Vec::new()));
+        let (u, p1, p2) = (unlocked.clone(), performed.clone(), performed.clone());
+        let mut table = Table::new(descriptor)
+            .with_guard("unlocked", move |_| *u.borrow())
+            .with_action("chime", move |_, _| p1.borrow_mut().push("chime"))
+            .with_action("buzz", move |_, _| p2.borrow_mut().push("buzz"));
+
+        let open = TableCommand("OPEN".to_string());
+        let (_, t) = TableFsm::step(&"closed".to_string(), &open, &mut table);
+        assert_eq!(t, Ok(Transition::Same));
+
+        *unlocked.borrow_mut() = true;
+        let (_, t) = TableFsm::step(&"closed".to_string(), &open, &mut table);
+        assert_eq!(t, Ok(Transition::Next("open".to_string())));
+        assert_eq!(*performed.borrow(), vec!["buzz", "chime"]);
+
+        assert_eq!(
+            from_xstate(r#"{"states": {"a": {"states": {}}}}"#),
+            Err(XstateError::Unsupported(
+                "state a has nested states".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_to_xstate() {
+        let descriptor = MachineDescriptor::new("door")
+            .initial("closed")
+            .transition("closed", "OPEN", "OPEN", "open")
+            .guard("unlocked")
+            .action("chime")
+            .transition("closed", "OPEN", "OPEN", "closed")
+            .transition("open", "CLOSE", "CLOSE", "closed")
+            .state("broken");
+
+        let json = to_xstate(&descriptor);
+        assert_eq!(
+            json,
+            r#"{"id":"door","initial":"closed","states":{"closed":{"on":{"OPEN":[{"target":"open","guard":"unlocked","actions":["chime"]},"closed"]}},"open":{"on":{"CLOSE":"closed"}},"broken":{}}}"#
+        );
+        assert_eq!(from_xstate(&json), Ok(descriptor));
+    }
+}